@@ -1,4 +1,4 @@
-use depth_estimate::{DepthEstimate, DepthEstimateConfig, midas::transforms::MidasTransform};
+use depth_estimate::{DepthEstimate, DepthEstimateConfig, model::MidasSmall};
 use opencv::imgcodecs::{self, IMREAD_ANYCOLOR};
 use std::path::PathBuf;
 //
@@ -15,15 +15,7 @@ fn main() {
         1,
         PathBuf::from("model-small.onnx"),
     );
-    let mut estimate = DepthEstimate::new(
-        config,
-        Box::new(MidasTransform {
-            target_height: 384,
-            target_width: 384,
-            mean: opencv::core::Scalar::from_array([0.485, 0.456, 0.406, 0.0]),
-            std: opencv::core::Scalar::from_array([0.299, 0.224, 0.225, 0.0]),
-        }),
-    )
-    .unwrap();
-    let outputs = estimate.estimate(image).unwrap();
+    let mut estimate = DepthEstimate::new(config, Box::new(MidasSmall)).unwrap();
+    let depth = estimate.estimate(image).unwrap();
+    depth.write_image("depth_map.png").unwrap();
 }
@@ -1,15 +1,20 @@
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 pub mod midas;
+pub mod model;
+pub mod yolo;
+
+pub use model::{DepthKind, DepthModel};
 
 use opencv::{
-    core::{CV_32FC1, Mat, Mat_, MatTraitConst, Scalar, Vector, no_array},
+    core::{CV_8U, CV_32FC1, Mat, MatTraitConst, MatTraitManual, Scalar, Size, Vector, no_array},
     imgcodecs,
-    imgproc::{self, COLOR_BGR2RGB, COLOR_BGRA2RGB},
+    imgproc::{self, COLOR_BGR2RGB, COLOR_BGRA2RGB, INTER_LINEAR},
 };
 use ort::{
     session::{Session, SessionOutputs, builder::GraphOptimizationLevel},
     value::Value,
 };
+use yolo::Letterbox;
 
 #[derive(Debug, thiserror::Error)]
 pub enum EstimateError {
@@ -21,39 +26,107 @@ pub enum EstimateError {
     ConversionError,
     #[error("Transforms Error")]
     TransForm(#[from] midas::transforms::TransformError),
+    #[error("Unexpected model output: {0}")]
+    UnexpectedOutput(String),
+    #[error("Unsupported model: {0}")]
+    UnsupportedModel(String),
+    #[error("Failed to write image to {0}")]
+    ImageWrite(PathBuf),
 }
 
 pub struct DepthEstimate {
     model: Session,
-    transform: Box<dyn midas::transforms::ImageTransform>,
+    depth_model: Box<dyn model::DepthModel>,
+    input_width: i32,
+    input_height: i32,
 }
 
 impl DepthEstimate {
     pub fn new(
         config: DepthEstimateConfig,
-        transform: Box<dyn midas::transforms::ImageTransform>,
+        depth_model: Box<dyn model::DepthModel>,
     ) -> Result<Self, EstimateError> {
-        let mut model = Session::builder()?
+        let model = Session::builder()?
             .with_optimization_level(config.optimization_level)?
             .with_intra_threads(config.intra_threads)?
             .commit_from_file(config.file_path.clone())?;
-        Ok(Self { model, transform })
+        let (input_width, input_height) = input_size(&model, depth_model.as_ref())?;
+        check_output(&model)?;
+        Ok(Self {
+            model,
+            depth_model,
+            input_width,
+            input_height,
+        })
+    }
+
+    /// Network input size as (width, height).
+    pub fn input_size(&self) -> (i32, i32) {
+        (self.input_width, self.input_height)
+    }
+
+    pub fn output_kind(&self) -> model::DepthKind {
+        self.depth_model.output_kind()
     }
-    // should be model agnostic in future
+
+    /// Predicts depth for a BGR or BGRA image, at the image's resolution.
     #[inline]
-    pub fn estimate(&mut self, image: Mat) -> Result<Mat, EstimateError> {
-        let transformed_image = self.transform.apply(image.clone()).map_err(|e| {
-            EstimateError::TransForm(midas::transforms::TransformError::OpenCV(
-                opencv::Error::new(0, e.to_string()),
-            ))
-        })?;
-        let input_tensor_values = preprocess_mat_to_ort_tensor(&transformed_image, 384, 384)?;
-
-        let shape = [1, 3, 384, 384];
+    pub fn estimate(&mut self, image: Mat) -> Result<DepthMap, EstimateError> {
+        let (mean, std) = self.depth_model.normalization();
+        let (input_tensor_values, letterbox) =
+            preprocess_mat_to_ort_tensor(&image, self.input_height, self.input_width, mean, std)?;
+
+        let shape = [1, 3, self.input_height as usize, self.input_width as usize];
         let input_tensor = Value::from_array((shape, input_tensor_values))?;
 
-        let mut outputs = self.model.run(ort::inputs![input_tensor])?;
-        save_depth_map(&mut outputs)
+        let outputs = self.model.run(ort::inputs![input_tensor])?;
+        let prediction = prediction_to_mat(&outputs)?;
+        let depth = self.depth_model.postprocess(prediction, letterbox.scale)?;
+        Ok(DepthMap {
+            depth: remove_letterbox(&depth, &letterbox, self.input_width, self.input_height)?,
+            kind: self.depth_model.output_kind(),
+        })
+    }
+}
+
+/// A depth prediction at the resolution of the image it was made from.
+#[derive(Debug, Clone)]
+pub struct DepthMap {
+    /// CV_32FC1 with the network's raw values, see `kind`.
+    pub depth: Mat,
+    pub kind: model::DepthKind,
+}
+
+impl DepthMap {
+    /// 8-bit grayscale rendering for inspection, stretched to the full
+    /// range with near bright whichever `kind` the values are.
+    pub fn to_image(&self) -> Result<Mat, EstimateError> {
+        let mut normalized_depth = Mat::default();
+        opencv::core::normalize(
+            &self.depth,
+            &mut normalized_depth,
+            0.0,
+            1.0,
+            opencv::core::NORM_MINMAX,
+            -1,
+            &no_array(),
+        )?;
+        let (alpha, beta) = match self.kind {
+            model::DepthKind::InverseRelative => (255.0, 0.0),
+            model::DepthKind::Metric => (-255.0, 255.0),
+        };
+        let mut image = Mat::default();
+        normalized_depth.convert_to(&mut image, CV_8U, alpha, beta)?;
+        Ok(image)
+    }
+
+    /// Writes `to_image` to `path`, in the format its extension names.
+    pub fn write_image(&self, path: impl AsRef<Path>) -> Result<(), EstimateError> {
+        let path = path.as_ref();
+        if !imgcodecs::imwrite(&path.to_string_lossy(), &self.to_image()?, &Vector::new())? {
+            return Err(EstimateError::ImageWrite(path.to_path_buf()));
+        }
+        Ok(())
     }
 }
 
@@ -77,11 +150,107 @@ impl DepthEstimateConfig {
     }
 }
 
+/// Network input size from the session's input shape, falling back to the
+/// model's for dynamic dimensions.
+fn input_size(
+    session: &Session,
+    depth_model: &dyn model::DepthModel,
+) -> Result<(i32, i32), EstimateError> {
+    let shape = session
+        .inputs
+        .first()
+        .and_then(|input| input.input_type.tensor_shape())
+        .ok_or_else(|| EstimateError::UnsupportedModel("model has no tensor input".to_string()))?;
+    network_input_size(shape, depth_model.input_size(), depth_model.size_multiple())
+}
+
+/// Input size as (width, height) for an input of `shape`, `[batch, 3,
+/// height, width]`. Dynamic dimensions, negative in ONNX, take the
+/// corresponding one of `default_size` rounded up to a multiple of
+/// `multiple`.
+fn network_input_size(
+    shape: &[i64],
+    default_size: (i32, i32),
+    multiple: i32,
+) -> Result<(i32, i32), EstimateError> {
+    let &[_, 3, height, width] = shape else {
+        return Err(EstimateError::UnsupportedModel(format!(
+            "expected an input of shape [batch, 3, height, width], got {shape:?}"
+        )));
+    };
+
+    let (default_width, default_height) = default_size;
+    let multiple = multiple.max(1);
+    let round_up = |size: i32| (size + multiple - 1) / multiple * multiple;
+    let width = if width > 0 {
+        width as i32
+    } else {
+        round_up(default_width)
+    };
+    let height = if height > 0 {
+        height as i32
+    } else {
+        round_up(default_height)
+    };
+    Ok((width, height))
+}
+
+fn check_output(session: &Session) -> Result<(), EstimateError> {
+    let shape = session
+        .outputs
+        .first()
+        .and_then(|output| output.output_type.tensor_shape())
+        .ok_or_else(|| EstimateError::UnsupportedModel("model has no tensor output".to_string()))?;
+    check_output_shape(shape)
+}
+
+/// Depth outputs are `[height, width]` with optional batch and channel
+/// dimensions.
+fn check_output_shape(shape: &[i64]) -> Result<(), EstimateError> {
+    if !(2..=4).contains(&shape.len()) {
+        return Err(EstimateError::UnsupportedModel(format!(
+            "expected a depth output of shape [height, width] with optional batch and channel dimensions, got {shape:?}"
+        )));
+    }
+    Ok(())
+}
+
+/// The first output as a CV_32FC1 map. Leading batch and channel dimensions
+/// must be one.
+fn prediction_to_mat(outputs: &SessionOutputs) -> Result<Mat, EstimateError> {
+    let output_tensor = outputs[0].try_extract_array::<f32>()?;
+    let output_shape = output_tensor.shape();
+    let (leading, spatial) = output_shape.split_at(output_shape.len().saturating_sub(2));
+    let &[height, width] = spatial else {
+        return Err(EstimateError::UnexpectedOutput(format!(
+            "expected a depth map, got shape {output_shape:?}"
+        )));
+    };
+    if leading.iter().any(|&d| d != 1) {
+        return Err(EstimateError::UnexpectedOutput(format!(
+            "expected a single depth map, got shape {output_shape:?}"
+        )));
+    }
+
+    let mut depth_map =
+        Mat::new_rows_cols_with_default(height as i32, width as i32, CV_32FC1, Scalar::all(0.0))?;
+    for (value, &prediction) in depth_map
+        .data_typed_mut::<f32>()?
+        .iter_mut()
+        .zip(output_tensor.iter())
+    {
+        *value = prediction;
+    }
+    Ok(depth_map)
+}
+
 fn preprocess_mat_to_ort_tensor(
     input_image: &Mat,
     target_height: i32,
     target_width: i32,
-) -> Result<Vec<f32>, EstimateError> {
+    mean: [f64; 3],
+    std: [f64; 3],
+) -> Result<(Vec<f32>, Letterbox), EstimateError> {
     let mut rgb_image = Mat::default();
     match input_image.channels() {
         4 => {
@@ -93,20 +262,19 @@ fn preprocess_mat_to_ort_tensor(
         _ => return Err(EstimateError::ConversionError),
     }
 
-    let original_height = rgb_image.rows();
-    let original_width = rgb_image.cols();
-    let aspect_ratio = original_width as f32 / original_height as f32;
-    let (new_width, new_height) = if aspect_ratio > 1.0 {
-        (target_width, (target_width as f32 / aspect_ratio) as i32)
-    } else {
-        ((target_height as f32 * aspect_ratio) as i32, target_height)
-    };
+    let letterbox = Letterbox::new(
+        rgb_image.cols(),
+        rgb_image.rows(),
+        target_width,
+        target_height,
+    );
+    let (new_width, new_height) = letterbox.scaled_size();
 
     let resized_image = midas::transforms::resize_image(rgb_image, new_height, new_width)?;
     let padded_image = midas::transforms::pad_image(resized_image, target_height, target_width)?;
 
-    let mean = opencv::core::Scalar::from_array([0.485, 0.456, 0.406, 0.0]);
-    let std = opencv::core::Scalar::from_array([0.299, 0.224, 0.225, 0.0]);
+    let mean = opencv::core::Scalar::from_array([mean[0], mean[1], mean[2], 0.0]);
+    let std = opencv::core::Scalar::from_array([std[0], std[1], std[2], 1.0]);
     let normalized_image = midas::transforms::normalize(padded_image, mean, std)?;
 
     let mut input_tensor_values =
@@ -124,35 +292,125 @@ fn preprocess_mat_to_ort_tensor(
         }
     }
 
-    Ok(input_tensor_values)
+    Ok((input_tensor_values, letterbox))
 }
 
-fn save_depth_map(outputs: &mut SessionOutputs) -> Result<Mat, EstimateError> {
-    // Process output (example: save depth map)
-    let output_tensor = outputs[0].try_extract_array_mut::<f32>()?;
-    let output_shape = output_tensor.shape();
-    println!("Output tensor shape: {:?}", output_shape);
-
-    // Convert output to depth map and save
-    let output_data = output_tensor.view();
-    let mut depth_mat = Mat::new_rows_cols_with_default(386, 386, CV_32FC1, Scalar::all(0.0))?;
-    let mut depth_mat_ = Mat_::try_from(depth_mat)?;
-    for h in 0..386 {
-        for w in 0..386 {
-            depth_mat_.at_row_mut(h)?[w as usize] = output_tensor[[0, h as usize, w as usize]];
-        }
-    }
-    let depth_map: Mat = depth_mat_.into();
-    let mut normalized_depth = Mat::default();
-    opencv::core::normalize(
-        &depth_map,
-        &mut normalized_depth,
+/// Crops the padding off a map at network output resolution and resizes
+/// what is left to the original image.
+fn remove_letterbox(
+    depth_map: &Mat,
+    letterbox: &Letterbox,
+    input_width: i32,
+    input_height: i32,
+) -> Result<Mat, EstimateError> {
+    let content = letterbox.content(
+        depth_map.cols(),
+        depth_map.rows(),
+        input_width,
+        input_height,
+    );
+    let content = Mat::roi(depth_map, content)?;
+    let mut depth = Mat::default();
+    imgproc::resize(
+        &content,
+        &mut depth,
+        Size::new(letterbox.width, letterbox.height),
+        0.0,
         0.0,
-        255.0,
-        opencv::core::NORM_MINMAX,
-        -1,
-        &no_array(),
+        INTER_LINEAR,
     )?;
-    imgcodecs::imwrite("depth_map.png", &normalized_depth, &Vector::new())?;
-    Ok(normalized_depth)
+    Ok(depth)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use opencv::core::MatTrait;
+
+    /// A network resolution map of `size` holding `value` where a letterboxed
+    /// image landed and a far off value on the padding.
+    fn letterboxed(letterbox: &Letterbox, size: i32, input_size: i32, value: f32) -> Mat {
+        let mut map =
+            Mat::new_rows_cols_with_default(size, size, CV_32FC1, Scalar::all(1000.0)).unwrap();
+        let content = letterbox.content(size, size, input_size, input_size);
+        Mat::roi_mut(&mut map, content)
+            .unwrap()
+            .set_to(&Scalar::all(value as f64), &no_array())
+            .unwrap();
+        map
+    }
+
+    fn min_max(map: &Mat) -> (f64, f64) {
+        let (mut min, mut max) = (0.0, 0.0);
+        opencv::core::min_max_loc(map, Some(&mut min), Some(&mut max), None, None, &no_array())
+            .unwrap();
+        (min, max)
+    }
+
+    #[test]
+    fn test_remove_letterbox_restores_image_size() {
+        // Wide and tall images into a square input, with outputs at the
+        // input resolution and at half of it.
+        for (width, height) in [(40, 20), (20, 40)] {
+            let letterbox = Letterbox::new(width, height, 32, 32);
+            for size in [32, 16] {
+                let map = letterboxed(&letterbox, size, 32, 2.5);
+                let depth = remove_letterbox(&map, &letterbox, 32, 32).unwrap();
+                assert_eq!(depth.size().unwrap(), Size::new(width, height));
+                assert_eq!(depth.typ(), CV_32FC1);
+                // None of the padding is left.
+                let (min, max) = min_max(&depth);
+                assert!((min - 2.5).abs() < 1e-6 && (max - 2.5).abs() < 1e-6);
+            }
+        }
+    }
+
+    #[test]
+    fn test_network_input_size() {
+        // Fixed exports keep their own size.
+        assert_eq!(
+            network_input_size(&[1, 3, 384, 512], (518, 518), 14).unwrap(),
+            (512, 384)
+        );
+        // Dynamic dimensions take the model's, rounded up to the stride.
+        assert_eq!(
+            network_input_size(&[-1, 3, -1, -1], (518, 518), 14).unwrap(),
+            (518, 518)
+        );
+        assert_eq!(
+            network_input_size(&[1, 3, -1, -1], (500, 300), 14).unwrap(),
+            (504, 308)
+        );
+        assert_eq!(
+            network_input_size(&[1, 3, 480, -1], (630, 300), 32).unwrap(),
+            (640, 480)
+        );
+        assert_eq!(
+            network_input_size(&[1, 3, -1, -1], (500, 300), 0).unwrap(),
+            (500, 300)
+        );
+    }
+
+    #[test]
+    fn test_network_input_size_rejects_other_shapes() {
+        for shape in [&[3, 384, 384][..], &[1, 1, 384, 384], &[1, 3, 384, 384, 1]] {
+            assert!(matches!(
+                network_input_size(shape, (384, 384), 32),
+                Err(EstimateError::UnsupportedModel(_))
+            ));
+        }
+    }
+
+    #[test]
+    fn test_check_output_shape() {
+        for shape in [&[384, 384][..], &[-1, 384, 384], &[1, 1, -1, -1]] {
+            assert!(check_output_shape(shape).is_ok());
+        }
+        for shape in [&[384][..], &[1, 1, 1, 384, 384]] {
+            assert!(matches!(
+                check_output_shape(shape),
+                Err(EstimateError::UnsupportedModel(_))
+            ));
+        }
+    }
 }
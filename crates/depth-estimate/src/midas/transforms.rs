@@ -67,10 +67,12 @@ pub fn normalize(
     Ok(processed_image)
 }
 
+#[deprecated(note = "preprocessing is described by `DepthModel` and done by `DepthEstimate`")]
 pub trait ImageTransform {
     fn apply(&self, image: Mat) -> Result<Mat, TransformError>;
 }
 
+#[deprecated(note = "use `MidasSmall` or another `DepthModel` with `DepthEstimate`")]
 pub struct MidasTransform {
     pub target_height: i32,
     pub target_width: i32,
@@ -78,6 +80,7 @@ pub struct MidasTransform {
     pub std: opencv::core::Scalar,
 }
 
+#[allow(deprecated)]
 impl ImageTransform for MidasTransform {
     fn apply(&self, image: Mat) -> Result<Mat, TransformError> {
         let resized = resize_image(image, self.target_height, self.target_width)?;
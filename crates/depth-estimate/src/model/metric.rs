@@ -0,0 +1,76 @@
+use opencv::core::{Mat, MatTraitConst};
+
+use super::{DepthKind, DepthModel, IMAGENET_MEAN, IMAGENET_STD};
+use crate::EstimateError;
+
+/// ZoeDepth, which predicts metres directly.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct ZoeDepth;
+
+impl DepthModel for ZoeDepth {
+    fn input_size(&self) -> (i32, i32) {
+        (512, 384)
+    }
+
+    fn size_multiple(&self) -> i32 {
+        32
+    }
+
+    fn normalization(&self) -> ([f64; 3], [f64; 3]) {
+        ([0.5; 3], [0.5; 3])
+    }
+
+    fn output_kind(&self) -> DepthKind {
+        DepthKind::Metric
+    }
+}
+
+/// Metric3D v2 ViT models.
+///
+/// They predict depth for a canonical camera with a focal length of 1000
+/// pixels, which is rescaled to the real camera's.
+#[derive(Debug, Clone, Copy)]
+pub struct Metric3D {
+    /// Focal length of the camera, in pixels of the images given to
+    /// `DepthEstimate::estimate`.
+    pub focal_length: f64,
+}
+
+impl Metric3D {
+    const CANONICAL_FOCAL_LENGTH: f64 = 1000.0;
+
+    pub fn new(focal_length: f64) -> Self {
+        Self { focal_length }
+    }
+}
+
+impl DepthModel for Metric3D {
+    fn input_size(&self) -> (i32, i32) {
+        (1064, 616)
+    }
+
+    fn size_multiple(&self) -> i32 {
+        14
+    }
+
+    fn normalization(&self) -> ([f64; 3], [f64; 3]) {
+        (IMAGENET_MEAN, IMAGENET_STD)
+    }
+
+    fn output_kind(&self) -> DepthKind {
+        DepthKind::Metric
+    }
+
+    fn postprocess(&self, prediction: Mat, scale: f32) -> Result<Mat, EstimateError> {
+        // The focal length the network saw, after resizing.
+        let focal_length = self.focal_length * scale as f64;
+        let mut depth = Mat::default();
+        prediction.convert_to(
+            &mut depth,
+            -1,
+            focal_length / Self::CANONICAL_FOCAL_LENGTH,
+            0.0,
+        )?;
+        Ok(depth)
+    }
+}
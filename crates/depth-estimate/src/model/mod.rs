@@ -0,0 +1,53 @@
+pub mod metric;
+pub mod relative;
+
+use opencv::core::Mat;
+
+pub use metric::{Metric3D, ZoeDepth};
+pub use relative::{DepthAnythingV1, DepthAnythingV2, DptHybrid, DptLarge, MidasSmall};
+
+use crate::EstimateError;
+
+/// ImageNet channel statistics, used by most depth networks.
+pub const IMAGENET_MEAN: [f64; 3] = [0.485, 0.456, 0.406];
+pub const IMAGENET_STD: [f64; 3] = [0.229, 0.224, 0.225];
+
+/// What the values a depth network predicts mean.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DepthKind {
+    /// Inverse depth up to an unknown scale and shift: larger is closer and
+    /// only the ordering is meaningful without alignment.
+    InverseRelative,
+    /// Depth in metres.
+    Metric,
+}
+
+/// Describes how to feed a monocular depth network exported to ONNX and how
+/// to read its prediction.
+///
+/// Images are letterboxed into the network input: resized to fit without
+/// distortion, centred and padded.
+pub trait DepthModel: Send {
+    /// Network input size as (width, height). Only used for exports with
+    /// dynamic spatial dimensions, fixed exports use their own.
+    fn input_size(&self) -> (i32, i32);
+
+    /// Dynamic input dimensions are rounded up to a multiple of this, e.g.
+    /// the patch size of a vision transformer.
+    fn size_multiple(&self) -> i32 {
+        1
+    }
+
+    /// Per channel RGB mean and standard deviation, for values in [0, 1].
+    fn normalization(&self) -> ([f64; 3], [f64; 3]);
+
+    fn output_kind(&self) -> DepthKind;
+
+    /// Turns the raw prediction, a CV_32FC1 map at network resolution, into
+    /// depth of `output_kind`. `scale` is how many network input pixels one
+    /// image pixel became.
+    fn postprocess(&self, prediction: Mat, scale: f32) -> Result<Mat, EstimateError> {
+        let _ = scale;
+        Ok(prediction)
+    }
+}
@@ -0,0 +1,93 @@
+use super::{DepthKind, DepthModel, IMAGENET_MEAN, IMAGENET_STD};
+
+/// MiDaS v2.1 small, `midas_v21_small_256`.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct MidasSmall;
+
+impl DepthModel for MidasSmall {
+    fn input_size(&self) -> (i32, i32) {
+        (256, 256)
+    }
+
+    fn size_multiple(&self) -> i32 {
+        32
+    }
+
+    fn normalization(&self) -> ([f64; 3], [f64; 3]) {
+        (IMAGENET_MEAN, IMAGENET_STD)
+    }
+
+    fn output_kind(&self) -> DepthKind {
+        DepthKind::InverseRelative
+    }
+}
+
+/// MiDaS v3 DPT with a hybrid ViT-ResNet backbone, `dpt_hybrid_384`.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct DptHybrid;
+
+impl DepthModel for DptHybrid {
+    fn input_size(&self) -> (i32, i32) {
+        (384, 384)
+    }
+
+    fn size_multiple(&self) -> i32 {
+        32
+    }
+
+    fn normalization(&self) -> ([f64; 3], [f64; 3]) {
+        ([0.5; 3], [0.5; 3])
+    }
+
+    fn output_kind(&self) -> DepthKind {
+        DepthKind::InverseRelative
+    }
+}
+
+/// MiDaS v3 DPT with a ViT-Large backbone, `dpt_large_384`.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct DptLarge;
+
+impl DepthModel for DptLarge {
+    fn input_size(&self) -> (i32, i32) {
+        (384, 384)
+    }
+
+    fn size_multiple(&self) -> i32 {
+        32
+    }
+
+    fn normalization(&self) -> ([f64; 3], [f64; 3]) {
+        ([0.5; 3], [0.5; 3])
+    }
+
+    fn output_kind(&self) -> DepthKind {
+        DepthKind::InverseRelative
+    }
+}
+
+/// Depth Anything, any of the small, base or large relative depth exports.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct DepthAnythingV1;
+
+impl DepthModel for DepthAnythingV1 {
+    fn input_size(&self) -> (i32, i32) {
+        (518, 518)
+    }
+
+    fn size_multiple(&self) -> i32 {
+        14
+    }
+
+    fn normalization(&self) -> ([f64; 3], [f64; 3]) {
+        (IMAGENET_MEAN, IMAGENET_STD)
+    }
+
+    fn output_kind(&self) -> DepthKind {
+        DepthKind::InverseRelative
+    }
+}
+
+/// Depth Anything V2 relative depth exports, fed exactly like the first
+/// version.
+pub type DepthAnythingV2 = DepthAnythingV1;
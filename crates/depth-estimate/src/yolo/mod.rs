@@ -0,0 +1,273 @@
+pub mod postprocess;
+pub mod segmentation;
+pub mod tracker;
+
+use std::collections::HashSet;
+use std::path::PathBuf;
+
+use opencv::{
+    core::{
+        BORDER_CONSTANT, CV_32FC3, Mat, MatTraitConst, MatTraitConstManual, Scalar, Size, Vec3f,
+    },
+    imgproc::{self, COLOR_BGR2RGB, COLOR_BGRA2RGB, COLOR_GRAY2RGB, INTER_LINEAR},
+};
+use ort::{
+    session::{Session, builder::GraphOptimizationLevel},
+    value::Value,
+};
+
+pub use postprocess::{Detection, Letterbox};
+pub use segmentation::{InstanceMask, exclusion_mask};
+pub use tracker::{ObjectTracker, TrackSummary, TrackedObject, TrackerConfig};
+
+use crate::EstimateError;
+use segmentation::Prototypes;
+
+/// COCO classes that move on their own: person, bicycle, car, motorcycle,
+/// airplane, bus, train, truck, boat and the animals.
+pub const COCO_DYNAMIC_CLASSES: [usize; 18] = [
+    0, 1, 2, 3, 4, 5, 6, 7, 8, 14, 15, 16, 17, 18, 19, 20, 21, 22,
+];
+
+/// Letterbox padding value used when training YOLOv8.
+const PAD_VALUE: f64 = 114.0;
+
+pub struct ObjectDetectorConfig {
+    optimization_level: GraphOptimizationLevel,
+    intra_threads: usize,
+    file_path: PathBuf,
+    input_width: i32,
+    input_height: i32,
+    confidence_threshold: f32,
+    iou_threshold: f32,
+    dynamic_classes: HashSet<usize>,
+}
+
+impl ObjectDetectorConfig {
+    /// A 640x640 COCO model, keeping boxes scoring at least 0.25 and
+    /// suppressing overlaps above 0.45 IoU.
+    pub fn new(
+        optimization_level: GraphOptimizationLevel,
+        intra_threads: usize,
+        file_path: PathBuf,
+    ) -> Self {
+        Self {
+            optimization_level,
+            intra_threads,
+            file_path,
+            input_width: 640,
+            input_height: 640,
+            confidence_threshold: 0.25,
+            iou_threshold: 0.45,
+            dynamic_classes: COCO_DYNAMIC_CLASSES.into_iter().collect(),
+        }
+    }
+
+    /// Size the model was exported with.
+    pub fn with_input_size(mut self, width: i32, height: i32) -> Self {
+        self.input_width = width;
+        self.input_height = height;
+        self
+    }
+
+    pub fn with_confidence_threshold(mut self, threshold: f32) -> Self {
+        self.confidence_threshold = threshold;
+        self
+    }
+
+    pub fn with_iou_threshold(mut self, threshold: f32) -> Self {
+        self.iou_threshold = threshold;
+        self
+    }
+
+    /// Class ids counted as dynamic, replacing the COCO defaults.
+    pub fn with_dynamic_classes(mut self, classes: impl IntoIterator<Item = usize>) -> Self {
+        self.dynamic_classes = classes.into_iter().collect();
+        self
+    }
+}
+
+/// Runs YOLOv8 detection and instance segmentation models exported to ONNX.
+pub struct ObjectDetector {
+    model: Session,
+    config: ObjectDetectorConfig,
+}
+
+impl ObjectDetector {
+    pub fn new(config: ObjectDetectorConfig) -> Result<Self, EstimateError> {
+        let model = Session::builder()?
+            .with_optimization_level(config.optimization_level)?
+            .with_intra_threads(config.intra_threads)?
+            .commit_from_file(config.file_path.clone())?;
+        Ok(Self { model, config })
+    }
+
+    /// Detects objects in a BGR, BGRA or grayscale image. Boxes are in the
+    /// pixels of `image`, best first.
+    pub fn detect(&mut self, image: &Mat) -> Result<Vec<Detection>, EstimateError> {
+        Ok(self
+            .run(image, false)?
+            .into_iter()
+            .map(|instance| instance.detection)
+            .collect())
+    }
+
+    /// Like `detect`, with a mask of each object at the resolution of
+    /// `image`. Needs a segmentation model, e.g. a `yolov8n-seg` export.
+    pub fn segment(&mut self, image: &Mat) -> Result<Vec<InstanceMask>, EstimateError> {
+        self.run(image, true)
+    }
+
+    /// Only the detections of dynamic classes.
+    pub fn detect_dynamic(&mut self, image: &Mat) -> Result<Vec<Detection>, EstimateError> {
+        let mut detections = self.detect(image)?;
+        detections.retain(|detection| self.is_dynamic(detection));
+        Ok(detections)
+    }
+
+    /// Only the instances of dynamic classes.
+    pub fn segment_dynamic(&mut self, image: &Mat) -> Result<Vec<InstanceMask>, EstimateError> {
+        let mut instances = self.segment(image)?;
+        instances.retain(|instance| self.is_dynamic(&instance.detection));
+        Ok(instances)
+    }
+
+    pub fn is_dynamic(&self, detection: &Detection) -> bool {
+        self.config.dynamic_classes.contains(&detection.class_id)
+    }
+
+    /// Masks are left empty unless `with_masks` is set.
+    fn run(&mut self, image: &Mat, with_masks: bool) -> Result<Vec<InstanceMask>, EstimateError> {
+        let (input_width, input_height) = (self.config.input_width, self.config.input_height);
+        let (input, letterbox) = letterbox(image, input_width, input_height)?;
+        let shape = [1, 3, input_height as usize, input_width as usize];
+        let input_tensor = Value::from_array((shape, input))?;
+        let outputs = self.model.run(ort::inputs![input_tensor])?;
+
+        let predictions = outputs[0].try_extract_array::<f32>()?;
+        let (rows, columns) = match predictions.shape() {
+            &[1, rows, columns] => (rows, columns),
+            shape => {
+                return Err(EstimateError::UnexpectedOutput(format!(
+                    "expected predictions of shape [1, 4 + classes, anchors], got {shape:?}"
+                )));
+            }
+        };
+
+        // Segmentation models have a second output holding the prototypes,
+        // and one coefficient per prototype after the class scores.
+        let mut prototypes = None;
+        if outputs.len() > 1 {
+            let output = outputs[1].try_extract_array::<f32>()?;
+            let &[1, count, height, width] = output.shape() else {
+                return Err(EstimateError::UnexpectedOutput(format!(
+                    "expected prototypes of shape [1, count, height, width], got {:?}",
+                    output.shape()
+                )));
+            };
+            prototypes = Some(Prototypes {
+                data: if with_masks {
+                    output.iter().copied().collect()
+                } else {
+                    Vec::new()
+                },
+                count,
+                width: width as i32,
+                height: height as i32,
+            });
+        }
+        if with_masks && prototypes.is_none() {
+            return Err(EstimateError::UnexpectedOutput(
+                "model has no mask prototypes output".to_string(),
+            ));
+        }
+        let mask_coefficients = prototypes.as_ref().map_or(0, |p| p.count);
+
+        // Exports put the attributes first, but there are always far more
+        // anchors than classes, so a transposed export is easy to spot.
+        let (detections, coefficients): (Vec<Detection>, Vec<Vec<f32>>) = postprocess::decode(
+            rows,
+            columns,
+            rows > columns,
+            |row, column| predictions[[0, row, column]],
+            mask_coefficients,
+            self.config.confidence_threshold,
+        )
+        .into_iter()
+        .unzip();
+
+        let mut instances = Vec::new();
+        for i in postprocess::non_max_suppression(&detections, self.config.iou_threshold) {
+            let detection = Detection {
+                bbox: letterbox.unmap(detections[i].bbox),
+                ..detections[i]
+            };
+            if detection.bbox.width <= 0.0 || detection.bbox.height <= 0.0 {
+                continue;
+            }
+            let mask = match &prototypes {
+                Some(prototypes) if with_masks => prototypes.instance_mask(
+                    &coefficients[i],
+                    detection.rect(),
+                    &letterbox,
+                    input_width,
+                    input_height,
+                )?,
+                _ => Mat::default(),
+            };
+            instances.push(InstanceMask { detection, mask });
+        }
+        Ok(instances)
+    }
+}
+
+/// Resizes `image` to fit `width` x `height` without distorting it, pads the
+/// rest with grey and lays it out as a normalized RGB CHW tensor.
+fn letterbox(image: &Mat, width: i32, height: i32) -> Result<(Vec<f32>, Letterbox), EstimateError> {
+    let code = match image.channels() {
+        1 => COLOR_GRAY2RGB,
+        3 => COLOR_BGR2RGB,
+        4 => COLOR_BGRA2RGB,
+        _ => return Err(EstimateError::ConversionError),
+    };
+    let mut rgb_image = Mat::default();
+    imgproc::cvt_color(image, &mut rgb_image, code, 0)?;
+
+    let letterbox = Letterbox::new(rgb_image.cols(), rgb_image.rows(), width, height);
+    let (scaled_width, scaled_height) = letterbox.scaled_size();
+    let mut resized_image = Mat::default();
+    imgproc::resize(
+        &rgb_image,
+        &mut resized_image,
+        Size::new(scaled_width, scaled_height),
+        0.0,
+        0.0,
+        INTER_LINEAR,
+    )?;
+
+    let top = letterbox.pad_y as i32;
+    let left = letterbox.pad_x as i32;
+    let mut padded_image = Mat::default();
+    opencv::core::copy_make_border(
+        &resized_image,
+        &mut padded_image,
+        top,
+        height - scaled_height - top,
+        left,
+        width - scaled_width - left,
+        BORDER_CONSTANT,
+        Scalar::all(PAD_VALUE),
+    )?;
+
+    let mut float_image = Mat::default();
+    padded_image.convert_to(&mut float_image, CV_32FC3, 1.0 / 255.0, 0.0)?;
+
+    let plane = (width * height) as usize;
+    let mut input = vec![0.0f32; 3 * plane];
+    for (i, pixel) in float_image.data_typed::<Vec3f>()?.iter().enumerate() {
+        for c in 0..3 {
+            input[c * plane + i] = pixel[c];
+        }
+    }
+    Ok((input, letterbox))
+}
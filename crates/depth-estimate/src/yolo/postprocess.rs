@@ -0,0 +1,285 @@
+use opencv::core::{Rect, Rect2f};
+
+/// A detected object in original image coordinates.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Detection {
+    pub bbox: Rect2f,
+    pub class_id: usize,
+    pub score: f32,
+}
+
+impl Detection {
+    /// Smallest integer rectangle covering `bbox`.
+    pub fn rect(&self) -> Rect {
+        let x = self.bbox.x.floor();
+        let y = self.bbox.y.floor();
+        Rect::new(
+            x as i32,
+            y as i32,
+            ((self.bbox.x + self.bbox.width).ceil() - x) as i32,
+            ((self.bbox.y + self.bbox.height).ceil() - y) as i32,
+        )
+    }
+}
+
+/// How an image was scaled and padded into the network input, to map
+/// results back.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Letterbox {
+    pub scale: f32,
+    pub pad_x: f32,
+    pub pad_y: f32,
+    /// Original image size.
+    pub width: i32,
+    pub height: i32,
+}
+
+impl Letterbox {
+    /// Fits a `width` x `height` image into `input_width` x `input_height`
+    /// keeping its aspect ratio, centred.
+    pub fn new(width: i32, height: i32, input_width: i32, input_height: i32) -> Self {
+        let scale = (input_width as f32 / width as f32).min(input_height as f32 / height as f32);
+        let (scaled_width, scaled_height) = scaled_size(width, height, scale);
+        Self {
+            scale,
+            pad_x: ((input_width - scaled_width) / 2) as f32,
+            pad_y: ((input_height - scaled_height) / 2) as f32,
+            width,
+            height,
+        }
+    }
+
+    /// Size of the image inside the letterbox.
+    pub fn scaled_size(&self) -> (i32, i32) {
+        scaled_size(self.width, self.height, self.scale)
+    }
+
+    /// The part of a `width` x `height` map covering the network input that
+    /// holds the image rather than padding, e.g. of a lower resolution
+    /// network output.
+    pub fn content(&self, width: i32, height: i32, input_width: i32, input_height: i32) -> Rect {
+        let scale_x = width as f32 / input_width as f32;
+        let scale_y = height as f32 / input_height as f32;
+        let (scaled_width, scaled_height) = self.scaled_size();
+        Rect::new(
+            (self.pad_x * scale_x).round() as i32,
+            (self.pad_y * scale_y).round() as i32,
+            ((scaled_width as f32 * scale_x).round() as i32).max(1),
+            ((scaled_height as f32 * scale_y).round() as i32).max(1),
+        ) & Rect::new(0, 0, width, height)
+    }
+
+    /// Maps a box in network input coordinates back to the original image,
+    /// clipped to it.
+    pub fn unmap(&self, bbox: Rect2f) -> Rect2f {
+        let x1 = ((bbox.x - self.pad_x) / self.scale).clamp(0.0, self.width as f32);
+        let y1 = ((bbox.y - self.pad_y) / self.scale).clamp(0.0, self.height as f32);
+        let x2 = ((bbox.x + bbox.width - self.pad_x) / self.scale).clamp(0.0, self.width as f32);
+        let y2 = ((bbox.y + bbox.height - self.pad_y) / self.scale).clamp(0.0, self.height as f32);
+        Rect2f::new(x1, y1, x2 - x1, y2 - y1)
+    }
+}
+
+fn scaled_size(width: i32, height: i32, scale: f32) -> (i32, i32) {
+    (
+        ((width as f32 * scale).round() as i32).max(1),
+        ((height as f32 * scale).round() as i32).max(1),
+    )
+}
+
+/// Reads YOLOv8 predictions laid out as `[4 + classes + coefficients,
+/// anchors]` (or transposed) from `get(row, column)`: box centre and size, a
+/// score per class, then `mask_coefficients` prototype weights for
+/// segmentation models. Keeps the best class of each anchor scoring at least
+/// `confidence_threshold`, in network input coordinates, along with its mask
+/// coefficients.
+pub fn decode(
+    rows: usize,
+    columns: usize,
+    transposed: bool,
+    get: impl Fn(usize, usize) -> f32,
+    mask_coefficients: usize,
+    confidence_threshold: f32,
+) -> Vec<(Detection, Vec<f32>)> {
+    // Attributes along rows unless the model was exported transposed.
+    let (attributes, anchors) = if transposed {
+        (columns, rows)
+    } else {
+        (rows, columns)
+    };
+    let value = |attribute: usize, anchor: usize| {
+        if transposed {
+            get(anchor, attribute)
+        } else {
+            get(attribute, anchor)
+        }
+    };
+
+    let mut detections = Vec::new();
+    let Some(classes) = attributes.checked_sub(4 + mask_coefficients) else {
+        return detections;
+    };
+    if classes == 0 {
+        return detections;
+    }
+    for anchor in 0..anchors {
+        let (class_id, score) = (4..4 + classes).map(|a| (a - 4, value(a, anchor))).fold(
+            (0, f32::MIN),
+            |best, candidate| {
+                if candidate.1 > best.1 {
+                    candidate
+                } else {
+                    best
+                }
+            },
+        );
+        if score < confidence_threshold {
+            continue;
+        }
+        let (cx, cy) = (value(0, anchor), value(1, anchor));
+        let (w, h) = (value(2, anchor), value(3, anchor));
+        let detection = Detection {
+            bbox: Rect2f::new(cx - w / 2.0, cy - h / 2.0, w, h),
+            class_id,
+            score,
+        };
+        let coefficients = (0..mask_coefficients)
+            .map(|k| value(4 + classes + k, anchor))
+            .collect();
+        detections.push((detection, coefficients));
+    }
+    detections
+}
+
+pub fn iou(a: &Rect2f, b: &Rect2f) -> f32 {
+    let x1 = a.x.max(b.x);
+    let y1 = a.y.max(b.y);
+    let x2 = (a.x + a.width).min(b.x + b.width);
+    let y2 = (a.y + a.height).min(b.y + b.height);
+    let intersection = (x2 - x1).max(0.0) * (y2 - y1).max(0.0);
+    let union = a.width * a.height + b.width * b.height - intersection;
+    if union <= 0.0 {
+        0.0
+    } else {
+        intersection / union
+    }
+}
+
+/// Class-wise non-maximum suppression: drops every detection overlapping a
+/// higher scoring one of the same class by more than `iou_threshold`.
+/// Returns the indices of the survivors, best first.
+pub fn non_max_suppression(detections: &[Detection], iou_threshold: f32) -> Vec<usize> {
+    let mut order: Vec<usize> = (0..detections.len()).collect();
+    order.sort_by(|&a, &b| detections[b].score.total_cmp(&detections[a].score));
+    let mut kept: Vec<usize> = Vec::with_capacity(detections.len());
+    for i in order {
+        let detection = &detections[i];
+        let suppressed = kept.iter().any(|&k| {
+            detections[k].class_id == detection.class_id
+                && iou(&detections[k].bbox, &detection.bbox) > iou_threshold
+        });
+        if !suppressed {
+            kept.push(i);
+        }
+    }
+    kept
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn assert_rect_eq(a: Rect2f, b: Rect2f) {
+        let close = |x: f32, y: f32| (x - y).abs() < 1e-3;
+        assert!(
+            close(a.x, b.x)
+                && close(a.y, b.y)
+                && close(a.width, b.width)
+                && close(a.height, b.height),
+            "{a:?} != {b:?}"
+        );
+    }
+
+    #[test]
+    fn test_decode_both_layouts() {
+        // Two classes, three anchors, attributes along rows.
+        let predictions = [
+            [50.0, 100.0, 10.0], // cx
+            [60.0, 100.0, 10.0], // cy
+            [20.0, 40.0, 4.0],   // w
+            [10.0, 40.0, 4.0],   // h
+            [0.9, 0.1, 0.2],     // class 0
+            [0.05, 0.8, 0.1],    // class 1
+        ];
+        let expected = vec![
+            (
+                Detection {
+                    bbox: Rect2f::new(40.0, 55.0, 20.0, 10.0),
+                    class_id: 0,
+                    score: 0.9,
+                },
+                Vec::new(),
+            ),
+            (
+                Detection {
+                    bbox: Rect2f::new(80.0, 80.0, 40.0, 40.0),
+                    class_id: 1,
+                    score: 0.8,
+                },
+                Vec::new(),
+            ),
+        ];
+
+        let standard = decode(6, 3, false, |r, c| predictions[r][c], 0, 0.25);
+        assert_eq!(standard, expected);
+        // `[1, anchors, 4 + classes]` exports.
+        let transposed = decode(3, 6, true, |r, c| predictions[c][r], 0, 0.25);
+        assert_eq!(transposed, expected);
+        // Trailing mask coefficients are not classes.
+        let segmentation = decode(6, 3, false, |r, c| predictions[r][c], 1, 0.25);
+        assert_eq!(segmentation, vec![(expected[0].0, vec![0.05])]);
+    }
+
+    #[test]
+    fn test_nms_is_class_wise() {
+        let detection = |x: f32, class_id: usize, score: f32| Detection {
+            bbox: Rect2f::new(x, 0.0, 100.0, 100.0),
+            class_id,
+            score,
+        };
+        let detections = [
+            detection(0.0, 0, 0.6),
+            detection(5.0, 0, 0.9),
+            detection(5.0, 1, 0.7),
+            detection(300.0, 0, 0.5),
+        ];
+        // The weaker class 0 box overlapping the best one goes, the class 1
+        // box on top of it stays.
+        assert_eq!(non_max_suppression(&detections, 0.45), vec![1, 2, 3]);
+    }
+
+    #[test]
+    fn test_letterbox_unmaps_to_image() {
+        // 1280x720 into 640x640: scaled by half, 140 rows of padding above.
+        let letterbox = Letterbox::new(1280, 720, 640, 640);
+        assert_eq!(letterbox.scale, 0.5);
+        assert_eq!((letterbox.pad_x, letterbox.pad_y), (0.0, 140.0));
+        assert_eq!(letterbox.scaled_size(), (640, 360));
+        assert_eq!(
+            letterbox.content(160, 160, 640, 640),
+            Rect::new(0, 35, 160, 90)
+        );
+
+        assert_rect_eq(
+            letterbox.unmap(Rect2f::new(50.0, 240.0, 150.0, 75.0)),
+            Rect2f::new(100.0, 200.0, 300.0, 150.0),
+        );
+
+        // Unmapped boxes are clipped to the image.
+        let outside = Rect2f::new(600.0, 100.0, 100.0, 100.0);
+        assert_rect_eq(
+            letterbox.unmap(outside),
+            Rect2f::new(1200.0, 0.0, 80.0, 120.0),
+        );
+    }
+}
@@ -0,0 +1,227 @@
+use opencv::{
+    core::{
+        CV_8U, CV_8UC1, CV_32FC1, Mat, MatTrait, MatTraitConst, MatTraitConstManual,
+        MatTraitManual, Rect, Scalar, Size,
+    },
+    imgproc::{self, INTER_LINEAR, THRESH_BINARY},
+};
+
+use super::postprocess::{Detection, Letterbox};
+use crate::EstimateError;
+
+/// A detection together with the pixels it covers.
+#[derive(Debug, Clone)]
+pub struct InstanceMask {
+    pub detection: Detection,
+    /// CV_8UC1 at the resolution of the detected image, 255 on the object and
+    /// zero elsewhere.
+    pub mask: Mat,
+}
+
+/// CV_8UC1 mask of `size` that is zero on the union of `instances` and 255
+/// everywhere else, following the OpenCV convention of zero meaning
+/// excluded. It can be given as is to visual odometry's `FrameMask::Image`
+/// and `r_slam_common::backprojection::depth_map_to_point_cloud_masked`.
+pub fn exclusion_mask(instances: &[InstanceMask], size: Size) -> Result<Mat, EstimateError> {
+    let mut mask = Mat::new_size_with_default(size, CV_8UC1, Scalar::all(255.0))?;
+    for instance in instances {
+        if instance.mask.size()? != size {
+            return Err(EstimateError::UnexpectedOutput(format!(
+                "instance mask is {}x{}, expected {}x{}",
+                instance.mask.cols(),
+                instance.mask.rows(),
+                size.width,
+                size.height
+            )));
+        }
+        mask.set_to(&Scalar::all(0.0), &instance.mask)?;
+    }
+    Ok(mask)
+}
+
+/// Mask prototypes of a YOLOv8-seg model, `count` maps of `width` x
+/// `height` covering the network input at a lower resolution.
+pub(crate) struct Prototypes {
+    pub data: Vec<f32>,
+    pub count: usize,
+    pub width: i32,
+    pub height: i32,
+}
+
+impl Prototypes {
+    /// Combines the prototypes with an instance's `coefficients` and brings
+    /// the result back to the original image, keeping only what lies inside
+    /// the instance's box. `input_width` and `input_height` are the network
+    /// input size.
+    pub fn instance_mask(
+        &self,
+        coefficients: &[f32],
+        bbox: Rect,
+        letterbox: &Letterbox,
+        input_width: i32,
+        input_height: i32,
+    ) -> Result<Mat, EstimateError> {
+        let mut probabilities =
+            Mat::new_rows_cols_with_default(self.height, self.width, CV_32FC1, Scalar::all(0.0))?;
+        {
+            let values = probabilities.data_typed_mut::<f32>()?;
+            let plane = values.len();
+            for (k, &coefficient) in coefficients.iter().enumerate().take(self.count) {
+                let prototype = &self.data[k * plane..(k + 1) * plane];
+                for (value, &p) in values.iter_mut().zip(prototype) {
+                    *value += coefficient * p;
+                }
+            }
+            for value in values.iter_mut() {
+                *value = 1.0 / (1.0 + (-*value).exp());
+            }
+        }
+
+        let content = letterbox.content(self.width, self.height, input_width, input_height);
+        let content = Mat::roi(&probabilities, content)?;
+
+        let mut resized = Mat::default();
+        imgproc::resize(
+            &content,
+            &mut resized,
+            Size::new(letterbox.width, letterbox.height),
+            0.0,
+            0.0,
+            INTER_LINEAR,
+        )?;
+        let mut binary = Mat::default();
+        imgproc::threshold(&resized, &mut binary, 0.5, 255.0, THRESH_BINARY)?;
+
+        let mut mask = Mat::new_rows_cols_with_default(
+            letterbox.height,
+            letterbox.width,
+            CV_8UC1,
+            Scalar::all(0.0),
+        )?;
+        let bbox = bbox & Rect::new(0, 0, letterbox.width, letterbox.height);
+        if bbox.width > 0 && bbox.height > 0 {
+            let inside = Mat::roi(&binary, bbox)?;
+            let mut target = Mat::roi_mut(&mut mask, bbox)?;
+            inside.convert_to(&mut target, CV_8U, 1.0, 0.0)?;
+        }
+        Ok(mask)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use opencv::core::{Rect2f, no_array};
+
+    fn at(mask: &Mat, x: i32, y: i32) -> u8 {
+        *mask.at_2d::<u8>(y, x).unwrap()
+    }
+
+    fn count(mask: &Mat, value: u8) -> usize {
+        mask.data_typed::<u8>()
+            .unwrap()
+            .iter()
+            .filter(|&&v| v == value)
+            .count()
+    }
+
+    fn instance(rect: Rect, size: Size) -> InstanceMask {
+        let mut mask = Mat::new_size_with_default(size, CV_8UC1, Scalar::all(0.0)).unwrap();
+        Mat::roi_mut(&mut mask, rect)
+            .unwrap()
+            .set_to(&Scalar::all(255.0), &no_array())
+            .unwrap();
+        InstanceMask {
+            detection: Detection {
+                bbox: Rect2f::new(
+                    rect.x as f32,
+                    rect.y as f32,
+                    rect.width as f32,
+                    rect.height as f32,
+                ),
+                class_id: 0,
+                score: 1.0,
+            },
+            mask,
+        }
+    }
+
+    #[test]
+    fn test_instance_mask_is_cropped_to_box_and_sized_to_image() {
+        // A 40x20 image into a 32x32 input: scaled by 0.8 to 32x16 with 8
+        // rows of padding above and below, so rows 2..6 of 8x8 prototypes
+        // hold the image.
+        let letterbox = Letterbox::new(40, 20, 32, 32);
+        assert_eq!(letterbox.content(8, 8, 32, 32), Rect::new(0, 2, 8, 4));
+
+        // The first prototype is positive on the image and negative on the
+        // padding, the second the other way round.
+        let mut data = Vec::with_capacity(2 * 64);
+        for sign in [1.0, -1.0] {
+            for row in 0..8 {
+                let value = if (2..6).contains(&row) { 10.0 } else { -10.0 };
+                data.extend(std::iter::repeat_n(sign * value, 8));
+            }
+        }
+        let prototypes = Prototypes {
+            data,
+            count: 2,
+            width: 8,
+            height: 8,
+        };
+
+        let bbox = Rect::new(5, 4, 10, 8);
+        let mask = prototypes
+            .instance_mask(&[1.0, 0.0], bbox, &letterbox, 32, 32)
+            .unwrap();
+        assert_eq!(mask.size().unwrap(), Size::new(40, 20));
+        assert_eq!(mask.typ(), CV_8UC1);
+        assert_eq!(count(&mask, 255), 80);
+        assert_eq!(count(&mask, 0), 40 * 20 - 80);
+        assert_eq!(at(&mask, 5, 4), 255);
+        assert_eq!(at(&mask, 14, 11), 255);
+        assert_eq!(at(&mask, 4, 4), 0);
+        assert_eq!(at(&mask, 15, 11), 0);
+        assert_eq!(at(&mask, 14, 12), 0);
+
+        // Boxes are clipped to the image.
+        let mask = prototypes
+            .instance_mask(&[1.0, 0.0], Rect::new(30, 10, 20, 20), &letterbox, 32, 32)
+            .unwrap();
+        assert_eq!(count(&mask, 255), 100);
+
+        // Only the padding is positive for the second prototype, which must
+        // not leak into the image.
+        let mask = prototypes
+            .instance_mask(&[0.0, 1.0], Rect::new(0, 0, 40, 20), &letterbox, 32, 32)
+            .unwrap();
+        assert_eq!(count(&mask, 255), 0);
+    }
+
+    #[test]
+    fn test_exclusion_mask_zeroes_union() {
+        let size = Size::new(6, 4);
+        let instances = [
+            instance(Rect::new(0, 0, 3, 2), size),
+            instance(Rect::new(2, 1, 3, 3), size),
+        ];
+        let mask = exclusion_mask(&instances, size).unwrap();
+        assert_eq!(mask.size().unwrap(), size);
+        // 6 + 9 pixels overlapping in one.
+        assert_eq!(count(&mask, 0), 14);
+        assert_eq!(count(&mask, 255), 24 - 14);
+        assert_eq!(at(&mask, 2, 1), 0);
+        assert_eq!(at(&mask, 5, 0), 255);
+        assert_eq!(at(&mask, 0, 3), 255);
+
+        // Nothing detected excludes nothing.
+        let empty = exclusion_mask(&[], size).unwrap();
+        assert_eq!(count(&empty, 255), 24);
+
+        let other = [instance(Rect::new(0, 0, 2, 2), Size::new(8, 8))];
+        assert!(matches!(
+            exclusion_mask(&other, size),
+            Err(EstimateError::UnexpectedOutput(_))
+        ));
+    }
+}
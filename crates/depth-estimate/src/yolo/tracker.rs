@@ -0,0 +1,602 @@
+use std::collections::BTreeMap;
+
+use nalgebra::{SMatrix, SVector};
+use opencv::core::{Mat, Rect, Rect2f};
+
+use super::postprocess::{Detection, iou};
+use super::segmentation::InstanceMask;
+
+type State = SVector<f32, 7>;
+type Covariance = SMatrix<f32, 7, 7>;
+type Measurement = SVector<f32, 4>;
+
+#[derive(Debug, Clone, Copy)]
+pub struct TrackerConfig {
+    /// Detections scoring at least this are matched first and may start new
+    /// tracks.
+    pub high_threshold: f32,
+    /// Detections between this and `high_threshold` only keep existing
+    /// tracks alive, which carries objects through partial occlusion.
+    /// Anything lower is ignored.
+    pub low_threshold: f32,
+    /// Smallest IoU between a track's predicted box and a detection for them
+    /// to be matched.
+    pub min_iou: f32,
+    /// Frames a track goes on being reported, from its predicted box, after
+    /// its object was last detected. Bridges detector flicker.
+    pub max_age: usize,
+    /// Consecutive detections before a track gets an id and is reported.
+    pub min_hits: usize,
+}
+
+impl Default for TrackerConfig {
+    fn default() -> Self {
+        Self {
+            high_threshold: 0.5,
+            low_threshold: 0.1,
+            min_iou: 0.3,
+            max_age: 30,
+            min_hits: 3,
+        }
+    }
+}
+
+/// An object being tracked, as of the last `ObjectTracker::update`.
+#[derive(Debug, Clone)]
+pub struct TrackedObject {
+    pub id: u64,
+    pub class_id: usize,
+    /// Score of the last detection matched to the track.
+    pub score: f32,
+    /// Filtered box in image pixels, or the predicted one while the object
+    /// is not detected.
+    pub bbox: Rect2f,
+    /// Frames since the object was last detected, zero if it was detected in
+    /// this one.
+    pub frames_since_seen: usize,
+    /// Mask of the last matched instance, when tracking segmentations. It is
+    /// not moved with the box while the object is not detected.
+    pub mask: Option<Mat>,
+}
+
+impl TrackedObject {
+    /// Smallest integer rectangle covering `bbox`.
+    pub fn rect(&self) -> Rect {
+        Detection {
+            bbox: self.bbox,
+            class_id: self.class_id,
+            score: self.score,
+        }
+        .rect()
+    }
+}
+
+/// What the tracker remembers of an object after its track is gone.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct TrackSummary {
+    pub id: u64,
+    pub class_id: usize,
+    /// Frame numbers counted by `ObjectTracker::update` calls, from zero.
+    pub first_frame: u64,
+    pub last_frame: u64,
+    /// Frames the object was detected in.
+    pub detections: usize,
+    pub best_score: f32,
+}
+
+struct Track {
+    id: Option<u64>,
+    class_id: usize,
+    score: f32,
+    filter: BoxFilter,
+    hits: usize,
+    frames_since_seen: usize,
+    mask: Option<Mat>,
+}
+
+/// Links detections across frames into tracks with stable ids, in the
+/// manner of SORT and ByteTrack.
+///
+/// Every track carries a constant velocity Kalman filter over its box.
+/// Each frame the tracks are predicted forward and matched to detections of
+/// the same class by IoU with the Hungarian algorithm, first against
+/// confident detections and then, for the tracks left over, against weak
+/// ones.
+pub struct ObjectTracker {
+    config: TrackerConfig,
+    tracks: Vec<Track>,
+    next_id: u64,
+    frame: u64,
+    history: BTreeMap<u64, TrackSummary>,
+}
+
+impl ObjectTracker {
+    pub fn new(config: TrackerConfig) -> Self {
+        Self {
+            config,
+            tracks: Vec::new(),
+            next_id: 0,
+            frame: 0,
+            history: BTreeMap::new(),
+        }
+    }
+
+    pub fn config(&self) -> &TrackerConfig {
+        &self.config
+    }
+
+    /// Advances one frame with its detections and returns the confirmed
+    /// tracks, including those whose object went undetected for at most
+    /// `max_age` frames.
+    pub fn update(&mut self, detections: &[Detection]) -> Vec<TrackedObject> {
+        let masks = vec![None; detections.len()];
+        self.advance(detections, masks)
+    }
+
+    /// Like `update`, keeping each track's latest mask.
+    pub fn update_instances(&mut self, instances: &[InstanceMask]) -> Vec<TrackedObject> {
+        let detections: Vec<Detection> = instances.iter().map(|i| i.detection).collect();
+        let masks = instances.iter().map(|i| Some(i.mask.clone())).collect();
+        self.advance(&detections, masks)
+    }
+
+    /// Every object confirmed so far this session, by id, including those
+    /// still being tracked.
+    pub fn history(&self) -> impl Iterator<Item = &TrackSummary> {
+        self.history.values()
+    }
+
+    /// Forgets all tracks and the history, e.g. when a new sequence starts.
+    /// Ids keep counting up.
+    pub fn reset(&mut self) {
+        self.tracks.clear();
+        self.history.clear();
+        self.frame = 0;
+    }
+
+    fn advance(
+        &mut self,
+        detections: &[Detection],
+        mut masks: Vec<Option<Mat>>,
+    ) -> Vec<TrackedObject> {
+        for track in &mut self.tracks {
+            track.filter.predict();
+            track.frames_since_seen += 1;
+        }
+
+        let (high, low): (Vec<usize>, Vec<usize>) = (0..detections.len())
+            .filter(|&i| detections[i].score >= self.config.low_threshold)
+            .partition(|&i| detections[i].score >= self.config.high_threshold);
+
+        let all_tracks: Vec<usize> = (0..self.tracks.len()).collect();
+        let (matches, unmatched_tracks, unmatched_high) =
+            self.associate(&all_tracks, &high, detections);
+        // Unconfirmed tracks only get one chance, as in ByteTrack.
+        let remaining: Vec<usize> = unmatched_tracks
+            .into_iter()
+            .filter(|&t| self.tracks[t].id.is_some())
+            .collect();
+        let (low_matches, _, _) = self.associate(&remaining, &low, detections);
+
+        for (t, d) in matches.into_iter().chain(low_matches) {
+            let track = &mut self.tracks[t];
+            track.filter.update(&detections[d].bbox);
+            track.score = detections[d].score;
+            track.hits += 1;
+            track.frames_since_seen = 0;
+            if let Some(mask) = masks[d].take() {
+                track.mask = Some(mask);
+            }
+        }
+        for d in unmatched_high {
+            self.tracks.push(Track {
+                id: None,
+                class_id: detections[d].class_id,
+                score: detections[d].score,
+                filter: BoxFilter::new(&detections[d].bbox),
+                hits: 1,
+                frames_since_seen: 0,
+                mask: masks[d].take(),
+            });
+        }
+
+        let (max_age, min_hits) = (self.config.max_age, self.config.min_hits.max(1));
+        self.tracks.retain(|track| {
+            if track.id.is_some() {
+                track.frames_since_seen <= max_age
+            } else {
+                // Tentative tracks must be detected in consecutive frames.
+                track.frames_since_seen == 0
+            }
+        });
+
+        let frame = self.frame;
+        let mut objects = Vec::new();
+        for track in &mut self.tracks {
+            if track.id.is_none() && track.hits >= min_hits {
+                track.id = Some(self.next_id);
+                self.next_id += 1;
+            }
+            let Some(id) = track.id else {
+                continue;
+            };
+            let summary = self.history.entry(id).or_insert(TrackSummary {
+                id,
+                class_id: track.class_id,
+                first_frame: frame + 1 - track.hits as u64,
+                last_frame: frame,
+                detections: track.hits - 1,
+                best_score: track.score,
+            });
+            if track.frames_since_seen == 0 {
+                summary.last_frame = frame;
+                summary.detections += 1;
+                summary.best_score = summary.best_score.max(track.score);
+            }
+            objects.push(TrackedObject {
+                id,
+                class_id: track.class_id,
+                score: track.score,
+                bbox: track.filter.bbox(),
+                frames_since_seen: track.frames_since_seen,
+                mask: track.mask.clone(),
+            });
+        }
+        self.frame += 1;
+        objects
+    }
+
+    /// Matches `tracks` to `candidates` (indices into `self.tracks` and
+    /// `detections`). Returns the matched pairs and the tracks and
+    /// candidates left over.
+    fn associate(
+        &self,
+        tracks: &[usize],
+        candidates: &[usize],
+        detections: &[Detection],
+    ) -> (Vec<(usize, usize)>, Vec<usize>, Vec<usize>) {
+        let predicted: Vec<Rect2f> = tracks
+            .iter()
+            .map(|&t| self.tracks[t].filter.bbox())
+            .collect();
+        let overlap = |row: usize, column: usize| {
+            let (track, detection) = (&self.tracks[tracks[row]], &detections[candidates[column]]);
+            if track.class_id == detection.class_id {
+                iou(&predicted[row], &detection.bbox)
+            } else {
+                0.0
+            }
+        };
+        let costs: Vec<Vec<f32>> = (0..tracks.len())
+            .map(|row| {
+                (0..candidates.len())
+                    .map(|c| 1.0 - overlap(row, c))
+                    .collect()
+            })
+            .collect();
+
+        let mut matches = Vec::new();
+        let mut track_matched = vec![false; tracks.len()];
+        let mut candidate_matched = vec![false; candidates.len()];
+        for (row, column) in hungarian(&costs) {
+            if overlap(row, column) >= self.config.min_iou {
+                matches.push((tracks[row], candidates[column]));
+                track_matched[row] = true;
+                candidate_matched[column] = true;
+            }
+        }
+        let unmatched_tracks = (0..tracks.len())
+            .filter(|&row| !track_matched[row])
+            .map(|row| tracks[row])
+            .collect();
+        let unmatched_candidates = (0..candidates.len())
+            .filter(|&column| !candidate_matched[column])
+            .map(|column| candidates[column])
+            .collect();
+        (matches, unmatched_tracks, unmatched_candidates)
+    }
+}
+
+/// Constant velocity Kalman filter over a box's centre, area and aspect
+/// ratio, with SORT's noise settings. Area and aspect ratio make the filter
+/// more linear than corners would; the aspect ratio is assumed constant.
+struct BoxFilter {
+    state: State,
+    covariance: Covariance,
+}
+
+impl BoxFilter {
+    fn new(bbox: &Rect2f) -> Self {
+        let mut state = State::zeros();
+        state.fixed_rows_mut::<4>(0).copy_from(&measurement(bbox));
+        let covariance =
+            Covariance::from_diagonal(&SVector::from([10.0, 10.0, 10.0, 10.0, 1e4, 1e4, 1e4]));
+        Self { state, covariance }
+    }
+
+    fn predict(&mut self) {
+        // Keep the area from going negative.
+        if self.state[2] + self.state[6] <= 0.0 {
+            self.state[6] = 0.0;
+        }
+        let transition = transition();
+        let noise =
+            Covariance::from_diagonal(&SVector::from([1.0, 1.0, 1.0, 1.0, 0.01, 0.01, 1e-4]));
+        self.state = transition * self.state;
+        self.covariance = transition * self.covariance * transition.transpose() + noise;
+    }
+
+    fn update(&mut self, bbox: &Rect2f) {
+        let observation = SMatrix::<f32, 4, 7>::identity();
+        let noise = SMatrix::<f32, 4, 4>::from_diagonal(&SVector::from([1.0, 1.0, 10.0, 10.0]));
+        let innovation = measurement(bbox) - observation * self.state;
+        let innovation_covariance = observation * self.covariance * observation.transpose() + noise;
+        let Some(inverse) = innovation_covariance.try_inverse() else {
+            return;
+        };
+        let gain = self.covariance * observation.transpose() * inverse;
+        self.state += gain * innovation;
+        self.covariance = (Covariance::identity() - gain * observation) * self.covariance;
+    }
+
+    fn bbox(&self) -> Rect2f {
+        let area = self.state[2].max(0.0);
+        let ratio = self.state[3].max(f32::EPSILON);
+        let width = (area * ratio).sqrt();
+        let height = if width > 0.0 { area / width } else { 0.0 };
+        Rect2f::new(
+            self.state[0] - width / 2.0,
+            self.state[1] - height / 2.0,
+            width,
+            height,
+        )
+    }
+}
+
+fn transition() -> Covariance {
+    let mut transition = Covariance::identity();
+    transition[(0, 4)] = 1.0;
+    transition[(1, 5)] = 1.0;
+    transition[(2, 6)] = 1.0;
+    transition
+}
+
+fn measurement(bbox: &Rect2f) -> Measurement {
+    Measurement::new(
+        bbox.x + bbox.width / 2.0,
+        bbox.y + bbox.height / 2.0,
+        bbox.width * bbox.height,
+        bbox.width / bbox.height.max(f32::EPSILON),
+    )
+}
+
+/// Stands in for non-finite costs, e.g. the IoU of a degenerate box, which
+/// would otherwise keep the potentials from ever settling.
+const UNASSIGNABLE_COST: f64 = 1e9;
+
+/// Minimum cost assignment of rows to columns of a rectangular cost matrix,
+/// by the Hungarian algorithm with potentials. Every row or every column is
+/// assigned, whichever there are fewer of. Non-finite costs are treated as
+/// prohibitively large.
+fn hungarian(costs: &[Vec<f32>]) -> Vec<(usize, usize)> {
+    let rows = costs.len();
+    let columns = costs.first().map_or(0, Vec::len);
+    if rows == 0 || columns == 0 {
+        return Vec::new();
+    }
+    // The algorithm wants no more rows than columns.
+    let transposed = rows > columns;
+    let (n, m) = if transposed {
+        (columns, rows)
+    } else {
+        (rows, columns)
+    };
+    let cost = |i: usize, j: usize| {
+        let cost = if transposed { costs[j][i] } else { costs[i][j] } as f64;
+        if cost.is_finite() {
+            cost
+        } else {
+            UNASSIGNABLE_COST
+        }
+    };
+
+    // 1-based, with row and column 0 as sentinels.
+    let mut u = vec![0.0f64; n + 1];
+    let mut v = vec![0.0f64; m + 1];
+    let mut assigned_row = vec![0usize; m + 1];
+    let mut way = vec![0usize; m + 1];
+    for i in 1..=n {
+        assigned_row[0] = i;
+        let mut j0 = 0;
+        let mut min_to = vec![f64::INFINITY; m + 1];
+        let mut used = vec![false; m + 1];
+        loop {
+            used[j0] = true;
+            let i0 = assigned_row[j0];
+            let mut delta = f64::INFINITY;
+            let mut j1 = 0;
+            for j in 1..=m {
+                if used[j] {
+                    continue;
+                }
+                let reduced = cost(i0 - 1, j - 1) - u[i0] - v[j];
+                if reduced < min_to[j] {
+                    min_to[j] = reduced;
+                    way[j] = j0;
+                }
+                if min_to[j] < delta {
+                    delta = min_to[j];
+                    j1 = j;
+                }
+            }
+            for j in 0..=m {
+                if used[j] {
+                    u[assigned_row[j]] += delta;
+                    v[j] -= delta;
+                } else {
+                    min_to[j] -= delta;
+                }
+            }
+            j0 = j1;
+            if assigned_row[j0] == 0 {
+                break;
+            }
+        }
+        loop {
+            let j1 = way[j0];
+            assigned_row[j0] = assigned_row[j1];
+            j0 = j1;
+            if j0 == 0 {
+                break;
+            }
+        }
+    }
+
+    (1..=m)
+        .filter(|&j| assigned_row[j] != 0)
+        .map(|j| {
+            let (i, j) = (assigned_row[j] - 1, j - 1);
+            if transposed { (j, i) } else { (i, j) }
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn detection(x: f32, class_id: usize, score: f32) -> Detection {
+        Detection {
+            bbox: Rect2f::new(x, 10.0, 20.0, 40.0),
+            class_id,
+            score,
+        }
+    }
+
+    fn sorted(mut assignment: Vec<(usize, usize)>) -> Vec<(usize, usize)> {
+        assignment.sort();
+        assignment
+    }
+
+    #[test]
+    fn test_hungarian_finds_optimal_assignment() {
+        // Greedy would take (1, 1) at 0 and pay 3 + 3 for the rest.
+        let costs = vec![
+            vec![4.0, 1.0, 3.0],
+            vec![2.0, 0.0, 5.0],
+            vec![3.0, 2.0, 2.0],
+        ];
+        assert_eq!(sorted(hungarian(&costs)), vec![(0, 1), (1, 0), (2, 2)]);
+
+        // More rows than columns, and the other way round.
+        let tall = vec![vec![1.0, 4.0], vec![0.5, 3.0], vec![2.0, 0.1]];
+        assert_eq!(sorted(hungarian(&tall)), vec![(1, 0), (2, 1)]);
+        let wide = vec![vec![1.0, 0.2, 3.0]];
+        assert_eq!(hungarian(&wide), vec![(0, 1)]);
+        assert!(hungarian(&[]).is_empty());
+    }
+
+    #[test]
+    fn test_hungarian_tolerates_non_finite_costs() {
+        let costs = vec![vec![f32::NAN, 0.5], vec![0.2, f32::INFINITY]];
+        assert_eq!(sorted(hungarian(&costs)), vec![(0, 1), (1, 0)]);
+    }
+
+    #[test]
+    fn test_tracks_confirmed_after_min_hits() {
+        let mut tracker = ObjectTracker::new(TrackerConfig {
+            min_hits: 3,
+            ..TrackerConfig::default()
+        });
+        assert!(tracker.update(&[detection(100.0, 0, 0.9)]).is_empty());
+        assert!(tracker.update(&[detection(102.0, 0, 0.9)]).is_empty());
+        let objects = tracker.update(&[detection(104.0, 0, 0.9)]);
+        assert_eq!(objects.len(), 1);
+        assert_eq!(objects[0].frames_since_seen, 0);
+
+        // A tentative track missing a frame starts over.
+        let mut tracker = ObjectTracker::new(TrackerConfig::default());
+        tracker.update(&[detection(100.0, 0, 0.9)]);
+        tracker.update(&[detection(102.0, 0, 0.9)]);
+        tracker.update(&[]);
+        assert!(tracker.update(&[detection(106.0, 0, 0.9)]).is_empty());
+        assert_eq!(tracker.history().count(), 0);
+    }
+
+    #[test]
+    fn test_track_coasts_for_max_age_then_drops() {
+        let mut tracker = ObjectTracker::new(TrackerConfig {
+            max_age: 5,
+            min_hits: 1,
+            ..TrackerConfig::default()
+        });
+        let id = tracker.update(&[detection(100.0, 0, 0.9)])[0].id;
+        for missed in 1..=5 {
+            let objects = tracker.update(&[]);
+            assert_eq!(objects.len(), 1);
+            assert_eq!(objects[0].id, id);
+            assert_eq!(objects[0].frames_since_seen, missed);
+        }
+        assert!(tracker.update(&[]).is_empty());
+
+        let history: Vec<&TrackSummary> = tracker.history().collect();
+        assert_eq!(history.len(), 1);
+        assert_eq!((history[0].first_frame, history[0].last_frame), (0, 0));
+        assert_eq!(history[0].detections, 1);
+    }
+
+    #[test]
+    fn test_low_scores_only_extend_confirmed_tracks() {
+        let config = TrackerConfig::default();
+        let mut tracker = ObjectTracker::new(config);
+        let mut x = 100.0;
+        let mut id = None;
+        for _ in 0..config.min_hits {
+            id = tracker
+                .update(&[detection(x, 0, 0.9)])
+                .first()
+                .map(|o| o.id);
+            x += 2.0;
+        }
+        let id = id.unwrap();
+
+        // Too weak to start a track, but enough to keep one: the second
+        // stage matches it and the object counts as seen.
+        let weak = detection(x, 0, 0.3);
+        let objects = tracker.update(&[weak, detection(400.0, 2, 0.3)]);
+        assert_eq!(objects.len(), 1);
+        assert_eq!(objects[0].id, id);
+        assert_eq!(objects[0].frames_since_seen, 0);
+        assert_eq!(objects[0].score, 0.3);
+
+        // Below `low_threshold` detections are ignored.
+        let objects = tracker.update(&[detection(x + 2.0, 0, 0.05)]);
+        assert_eq!(objects[0].frames_since_seen, 1);
+
+        // Weak detections of another class do not match.
+        let objects = tracker.update(&[detection(x + 4.0, 1, 0.3)]);
+        assert_eq!(objects[0].frames_since_seen, 2);
+        assert_eq!(tracker.history().count(), 1);
+    }
+
+    #[test]
+    fn test_ids_stable_through_flicker() {
+        let mut tracker = ObjectTracker::new(TrackerConfig::default());
+        let mut ids = Vec::new();
+        for frame in 0..20 {
+            let x = 100.0 + 3.0 * frame as f32;
+            let detections = match frame {
+                8 | 9 => vec![],
+                _ => vec![detection(x, 0, 0.9), detection(400.0, 2, 0.8)],
+            };
+            for object in tracker.update(&detections) {
+                if object.class_id == 0 {
+                    ids.push(object.id);
+                }
+            }
+        }
+        assert_eq!(ids.len(), 18);
+        assert!(ids.iter().all(|&id| id == ids[0]));
+        assert_eq!(tracker.history().count(), 2);
+    }
+}
@@ -1,7 +1,7 @@
 use super::camera::Camera;
 use opencv::{
     Error as CvError,
-    core::{CV_32FC1, Mat, Point3f, Vector},
+    core::{CV_8UC1, CV_32FC1, Mat, Point3f, Vector},
     prelude::*,
 };
 use thiserror::Error;
@@ -62,6 +62,21 @@ pub fn depth_map_to_point_cloud(
     depth_map: &Mat,
     intrinsics: &Camera,
     config: Option<BackprojectionConfig>,
+) -> Result<Vector<Point3f>, BackprojectionError> {
+    depth_map_to_point_cloud_masked(depth_map, intrinsics, &Mat::default(), config)
+}
+
+/// Like `depth_map_to_point_cloud`, skipping pixels where `mask` is zero,
+/// e.g. people and cars found by instance segmentation, so moving objects do
+/// not end up in the cloud. Zero means excluded, as in OpenCV masks.
+///
+/// The mask must be CV_8UC1 and the size of the depth map. An empty mask
+/// excludes nothing.
+pub fn depth_map_to_point_cloud_masked(
+    depth_map: &Mat,
+    intrinsics: &Camera,
+    mask: &Mat,
+    config: Option<BackprojectionConfig>,
 ) -> Result<Vector<Point3f>, BackprojectionError> {
     if depth_map.empty() {
         return Err(BackprojectionError::InvalidInput(
@@ -74,6 +89,13 @@ pub fn depth_map_to_point_cloud(
         ));
     }
 
+    let masked = !mask.empty();
+    if masked && (mask.size()? != depth_map.size()? || mask.typ() != CV_8UC1) {
+        return Err(BackprojectionError::InvalidInput(
+            "Mask must be CV_8UC1 and the size of the depth map".to_string(),
+        ));
+    }
+
     let cfg = config.unwrap_or_else(|| BackprojectionConfig::new(0.0));
     let rows = depth_map.rows();
     let cols = depth_map.cols();
@@ -95,6 +117,9 @@ pub fn depth_map_to_point_cloud(
 
     for v in (0..rows_usize).step_by(stride) {
         for u in (0..cols_usize).step_by(stride) {
+            if masked && *mask.at_2d::<u8>(v as i32, u as i32)? == 0 {
+                continue;
+            }
             let depth_value = *depth_map.at_2d::<f32>(v as i32, u as i32)?;
 
             if !depth_value.is_finite() {
@@ -187,7 +212,7 @@ pub fn transform_point_cloud(
 #[cfg(test)]
 mod tests {
     use super::*;
-    use opencv::core::{CV_8UC1, CV_64F, Scalar};
+    use opencv::core::{CV_64F, Scalar};
 
     fn make_camera(fx: f64, fy: f64, cx: f64, cy: f64, width: i32, height: i32) -> Camera {
         let mut k = Mat::new_rows_cols_with_default(3, 3, CV_64F, Scalar::all(0.0)).unwrap();
@@ -267,6 +292,26 @@ mod tests {
         }
     }
 
+    #[test]
+    fn test_mask_skips_pixels() {
+        let depth = Mat::new_rows_cols_with_default(2, 2, CV_32FC1, Scalar::all(2.0)).unwrap();
+        let mut mask = Mat::new_rows_cols_with_default(2, 2, CV_8UC1, Scalar::all(0.0)).unwrap();
+        *mask.at_2d_mut::<u8>(0, 0).unwrap() = 255;
+        *mask.at_2d_mut::<u8>(1, 0).unwrap() = 1;
+
+        let cam = make_camera(1.0, 1.0, 0.0, 0.0, 2, 2);
+        let cloud = depth_map_to_point_cloud_masked(&depth, &cam, &mask, None).unwrap();
+        // Only the left column (u=0) survives.
+        assert_eq!(cloud.len(), 2);
+        assert!(cloud.iter().all(|p| p.x.abs() < 1e-6));
+
+        let wrong_size = Mat::new_rows_cols_with_default(3, 3, CV_8UC1, Scalar::all(0.0)).unwrap();
+        assert!(matches!(
+            depth_map_to_point_cloud_masked(&depth, &cam, &wrong_size, None),
+            Err(BackprojectionError::InvalidInput(_))
+        ));
+    }
+
     #[test]
     fn test_transform_point_cloud_identity_and_translation() {
         let mut pts = Vector::<Point3f>::new();
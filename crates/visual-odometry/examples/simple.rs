@@ -17,12 +17,16 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
     let mut gimage2 = Mat::default();
     imgproc::cvt_color(&image2, &mut gimage2, imgproc::COLOR_BGR2GRAY, 0).unwrap();
 
-    // process frame -> match frame -> matches -> camera pose
+    // image -> track -> camera pose
     //
-    let frame1 = vo.process_frame(gimage1)?;
-    let frame2 = vo.process_frame(gimage2)?;
-
-    let results = vo.frame_match(frame1, frame2, 0.7, 50.0)?; // DMatch
+    let first = vo.track(gimage1, 0.0)?;
+    let second = vo.track(gimage2, 1.0 / 30.0)?;
+
+    println!("frame {} state: {:?}", first.frame_id, first.state);
+    println!(
+        "frame {} state: {:?}, inliers: {}",
+        second.frame_id, second.state, second.inliers
+    );
 
     Ok(())
 }
@@ -0,0 +1,43 @@
+use opencv::{
+    core::{KeyPoint, Mat, Vector, no_array},
+    features2d::ORB,
+    imgcodecs,
+    prelude::*,
+};
+use visual_odometry::vocabulary::{Vocabulary, VocabularyConfig, descriptors_from_mat};
+
+// cargo run --example train_vocabulary -- <image dir> <output file>
+fn main() -> Result<(), Box<dyn std::error::Error>> {
+    let mut args = std::env::args().skip(1);
+    let (Some(dir), Some(output)) = (args.next(), args.next()) else {
+        eprintln!("usage: train_vocabulary <image dir> <output file>");
+        std::process::exit(1);
+    };
+
+    let mut orb = ORB::create_def()?;
+    let mut images = Vec::new();
+    let mut paths: Vec<_> = std::fs::read_dir(&dir)?
+        .filter_map(|entry| entry.ok().map(|e| e.path()))
+        .collect();
+    paths.sort();
+    for path in paths {
+        let image = imgcodecs::imread(&path.to_string_lossy(), imgcodecs::IMREAD_GRAYSCALE)?;
+        if image.empty() {
+            continue;
+        }
+        let mut keypoints = Vector::<KeyPoint>::new();
+        let mut descriptors = Mat::default();
+        orb.detect_and_compute(&image, &no_array(), &mut keypoints, &mut descriptors, false)?;
+        images.push(descriptors_from_mat(&descriptors)?);
+    }
+    println!(
+        "training on {} descriptors from {} images",
+        images.iter().map(Vec::len).sum::<usize>(),
+        images.len()
+    );
+
+    let vocabulary = Vocabulary::train(&images, &VocabularyConfig::default())?;
+    vocabulary.save(&output)?;
+    println!("saved {} words to {output}", vocabulary.num_words());
+    Ok(())
+}
@@ -0,0 +1,584 @@
+use std::collections::{BTreeMap, BTreeSet};
+
+use nalgebra::{DMatrix, DVector, Isometry3, Matrix3, SMatrix, Vector2, Vector3, Vector6};
+use opencv::core::Point3d;
+use r_slam_common::camera::Camera;
+
+use crate::OdometryError;
+use crate::map::{KeyFrameId, Map, MapPointId};
+use crate::utils;
+
+type Matrix6 = SMatrix<f64, 6, 6>;
+type Matrix6x3 = SMatrix<f64, 6, 3>;
+type Matrix2x6 = SMatrix<f64, 2, 6>;
+type Matrix2x3 = SMatrix<f64, 2, 3>;
+
+/// Points closer than this to the camera plane are left out of an iteration.
+const MIN_DEPTH: f64 = 1e-6;
+/// Lower bound on the diagonal entries the damping is scaled by.
+const MIN_DIAGONAL: f64 = 1e-6;
+const MAX_LAMBDA: f64 = 1e12;
+
+#[derive(Debug, Clone, Copy)]
+pub struct BundleAdjustmentConfig {
+    /// Keyframes optimized together: the new keyframe and its best covisible
+    /// neighbours.
+    pub window: usize,
+    /// Oldest keyframes of the window held fixed. Keyframes outside the window
+    /// that observe its points are always fixed.
+    pub pinned_keyframes: usize,
+    pub max_iterations: usize,
+    /// Reprojection error, in pixels, beyond which the Huber kernel turns linear.
+    pub huber_threshold: f64,
+    /// Squared reprojection error, in pixels, beyond which an observation is
+    /// an outlier and dropped from the map.
+    pub outlier_chi2: f64,
+    pub initial_lambda: f64,
+    /// Stop once an accepted step lowers the cost by less than this fraction.
+    pub min_relative_decrease: f64,
+}
+
+impl Default for BundleAdjustmentConfig {
+    fn default() -> Self {
+        Self {
+            window: 10,
+            pinned_keyframes: 1,
+            max_iterations: 10,
+            // 95% quantile of chi-square with two degrees of freedom.
+            huber_threshold: 5.991f64.sqrt(),
+            outlier_chi2: 5.991,
+            initial_lambda: 1e-4,
+            min_relative_decrease: 1e-6,
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct BaPose {
+    /// World to camera transform.
+    pub pose: Isometry3<f64>,
+    pub fixed: bool,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct BaObservation {
+    pub pose: usize,
+    pub point: usize,
+    /// Undistorted keypoint location, in pixels.
+    pub pixel: Vector2<f64>,
+}
+
+/// Poses, world points and the observations tying them together.
+#[derive(Debug, Clone, Default)]
+pub struct BaProblem {
+    pub poses: Vec<BaPose>,
+    pub points: Vec<Vector3<f64>>,
+    pub observations: Vec<BaObservation>,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct BaSummary {
+    /// Levenberg-Marquardt iterations run, including rejected steps.
+    pub iterations: usize,
+    /// Robust cost before and after optimization.
+    pub initial_cost: f64,
+    pub final_cost: f64,
+    /// Indices of observations exceeding `outlier_chi2` after optimization.
+    pub outliers: Vec<usize>,
+}
+
+/// Normal equations split into pose and point blocks.
+struct System {
+    /// Pose-pose blocks, indexed by free pose.
+    poses: Vec<Matrix6>,
+    pose_gradients: Vec<Vector6<f64>>,
+    /// Point-point blocks.
+    points: Vec<Matrix3<f64>>,
+    point_gradients: Vec<Vector3<f64>>,
+    /// Pose-point blocks, grouped by point as `(free pose, block)`.
+    coupling: Vec<Vec<(usize, Matrix6x3)>>,
+}
+
+/// Sparse bundle adjustment minimizing reprojection error.
+///
+/// Poses are updated on the left, `T <- exp(delta) * T`, and points in world
+/// coordinates. Each Levenberg-Marquardt step marginalizes the points with the
+/// Schur complement, solves the reduced camera system with a dense Cholesky
+/// factorization and back-substitutes the point updates, which keeps the cost
+/// proportional to the number of keyframes rather than points.
+pub struct BundleAdjuster {
+    camera: Camera,
+    config: BundleAdjustmentConfig,
+}
+
+impl BundleAdjuster {
+    pub fn new(camera: Camera, config: BundleAdjustmentConfig) -> Self {
+        Self { camera, config }
+    }
+
+    pub fn config(&self) -> &BundleAdjustmentConfig {
+        &self.config
+    }
+
+    /// Refines the free poses and all points of `problem` in place.
+    pub fn optimize(&self, problem: &mut BaProblem) -> BaSummary {
+        let mut free = vec![None; problem.poses.len()];
+        let mut num_free = 0;
+        for (slot, pose) in free.iter_mut().zip(&problem.poses) {
+            if !pose.fixed {
+                *slot = Some(num_free);
+                num_free += 1;
+            }
+        }
+
+        let mut cost = self.cost(problem);
+        let mut summary = BaSummary {
+            initial_cost: cost,
+            ..Default::default()
+        };
+        let mut lambda = self.config.initial_lambda;
+        let mut system = self.linearize(problem, &free, num_free);
+
+        while summary.iterations < self.config.max_iterations {
+            summary.iterations += 1;
+            let Some((pose_steps, point_steps)) = Self::solve(&system, lambda) else {
+                lambda *= 10.0;
+                if lambda > MAX_LAMBDA {
+                    break;
+                }
+                continue;
+            };
+
+            let mut candidate = problem.clone();
+            for (pose, slot) in candidate.poses.iter_mut().zip(&free) {
+                if let Some(i) = slot {
+                    let step = &pose_steps[*i];
+                    let update = Isometry3::new(
+                        Vector3::new(step[0], step[1], step[2]),
+                        Vector3::new(step[3], step[4], step[5]),
+                    );
+                    pose.pose = update * pose.pose;
+                }
+            }
+            for (point, step) in candidate.points.iter_mut().zip(&point_steps) {
+                if let Some(step) = step {
+                    *point += step;
+                }
+            }
+
+            let candidate_cost = self.cost(&candidate);
+            if candidate_cost < cost {
+                let decrease = (cost - candidate_cost) / cost.max(f64::EPSILON);
+                *problem = candidate;
+                cost = candidate_cost;
+                lambda = (lambda / 10.0).max(f64::EPSILON);
+                if decrease < self.config.min_relative_decrease {
+                    break;
+                }
+                system = self.linearize(problem, &free, num_free);
+            } else {
+                lambda *= 10.0;
+                if lambda > MAX_LAMBDA {
+                    break;
+                }
+            }
+        }
+
+        summary.final_cost = cost;
+        summary.outliers = problem
+            .observations
+            .iter()
+            .enumerate()
+            .filter(|(_, obs)| {
+                self.residual(problem, obs)
+                    .is_none_or(|(r, _)| r.norm_squared() > self.config.outlier_chi2)
+            })
+            .map(|(i, _)| i)
+            .collect();
+        summary
+    }
+
+    /// Optimizes the window around `keyframe` and writes the result back to
+    /// `map`, unbinding observations that remain outliers.
+    ///
+    /// Runs a second pass without the outliers of the first, so a few bad
+    /// matches do not drag the window off before they are rejected. The summary
+    /// is that of the last pass.
+    pub fn local_bundle_adjustment(
+        &self,
+        map: &mut Map,
+        keyframe: KeyFrameId,
+    ) -> Result<BaSummary, OdometryError> {
+        let mut window = vec![keyframe];
+        window.extend(map.best_covisible_keyframes(keyframe, self.config.window.saturating_sub(1)));
+        window.sort_unstable();
+        let pinned: BTreeSet<KeyFrameId> = window
+            .iter()
+            .take(self.config.pinned_keyframes)
+            .copied()
+            .collect();
+
+        let point_ids: BTreeSet<MapPointId> = window
+            .iter()
+            .filter_map(|id| map.keyframe(*id))
+            .flat_map(|kf| kf.observed_points())
+            .collect();
+        let observers: BTreeSet<KeyFrameId> = point_ids
+            .iter()
+            .filter_map(|id| map.map_point(*id))
+            .flat_map(|mp| mp.observations.keys().copied())
+            .collect();
+
+        let mut problem = BaProblem::default();
+        let mut pose_ids = Vec::with_capacity(observers.len());
+        let mut pose_index = BTreeMap::new();
+        let mut pixels = BTreeMap::new();
+        for &id in &observers {
+            let Some(kf) = map.keyframe(id) else {
+                continue;
+            };
+            pose_index.insert(id, problem.poses.len());
+            problem.poses.push(BaPose {
+                pose: utils::pose_to_isometry(&kf.pose)?,
+                fixed: pinned.contains(&id) || window.binary_search(&id).is_err(),
+            });
+            pose_ids.push(id);
+            pixels.insert(
+                id,
+                utils::undistorted_keypoints(&self.camera, &kf.frame.keypoints)?,
+            );
+        }
+
+        let mut problem_points = Vec::with_capacity(point_ids.len());
+        let mut links = Vec::new();
+        for &id in &point_ids {
+            let Some(mp) = map.map_point(id) else {
+                continue;
+            };
+            let point = problem.points.len();
+            problem_points.push(id);
+            problem
+                .points
+                .push(Vector3::new(mp.position.x, mp.position.y, mp.position.z));
+            for (kf, &keypoint) in &mp.observations {
+                let (Some(&pose), Some(pixel)) = (
+                    pose_index.get(kf),
+                    pixels.get(kf).and_then(|p| p.get(keypoint)),
+                ) else {
+                    continue;
+                };
+                problem.observations.push(BaObservation {
+                    pose,
+                    point,
+                    pixel: Vector2::new(pixel.x, pixel.y),
+                });
+                links.push((*kf, id));
+            }
+        }
+        if problem.observations.is_empty() || problem.poses.iter().all(|p| p.fixed) {
+            return Ok(BaSummary::default());
+        }
+
+        let first = self.optimize(&mut problem);
+        let mut rejected: Vec<(KeyFrameId, MapPointId)> =
+            first.outliers.iter().map(|&i| links[i]).collect();
+        let summary = if first.outliers.is_empty() {
+            first
+        } else {
+            let outliers: BTreeSet<usize> = first.outliers.iter().copied().collect();
+            let (kept_observations, kept_links): (Vec<_>, Vec<_>) = problem
+                .observations
+                .iter()
+                .zip(&links)
+                .enumerate()
+                .filter(|(i, _)| !outliers.contains(i))
+                .map(|(_, (obs, link))| (*obs, *link))
+                .unzip();
+            problem.observations = kept_observations;
+            let mut second = self.optimize(&mut problem);
+            rejected.extend(second.outliers.iter().map(|&i| kept_links[i]));
+            second.initial_cost = first.initial_cost;
+            second.iterations += first.iterations;
+            second
+        };
+
+        for (pose, id) in problem.poses.iter().zip(&pose_ids) {
+            if pose.fixed {
+                continue;
+            }
+            if let Some(kf) = map.keyframe_mut(*id) {
+                kf.pose = utils::isometry_to_pose(&pose.pose)?;
+            }
+        }
+        for (position, id) in problem.points.iter().zip(&problem_points) {
+            if let Some(mp) = map.map_point_mut(*id) {
+                mp.position = Point3d::new(position.x, position.y, position.z);
+            }
+        }
+        for &(kf, point) in &rejected {
+            map.remove_observation(kf, point)?;
+        }
+        for &id in &problem_points {
+            if map.map_point(id).is_some() {
+                map.update_viewing_direction(id)?;
+            }
+        }
+
+        tracing::debug!(
+            keyframe,
+            window = window.len(),
+            points = point_ids.len(),
+            outliers = rejected.len(),
+            initial_cost = summary.initial_cost,
+            final_cost = summary.final_cost,
+            "local bundle adjustment"
+        );
+        Ok(summary)
+    }
+
+    /// Reprojection residual (projected minus observed) and the camera frame
+    /// point, or `None` for points behind the camera.
+    fn residual(
+        &self,
+        problem: &BaProblem,
+        obs: &BaObservation,
+    ) -> Option<(Vector2<f64>, Vector3<f64>)> {
+        let camera_point = problem.poses[obs.pose]
+            .pose
+            .transform_point(&problem.points[obs.point].into())
+            .coords;
+        if camera_point.z < MIN_DEPTH {
+            return None;
+        }
+        let projected = Vector2::new(
+            self.camera.fx * camera_point.x / camera_point.z + self.camera.cx,
+            self.camera.fy * camera_point.y / camera_point.z + self.camera.cy,
+        );
+        Some((projected - obs.pixel, camera_point))
+    }
+
+    /// Huber cost and IRLS weight of a squared residual.
+    fn robust(&self, chi2: f64) -> (f64, f64) {
+        let k = self.config.huber_threshold;
+        let error = chi2.sqrt();
+        if error <= k {
+            (chi2, 1.0)
+        } else {
+            (2.0 * k * error - k * k, k / error)
+        }
+    }
+
+    fn cost(&self, problem: &BaProblem) -> f64 {
+        problem
+            .observations
+            .iter()
+            .filter_map(|obs| self.residual(problem, obs))
+            .map(|(r, _)| self.robust(r.norm_squared()).0)
+            .sum()
+    }
+
+    fn linearize(&self, problem: &BaProblem, free: &[Option<usize>], num_free: usize) -> System {
+        let mut system = System {
+            poses: vec![Matrix6::zeros(); num_free],
+            pose_gradients: vec![Vector6::zeros(); num_free],
+            points: vec![Matrix3::zeros(); problem.points.len()],
+            point_gradients: vec![Vector3::zeros(); problem.points.len()],
+            coupling: vec![Vec::new(); problem.points.len()],
+        };
+
+        for obs in &problem.observations {
+            let Some((residual, p)) = self.residual(problem, obs) else {
+                continue;
+            };
+            let (_, weight) = self.robust(residual.norm_squared());
+
+            let inv_z = 1.0 / p.z;
+            let inv_z2 = inv_z * inv_z;
+            let projection = Matrix2x3::new(
+                self.camera.fx * inv_z,
+                0.0,
+                -self.camera.fx * p.x * inv_z2,
+                0.0,
+                self.camera.fy * inv_z,
+                -self.camera.fy * p.y * inv_z2,
+            );
+            let rotation = problem.poses[obs.pose].pose.rotation.to_rotation_matrix();
+            let point_jacobian = projection * rotation.matrix();
+
+            let (point_block, point_gradient) = (
+                point_jacobian.transpose() * weight * point_jacobian,
+                point_jacobian.transpose() * weight * residual,
+            );
+            system.points[obs.point] += point_block;
+            system.point_gradients[obs.point] -= point_gradient;
+
+            let Some(i) = free[obs.pose] else {
+                continue;
+            };
+            // d(camera point) / d(translation, rotation) = [I | -[p]x]
+            let mut pose_jacobian = Matrix2x6::zeros();
+            pose_jacobian
+                .fixed_view_mut::<2, 3>(0, 0)
+                .copy_from(&projection);
+            pose_jacobian
+                .fixed_view_mut::<2, 3>(0, 3)
+                .copy_from(&(projection * -p.cross_matrix()));
+
+            system.poses[i] += pose_jacobian.transpose() * weight * pose_jacobian;
+            system.pose_gradients[i] -= pose_jacobian.transpose() * weight * residual;
+            let coupling = pose_jacobian.transpose() * weight * point_jacobian;
+            match system.coupling[obs.point].iter_mut().find(|(j, _)| *j == i) {
+                Some((_, block)) => *block += coupling,
+                None => system.coupling[obs.point].push((i, coupling)),
+            }
+        }
+        system
+    }
+
+    /// Solves the damped normal equations. Returns `None` when the reduced
+    /// camera system is not positive definite.
+    fn solve(
+        system: &System,
+        lambda: f64,
+    ) -> Option<(Vec<Vector6<f64>>, Vec<Option<Vector3<f64>>>)> {
+        let n = system.poses.len();
+        let mut reduced = DMatrix::<f64>::zeros(6 * n, 6 * n);
+        let mut rhs = DVector::<f64>::zeros(6 * n);
+        for (i, block) in system.poses.iter().enumerate() {
+            let mut damped = *block;
+            for k in 0..6 {
+                damped[(k, k)] += lambda * block[(k, k)].max(MIN_DIAGONAL);
+            }
+            reduced
+                .fixed_view_mut::<6, 6>(6 * i, 6 * i)
+                .copy_from(&damped);
+            rhs.fixed_rows_mut::<6>(6 * i)
+                .copy_from(&system.pose_gradients[i]);
+        }
+
+        let mut point_inverses = Vec::with_capacity(system.points.len());
+        for (j, block) in system.points.iter().enumerate() {
+            let mut damped = *block;
+            for k in 0..3 {
+                damped[(k, k)] += lambda * block[(k, k)].max(MIN_DIAGONAL);
+            }
+            // Points seen by no usable observation stay where they are.
+            let inverse = damped.try_inverse();
+            if let Some(inverse) = inverse {
+                let gradient = inverse * system.point_gradients[j];
+                for (a, coupling_a) in &system.coupling[j] {
+                    let scaled = coupling_a * inverse;
+                    let mut rows = rhs.fixed_rows_mut::<6>(6 * a);
+                    rows -= scaled * system.point_gradients[j];
+                    for (b, coupling_b) in &system.coupling[j] {
+                        let mut block = reduced.fixed_view_mut::<6, 6>(6 * a, 6 * b);
+                        block -= scaled * coupling_b.transpose();
+                    }
+                }
+                point_inverses.push(Some((inverse, gradient)));
+            } else {
+                point_inverses.push(None);
+            }
+        }
+
+        let pose_steps: Vec<Vector6<f64>> = if n == 0 {
+            Vec::new()
+        } else {
+            let solution = reduced.cholesky()?.solve(&rhs);
+            (0..n)
+                .map(|i| solution.fixed_rows::<6>(6 * i).into_owned())
+                .collect()
+        };
+
+        let point_steps = point_inverses
+            .iter()
+            .enumerate()
+            .map(|(j, entry)| {
+                entry.map(|(inverse, gradient)| {
+                    let coupled: Vector3<f64> = system.coupling[j]
+                        .iter()
+                        .map(|(a, block)| block.transpose() * pose_steps[*a])
+                        .sum();
+                    gradient - inverse * coupled
+                })
+            })
+            .collect();
+        Some((pose_steps, point_steps))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util;
+
+    /// Three cameras sliding along x looking at a cloud of points, with every
+    /// point observed by every camera.
+    fn scene(adjuster: &BundleAdjuster) -> BaProblem {
+        let mut problem = BaProblem::default();
+        for i in 0..3 {
+            problem.poses.push(BaPose {
+                pose: Isometry3::new(
+                    Vector3::new(-0.3 * i as f64, 0.0, 0.0),
+                    Vector3::new(0.0, 0.02 * i as f64, 0.0),
+                ),
+                fixed: i < 2,
+            });
+        }
+        problem.points = test_util::scene(60);
+        for pose in 0..problem.poses.len() {
+            for point in 0..problem.points.len() {
+                let mut obs = BaObservation {
+                    pose,
+                    point,
+                    pixel: Vector2::zeros(),
+                };
+                let (residual, _) = adjuster.residual(&problem, &obs).unwrap();
+                obs.pixel = residual;
+                problem.observations.push(obs);
+            }
+        }
+        problem
+    }
+
+    #[test]
+    fn test_recovers_perturbed_pose_and_points() {
+        let adjuster = BundleAdjuster::new(test_util::camera(), BundleAdjustmentConfig::default());
+        let truth = scene(&adjuster);
+
+        let mut problem = truth.clone();
+        problem.poses[2].pose = Isometry3::new(
+            Vector3::new(0.03, -0.02, 0.05),
+            Vector3::new(0.01, 0.0, -0.01),
+        ) * problem.poses[2].pose;
+        for (i, point) in problem.points.iter_mut().enumerate() {
+            *point += Vector3::new(0.02, -0.01, 0.03) * ((i % 5) as f64 - 2.0);
+        }
+
+        let summary = adjuster.optimize(&mut problem);
+        assert!(summary.final_cost < 1e-6 * summary.initial_cost);
+        assert!(summary.outliers.is_empty());
+        let error = problem.poses[2].pose.inverse() * truth.poses[2].pose;
+        assert!(error.translation.vector.norm() < 1e-4);
+        assert!(error.rotation.angle() < 1e-5);
+    }
+
+    #[test]
+    fn test_flags_outliers_without_being_dragged_by_them() {
+        let adjuster = BundleAdjuster::new(test_util::camera(), BundleAdjustmentConfig::default());
+        let truth = scene(&adjuster);
+
+        let mut problem = truth.clone();
+        let corrupted = [5, 70, 150];
+        for &i in &corrupted {
+            problem.observations[i].pixel += Vector2::new(40.0, -30.0);
+        }
+
+        let summary = adjuster.optimize(&mut problem);
+        // Points keep some pull from their bad observation, so it may push
+        // another observation of the same point over the threshold too.
+        assert!(corrupted.iter().all(|i| summary.outliers.contains(i)));
+        let error = problem.poses[2].pose.inverse() * truth.poses[2].pose;
+        assert!(error.translation.vector.norm() < 1e-2);
+    }
+}
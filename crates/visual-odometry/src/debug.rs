@@ -0,0 +1,249 @@
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+
+use opencv::core::{DMatch, Mat, Scalar, Vector};
+use opencv::features2d::{DrawMatchesFlags, draw_keypoints, draw_matches};
+use opencv::prelude::*;
+
+use crate::frame::Frame;
+
+/// Receives intermediate results of the odometry pipeline: match images,
+/// keypoint overlays and per-stage statistics.
+///
+/// Sinks must not fail the pipeline, so they handle their own errors.
+pub trait DebugSink: Send {
+    /// Whether images should be rendered for this sink. Drawing is skipped
+    /// entirely when this is false.
+    fn wants_images(&self) -> bool {
+        true
+    }
+
+    /// `stage` names what the image shows, e.g. `"matches"`.
+    fn image(&mut self, frame_id: usize, stage: &str, image: &Mat);
+
+    fn statistic(&mut self, frame_id: usize, stage: &str, name: &str, value: f64);
+}
+
+/// Discards everything. The default.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct NoopSink;
+
+impl DebugSink for NoopSink {
+    fn wants_images(&self) -> bool {
+        false
+    }
+
+    fn image(&mut self, _frame_id: usize, _stage: &str, _image: &Mat) {}
+
+    fn statistic(&mut self, _frame_id: usize, _stage: &str, _name: &str, _value: f64) {}
+}
+
+/// Writes every image to `<directory>/<frame id>_<stage>.png` and drops
+/// statistics.
+#[derive(Debug, Clone)]
+pub struct ImageDirectorySink {
+    directory: PathBuf,
+}
+
+impl ImageDirectorySink {
+    /// The directory is created on the first image if it does not exist.
+    pub fn new(directory: impl Into<PathBuf>) -> Self {
+        Self {
+            directory: directory.into(),
+        }
+    }
+}
+
+impl DebugSink for ImageDirectorySink {
+    fn image(&mut self, frame_id: usize, stage: &str, image: &Mat) {
+        if let Err(e) = std::fs::create_dir_all(&self.directory) {
+            tracing::warn!(directory = %self.directory.display(), "cannot create debug image directory: {e}");
+            return;
+        }
+        let path = self.directory.join(format!("{frame_id:06}_{stage}.png"));
+        match opencv::imgcodecs::imwrite(&path.to_string_lossy(), image, &Vector::new()) {
+            Ok(true) => {}
+            Ok(false) => tracing::warn!(path = %path.display(), "debug image was not written"),
+            Err(e) => tracing::warn!(path = %path.display(), "cannot write debug image: {e}"),
+        }
+    }
+
+    fn statistic(&mut self, _frame_id: usize, _stage: &str, _name: &str, _value: f64) {}
+}
+
+#[derive(Debug)]
+pub struct DebugImage {
+    pub frame_id: usize,
+    pub stage: String,
+    pub image: Mat,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct DebugStatistic {
+    pub frame_id: usize,
+    pub stage: String,
+    pub name: String,
+    pub value: f64,
+}
+
+#[derive(Debug, Default)]
+struct Recorded {
+    images: Vec<DebugImage>,
+    statistics: Vec<DebugStatistic>,
+}
+
+/// Keeps everything in memory. Clones share the same recording, so keep one
+/// to read back what the pipeline recorded.
+#[derive(Debug, Clone, Default)]
+pub struct MemorySink {
+    recorded: Arc<Mutex<Recorded>>,
+    record_images: bool,
+}
+
+impl MemorySink {
+    pub fn new() -> Self {
+        Self {
+            record_images: true,
+            ..Self::default()
+        }
+    }
+
+    /// Records statistics only, which skips rendering images.
+    pub fn statistics_only() -> Self {
+        Self::default()
+    }
+
+    /// Removes and returns the images recorded so far.
+    pub fn take_images(&self) -> Vec<DebugImage> {
+        std::mem::take(&mut self.lock().images)
+    }
+
+    /// Removes and returns the statistics recorded so far.
+    pub fn take_statistics(&self) -> Vec<DebugStatistic> {
+        std::mem::take(&mut self.lock().statistics)
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, Recorded> {
+        // A panic while pushing cannot leave the vectors inconsistent.
+        self.recorded.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+impl DebugSink for MemorySink {
+    fn wants_images(&self) -> bool {
+        self.record_images
+    }
+
+    fn image(&mut self, frame_id: usize, stage: &str, image: &Mat) {
+        if !self.record_images {
+            return;
+        }
+        match image.try_clone() {
+            Ok(image) => self.lock().images.push(DebugImage {
+                frame_id,
+                stage: stage.to_owned(),
+                image,
+            }),
+            Err(e) => tracing::warn!(frame_id, stage, "cannot copy debug image: {e}"),
+        }
+    }
+
+    fn statistic(&mut self, frame_id: usize, stage: &str, name: &str, value: f64) {
+        self.lock().statistics.push(DebugStatistic {
+            frame_id,
+            stage: stage.to_owned(),
+            name: name.to_owned(),
+            value,
+        });
+    }
+}
+
+/// Logs statistics as `tracing` debug events. Images are not rendered.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct TracingSink;
+
+impl DebugSink for TracingSink {
+    fn wants_images(&self) -> bool {
+        false
+    }
+
+    fn image(&mut self, _frame_id: usize, _stage: &str, _image: &Mat) {}
+
+    fn statistic(&mut self, frame_id: usize, stage: &str, name: &str, value: f64) {
+        tracing::debug!(frame_id, stage, name, value, "odometry statistic");
+    }
+}
+
+/// Reports the match count between two frames and, if the sink wants
+/// images, the matches drawn side by side. Filed under `frame2`.
+pub(crate) fn report_matches(
+    sink: &mut dyn DebugSink,
+    stage: &str,
+    frame1: &Frame,
+    frame2: &Frame,
+    matches: &Vector<DMatch>,
+) -> Result<(), opencv::Error> {
+    sink.statistic(frame2.id, stage, "matches", matches.len() as f64);
+    if !sink.wants_images() {
+        return Ok(());
+    }
+    let mut output = Mat::default();
+    draw_matches(
+        &frame1.image,
+        &frame1.keypoints,
+        &frame2.image,
+        &frame2.keypoints,
+        matches,
+        &mut output,
+        Scalar::all(-1.0),
+        Scalar::all(-1.0),
+        &Vector::new(),
+        DrawMatchesFlags::NOT_DRAW_SINGLE_POINTS,
+    )?;
+    sink.image(frame2.id, stage, &output);
+    Ok(())
+}
+
+/// Reports the keypoint count of a frame and, if the sink wants images, the
+/// keypoints drawn over it.
+pub(crate) fn report_keypoints(
+    sink: &mut dyn DebugSink,
+    frame: &Frame,
+) -> Result<(), opencv::Error> {
+    sink.statistic(frame.id, "keypoints", "count", frame.keypoints.len() as f64);
+    if !sink.wants_images() {
+        return Ok(());
+    }
+    let mut output = Mat::default();
+    draw_keypoints(
+        &frame.image,
+        &frame.keypoints,
+        &mut output,
+        Scalar::all(-1.0),
+        DrawMatchesFlags::DRAW_RICH_KEYPOINTS,
+    )?;
+    sink.image(frame.id, "keypoints", &output);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_memory_sink_clones_share_recording() {
+        let reader = MemorySink::statistics_only();
+        let mut sink: Box<dyn DebugSink> = Box::new(reader.clone());
+        assert!(!sink.wants_images());
+
+        sink.statistic(4, "matches", "count", 12.0);
+        sink.image(4, "matches", &Mat::default());
+
+        let statistics = reader.take_statistics();
+        assert_eq!(statistics.len(), 1);
+        assert_eq!(statistics[0].frame_id, 4);
+        assert_eq!(statistics[0].value, 12.0);
+        assert!(reader.take_images().is_empty());
+        assert!(reader.take_statistics().is_empty());
+    }
+}
@@ -0,0 +1,71 @@
+use std::sync::mpsc::{self, Receiver, Sender};
+
+use crate::TrackingState;
+use crate::loop_closing::LoopClosure;
+use crate::map::KeyFrameId;
+
+/// Something that happened to the tracker or its map, as delivered to
+/// `VisualOdometry::subscribe` receivers.
+#[derive(Debug, Clone)]
+pub enum OdometryEvent {
+    StateChanged {
+        /// First frame the new state applies to.
+        frame_id: usize,
+        from: TrackingState,
+        to: TrackingState,
+    },
+    KeyframeInserted {
+        frame_id: usize,
+        keyframe: KeyFrameId,
+    },
+    /// Keyframe poses and map points were corrected by a loop closure.
+    LoopClosed(LoopClosure),
+    /// The map was cleared. Keyframe and map point ids seen before are gone.
+    MapReset,
+}
+
+/// Fans events out to every subscriber, dropping those whose receiver is gone.
+#[derive(Debug, Default)]
+pub(crate) struct EventBus {
+    subscribers: Vec<Sender<OdometryEvent>>,
+}
+
+impl EventBus {
+    pub(crate) fn subscribe(&mut self) -> Receiver<OdometryEvent> {
+        let (sender, receiver) = mpsc::channel();
+        self.subscribers.push(sender);
+        receiver
+    }
+
+    pub(crate) fn emit(&mut self, event: OdometryEvent) {
+        self.subscribers
+            .retain(|subscriber| subscriber.send(event.clone()).is_ok());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_events_reach_live_subscribers() {
+        let mut bus = EventBus::default();
+        let first = bus.subscribe();
+        let second = bus.subscribe();
+        drop(second);
+
+        bus.emit(OdometryEvent::MapReset);
+        bus.emit(OdometryEvent::KeyframeInserted {
+            frame_id: 3,
+            keyframe: 1,
+        });
+
+        assert!(matches!(first.try_recv(), Ok(OdometryEvent::MapReset)));
+        assert!(matches!(
+            first.try_recv(),
+            Ok(OdometryEvent::KeyframeInserted { keyframe: 1, .. })
+        ));
+        assert!(first.try_recv().is_err());
+        assert_eq!(bus.subscribers.len(), 1);
+    }
+}
@@ -0,0 +1,237 @@
+use opencv::core::{KeyPoint, Mat, NORM_HAMMING, NORM_HAMMING2, NORM_L2, Ptr, Vector};
+use opencv::features2d::{AKAZE, BRISK, Feature2D, SIFT};
+use opencv::prelude::*;
+
+use crate::OdometryError;
+use crate::utils;
+
+/// How distances between two descriptors of an extractor are measured.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum DescriptorMetric {
+    /// Bit count of the XOR, for binary descriptors (CV_8U).
+    #[default]
+    Hamming,
+    /// Count of differing bit pairs, for ORB with `wta_k` 3 or 4 (CV_8U).
+    Hamming2,
+    /// Euclidean distance, for floating point descriptors (CV_32F).
+    L2,
+}
+
+impl DescriptorMetric {
+    /// Distance below which a match over the whole image is plausible, the
+    /// default `MatcherConfig::distance_threshold`. Tuned for 256-bit ORB
+    /// and for SIFT, whose correct matches mostly lie between 100 and 250.
+    pub fn default_max_distance(self) -> f32 {
+        match self {
+            DescriptorMetric::Hamming | DescriptorMetric::Hamming2 => 50.0,
+            DescriptorMetric::L2 => 250.0,
+        }
+    }
+
+    /// Looser distance for searches confined to a small window around a
+    /// predicted location, the default `PnpConfig::max_descriptor_distance`.
+    pub fn default_guided_max_distance(self) -> f32 {
+        match self {
+            DescriptorMetric::Hamming | DescriptorMetric::Hamming2 => 100.0,
+            DescriptorMetric::L2 => 350.0,
+        }
+    }
+
+    /// OpenCV norm type for matchers using this metric.
+    pub fn norm_type(self) -> i32 {
+        match self {
+            DescriptorMetric::Hamming => NORM_HAMMING,
+            DescriptorMetric::Hamming2 => NORM_HAMMING2,
+            DescriptorMetric::L2 => NORM_L2,
+        }
+    }
+
+    /// Distance between row `i` of `a` and row `j` of `b`.
+    pub(crate) fn distance(self, a: &Mat, i: i32, b: &Mat, j: i32) -> Result<f32, opencv::Error> {
+        Ok(match self {
+            DescriptorMetric::Hamming => {
+                utils::hamming(a.at_row::<u8>(i)?, b.at_row::<u8>(j)?) as f32
+            }
+            DescriptorMetric::Hamming2 => {
+                utils::hamming2(a.at_row::<u8>(i)?, b.at_row::<u8>(j)?) as f32
+            }
+            DescriptorMetric::L2 => utils::l2(a.at_row::<f32>(i)?, b.at_row::<f32>(j)?),
+        })
+    }
+}
+
+/// Detects keypoints and computes one descriptor row per keypoint.
+///
+/// `mask` is an OpenCV detection mask: no keypoint may come from its zero
+/// pixels. It is empty when nothing is masked.
+///
+/// Frames, map points and guided matching all work on whatever descriptors
+/// the extractor produces, compared with its `metric`. Loop closing and
+/// relocalization additionally need a vocabulary trained on the same
+/// descriptors, which only exists for 32 byte `DescriptorMetric::Hamming`
+/// descriptors.
+pub trait FeatureExtractor: Send {
+    fn detect_and_compute(
+        &mut self,
+        image: &Mat,
+        mask: &Mat,
+    ) -> Result<(Vector<KeyPoint>, Mat), OdometryError>;
+
+    fn metric(&self) -> DescriptorMetric;
+
+    /// Columns of each descriptor row: bytes for binary descriptors, floats
+    /// for the others.
+    fn descriptor_size(&self) -> Result<usize, OdometryError>;
+}
+
+/// Any OpenCV `Feature2D`, optionally with a second one computing the
+/// descriptors of the first one's keypoints.
+pub struct OpenCvExtractor {
+    detector: Ptr<Feature2D>,
+    descriptor: Option<Ptr<Feature2D>>,
+    metric: DescriptorMetric,
+}
+
+impl OpenCvExtractor {
+    /// `metric` must match the descriptors `feature` computes.
+    pub fn new(feature: impl Into<Ptr<Feature2D>>, metric: DescriptorMetric) -> Self {
+        Self {
+            detector: feature.into(),
+            descriptor: None,
+            metric,
+        }
+    }
+
+    /// Keypoints from `detector`, described by `descriptor`.
+    pub fn with_descriptor(
+        detector: impl Into<Ptr<Feature2D>>,
+        descriptor: impl Into<Ptr<Feature2D>>,
+        metric: DescriptorMetric,
+    ) -> Self {
+        Self {
+            detector: detector.into(),
+            descriptor: Some(descriptor.into()),
+            metric,
+        }
+    }
+
+    /// SIFT with OpenCV's default parameters, keeping the best `nfeatures`
+    /// keypoints (0 keeps all).
+    pub fn sift(nfeatures: i32) -> Result<Self, OdometryError> {
+        let sift = SIFT::create(nfeatures, 3, 0.04, 10.0, 1.6, false)?;
+        Ok(Self::new(sift, DescriptorMetric::L2))
+    }
+
+    /// AKAZE with its default binary (MLDB) descriptors.
+    pub fn akaze() -> Result<Self, OdometryError> {
+        Ok(Self::new(AKAZE::create_def()?, DescriptorMetric::Hamming))
+    }
+
+    /// BRISK with the given AGAST detection threshold (OpenCV's default is 30).
+    pub fn brisk(threshold: i32) -> Result<Self, OdometryError> {
+        let brisk = BRISK::create(threshold, 3, 1.0)?;
+        Ok(Self::new(brisk, DescriptorMetric::Hamming))
+    }
+
+    /// Shi-Tomasi corners described with 32 byte BRIEF descriptors.
+    ///
+    /// Good features to track keep well spread corners even where FAST finds
+    /// little, at the cost of BRIEF having no rotation invariance.
+    #[cfg(feature = "xfeatures2d")]
+    pub fn gftt_brief(
+        max_corners: i32,
+        quality_level: f64,
+        min_distance: f64,
+    ) -> Result<Self, OdometryError> {
+        use opencv::features2d::GFTTDetector;
+        use opencv::xfeatures2d::BriefDescriptorExtractor;
+
+        let gftt = GFTTDetector::create(max_corners, quality_level, min_distance, 3, false, 0.04)?;
+        let brief = BriefDescriptorExtractor::create(32, false)?;
+        Ok(Self::with_descriptor(
+            gftt,
+            brief,
+            DescriptorMetric::Hamming,
+        ))
+    }
+}
+
+impl FeatureExtractor for OpenCvExtractor {
+    fn detect_and_compute(
+        &mut self,
+        image: &Mat,
+        mask: &Mat,
+    ) -> Result<(Vector<KeyPoint>, Mat), OdometryError> {
+        let mut keypoints = Vector::<KeyPoint>::new();
+        let mut descriptors = Mat::default();
+        match &mut self.descriptor {
+            None => self.detector.detect_and_compute(
+                image,
+                mask,
+                &mut keypoints,
+                &mut descriptors,
+                false,
+            )?,
+            Some(descriptor) => {
+                self.detector.detect(image, &mut keypoints, mask)?;
+                // Keypoints the descriptor cannot be computed for are dropped.
+                descriptor.compute(image, &mut keypoints, &mut descriptors)?;
+            }
+        }
+        Ok((keypoints, descriptors))
+    }
+
+    fn metric(&self) -> DescriptorMetric {
+        self.metric
+    }
+
+    fn descriptor_size(&self) -> Result<usize, OdometryError> {
+        let descriptor = self.descriptor.as_ref().unwrap_or(&self.detector);
+        Ok(descriptor.descriptor_size()? as usize)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use opencv::core::{CV_8U, CV_32F, Scalar};
+
+    #[test]
+    fn test_metric_distances() {
+        let mut a = Mat::new_rows_cols_with_default(1, 2, CV_8U, Scalar::all(0.0)).unwrap();
+        let mut b = a.try_clone().unwrap();
+        *a.at_2d_mut::<u8>(0, 0).unwrap() = 0b0000_0011;
+        *b.at_2d_mut::<u8>(0, 1).unwrap() = 0b1000_0001;
+        assert_eq!(
+            DescriptorMetric::Hamming.distance(&a, 0, &b, 0).unwrap(),
+            4.0
+        );
+        // 0b11 differs in one bit pair, 0b1000_0001 in two.
+        assert_eq!(
+            DescriptorMetric::Hamming2.distance(&a, 0, &b, 0).unwrap(),
+            3.0
+        );
+
+        let a =
+            Mat::new_rows_cols_with_default(1, 2, CV_32F, Scalar::new(3.0, 0.0, 0.0, 0.0)).unwrap();
+        let mut b = Mat::new_rows_cols_with_default(1, 2, CV_32F, Scalar::all(0.0)).unwrap();
+        *b.at_2d_mut::<f32>(0, 1).unwrap() = 4.0;
+        // (3, 3) against (0, 4).
+        let expected = (9.0f32 + 1.0).sqrt();
+        assert!((DescriptorMetric::L2.distance(&a, 0, &b, 0).unwrap() - expected).abs() < 1e-6);
+    }
+
+    #[test]
+    fn test_default_thresholds_follow_metric() {
+        use crate::matcher::MatcherConfig;
+        use crate::pnp::PnpConfig;
+
+        assert_eq!(MatcherConfig::default().distance_threshold, 50.0);
+        assert_eq!(PnpConfig::default().max_descriptor_distance, 100.0);
+        // SIFT matches mostly lie between 100 and 250.
+        let matcher = MatcherConfig::for_metric(DescriptorMetric::L2);
+        let pnp = PnpConfig::for_metric(DescriptorMetric::L2);
+        assert!(matcher.distance_threshold >= 250.0);
+        assert!(pnp.max_descriptor_distance >= matcher.distance_threshold);
+    }
+}
@@ -1,5 +1,6 @@
 use opencv::core::{KeyPoint, Mat, Vector};
 
+#[derive(Debug, Clone)]
 pub struct Frame {
     pub id: usize,
     pub image: Mat,
@@ -0,0 +1,71 @@
+use opencv::core::Point2d;
+
+/// Buckets keypoint positions into square cells so radius queries only touch
+/// nearby keypoints.
+pub(crate) struct FeatureGrid {
+    origin: Point2d,
+    cell_size: f64,
+    cols: usize,
+    rows: usize,
+    cells: Vec<Vec<usize>>,
+    points: Vec<Point2d>,
+}
+
+impl FeatureGrid {
+    pub(crate) fn new(points: &[Point2d], cell_size: f64) -> Self {
+        let cell_size = cell_size.max(1.0);
+        let (mut min_x, mut min_y) = (f64::MAX, f64::MAX);
+        let (mut max_x, mut max_y) = (f64::MIN, f64::MIN);
+        for p in points {
+            min_x = min_x.min(p.x);
+            min_y = min_y.min(p.y);
+            max_x = max_x.max(p.x);
+            max_y = max_y.max(p.y);
+        }
+        if points.is_empty() {
+            (min_x, min_y, max_x, max_y) = (0.0, 0.0, 0.0, 0.0);
+        }
+
+        let cols = ((max_x - min_x) / cell_size) as usize + 1;
+        let rows = ((max_y - min_y) / cell_size) as usize + 1;
+        let mut grid = Self {
+            origin: Point2d::new(min_x, min_y),
+            cell_size,
+            cols,
+            rows,
+            cells: vec![Vec::new(); cols * rows],
+            points: points.to_vec(),
+        };
+        for (i, p) in points.iter().enumerate() {
+            let (c, r) = grid.cell(p.x, p.y);
+            grid.cells[r * cols + c].push(i);
+        }
+        grid
+    }
+
+    /// Indices of the points within `radius` of `center`.
+    pub(crate) fn within(&self, center: Point2d, radius: f64) -> Vec<usize> {
+        let (min_c, min_r) = self.cell(center.x - radius, center.y - radius);
+        let (max_c, max_r) = self.cell(center.x + radius, center.y + radius);
+        let radius_sq = radius * radius;
+
+        let mut out = Vec::new();
+        for r in min_r..=max_r {
+            for c in min_c..=max_c {
+                for &i in &self.cells[r * self.cols + c] {
+                    let p = self.points[i];
+                    if (p.x - center.x).powi(2) + (p.y - center.y).powi(2) <= radius_sq {
+                        out.push(i);
+                    }
+                }
+            }
+        }
+        out
+    }
+
+    fn cell(&self, x: f64, y: f64) -> (usize, usize) {
+        let c = ((x - self.origin.x) / self.cell_size).floor().max(0.0) as usize;
+        let r = ((y - self.origin.y) / self.cell_size).floor().max(0.0) as usize;
+        (c.min(self.cols - 1), r.min(self.rows - 1))
+    }
+}
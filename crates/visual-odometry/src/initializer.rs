@@ -0,0 +1,571 @@
+use opencv::calib3d::{
+    RANSAC, decompose_homography_mat, find_essential_mat, find_homography, recover_pose_estimated,
+};
+use opencv::core::{DMatch, Mat, Point2d, Point3d, Vector};
+use opencv::prelude::*;
+use r_slam_common::camera::Camera;
+
+use crate::OdometryError;
+use crate::frame::Frame;
+use crate::triangulation;
+use crate::utils::{self, add3, dot3, invert3, mul3, mul3v, norm3, scale3, sub3, transpose3};
+
+/// Chi-square thresholds at 95% for 1 and 2 degrees of freedom.
+const CHI2_1DOF: f64 = 3.841;
+const CHI2_2DOF: f64 = 5.991;
+/// Points whose viewing rays are closer to parallel than this are kept in the
+/// inlier count but not triangulated (about 0.36 degrees).
+const MIN_POINT_COS_PARALLAX: f64 = 0.99998;
+
+#[derive(Debug, Clone, Copy)]
+pub struct InitializerConfig {
+    pub min_matches: usize,
+    /// Standard deviation of keypoint measurement noise, in pixels.
+    pub sigma: f64,
+    /// Median parallax the initial points need, in degrees.
+    pub min_parallax_deg: f64,
+    pub min_triangulated: usize,
+    /// The homography is chosen when `S_H / (S_H + S_F)` exceeds this.
+    pub homography_ratio: f64,
+}
+
+impl Default for InitializerConfig {
+    /// Values follow ORB-SLAM's monocular initializer.
+    fn default() -> Self {
+        Self {
+            min_matches: 100,
+            sigma: 1.0,
+            min_parallax_deg: 1.0,
+            min_triangulated: 50,
+            homography_ratio: 0.45,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum InitModel {
+    Homography,
+    Essential,
+}
+
+/// Result of a successful two-view initialization.
+///
+/// `rotation` and `translation` map points from the first frame's camera
+/// coordinates into the second's. The scene is scaled so the median depth of
+/// `points` in the first frame is 1.
+#[derive(Debug, Clone)]
+pub struct Initialization {
+    pub model: InitModel,
+    pub rotation: Mat,
+    pub translation: Mat,
+    /// Triangulated points in the first frame's camera coordinates.
+    pub points: Vec<Point3d>,
+    /// The match each point was triangulated from, index aligned with `points`.
+    pub matches: Vector<DMatch>,
+    /// Median parallax of `points`, in degrees.
+    pub parallax_deg: f64,
+}
+
+/// Monocular two-view initializer.
+///
+/// Fits a homography and an essential matrix to the same matches, scores both
+/// with the symmetric transfer error and reconstructs from the better model.
+/// Planar or low-parallax scenes favour the homography.
+pub struct Initializer {
+    camera: Camera,
+    config: InitializerConfig,
+}
+
+struct Reconstruction {
+    rotation: [[f64; 3]; 3],
+    translation: [f64; 3],
+    /// Indices into the match list of points with enough parallax.
+    triangulated: Vec<(usize, [f64; 3])>,
+    /// Points passing cheirality and reprojection checks.
+    good: usize,
+    parallax_deg: f64,
+}
+
+impl Initializer {
+    pub fn new(camera: Camera, config: InitializerConfig) -> Self {
+        Self { camera, config }
+    }
+
+    /// Attempts to initialize from `matches` between `frame1` and `frame2`, as
+    /// produced by `frame_match`.
+    ///
+    /// Fails with `LowParallax` or `AmbiguousInitialization` while the frames
+    /// are too close together, so a later frame may still succeed against
+    /// `frame1`, and with `NotEnoughPoints` or `DegenerateEssential` when the
+    /// matches support no motion at all.
+    pub fn initialize(
+        &self,
+        frame1: &Frame,
+        frame2: &Frame,
+        matches: &Vector<DMatch>,
+    ) -> Result<Initialization, OdometryError> {
+        if matches.len() < self.config.min_matches {
+            return Err(OdometryError::NotEnoughPoints);
+        }
+
+        let (points1, points2) = utils::undistorted_matches(&self.camera, frame1, frame2, matches)?;
+        let cv_points1 = Vector::<Point2d>::from_iter(points1.iter().copied());
+        let cv_points2 = Vector::<Point2d>::from_iter(points2.iter().copied());
+        let sigma = self.config.sigma;
+
+        let homography = find_homography(
+            &cv_points1,
+            &cv_points2,
+            &mut Mat::default(),
+            RANSAC,
+            CHI2_2DOF.sqrt() * sigma,
+        )?;
+        let essential = find_essential_mat(
+            &cv_points1,
+            &cv_points2,
+            &self.camera.camera_matrix,
+            RANSAC,
+            0.999,
+            CHI2_1DOF.sqrt() * sigma,
+            1000,
+            &mut Mat::default(),
+        )?;
+        let essential = if essential.rows() >= 3 && essential.cols() == 3 {
+            Some(essential.row_bounds(0, 3)?.try_clone()?)
+        } else {
+            None
+        };
+
+        let (score_h, inliers_h) = if homography.empty() {
+            (0.0, vec![false; points1.len()])
+        } else {
+            score_homography(&utils::mat3(&homography)?, &points1, &points2, sigma)
+        };
+        let (score_f, inliers_f) = match &essential {
+            Some(essential) => {
+                let fundamental = self.fundamental(&utils::mat3(essential)?)?;
+                score_fundamental(&fundamental, &points1, &points2, sigma)
+            }
+            None => (0.0, vec![false; points1.len()]),
+        };
+        if score_h + score_f <= 0.0 {
+            return Err(OdometryError::NotEnoughPoints);
+        }
+
+        let ratio = score_h / (score_h + score_f);
+        tracing::debug!(score_h, score_f, ratio, "initializer model scores");
+
+        let (model, reconstruction) = if ratio > self.config.homography_ratio {
+            let reconstruction =
+                self.reconstruct_homography(&homography, &points1, &points2, &inliers_h)?;
+            (InitModel::Homography, reconstruction)
+        } else {
+            let essential = essential.ok_or(OdometryError::DegenerateEssential)?;
+            let reconstruction = self.reconstruct_essential(
+                &essential,
+                &cv_points1,
+                &cv_points2,
+                &points1,
+                &points2,
+                &inliers_f,
+            )?;
+            (InitModel::Essential, reconstruction)
+        };
+
+        // Points seen with too little parallax are not triangulated, so a
+        // short baseline shows up as too few points rather than as a low
+        // median parallax.
+        if reconstruction.good < self.config.min_triangulated
+            || reconstruction.triangulated.len() < self.config.min_triangulated
+            || reconstruction.parallax_deg < self.config.min_parallax_deg
+        {
+            return Err(OdometryError::LowParallax(reconstruction.parallax_deg));
+        }
+
+        self.finish(model, reconstruction, matches)
+    }
+
+    /// `F = K^-T E K^-1`.
+    fn fundamental(&self, essential: &[[f64; 3]; 3]) -> Result<[[f64; 3]; 3], OdometryError> {
+        let k_inv = invert3(&utils::mat3(&self.camera.camera_matrix)?)
+            .ok_or(OdometryError::DegenerateEssential)?;
+        Ok(mul3(&transpose3(&k_inv), &mul3(essential, &k_inv)))
+    }
+
+    fn reconstruct_essential(
+        &self,
+        essential: &Mat,
+        cv_points1: &Vector<Point2d>,
+        cv_points2: &Vector<Point2d>,
+        points1: &[Point2d],
+        points2: &[Point2d],
+        inliers: &[bool],
+    ) -> Result<Reconstruction, OdometryError> {
+        let mut rotation = Mat::default();
+        let mut translation = Mat::default();
+        recover_pose_estimated(
+            essential,
+            cv_points1,
+            cv_points2,
+            &self.camera.camera_matrix,
+            &mut rotation,
+            &mut translation,
+            &mut Mat::default(),
+        )?;
+        self.check_rt(
+            utils::mat3(&rotation)?,
+            utils::vec3(&translation)?,
+            points1,
+            points2,
+            inliers,
+        )
+    }
+
+    /// Tries every decomposition of the homography and keeps the one that
+    /// triangulates clearly more points than the others.
+    fn reconstruct_homography(
+        &self,
+        homography: &Mat,
+        points1: &[Point2d],
+        points2: &[Point2d],
+        inliers: &[bool],
+    ) -> Result<Reconstruction, OdometryError> {
+        let mut rotations = Vector::<Mat>::new();
+        let mut translations = Vector::<Mat>::new();
+        let mut normals = Vector::<Mat>::new();
+        decompose_homography_mat(
+            homography,
+            &self.camera.camera_matrix,
+            &mut rotations,
+            &mut translations,
+            &mut normals,
+        )?;
+
+        let mut best: Option<Reconstruction> = None;
+        let mut second_good = 0;
+        for (rotation, translation) in rotations.iter().zip(translations.iter()) {
+            let candidate = self.check_rt(
+                utils::mat3(&rotation)?,
+                utils::vec3(&translation)?,
+                points1,
+                points2,
+                inliers,
+            )?;
+            match &best {
+                Some(b) if candidate.good <= b.good => {
+                    second_good = second_good.max(candidate.good)
+                }
+                _ => {
+                    second_good = best.as_ref().map_or(0, |b| b.good);
+                    best = Some(candidate);
+                }
+            }
+        }
+
+        let best = best.ok_or(OdometryError::NotEnoughPoints)?;
+        if second_good as f64 > 0.75 * best.good as f64 {
+            return Err(OdometryError::AmbiguousInitialization);
+        }
+        Ok(best)
+    }
+
+    /// Triangulates the inlier matches for a candidate motion and counts the
+    /// points that lie in front of both cameras and reproject within `2 sigma`.
+    fn check_rt(
+        &self,
+        rotation: [[f64; 3]; 3],
+        translation: [f64; 3],
+        points1: &[Point2d],
+        points2: &[Point2d],
+        inliers: &[bool],
+    ) -> Result<Reconstruction, OdometryError> {
+        let indices: Vec<usize> = (0..points1.len()).filter(|&i| inliers[i]).collect();
+        let identity = [[1.0, 0.0, 0.0], [0.0, 1.0, 0.0], [0.0, 0.0, 1.0]];
+        let selected1: Vec<Point2d> = indices.iter().map(|&i| points1[i]).collect();
+        let selected2: Vec<Point2d> = indices.iter().map(|&i| points2[i]).collect();
+        let points = triangulation::linear_triangulate(
+            &triangulation::projection_matrix(&self.camera, &identity, &[0.0; 3])?,
+            &triangulation::projection_matrix(&self.camera, &rotation, &translation)?,
+            &selected1,
+            &selected2,
+        )?;
+
+        // Second camera centre in the first camera's frame: -R^T t.
+        let rt = transpose3(&rotation);
+        let centre2 = scale3(&mul3v(&rt, &translation), -1.0);
+        let max_error = 4.0 * self.config.sigma * self.config.sigma;
+
+        let mut triangulated = Vec::new();
+        let mut parallaxes = Vec::new();
+        let mut good = 0;
+        for (&i, point) in indices.iter().zip(points) {
+            if !point.iter().all(|v| v.is_finite()) {
+                continue;
+            }
+
+            let ray1 = point;
+            let ray2 = sub3(&point, &centre2);
+            let cos_parallax = dot3(&ray1, &ray2) / (norm3(&ray1) * norm3(&ray2));
+
+            let point2 = add3(&mul3v(&rotation, &point), &translation);
+            if (point[2] <= 0.0 || point2[2] <= 0.0) && cos_parallax < MIN_POINT_COS_PARALLAX {
+                continue;
+            }
+            if utils::reprojection_error_sq(&self.camera, &point, &points1[i]) > max_error
+                || utils::reprojection_error_sq(&self.camera, &point2, &points2[i]) > max_error
+            {
+                continue;
+            }
+
+            good += 1;
+            if cos_parallax < MIN_POINT_COS_PARALLAX {
+                triangulated.push((i, point));
+                parallaxes.push(cos_parallax.clamp(-1.0, 1.0).acos().to_degrees());
+            }
+        }
+
+        parallaxes.sort_by(|a, b| a.total_cmp(b));
+        let parallax_deg = parallaxes.get(parallaxes.len() / 2).copied().unwrap_or(0.0);
+
+        Ok(Reconstruction {
+            rotation,
+            translation,
+            triangulated,
+            good,
+            parallax_deg,
+        })
+    }
+
+    /// Rescales the reconstruction to unit median depth and packs it up.
+    fn finish(
+        &self,
+        model: InitModel,
+        reconstruction: Reconstruction,
+        matches: &Vector<DMatch>,
+    ) -> Result<Initialization, OdometryError> {
+        let mut depths: Vec<f64> = reconstruction
+            .triangulated
+            .iter()
+            .map(|(_, p)| p[2])
+            .collect();
+        depths.sort_by(|a, b| a.total_cmp(b));
+        let Some(&median_depth) = depths.get(depths.len() / 2) else {
+            return Err(OdometryError::NotEnoughPoints);
+        };
+        if median_depth <= 0.0 {
+            return Err(OdometryError::NotEnoughPoints);
+        }
+        let scale = 1.0 / median_depth;
+
+        let mut points = Vec::with_capacity(reconstruction.triangulated.len());
+        let mut kept = Vector::<DMatch>::with_capacity(reconstruction.triangulated.len());
+        for (i, p) in &reconstruction.triangulated {
+            points.push(Point3d::new(p[0] * scale, p[1] * scale, p[2] * scale));
+            kept.push(matches.get(*i)?);
+        }
+
+        Ok(Initialization {
+            model,
+            rotation: Mat::from_slice_2d(&reconstruction.rotation)?,
+            translation: utils::column3(scale3(&reconstruction.translation, scale))?,
+            points,
+            matches: kept,
+            parallax_deg: reconstruction.parallax_deg,
+        })
+    }
+}
+
+/// ORB-SLAM style homography score over the symmetric transfer error.
+fn score_homography(
+    h: &[[f64; 3]; 3],
+    points1: &[Point2d],
+    points2: &[Point2d],
+    sigma: f64,
+) -> (f64, Vec<bool>) {
+    let Some(h_inv) = invert3(h) else {
+        return (0.0, vec![false; points1.len()]);
+    };
+    let inv_sigma2 = 1.0 / (sigma * sigma);
+
+    let mut score = 0.0;
+    let mut inliers = vec![true; points1.len()];
+    for (i, (p1, p2)) in points1.iter().zip(points2).enumerate() {
+        for (from, to, m) in [(p1, p2, h), (p2, p1, &h_inv)] {
+            let chi2 = transfer_error(m, from, to) * inv_sigma2;
+            if chi2 > CHI2_2DOF {
+                inliers[i] = false;
+            } else {
+                score += CHI2_2DOF - chi2;
+            }
+        }
+    }
+    (score, inliers)
+}
+
+/// ORB-SLAM style fundamental score over the symmetric point-to-epipolar-line
+/// distance. Scored against the 2 DOF threshold so it is comparable to the
+/// homography score.
+fn score_fundamental(
+    f: &[[f64; 3]; 3],
+    points1: &[Point2d],
+    points2: &[Point2d],
+    sigma: f64,
+) -> (f64, Vec<bool>) {
+    let f_t = transpose3(f);
+    let inv_sigma2 = 1.0 / (sigma * sigma);
+
+    let mut score = 0.0;
+    let mut inliers = vec![true; points1.len()];
+    for (i, (p1, p2)) in points1.iter().zip(points2).enumerate() {
+        for (from, to, m) in [(p1, p2, f), (p2, p1, &f_t)] {
+            let chi2 = epipolar_error(m, from, to) * inv_sigma2;
+            if chi2 > CHI2_1DOF {
+                inliers[i] = false;
+            } else {
+                score += CHI2_2DOF - chi2;
+            }
+        }
+    }
+    (score, inliers)
+}
+
+/// Squared distance between `to` and `from` mapped through homography `h`.
+fn transfer_error(h: &[[f64; 3]; 3], from: &Point2d, to: &Point2d) -> f64 {
+    let p = mul3v(h, &[from.x, from.y, 1.0]);
+    let inv_w = 1.0 / p[2];
+    (to.x - p[0] * inv_w).powi(2) + (to.y - p[1] * inv_w).powi(2)
+}
+
+/// Squared distance between `to` and the epipolar line `f * from`.
+fn epipolar_error(f: &[[f64; 3]; 3], from: &Point2d, to: &Point2d) -> f64 {
+    let line = mul3v(f, &[from.x, from.y, 1.0]);
+    let num = line[0] * to.x + line[1] * to.y + line[2];
+    num * num / (line[0] * line[0] + line[1] * line[1])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util;
+    use nalgebra::{Isometry3, Vector3};
+
+    /// Motion from the first camera's coordinates into the second's.
+    fn motion() -> Isometry3<f64> {
+        Isometry3::new(Vector3::new(-0.5, 0.05, 0.1), Vector3::new(0.0, 0.05, 0.0))
+    }
+
+    /// Points spread over the view of the first camera, at depth `depth(x, y)`.
+    fn scene(depth: impl Fn(f64, f64, &mut utils::SplitMix64) -> f64) -> Vec<Vector3<f64>> {
+        let mut rng = utils::SplitMix64(7);
+        (0..150)
+            .map(|_| {
+                let x = 4.0 * rng.unit() - 2.0;
+                let y = 3.0 * rng.unit() - 1.5;
+                let z = depth(x, y, &mut rng);
+                // (x, y) is where the point lies at depth 4.
+                Vector3::new(x * z / 4.0, y * z / 4.0, z)
+            })
+            .collect()
+    }
+
+    /// Frames seeing `points`, in the first camera's coordinates, from the
+    /// origin and after `motion`, matched one to one.
+    fn two_views(points: &[Vector3<f64>]) -> (Frame, Frame, Vector<DMatch>) {
+        (
+            test_util::frame(0, points, &Isometry3::identity(), &Mat::default()),
+            test_util::frame(1, points, &motion(), &Mat::default()),
+            test_util::one_to_one(points.len()),
+        )
+    }
+
+    /// The motion is recovered up to the scale of the translation, and the
+    /// points are scaled to unit median depth.
+    fn assert_recovered(initialization: &Initialization) {
+        let rotation = utils::mat3(&initialization.rotation).unwrap();
+        let expected = motion().rotation.to_rotation_matrix();
+        for (r, row) in rotation.iter().enumerate() {
+            for (c, value) in row.iter().enumerate() {
+                assert!((value - expected[(r, c)]).abs() < 1e-3, "{rotation:?}");
+            }
+        }
+        let translation = Vector3::from(utils::vec3(&initialization.translation).unwrap());
+        let cos = translation
+            .normalize()
+            .dot(&motion().translation.vector.normalize());
+        assert!(cos > 0.999, "{translation:?}");
+
+        assert!(initialization.points.len() >= 140);
+        assert_eq!(initialization.points.len(), initialization.matches.len());
+        let mut depths: Vec<f64> = initialization.points.iter().map(|p| p.z).collect();
+        depths.sort_by(|a, b| a.total_cmp(b));
+        assert!((depths[depths.len() / 2] - 1.0).abs() < 1e-9);
+        assert!(initialization.parallax_deg > 1.0);
+    }
+
+    #[test]
+    fn test_initializes_general_scene_from_essential() {
+        let points = scene(|_, _, rng| 3.0 + 4.0 * rng.unit());
+        let (frame1, frame2, matches) = two_views(&points);
+        let initializer = Initializer::new(test_util::camera(), InitializerConfig::default());
+        let initialization = initializer.initialize(&frame1, &frame2, &matches).unwrap();
+        assert_eq!(initialization.model, InitModel::Essential);
+        assert_recovered(&initialization);
+    }
+
+    #[test]
+    fn test_initializes_planar_scene_from_homography() {
+        // The plane z = 4 + 0.2 x + 0.1 y, intersected with the ray through
+        // (x, y, 4).
+        let points = scene(|x, y, _| 4.0 / (1.0 - 0.05 * x - 0.025 * y));
+        let (frame1, frame2, matches) = two_views(&points);
+        let initializer = Initializer::new(test_util::camera(), InitializerConfig::default());
+        let initialization = initializer.initialize(&frame1, &frame2, &matches).unwrap();
+        assert_eq!(initialization.model, InitModel::Homography);
+        assert_recovered(&initialization);
+    }
+
+    #[test]
+    fn test_too_few_matches() {
+        let points = scene(|_, _, rng| 3.0 + 4.0 * rng.unit());
+        let (frame1, frame2, matches) = two_views(&points[..50]);
+        let initializer = Initializer::new(test_util::camera(), InitializerConfig::default());
+        assert!(matches!(
+            initializer.initialize(&frame1, &frame2, &matches),
+            Err(OdometryError::NotEnoughPoints)
+        ));
+    }
+
+    #[test]
+    fn test_short_baseline_is_low_parallax() {
+        let points = scene(|_, _, rng| 3.0 + 4.0 * rng.unit());
+        let frame1 = test_util::frame(0, &points, &Isometry3::identity(), &Mat::default());
+        let frame2 = test_util::frame(
+            1,
+            &points,
+            &Isometry3::translation(-0.02, 0.0, 0.0),
+            &Mat::default(),
+        );
+        let matches = test_util::one_to_one(points.len());
+        let initializer = Initializer::new(test_util::camera(), InitializerConfig::default());
+        assert!(matches!(
+            initializer.initialize(&frame1, &frame2, &matches),
+            Err(OdometryError::LowParallax(_) | OdometryError::AmbiguousInitialization)
+        ));
+    }
+
+    #[test]
+    fn test_finish_without_points() {
+        let initializer = Initializer::new(test_util::camera(), InitializerConfig::default());
+        let reconstruction = Reconstruction {
+            rotation: [[1.0, 0.0, 0.0], [0.0, 1.0, 0.0], [0.0, 0.0, 1.0]],
+            translation: [0.0, 0.0, 1.0],
+            triangulated: Vec::new(),
+            good: 0,
+            parallax_deg: 0.0,
+        };
+        assert!(matches!(
+            initializer.finish(InitModel::Essential, reconstruction, &Vector::new()),
+            Err(OdometryError::NotEnoughPoints)
+        ));
+    }
+}
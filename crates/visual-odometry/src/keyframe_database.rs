@@ -0,0 +1,143 @@
+use std::collections::{BTreeSet, HashMap};
+
+use crate::map::KeyFrameId;
+use crate::vocabulary::{BowVector, WordId};
+
+/// Candidates must share at least this fraction of the words shared by the
+/// best candidate.
+const MIN_COMMON_WORDS_RATIO: f64 = 0.8;
+
+/// Inverted index from vocabulary words to the keyframes containing them, for
+/// place recognition.
+#[derive(Debug, Clone, Default)]
+pub struct KeyFrameDatabase {
+    inverted: HashMap<WordId, Vec<KeyFrameId>>,
+    bows: HashMap<KeyFrameId, BowVector>,
+}
+
+impl KeyFrameDatabase {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Indexes `keyframe` under the words of `bow`, replacing any previous
+    /// entry for it.
+    pub fn add(&mut self, keyframe: KeyFrameId, bow: BowVector) {
+        self.erase(keyframe);
+        for (word, _) in bow.iter() {
+            self.inverted.entry(word).or_default().push(keyframe);
+        }
+        self.bows.insert(keyframe, bow);
+    }
+
+    pub fn erase(&mut self, keyframe: KeyFrameId) {
+        let Some(bow) = self.bows.remove(&keyframe) else {
+            return;
+        };
+        for (word, _) in bow.iter() {
+            if let Some(keyframes) = self.inverted.get_mut(&word) {
+                keyframes.retain(|&kf| kf != keyframe);
+            }
+        }
+    }
+
+    pub fn clear(&mut self) {
+        self.inverted.clear();
+        self.bows.clear();
+    }
+
+    pub fn len(&self) -> usize {
+        self.bows.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.bows.is_empty()
+    }
+
+    /// Keyframes resembling `bow`, best first, with their similarity scores.
+    ///
+    /// Only keyframes sharing enough words with `bow` are scored, and those
+    /// scoring below `min_score` or listed in `exclude` are dropped.
+    pub fn query(
+        &self,
+        bow: &BowVector,
+        min_score: f64,
+        exclude: &BTreeSet<KeyFrameId>,
+    ) -> Vec<(KeyFrameId, f64)> {
+        let mut common_words: HashMap<KeyFrameId, usize> = HashMap::new();
+        for (word, _) in bow.iter() {
+            for &keyframe in self.inverted.get(&word).into_iter().flatten() {
+                if !exclude.contains(&keyframe) {
+                    *common_words.entry(keyframe).or_default() += 1;
+                }
+            }
+        }
+        let Some(&max_common) = common_words.values().max() else {
+            return Vec::new();
+        };
+        let min_common = (MIN_COMMON_WORDS_RATIO * max_common as f64) as usize;
+
+        let mut candidates: Vec<(KeyFrameId, f64)> = common_words
+            .into_iter()
+            .filter(|&(_, common)| common >= min_common)
+            .filter_map(|(keyframe, _)| {
+                let score = self.bows.get(&keyframe)?.score(bow);
+                (score >= min_score).then_some((keyframe, score))
+            })
+            .collect();
+        candidates.sort_by(|a, b| b.1.total_cmp(&a.1).then(a.0.cmp(&b.0)));
+        candidates
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::ops::Range;
+
+    use super::*;
+    use crate::test_util;
+    use crate::vocabulary;
+
+    #[test]
+    fn test_query_drops_excluded_and_few_common_words() {
+        let descriptors = test_util::descriptors(40, 9);
+        let vocabulary = test_util::vocabulary(&descriptors);
+        let descriptors = vocabulary::descriptors_from_mat(&descriptors).unwrap();
+        let bow = |ranges: &[Range<usize>]| {
+            let selected: Vec<_> = ranges
+                .iter()
+                .cloned()
+                .flatten()
+                .map(|i| descriptors[i])
+                .collect();
+            vocabulary.transform(&selected, 0).0
+        };
+
+        // Of the 20 query words, keyframe 1 has all, 2 has 17, 3 has 10 and 4
+        // none.
+        let mut database = KeyFrameDatabase::new();
+        database.add(1, bow(&[0..20]));
+        database.add(2, bow(&[0..17, 30..33]));
+        database.add(3, bow(&[0..10, 20..30]));
+        database.add(4, bow(&[30..40]));
+        let query = bow(&[0..20]);
+        let ids = |database: &KeyFrameDatabase, exclude: &[KeyFrameId], min_score: f64| {
+            let exclude: BTreeSet<KeyFrameId> = exclude.iter().copied().collect();
+            database
+                .query(&query, min_score, &exclude)
+                .into_iter()
+                .map(|(keyframe, _)| keyframe)
+                .collect::<Vec<_>>()
+        };
+
+        // 3 shares less than 80% of the words the best candidate shares.
+        assert_eq!(ids(&database, &[], 0.0), [1, 2]);
+        assert_eq!(ids(&database, &[1], 0.0), [2]);
+        // Excluded keyframes do not raise the bar for the others.
+        assert_eq!(ids(&database, &[1, 2], 0.0), [3]);
+        assert_eq!(ids(&database, &[], 0.9), [1]);
+
+        database.erase(1);
+        assert_eq!(ids(&database, &[], 0.0), [2]);
+    }
+}
@@ -0,0 +1,206 @@
+use crate::utils::{dot3, mul3, norm3, sub3, transpose3};
+
+/// When a tracked frame is promoted to a keyframe.
+///
+/// A frame is only considered once `min_frames` frames have passed since the
+/// last keyframe and it still tracks `min_tracked` points. It is then inserted
+/// as soon as any one of the other criteria fires.
+#[derive(Debug, Clone, Copy)]
+pub struct KeyframeConfig {
+    /// Frames to skip after a keyframe before another one is considered.
+    pub min_frames: usize,
+    /// A keyframe is inserted at least this often, in frames.
+    pub max_frames: usize,
+    /// Frames tracking fewer points than this are never inserted.
+    pub min_tracked: usize,
+    /// Insert once the frame tracks less than this fraction of the points
+    /// observed by the reference keyframe.
+    pub tracked_ratio: f64,
+    /// Insert once the median angle between the rays from the reference
+    /// keyframe and from the frame to the tracked points reaches this, in
+    /// degrees.
+    pub parallax_deg: f64,
+    /// Insert once the camera has rotated this much since the reference
+    /// keyframe, in degrees.
+    pub rotation_deg: f64,
+    /// Insert once the camera centre has moved this far from the reference
+    /// keyframe, in map units.
+    pub translation: f64,
+}
+
+impl Default for KeyframeConfig {
+    fn default() -> Self {
+        Self {
+            min_frames: 0,
+            max_frames: 30,
+            min_tracked: 15,
+            tracked_ratio: 0.75,
+            parallax_deg: 5.0,
+            rotation_deg: 15.0,
+            translation: f64::INFINITY,
+        }
+    }
+}
+
+/// Motion and tracking quality of a frame relative to the reference keyframe.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct KeyframeStats {
+    pub frames_since_keyframe: usize,
+    /// Map points tracked in the frame.
+    pub tracked: usize,
+    /// Map points observed by the reference keyframe.
+    pub reference_tracked: usize,
+    pub parallax_deg: f64,
+    pub rotation_deg: f64,
+    pub translation: f64,
+}
+
+/// The criterion that made a frame a keyframe.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum KeyframeReason {
+    MaxFrames,
+    TrackedRatio,
+    Parallax,
+    Rotation,
+    Translation,
+}
+
+pub struct KeyframePolicy {
+    config: KeyframeConfig,
+}
+
+impl KeyframePolicy {
+    pub fn new(config: KeyframeConfig) -> Self {
+        Self { config }
+    }
+
+    pub fn config(&self) -> &KeyframeConfig {
+        &self.config
+    }
+
+    /// Returns why the frame should become a keyframe, or `None` to skip it.
+    pub fn decide(&self, stats: &KeyframeStats) -> Option<KeyframeReason> {
+        let config = &self.config;
+        if stats.frames_since_keyframe < config.min_frames || stats.tracked < config.min_tracked {
+            return None;
+        }
+
+        if stats.frames_since_keyframe >= config.max_frames {
+            Some(KeyframeReason::MaxFrames)
+        } else if (stats.tracked as f64) < config.tracked_ratio * stats.reference_tracked as f64 {
+            Some(KeyframeReason::TrackedRatio)
+        } else if stats.parallax_deg >= config.parallax_deg {
+            Some(KeyframeReason::Parallax)
+        } else if stats.rotation_deg >= config.rotation_deg {
+            Some(KeyframeReason::Rotation)
+        } else if stats.translation >= config.translation {
+            Some(KeyframeReason::Translation)
+        } else {
+            None
+        }
+    }
+}
+
+/// Median angle, in degrees, subtended at each point by the two camera
+/// centres. Zero without points.
+pub(crate) fn median_parallax(points: &[[f64; 3]], centre1: &[f64; 3], centre2: &[f64; 3]) -> f64 {
+    let mut angles: Vec<f64> = points
+        .iter()
+        .filter_map(|point| {
+            let ray1 = sub3(point, centre1);
+            let ray2 = sub3(point, centre2);
+            let norms = norm3(&ray1) * norm3(&ray2);
+            (norms > 0.0).then(|| (dot3(&ray1, &ray2) / norms).clamp(-1.0, 1.0).acos())
+        })
+        .collect();
+    if angles.is_empty() {
+        return 0.0;
+    }
+    let mid = angles.len() / 2;
+    let (_, median, _) = angles.select_nth_unstable_by(mid, f64::total_cmp);
+    median.to_degrees()
+}
+
+/// Angle of the rotation taking `rotation1` to `rotation2`, in degrees.
+pub(crate) fn rotation_angle(rotation1: &[[f64; 3]; 3], rotation2: &[[f64; 3]; 3]) -> f64 {
+    let relative = mul3(rotation2, &transpose3(rotation1));
+    let trace = relative[0][0] + relative[1][1] + relative[2][2];
+    ((trace - 1.0) / 2.0).clamp(-1.0, 1.0).acos().to_degrees()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn stats() -> KeyframeStats {
+        KeyframeStats {
+            frames_since_keyframe: 5,
+            tracked: 90,
+            reference_tracked: 100,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_steady_tracking_is_not_a_keyframe() {
+        let policy = KeyframePolicy::new(KeyframeConfig::default());
+        assert_eq!(policy.decide(&stats()), None);
+    }
+
+    #[test]
+    fn test_first_firing_criterion_is_reported() {
+        let policy = KeyframePolicy::new(KeyframeConfig::default());
+        let lost_points = KeyframeStats {
+            tracked: 60,
+            ..stats()
+        };
+        assert_eq!(
+            policy.decide(&lost_points),
+            Some(KeyframeReason::TrackedRatio)
+        );
+
+        let rotated = KeyframeStats {
+            rotation_deg: 20.0,
+            ..stats()
+        };
+        assert_eq!(policy.decide(&rotated), Some(KeyframeReason::Rotation));
+
+        let stale = KeyframeStats {
+            frames_since_keyframe: 30,
+            ..stats()
+        };
+        assert_eq!(policy.decide(&stale), Some(KeyframeReason::MaxFrames));
+    }
+
+    #[test]
+    fn test_gates_override_criteria() {
+        let policy = KeyframePolicy::new(KeyframeConfig {
+            min_frames: 10,
+            ..Default::default()
+        });
+        let early = KeyframeStats {
+            tracked: 60,
+            ..stats()
+        };
+        assert_eq!(policy.decide(&early), None);
+
+        let weak = KeyframeStats {
+            frames_since_keyframe: 40,
+            tracked: 10,
+            ..stats()
+        };
+        assert_eq!(policy.decide(&weak), None);
+    }
+
+    #[test]
+    fn test_motion_measures() {
+        let points = [[0.0, 0.0, 1.0], [0.0, 0.0, 2.0], [0.0, 0.0, 3.0]];
+        let parallax = median_parallax(&points, &[0.0; 3], &[1.0, 0.0, 0.0]);
+        assert!((parallax - 26.565).abs() < 1e-3);
+
+        let identity = [[1.0, 0.0, 0.0], [0.0, 1.0, 0.0], [0.0, 0.0, 1.0]];
+        let (s, c) = 30f64.to_radians().sin_cos();
+        let yaw = [[c, -s, 0.0], [s, c, 0.0], [0.0, 0.0, 1.0]];
+        assert!((rotation_angle(&identity, &yaw) - 30.0).abs() < 1e-9);
+    }
+}
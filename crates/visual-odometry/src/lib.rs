@@ -1,11 +1,73 @@
+use std::collections::HashSet;
+use std::sync::Arc;
+use std::sync::mpsc::Receiver;
+
+use bundle_adjustment::{BundleAdjuster, BundleAdjustmentConfig};
 use camera::Camera;
-use frame::Frame;
-use opencv::core::{DMatch, Mat, NORM_HAMMING, Ptr, Scalar, Vector, no_array};
-use opencv::features2d::{BFMatcher, ORB, ORB_ScoreType, draw_matches};
+use debug::{DebugSink, NoopSink};
+use events::{EventBus, OdometryEvent};
+use feature_extractor::{DescriptorMetric, FeatureExtractor};
+use initializer::{Initializer, InitializerConfig};
+use keyframe_database::KeyFrameDatabase;
+use keyframe_policy::{KeyframeConfig, KeyframePolicy, KeyframeStats};
+use loop_closing::{LoopCloser, LoopClosingConfig};
+use map::{KeyFrame, KeyFrameId, Map, MapError, MapPointId, SharedMap};
+use masking::FrameMask;
+use matcher::{FeatureMatcher, MatcherConfig};
+use motion_model::{MotionModel, MotionModelConfig};
+use nalgebra::Isometry3;
+use opencv::calib3d::{RANSAC, find_essential_mat, recover_pose_estimated};
+use opencv::core::{CV_64F, DMatch, Mat, Point2f, Vector};
+use opencv::features2d::ORB_ScoreType;
 use opencv::prelude::*;
+use optical_flow::{KltConfig, KltTracker};
+use pnp::{LocalMapPoint, PnpConfig, PnpResult, PnpTracker};
+use pose_graph::PoseGraphError;
+use relocalization::{Relocalization, RelocalizationConfig, Relocalizer};
+use triangulation::TriangulationConfig;
+use utils::{mul3v, norm3, scale3, sub3, transpose3};
+use vocabulary::{DESCRIPTOR_BYTES, Vocabulary, VocabularyError};
 
 use r_slam_common::camera;
+pub mod bundle_adjustment;
+pub mod debug;
+pub mod events;
+pub mod feature_extractor;
 mod frame;
+mod grid;
+pub mod initializer;
+pub mod keyframe_database;
+pub mod keyframe_policy;
+pub mod lie;
+pub mod loop_closing;
+pub mod map;
+pub mod masking;
+pub mod matcher;
+pub mod motion_model;
+pub mod optical_flow;
+mod orb_extractor;
+pub mod pnp;
+pub mod pose_graph;
+pub mod relocalization;
+pub mod sim3_solver;
+#[cfg(test)]
+mod test_util;
+pub mod triangulation;
+mod utils;
+pub mod vocabulary;
+
+pub use frame::Frame;
+pub use orb_extractor::{KeypointDistribution, OrbExtractor};
+
+/// The five-point solver needs at least 5 correspondences, but RANSAC is
+/// unreliable with that few. 8 is the usual lower bound in practice.
+const MIN_POSE_MATCHES: usize = 8;
+const RANSAC_PROB: f64 = 0.999;
+/// Inlier threshold in pixels. Scaled into normalized coordinates before use.
+const RANSAC_THRESHOLD_PX: f64 = 1.0;
+const RANSAC_MAX_ITERS: i32 = 1000;
+/// Covisible keyframes, besides the reference, whose points are tracked.
+const LOCAL_MAP_NEIGHBOURS: usize = 10;
 
 #[derive(thiserror::Error, Debug)]
 pub enum OdometryError {
@@ -13,14 +75,95 @@ pub enum OdometryError {
     OpenCv(#[from] opencv::Error),
     #[error("Not enough points to estimate pose")]
     NotEnoughPoints,
+    #[error("Failed to estimate essential matrix")]
+    DegenerateEssential,
+    #[error("Insufficient parallax to initialize: {0:.2} deg")]
+    LowParallax(f64),
+    #[error("No homography decomposition is clearly better than the others")]
+    AmbiguousInitialization,
+    #[error("Map error: {0}")]
+    Map(#[from] MapError),
+    #[error("Pose graph error: {0}")]
+    PoseGraph(#[from] PoseGraphError),
+    #[error("Vocabulary error: {0}")]
+    Vocabulary(#[from] VocabularyError),
+    #[error("Invalid mask: {0}")]
+    InvalidMask(String),
+}
+
+/// Relative motion between two frames.
+///
+/// `rotation` (3x3, CV_64F) and `translation` (3x1, CV_64F) map points from the
+/// first frame's camera coordinates into the second's. Monocular motion is only
+/// recovered up to scale, so `translation` has unit norm.
+#[derive(Debug, Clone)]
+pub struct RelativePose {
+    pub rotation: Mat,
+    pub translation: Mat,
+    /// One entry per input match (CV_8U), non-zero for matches that survived
+    /// both RANSAC and the cheirality check.
+    pub inlier_mask: Mat,
+    pub inliers: usize,
 }
 
 pub struct VisualOdometry {
     camera: Camera,
-    config: ORBConfig,
-    orb: Ptr<ORB>,
-    matcher: Ptr<BFMatcher>,
+    initializer: Initializer,
+    pnp: PnpTracker,
+    keyframe_policy: KeyframePolicy,
+    bundle_adjuster: BundleAdjuster,
+    loop_closer: LoopCloser,
+    relocalizer: Relocalizer,
+    motion_model: MotionModel,
+    /// Place recognition is off until a vocabulary is given.
+    vocabulary: Option<Arc<Vocabulary>>,
+    database: KeyFrameDatabase,
+    extractor: Box<dyn FeatureExtractor>,
+    klt: KltTracker,
+    /// Matches with the extractor's descriptor metric.
+    matcher: FeatureMatcher,
     frame_id: usize,
+    state: TrackingState,
+    /// First frame of the initialization pair, and its timestamp.
+    init_frame: Option<(Frame, f64)>,
+    /// Keyframe new map points are triangulated against.
+    reference_keyframe: Option<KeyFrameId>,
+    /// Frame id of the most recent keyframe.
+    last_keyframe_frame: usize,
+    /// World to camera transform (4x4, CV_64F) of the last tracked frame.
+    pose: Mat,
+    map: SharedMap,
+    events: EventBus,
+    debug: Box<dyn DebugSink>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TrackingState {
+    /// No frame seen since creation or the last reset.
+    NotInitialized,
+    /// Holding the first frame of a pair, waiting for one the initializer
+    /// accepts.
+    Initializing,
+    Ok,
+    /// The last frame could not be registered against the local map. With a
+    /// vocabulary, following frames are relocalized against the whole map.
+    Lost,
+    /// The frame was found again in the map after tracking was lost. The next
+    /// tracked frame is `Ok`.
+    Relocalized,
+}
+
+#[derive(Debug, Clone)]
+pub struct TrackingResult {
+    pub frame_id: usize,
+    pub timestamp: f64,
+    /// Camera to world transform (4x4, CV_64F) of the tracked frame. Can be
+    /// passed straight to `transform_point_cloud`.
+    pub pose: Mat,
+    pub inliers: usize,
+    pub state: TrackingState,
+    /// Set when the frame was inserted into the map as a keyframe.
+    pub keyframe: Option<KeyFrameId>,
 }
 
 #[derive(Debug, Clone, Copy)]
@@ -34,21 +177,32 @@ pub struct ORBConfig {
     pub score_type: ORB_ScoreType,
     pub patch_size: i32,
     pub fast_threshold: i32,
+    /// How keypoints are spread over the image and pyramid levels.
+    pub distribution: KeypointDistribution,
+    /// FAST threshold retried in grid cells where `fast_threshold` finds no
+    /// corner. Ignored by `KeypointDistribution::Native`.
+    pub min_fast_threshold: i32,
+    /// Side of the grid cells FAST runs in, in pixels of each pyramid level.
+    /// Ignored by `KeypointDistribution::Native`.
+    pub cell_size: i32,
 }
 
 impl Default for ORBConfig {
     /// Creates a default ORB configuration.
     ///
     /// These are the default values:
-    /// - `nfeatures`: 1000
+    /// - `nfeatures`: 500
     /// - `scale_factor`: 1.2
     /// - `nlevels`: 8
     /// - `edge_threshold`: 31
     /// - `first_level`: 0
     /// - `wta_k`: 2
-    /// - `score_type`: `ORB_ScoreType::HARRIS_SCORE`
+    /// - `score_type`: `ORB_ScoreType::FAST_SCORE`
     /// - `patch_size`: 31
     /// - `fast_threshold`: 20
+    /// - `distribution`: `KeypointDistribution::Native`
+    /// - `min_fast_threshold`: 7
+    /// - `cell_size`: 30
     fn default() -> Self {
         Self {
             nfeatures: 500,
@@ -60,103 +214,947 @@ impl Default for ORBConfig {
             score_type: ORB_ScoreType::FAST_SCORE,
             patch_size: 31,
             fast_threshold: 20,
+            distribution: KeypointDistribution::Native,
+            min_fast_threshold: 7,
+            cell_size: 30,
         }
     }
 }
 
 impl VisualOdometry {
     pub fn new(config: ORBConfig, camera: Camera) -> Result<Self, OdometryError> {
-        let orb = ORB::create(
-            config.nfeatures,
-            config.scale_factor,
-            config.nlevels,
-            config.edge_threshold,
-            config.first_level,
-            config.wta_k,
-            config.score_type,
-            config.patch_size,
-            config.fast_threshold,
-        )?;
-        let matcher = BFMatcher::create(NORM_HAMMING, false)?;
+        Self::from_extractor(Box::new(OrbExtractor::new(config)?), camera)
+    }
+
+    /// Tracks with features from `extractor`, matched with its descriptor
+    /// metric. Loop closing and relocalization need Hamming descriptors and
+    /// a vocabulary trained on them.
+    pub fn from_extractor(
+        extractor: Box<dyn FeatureExtractor>,
+        camera: Camera,
+    ) -> Result<Self, OdometryError> {
+        let metric = extractor.metric();
 
         Ok(Self {
+            initializer: Initializer::new(camera.clone(), InitializerConfig::default()),
+            pnp: PnpTracker::new(camera.clone(), PnpConfig::for_metric(metric))
+                .with_descriptor_metric(metric),
+            keyframe_policy: KeyframePolicy::new(KeyframeConfig::default()),
+            bundle_adjuster: BundleAdjuster::new(camera.clone(), BundleAdjustmentConfig::default()),
+            loop_closer: LoopCloser::new(camera.clone(), LoopClosingConfig::default()),
+            relocalizer: Relocalizer::new(camera.clone(), RelocalizationConfig::default()),
+            motion_model: MotionModel::new(MotionModelConfig::default()),
+            vocabulary: None,
+            database: KeyFrameDatabase::new(),
             camera,
-            config,
-            orb,
-            matcher,
+            extractor,
+            klt: KltTracker::new(KltConfig::default()),
+            matcher: FeatureMatcher::new(MatcherConfig::for_metric(metric), metric),
             frame_id: 0,
+            state: TrackingState::NotInitialized,
+            init_frame: None,
+            reference_keyframe: None,
+            last_keyframe_frame: 0,
+            pose: Mat::eye(4, 4, CV_64F)?.to_mat()?,
+            map: Map::with_descriptor_metric(metric).into_shared(),
+            events: EventBus::default(),
+            debug: Box::new(NoopSink),
         })
     }
 
-    #[inline]
-    pub fn process_frame(&mut self, image: Mat) -> Result<Frame, OdometryError> {
-        let mut keypoints: Vector<opencv::core::KeyPoint> = Vector::new();
-        let mut descriptors = Mat::default();
-        self.orb.detect_and_compute(
-            &image,
-            &no_array(),
-            &mut keypoints,
-            &mut descriptors,
-            false,
+    /// Matching used to initialize, to triangulate new points and by
+    /// `frame_match`.
+    pub fn with_matcher_config(mut self, config: MatcherConfig) -> Self {
+        self.matcher = FeatureMatcher::new(config, self.extractor.metric());
+        self
+    }
+
+    /// Receives match images, keypoint overlays and per-stage statistics.
+    /// Nothing is drawn or recorded without one.
+    pub fn with_debug_sink(mut self, sink: Box<dyn DebugSink>) -> Self {
+        self.debug = sink;
+        self
+    }
+
+    pub fn with_initializer_config(mut self, config: InitializerConfig) -> Self {
+        self.initializer = Initializer::new(self.camera.clone(), config);
+        self
+    }
+
+    pub fn with_pnp_config(mut self, config: PnpConfig) -> Self {
+        self.pnp = PnpTracker::new(self.camera.clone(), config)
+            .with_descriptor_metric(self.extractor.metric());
+        self
+    }
+
+    pub fn with_keyframe_config(mut self, config: KeyframeConfig) -> Self {
+        self.keyframe_policy = KeyframePolicy::new(config);
+        self
+    }
+
+    pub fn with_bundle_adjustment_config(mut self, config: BundleAdjustmentConfig) -> Self {
+        self.bundle_adjuster = BundleAdjuster::new(self.camera.clone(), config);
+        self
+    }
+
+    pub fn with_loop_closing_config(mut self, config: LoopClosingConfig) -> Self {
+        self.loop_closer = LoopCloser::new(self.camera.clone(), config);
+        self
+    }
+
+    pub fn with_klt_config(mut self, config: KltConfig) -> Self {
+        self.klt = KltTracker::new(config);
+        self
+    }
+
+    pub fn with_motion_model_config(mut self, config: MotionModelConfig) -> Self {
+        self.motion_model = MotionModel::new(config);
+        self
+    }
+
+    pub fn with_relocalization_config(mut self, config: RelocalizationConfig) -> Self {
+        self.relocalizer = Relocalizer::new(self.camera.clone(), config);
+        self
+    }
+
+    /// Enables loop closure and relocalization. Keyframes are only indexed from here on, so set
+    /// this before tracking. Ignored unless the extractor's descriptors are compared with
+    /// Hamming distance and are as long as the vocabulary's.
+    pub fn with_vocabulary(mut self, vocabulary: Arc<Vocabulary>) -> Self {
+        let metric = self.extractor.metric();
+        let size = self.extractor.descriptor_size();
+        if metric != DescriptorMetric::Hamming || !matches!(size, Ok(DESCRIPTOR_BYTES)) {
+            tracing::warn!(
+                ?metric,
+                ?size,
+                "vocabulary needs {DESCRIPTOR_BYTES} byte Hamming descriptors, \
+                 loop closing and relocalization stay off"
+            );
+            return self;
+        }
+        self.vocabulary = Some(vocabulary);
+        self
+    }
+
+    pub fn state(&self) -> TrackingState {
+        self.state
+    }
+
+    /// Handle to the map built by `track`.
+    pub fn map(&self) -> SharedMap {
+        self.map.clone()
+    }
+
+    /// Receives every event from now on. Events are queued until read, and
+    /// dropping the receiver unsubscribes.
+    pub fn subscribe(&mut self) -> Receiver<OdometryEvent> {
+        self.events.subscribe()
+    }
+
+    /// Discards the map and starts over with the next frame.
+    pub fn reset(&mut self) -> Result<(), OdometryError> {
+        map::lock(&self.map)?.clear();
+        self.database.clear();
+        self.loop_closer.reset();
+        self.init_frame = None;
+        self.reference_keyframe = None;
+        self.motion_model.reset();
+        self.pose = Mat::eye(4, 4, CV_64F)?.to_mat()?;
+        self.events.emit(OdometryEvent::MapReset);
+        self.set_state(self.frame_id, TrackingState::NotInitialized);
+        Ok(())
+    }
+
+    fn set_state(&mut self, frame_id: usize, state: TrackingState) {
+        if state == self.state {
+            return;
+        }
+        self.events.emit(OdometryEvent::StateChanged {
+            frame_id,
+            from: self.state,
+            to: state,
+        });
+        self.state = state;
+    }
+
+    /// Extracts features from `image` and localizes it against the local map.
+    ///
+    /// Until the two-view initializer accepts a frame pair the pose stays at the
+    /// world origin, which is defined by the first frame of that pair. After
+    /// that every frame is tracked with PnP against the map points of the
+    /// reference keyframe and its covisible neighbours, searched around the
+    /// pose a constant-velocity motion model predicts for `timestamp`, and new
+    /// keyframes are added as the camera moves away. The map scale is arbitrary, fixed by
+    /// the initial median depth.
+    ///
+    /// Once tracking is lost, frames are relocalized through the keyframe
+    /// database if a vocabulary was given, so tracking resumes in the same map
+    /// coordinate frame.
+    pub fn track(&mut self, image: Mat, timestamp: f64) -> Result<TrackingResult, OdometryError> {
+        self.track_masked(image, timestamp, &FrameMask::None)
+    }
+
+    /// Like `track`, ignoring the regions `mask` excludes, typically moving
+    /// objects. No keypoints are extracted there, and map points projecting
+    /// into them are left out of tracking.
+    pub fn track_masked(
+        &mut self,
+        image: Mat,
+        timestamp: f64,
+        mask: &FrameMask,
+    ) -> Result<TrackingResult, OdometryError> {
+        let mask = mask.to_detection_mask(image.size()?)?;
+        let frame = self.extract(image, &mask)?;
+        let frame_id = frame.id;
+
+        let Some(reference) = self.reference_keyframe.filter(|_| {
+            !matches!(
+                self.state,
+                TrackingState::NotInitialized | TrackingState::Initializing
+            )
+        }) else {
+            let (inliers, keyframe) = self.try_initialize(frame, timestamp)?;
+            return self.tracking_result(frame_id, timestamp, inliers, keyframe);
+        };
+
+        // The last pose says nothing about where a lost camera is, so search
+        // the whole map instead when place recognition is available.
+        if self.state == TrackingState::Lost && self.vocabulary.is_some() {
+            return match self.relocalize(&frame)? {
+                Some(relocalization) => {
+                    let tracked = relocalization.tracked;
+                    self.pose = tracked.pose.clone();
+                    self.reference_keyframe = Some(relocalization.keyframe);
+                    self.motion_model
+                        .update(&utils::pose_to_isometry(&self.pose)?, timestamp);
+                    self.set_state(frame_id, TrackingState::Relocalized);
+                    self.tracking_result(frame_id, timestamp, tracked.inliers, None)
+                }
+                None => self.tracking_result(frame_id, timestamp, 0, None),
+            };
+        }
+
+        let local_points = map::lock(&self.map)?.local_map_points(reference, LOCAL_MAP_NEIGHBOURS);
+        let Some(tracked) = self.track_local_map(&frame, &local_points, &mask, timestamp)? else {
+            // Keep the last good pose and map so later frames can re-acquire them.
+            self.motion_model.reset();
+            self.set_state(frame_id, TrackingState::Lost);
+            return self.tracking_result(frame_id, timestamp, 0, None);
+        };
+
+        self.pose = tracked.pose.clone();
+        self.set_state(frame_id, TrackingState::Ok);
+        let stats = self.keyframe_stats(reference, frame_id, &tracked)?;
+        let keyframe = match self.keyframe_policy.decide(&stats) {
+            Some(reason) => {
+                tracing::debug!(frame_id, ?reason, "inserting keyframe");
+                Some(self.insert_keyframe(reference, frame, timestamp, &tracked)?)
+            }
+            None => None,
+        };
+        // Keyframe insertion refines the pose with bundle adjustment, and
+        // resets the motion model when it closes a loop.
+        self.motion_model
+            .update(&utils::pose_to_isometry(&self.pose)?, timestamp);
+        self.tracking_result(frame_id, timestamp, tracked.inliers, keyframe)
+    }
+
+    /// Tracks `frame` against the local map in a narrow window around the
+    /// motion model's prediction, then in a wide one around the last pose if
+    /// that finds too few matches. Map points projecting into the zero pixels
+    /// of `mask` are skipped. Returns `None` when both fail.
+    fn track_local_map(
+        &self,
+        frame: &Frame,
+        local_points: &[LocalMapPoint],
+        mask: &Mat,
+        timestamp: f64,
+    ) -> Result<Option<PnpResult>, OdometryError> {
+        let config = self.motion_model.config();
+        if let Some(predicted) = self.motion_model.predict(timestamp) {
+            let predicted = utils::isometry_to_pose(&predicted)?;
+            let points = masking::unmasked_points(&self.camera, &predicted, local_points, mask)?;
+            match self
+                .pnp
+                .track_predicted(frame, &points, &predicted, config.search_radius)
+            {
+                Ok(tracked) => return Ok(Some(tracked)),
+                Err(OdometryError::NotEnoughPoints) => {
+                    tracing::debug!(frame_id = frame.id, "motion model search failed, widening");
+                }
+                Err(e) => return Err(e),
+            }
+        }
+        let points = masking::unmasked_points(&self.camera, &self.pose, local_points, mask)?;
+        match self
+            .pnp
+            .track_predicted(frame, &points, &self.pose, config.fallback_search_radius)
+        {
+            Ok(tracked) => Ok(Some(tracked)),
+            Err(OdometryError::NotEnoughPoints) => Ok(None),
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Looks `frame` up in the keyframe database and solves its pose against
+    /// the map points of the best matching keyframe.
+    fn relocalize(&self, frame: &Frame) -> Result<Option<Relocalization>, OdometryError> {
+        let Some(vocabulary) = self.vocabulary.as_deref() else {
+            return Ok(None);
+        };
+        let (bow, features) =
+            vocabulary.transform_frame(frame, self.loop_closer.config().levels_up)?;
+        let map = map::lock(&self.map)?;
+        self.relocalizer
+            .relocalize(&map, &self.database, frame, &bow, &features)
+    }
+
+    /// Measures how far `tracked` has moved away from the reference keyframe.
+    fn keyframe_stats(
+        &self,
+        reference: KeyFrameId,
+        frame_id: usize,
+        tracked: &PnpResult,
+    ) -> Result<KeyframeStats, OdometryError> {
+        let map = map::lock(&self.map)?;
+        let Some(keyframe) = map.keyframe(reference) else {
+            return Err(MapError::UnknownKeyFrame(reference).into());
+        };
+        let (reference_rotation, reference_translation) = utils::split_pose(&keyframe.pose)?;
+        let (rotation, translation) = utils::split_pose(&tracked.pose)?;
+        let reference_centre = scale3(
+            &mul3v(&transpose3(&reference_rotation), &reference_translation),
+            -1.0,
+        );
+        let centre = scale3(&mul3v(&transpose3(&rotation), &translation), -1.0);
+
+        let points: Vec<[f64; 3]> = tracked
+            .matches
+            .iter()
+            .filter_map(|&(point, _)| map.map_point(point))
+            .map(|point| [point.position.x, point.position.y, point.position.z])
+            .collect();
+
+        Ok(KeyframeStats {
+            frames_since_keyframe: frame_id.saturating_sub(self.last_keyframe_frame),
+            tracked: tracked.inliers,
+            reference_tracked: keyframe.num_observations(),
+            parallax_deg: keyframe_policy::median_parallax(&points, &reference_centre, &centre),
+            rotation_deg: keyframe_policy::rotation_angle(&reference_rotation, &rotation),
+            translation: norm3(&sub3(&centre, &reference_centre)),
+        })
+    }
+
+    /// Feeds a frame to the two-view initializer and seeds the map with two
+    /// keyframes on success. Returns the number of initial map points and the
+    /// keyframe `frame` was inserted as.
+    fn try_initialize(
+        &mut self,
+        frame: Frame,
+        timestamp: f64,
+    ) -> Result<(usize, Option<KeyFrameId>), OdometryError> {
+        let Some((first, _)) = self.init_frame.as_ref() else {
+            self.set_state(frame.id, TrackingState::Initializing);
+            self.init_frame = Some((frame, timestamp));
+            return Ok((0, None));
+        };
+
+        let matches = self.match_frames(first, &frame)?;
+        debug::report_matches(
+            self.debug.as_mut(),
+            "initialization",
+            first,
+            &frame,
+            &matches,
+        )?;
+        let init = match self.initializer.initialize(first, &frame, &matches) {
+            Ok(init) => init,
+            // Not enough motion yet, keep waiting on the same first frame.
+            Err(OdometryError::LowParallax(_) | OdometryError::AmbiguousInitialization) => {
+                return Ok((0, None));
+            }
+            // Too few matches or no usable model: the first frame is not
+            // useful, start over from this one.
+            Err(OdometryError::NotEnoughPoints | OdometryError::DegenerateEssential) => {
+                self.init_frame = Some((frame, timestamp));
+                return Ok((0, None));
+            }
+            Err(e) => return Err(e),
+        };
+        let Some((first, first_timestamp)) = self.init_frame.take() else {
+            return Ok((0, None));
+        };
+
+        // The first frame of the pair is the world origin, so its camera
+        // coordinates are world coordinates.
+        let pose = utils::to_homogeneous(&init.rotation, &init.translation)?;
+        let mut descriptors = Vec::with_capacity(init.matches.len());
+        for m in init.matches.iter() {
+            descriptors.push(frame.descriptors.row(m.train_idx)?.try_clone()?);
+        }
+
+        let frame_id = frame.id;
+        let first_frame_id = first.id;
+        let mut map = map::lock(&self.map)?;
+        if map.num_keyframes() > 0 {
+            self.events.emit(OdometryEvent::MapReset);
+        }
+        map.clear();
+        self.database.clear();
+        self.loop_closer.reset();
+        let kf1 = map.insert_keyframe(first, first_timestamp, Mat::eye(4, 4, CV_64F)?.to_mat()?);
+        let kf2 = map.insert_keyframe(frame, timestamp, pose.clone());
+        for ((position, m), descriptor) in
+            init.points.iter().zip(init.matches.iter()).zip(descriptors)
+        {
+            let point = map.insert_map_point(*position, descriptor, kf1)?;
+            map.add_observation(kf1, point, m.query_idx as usize)?;
+            map.add_observation(kf2, point, m.train_idx as usize)?;
+            map.update_descriptor(point)?;
+            map.update_viewing_direction(point)?;
+        }
+        // The first keyframe is pinned, so this only refines the second pose
+        // and the points.
+        self.bundle_adjuster
+            .local_bundle_adjustment(&mut map, kf2)?;
+        if let Some(vocabulary) = self.vocabulary.as_deref() {
+            let levels_up = self.loop_closer.config().levels_up;
+            for id in [kf1, kf2] {
+                if let Some(kf) = map.keyframe_mut(id) {
+                    (kf.bow, kf.features) = vocabulary.transform_frame(&kf.frame, levels_up)?;
+                    self.database.add(id, kf.bow.clone());
+                }
+            }
+        }
+
+        self.pose = map.keyframe(kf2).map_or(pose, |kf| kf.pose.clone());
+        drop(map);
+        self.motion_model.reset();
+        self.motion_model
+            .update(&Isometry3::identity(), first_timestamp);
+        self.motion_model
+            .update(&utils::pose_to_isometry(&self.pose)?, timestamp);
+        self.reference_keyframe = Some(kf2);
+        self.last_keyframe_frame = frame_id;
+        for (frame_id, keyframe) in [(first_frame_id, kf1), (frame_id, kf2)] {
+            self.events
+                .emit(OdometryEvent::KeyframeInserted { frame_id, keyframe });
+        }
+        self.set_state(frame_id, TrackingState::Ok);
+        Ok((init.points.len(), Some(kf2)))
+    }
+
+    /// Makes `frame` a keyframe observing the points it tracked, and
+    /// triangulates its remaining matches with the reference keyframe into new
+    /// map points. The new keyframe becomes the reference.
+    fn insert_keyframe(
+        &mut self,
+        reference: KeyFrameId,
+        frame: Frame,
+        timestamp: f64,
+        tracked: &PnpResult,
+    ) -> Result<KeyFrameId, OdometryError> {
+        let frame_id = frame.id;
+        let mut map = map::lock(&self.map)?;
+        let (reference_frame, reference_pose) = match map.keyframe(reference) {
+            Some(kf) => (kf.frame.clone(), kf.pose.clone()),
+            None => return Err(MapError::UnknownKeyFrame(reference).into()),
+        };
+
+        let matches = self.match_frames(&reference_frame, &frame)?;
+        debug::report_matches(
+            self.debug.as_mut(),
+            "triangulation",
+            &reference_frame,
+            &frame,
+            &matches,
+        )?;
+        let Some(reference_keyframe) = map.keyframe(reference) else {
+            return Err(MapError::UnknownKeyFrame(reference).into());
+        };
+        let fresh = fresh_matches(reference_keyframe, &tracked.matches, &matches);
+        let points = triangulation::triangulate(
+            &self.camera,
+            &reference_frame,
+            &reference_pose,
+            &frame,
+            &self.pose,
+            &fresh,
+            &TriangulationConfig::default(),
         )?;
+        let mut new_points = Vec::with_capacity(points.len());
+        for point in &points {
+            let m = fresh.get(point.match_index)?;
+            let descriptor = frame.descriptors.row(m.train_idx)?.try_clone()?;
+            new_points.push((point.position, m, descriptor));
+        }
+
+        let keyframe = map.insert_keyframe(frame, timestamp, self.pose.clone());
+        for &(point, keypoint) in &tracked.matches {
+            // Points may have been culled since the local map was gathered.
+            if map.map_point(point).is_some() {
+                map.add_observation(keyframe, point, keypoint)?;
+                map.update_viewing_direction(point)?;
+            }
+        }
+        for (position, m, descriptor) in new_points {
+            let point = map.insert_map_point(position, descriptor, keyframe)?;
+            map.add_observation(reference, point, m.query_idx as usize)?;
+            map.add_observation(keyframe, point, m.train_idx as usize)?;
+            map.update_descriptor(point)?;
+            map.update_viewing_direction(point)?;
+        }
+        map.cull_map_points(2, reference);
+        self.bundle_adjuster
+            .local_bundle_adjustment(&mut map, keyframe)?;
 
-        Ok(Frame::new(self.frame_id, image, keypoints, descriptors))
+        if let Some(vocabulary) = self.vocabulary.as_deref() {
+            let levels_up = self.loop_closer.config().levels_up;
+            if let Some(kf) = map.keyframe_mut(keyframe) {
+                (kf.bow, kf.features) = vocabulary.transform_frame(&kf.frame, levels_up)?;
+            }
+            if let Some(closure) = self
+                .loop_closer
+                .process(&mut map, &self.database, keyframe)?
+            {
+                tracing::info!(
+                    keyframe,
+                    loop_keyframe = closure.loop_keyframe,
+                    matches = closure.matches,
+                    scale = closure.sim3.scale,
+                    fused = closure.fused_points,
+                    "loop closed"
+                );
+                self.events.emit(OdometryEvent::LoopClosed(closure));
+                // The correction moves this keyframe, which is no motion of
+                // the camera. Velocity is measured again from the corrected
+                // pose on.
+                self.motion_model.reset();
+            }
+            if let Some(kf) = map.keyframe(keyframe) {
+                self.database.add(keyframe, kf.bow.clone());
+            }
+        }
+        if let Some(kf) = map.keyframe(keyframe) {
+            self.pose = kf.pose.clone();
+        }
+
+        self.reference_keyframe = Some(keyframe);
+        self.last_keyframe_frame = frame_id;
+        self.events
+            .emit(OdometryEvent::KeyframeInserted { frame_id, keyframe });
+        Ok(keyframe)
+    }
+
+    fn tracking_result(
+        &mut self,
+        frame_id: usize,
+        timestamp: f64,
+        inliers: usize,
+        keyframe: Option<KeyFrameId>,
+    ) -> Result<TrackingResult, OdometryError> {
+        self.debug
+            .statistic(frame_id, "tracking", "inliers", inliers as f64);
+        Ok(TrackingResult {
+            frame_id,
+            timestamp,
+            pose: utils::invert_pose(&self.pose)?,
+            inliers,
+            state: self.state,
+            keyframe,
+        })
     }
 
     #[inline]
+    pub fn process_frame(&mut self, image: Mat) -> Result<Frame, OdometryError> {
+        self.process_frame_masked(image, &FrameMask::None)
+    }
+
+    /// Like `process_frame`, without keypoints in the regions `mask` excludes.
+    pub fn process_frame_masked(
+        &mut self,
+        image: Mat,
+        mask: &FrameMask,
+    ) -> Result<Frame, OdometryError> {
+        let mask = mask.to_detection_mask(image.size()?)?;
+        self.extract(image, &mask)
+    }
+
+    /// `mask` is a detection mask from `FrameMask::to_detection_mask`.
+    fn extract(&mut self, image: Mat, mask: &Mat) -> Result<Frame, OdometryError> {
+        let (keypoints, descriptors) = self.extractor.detect_and_compute(&image, mask)?;
+
+        let id = self.frame_id;
+        self.frame_id += 1;
+        let frame = Frame::new(id, image, keypoints, descriptors);
+        debug::report_keypoints(self.debug.as_mut(), &frame)?;
+        Ok(frame)
+    }
+
+    /// Optical flow alternative to `process_frame` followed by `frame_match`:
+    /// tracks `previous.keypoints` into `image` and tops the keypoints up with
+    /// new corners when too few survive.
+    ///
+    /// The matches index `previous` and the returned frame like `frame_match`'s,
+    /// so they feed `detect_pose` and triangulation unchanged. The returned
+    /// frame has no descriptors, so it cannot be matched by descriptor or
+    /// inserted into the map. Start a chain with `KltTracker::detect` or with
+    /// a frame from `process_frame`. No corners are kept or detected in the
+    /// regions `mask` excludes.
+    pub fn flow_match(
+        &mut self,
+        previous: &Frame,
+        image: Mat,
+        mask: &FrameMask,
+    ) -> Result<(Frame, Vector<DMatch>), OdometryError> {
+        let mask = mask.to_detection_mask(image.size()?)?;
+        let tracks = self.klt.track(previous, &image, &mask)?;
+
+        let id = self.frame_id;
+        self.frame_id += 1;
+        let frame = Frame::new(id, image, tracks.keypoints, Mat::default());
+        Ok((frame, tracks.matches))
+    }
+
+    /// Matches the keypoints of two frames with the configured strategy.
+    /// `query_idx` indexes `frame1.keypoints` and `train_idx` indexes
+    /// `frame2.keypoints`.
     pub fn frame_match(
-        self,
-        frame1: Frame,
-        frame2: Frame,
-        ratio_threshold: f32,
-        distance_threshold: f32,
+        &mut self,
+        frame1: &Frame,
+        frame2: &Frame,
     ) -> Result<Vector<DMatch>, OdometryError> {
-        let mut matches = Vector::new();
-        // YOU FUCKING HAVE TO USE TRAIN MATCH BTW. FOUND THIS SHIT IN A GITHUB ISSUE.
-        self.matcher.knn_train_match(
-            &frame1.descriptors.clone(),
-            &frame2.descriptors.clone(),
-            &mut matches,
-            2,
-            &no_array(),
-            false,
-        )?;
+        let matches = self.match_frames(frame1, frame2)?;
+        debug::report_matches(self.debug.as_mut(), "frame_match", frame1, frame2, &matches)?;
+        Ok(matches)
+    }
+
+    /// Like `frame_match`, but each `frame1` keypoint is only matched within
+    /// `MatcherConfig::search_radius` of its predicted location in `frame2`.
+    /// `predicted` has one entry per `frame1` keypoint.
+    pub fn guided_match(
+        &self,
+        frame1: &Frame,
+        frame2: &Frame,
+        predicted: &[Option<Point2f>],
+    ) -> Result<Vector<DMatch>, OdometryError> {
+        self.matcher.match_guided(frame1, frame2, predicted)
+    }
+
+    fn match_frames(
+        &self,
+        frame1: &Frame,
+        frame2: &Frame,
+    ) -> Result<Vector<DMatch>, OdometryError> {
+        self.matcher.match_frames(frame1, frame2)
+    }
+
+    /// Recovers the relative pose between two frames from their matches.
+    ///
+    /// Matches are expected as produced by `frame_match`: `query_idx` indexes
+    /// `frame1.keypoints` and `train_idx` indexes `frame2.keypoints`.
+    pub fn detect_pose(
+        &self,
+        frame1: &Frame,
+        frame2: &Frame,
+        matches: &Vector<DMatch>,
+    ) -> Result<RelativePose, OdometryError> {
+        if matches.len() < MIN_POSE_MATCHES {
+            return Err(OdometryError::NotEnoughPoints);
+        }
 
-        let mut good_matches = Vector::<DMatch>::new();
+        let mut points1 = Vector::<Point2f>::with_capacity(matches.len());
+        let mut points2 = Vector::<Point2f>::with_capacity(matches.len());
         for m in matches.iter() {
-            if m.len() >= 2 {
-                let nearest = m.get(0)?;
-                let second_nearest = m.get(1)?;
-
-                if nearest.distance < ratio_threshold * second_nearest.distance
-                    && nearest.distance < distance_threshold
-                {
-                    good_matches.push(nearest);
-                }
-            }
+            points1.push(frame1.keypoints.get(m.query_idx as usize)?.pt());
+            points2.push(frame2.keypoints.get(m.train_idx as usize)?.pt());
         }
 
-        println!("match len: {}", matches.len());
-
-        let mut output = Mat::default();
-        draw_matches(
-            &frame1.image,
-            &frame1.keypoints,
-            &frame2.image,
-            &frame2.keypoints,
-            &good_matches,
-            &mut output,
-            Scalar::all(-1.0),
-            Scalar::all(-1.0),
-            &Vector::new(),
-            opencv::features2d::DrawMatchesFlags::NOT_DRAW_SINGLE_POINTS,
+        // Without a projection matrix undistort_points returns normalized
+        // image coordinates, so K is the identity from here on.
+        let points1 = self.camera.undistort_points(&points1)?;
+        let points2 = self.camera.undistort_points(&points2)?;
+        let identity = Mat::eye(3, 3, CV_64F)?.to_mat()?;
+        let threshold = RANSAC_THRESHOLD_PX / self.camera.fx.max(self.camera.fy);
+
+        let mut inlier_mask = Mat::default();
+        let essential = find_essential_mat(
+            &points1,
+            &points2,
+            &identity,
+            RANSAC,
+            RANSAC_PROB,
+            threshold,
+            RANSAC_MAX_ITERS,
+            &mut inlier_mask,
         )?;
+        // The five-point solver can return several stacked 3x3 solutions.
+        if essential.rows() < 3 || essential.cols() != 3 {
+            return Err(OdometryError::DegenerateEssential);
+        }
+        let essential = essential.row_bounds(0, 3)?.try_clone()?;
 
-        opencv::imgcodecs::imwrite("output.jpg", &output, &Vector::new())?;
+        let mut rotation = Mat::default();
+        let mut translation = Mat::default();
+        let inliers = recover_pose_estimated(
+            &essential,
+            &points1,
+            &points2,
+            &identity,
+            &mut rotation,
+            &mut translation,
+            &mut inlier_mask,
+        )?;
+        if inliers <= 0 {
+            return Err(OdometryError::NotEnoughPoints);
+        }
 
-        Ok(good_matches)
+        Ok(RelativePose {
+            rotation,
+            translation,
+            inlier_mask,
+            inliers: inliers as usize,
+        })
     }
+}
 
-    #[inline]
-    pub fn detect_pose(dmatches: Vector<DMatch>) {}
+/// Matches of `reference` with a new keyframe whose keypoints are free on
+/// both sides: the new keypoint tracked no map point and the reference
+/// keypoint observes none. Only these are triangulated, anything else would
+/// duplicate a point and rebind the keypoint away from it.
+fn fresh_matches(
+    reference: &KeyFrame,
+    tracked: &[(MapPointId, usize)],
+    matches: &Vector<DMatch>,
+) -> Vector<DMatch> {
+    let tracked_keypoints: HashSet<usize> = tracked.iter().map(|&(_, k)| k).collect();
+    Vector::from_iter(matches.iter().filter(|m| {
+        !tracked_keypoints.contains(&(m.train_idx as usize))
+            && reference
+                .map_points
+                .get(m.query_idx as usize)
+                .is_some_and(|point| point.is_none())
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::{self, ReplayExtractor};
+    use nalgebra::Vector3;
+    use opencv::core::{CV_8UC1, Point3d, Scalar};
+
+    /// World to camera poses of a camera sliding 2 cm per frame along x while
+    /// turning slightly.
+    fn slow_poses(n: usize) -> Vec<Isometry3<f64>> {
+        (0..n)
+            .map(|i| {
+                let i = i as f64;
+                Isometry3::new(
+                    Vector3::new(-0.02 * i, 0.0, 0.0),
+                    Vector3::new(0.0, 0.002 * i, 0.0),
+                )
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_detect_pose_recovers_relative_motion() {
+        let points = test_util::scene(100);
+        let descriptors = test_util::descriptors(points.len(), 7);
+        let motion = Isometry3::new(Vector3::new(-0.3, 0.05, 0.0), Vector3::new(0.0, 0.05, 0.02));
+        let frame1 = test_util::frame(0, &points, &Isometry3::identity(), &descriptors);
+        let mut frame2 = test_util::frame(1, &points, &motion, &descriptors);
+        // Epipolar lines run almost along x, so moving the first ten
+        // keypoints 30 pixels down leaves them far off theirs.
+        for k in 0..10 {
+            let mut keypoint = frame2.keypoints.get(k).unwrap();
+            keypoint.set_pt(keypoint.pt() + Point2f::new(0.0, 30.0));
+            frame2.keypoints.set(k, keypoint).unwrap();
+        }
+        let extractor = ReplayExtractor::new(&points, &descriptors, &[]);
+        let odometry =
+            VisualOdometry::from_extractor(Box::new(extractor), test_util::camera()).unwrap();
+
+        let matches = test_util::one_to_one(points.len());
+        let relative = odometry.detect_pose(&frame1, &frame2, &matches).unwrap();
+        let recovered = utils::pose_to_isometry(
+            &utils::to_homogeneous(&relative.rotation, &relative.translation).unwrap(),
+        )
+        .unwrap();
+        let rotation_error = recovered.rotation * motion.rotation.inverse();
+        assert!(rotation_error.angle() < 1e-3, "{rotation_error}");
+        let direction = motion.translation.vector.normalize();
+        assert!((recovered.translation.vector - direction).norm() < 1e-2);
+        assert_eq!(relative.inliers, points.len() - 10);
+        for k in 0..points.len() {
+            let inlier = *relative.inlier_mask.at::<u8>(k as i32).unwrap() != 0;
+            assert_eq!(inlier, k >= 10, "match {k}");
+        }
+
+        let few = test_util::one_to_one(MIN_POSE_MATCHES - 1);
+        assert!(matches!(
+            odometry.detect_pose(&frame1, &frame2, &few),
+            Err(OdometryError::NotEnoughPoints)
+        ));
+    }
+
+    #[test]
+    fn test_slow_motion_initializes_against_first_frame() {
+        let points = test_util::scene(200);
+        let poses = slow_poses(15);
+        let descriptors = test_util::descriptors(points.len(), 3);
+        let extractor = ReplayExtractor::new(&points, &descriptors, &poses);
+        let mut odometry =
+            VisualOdometry::from_extractor(Box::new(extractor), test_util::camera()).unwrap();
+
+        let mut initialized = None;
+        for i in 0..poses.len() {
+            let result = odometry
+                .track(test_util::view_image(i), i as f64 / 30.0)
+                .unwrap();
+            if result.state == TrackingState::Ok {
+                initialized = Some(i);
+                break;
+            }
+            assert_eq!(result.state, TrackingState::Initializing);
+        }
+        let initialized = initialized.expect("never initialized");
+        // Neighbouring frames are too close to triangulate enough points.
+        assert!(initialized > 2);
+
+        let shared = odometry.map();
+        let map = map::lock(&shared).unwrap();
+        let mut frames: Vec<usize> = map.keyframes().map(|kf| kf.frame.id).collect();
+        frames.sort();
+        assert_eq!(frames, [0, initialized]);
+    }
+
+    #[test]
+    fn test_tracking_states_and_poses() {
+        let points = test_util::scene(200);
+        let poses = slow_poses(20);
+        let descriptors = test_util::descriptors(points.len(), 3);
+        let extractor = ReplayExtractor::new(&points, &descriptors, &poses);
+        let mut odometry =
+            VisualOdometry::from_extractor(Box::new(extractor), test_util::camera()).unwrap();
+        assert_eq!(odometry.state(), TrackingState::NotInitialized);
+
+        let mut states = Vec::new();
+        let mut frame = 0;
+        while states.last() != Some(&TrackingState::Ok) {
+            assert!(frame + 1 < poses.len(), "never initialized");
+            let result = odometry
+                .track(test_util::view_image(frame), frame as f64 / 30.0)
+                .unwrap();
+            states.push(result.state);
+            frame += 1;
+        }
+        states.dedup();
+        assert_eq!(states, [TrackingState::Initializing, TrackingState::Ok]);
+
+        // Tracked against the map, the pose is the camera's in the world of
+        // the first frame, up to the scale of the initial baseline.
+        let result = odometry
+            .track(test_util::view_image(frame), frame as f64 / 30.0)
+            .unwrap();
+        assert_eq!(result.state, TrackingState::Ok);
+        let camera_to_world = poses[frame].inverse();
+        let pose = utils::pose_to_isometry(&result.pose).unwrap();
+        let rotation_error = pose.rotation * camera_to_world.rotation.inverse();
+        assert!(rotation_error.angle() < 1e-3, "{rotation_error}");
+        let direction = pose.translation.vector.normalize();
+        let expected = camera_to_world.translation.vector.normalize();
+        assert!(
+            (direction - expected).norm() < 1e-2,
+            "{direction} vs {expected}"
+        );
+
+        // Nothing to match in a black image.
+        let blank = Mat::new_rows_cols_with_default(
+            test_util::HEIGHT,
+            test_util::WIDTH,
+            CV_8UC1,
+            Scalar::all(0.0),
+        )
+        .unwrap();
+        let result = odometry.track(blank, (frame + 1) as f64 / 30.0).unwrap();
+        assert_eq!(result.state, TrackingState::Lost);
+        assert_eq!(result.inliers, 0);
+    }
+
+    #[test]
+    fn test_keyframe_insertion_keeps_mapped_keypoints() {
+        let points = test_util::scene(20);
+        let descriptors = test_util::descriptors(points.len(), 5);
+        let pose = Mat::eye(4, 4, CV_64F).unwrap().to_mat().unwrap();
+        let mut map = Map::new();
+        let reference = map.insert_keyframe(
+            test_util::frame(0, &points, &Isometry3::identity(), &descriptors),
+            0.0,
+            pose.clone(),
+        );
+        let insert_point = |map: &mut Map, keypoint: i32| {
+            let descriptor = descriptors.row(keypoint).unwrap().try_clone().unwrap();
+            map.insert_map_point(Point3d::default(), descriptor, reference)
+                .unwrap()
+        };
+        // Reference keypoints 0 to 9 already observe points, and keypoints 15
+        // to 19 of the new frame were tracked.
+        for keypoint in 0..10 {
+            let point = insert_point(&mut map, keypoint);
+            map.add_observation(reference, point, keypoint as usize)
+                .unwrap();
+        }
+        let tracked: Vec<(MapPointId, usize)> = (15..20).map(|k| (1000 + k, k)).collect();
+        let before = map.keyframe(reference).unwrap().map_points.clone();
+
+        let matches = test_util::one_to_one(points.len());
+        let fresh = fresh_matches(map.keyframe(reference).unwrap(), &tracked, &matches);
+        let fresh_keypoints: Vec<i32> = fresh.iter().map(|m| m.query_idx).collect();
+        assert_eq!(fresh_keypoints, (10..15).collect::<Vec<_>>());
+
+        // Triangulate them the way `insert_keyframe` does.
+        let keyframe = map.insert_keyframe(
+            test_util::frame(1, &points, &Isometry3::identity(), &descriptors),
+            1.0,
+            pose,
+        );
+        for m in fresh.iter() {
+            let point = insert_point(&mut map, m.query_idx);
+            map.add_observation(reference, point, m.query_idx as usize)
+                .unwrap();
+            map.add_observation(keyframe, point, m.train_idx as usize)
+                .unwrap();
+        }
+        let after = &map.keyframe(reference).unwrap().map_points;
+        for (keypoint, point) in before.iter().enumerate() {
+            if point.is_some() {
+                assert_eq!(after[keypoint], *point, "keypoint {keypoint}");
+            }
+        }
+        assert!(after[10..15].iter().all(Option::is_some));
+    }
+
+    #[test]
+    fn test_vocabulary_needs_matching_descriptors() {
+        let points = test_util::scene(10);
+        let vocabulary = Arc::new(test_util::vocabulary(&test_util::descriptors(50, 1)));
+
+        // ORB and BRIEF descriptors are 32 bytes, AKAZE's 61.
+        for (cols, enabled) in [(32, true), (61, false)] {
+            let descriptors = Mat::new_rows_cols_with_default(
+                points.len() as i32,
+                cols,
+                CV_8UC1,
+                Scalar::all(0.0),
+            )
+            .unwrap();
+            let extractor = ReplayExtractor::new(&points, &descriptors, &[Isometry3::identity()]);
+            let odometry = VisualOdometry::from_extractor(Box::new(extractor), test_util::camera())
+                .unwrap()
+                .with_vocabulary(vocabulary.clone());
+            assert_eq!(odometry.vocabulary.is_some(), enabled, "{cols} columns");
+        }
+    }
 }
@@ -0,0 +1,362 @@
+//! Rigid and similarity transforms with their exponential maps.
+//!
+//! Tangent vectors put translation first: `[v, w]` for SE(3) and `[v, w, s]`
+//! for Sim(3), where `w` is a rotation vector and `s` the log of the scale.
+//! Perturbations are applied on the left, `T <- exp(delta) * T`, matching the
+//! bundle adjuster.
+
+use std::ops::Mul;
+
+use nalgebra::{Isometry3, Matrix3, SMatrix, SVector, Translation3, UnitQuaternion, Vector3};
+
+pub type Vector6 = SVector<f64, 6>;
+pub type Vector7 = SVector<f64, 7>;
+pub type Matrix6 = SMatrix<f64, 6, 6>;
+pub type Matrix7 = SMatrix<f64, 7, 7>;
+
+/// Below this, angles and log-scales use their series expansions.
+const EPSILON: f64 = 1e-8;
+
+/// Skew-symmetric matrix with `hat(a) * b == a.cross(b)`.
+pub fn hat(v: &Vector3<f64>) -> Matrix3<f64> {
+    v.cross_matrix()
+}
+
+/// Left Jacobian of SO(3), which maps rotation increments into the
+/// translation part of the SE(3) exponential.
+fn so3_left_jacobian(w: &Vector3<f64>) -> Matrix3<f64> {
+    let theta = w.norm();
+    let wx = hat(w);
+    if theta < EPSILON {
+        return Matrix3::identity() + 0.5 * wx;
+    }
+    let theta2 = theta * theta;
+    Matrix3::identity()
+        + (1.0 - theta.cos()) / theta2 * wx
+        + (theta - theta.sin()) / (theta2 * theta) * wx * wx
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct SE3 {
+    pub rotation: UnitQuaternion<f64>,
+    pub translation: Vector3<f64>,
+}
+
+impl Default for SE3 {
+    fn default() -> Self {
+        Self::identity()
+    }
+}
+
+impl SE3 {
+    pub fn new(rotation: UnitQuaternion<f64>, translation: Vector3<f64>) -> Self {
+        Self {
+            rotation,
+            translation,
+        }
+    }
+
+    pub fn identity() -> Self {
+        Self::new(UnitQuaternion::identity(), Vector3::zeros())
+    }
+
+    pub fn exp(tangent: &Vector6) -> Self {
+        let v = tangent.fixed_rows::<3>(0).into_owned();
+        let w = tangent.fixed_rows::<3>(3).into_owned();
+        Self::new(
+            UnitQuaternion::from_scaled_axis(w),
+            so3_left_jacobian(&w) * v,
+        )
+    }
+
+    pub fn log(&self) -> Vector6 {
+        let w = self.rotation.scaled_axis();
+        let v = so3_left_jacobian(&w)
+            .try_inverse()
+            .unwrap_or_else(Matrix3::identity)
+            * self.translation;
+        let mut out = Vector6::zeros();
+        out.fixed_rows_mut::<3>(0).copy_from(&v);
+        out.fixed_rows_mut::<3>(3).copy_from(&w);
+        out
+    }
+
+    pub fn inverse(&self) -> Self {
+        let rotation = self.rotation.inverse();
+        Self::new(rotation, -(rotation * self.translation))
+    }
+
+    pub fn transform_point(&self, point: &Vector3<f64>) -> Vector3<f64> {
+        self.rotation * point + self.translation
+    }
+
+    /// Maps tangent vectors at the identity through conjugation,
+    /// `T * exp(x) * T^-1 == exp(adjoint() * x)`.
+    pub fn adjoint(&self) -> Matrix6 {
+        let r = self.rotation.to_rotation_matrix().into_inner();
+        let mut out = Matrix6::zeros();
+        out.fixed_view_mut::<3, 3>(0, 0).copy_from(&r);
+        out.fixed_view_mut::<3, 3>(0, 3)
+            .copy_from(&(hat(&self.translation) * r));
+        out.fixed_view_mut::<3, 3>(3, 3).copy_from(&r);
+        out
+    }
+}
+
+impl Mul for SE3 {
+    type Output = SE3;
+
+    fn mul(self, rhs: SE3) -> SE3 {
+        SE3::new(
+            self.rotation * rhs.rotation,
+            self.rotation * rhs.translation + self.translation,
+        )
+    }
+}
+
+impl From<Isometry3<f64>> for SE3 {
+    fn from(isometry: Isometry3<f64>) -> Self {
+        Self::new(isometry.rotation, isometry.translation.vector)
+    }
+}
+
+impl From<SE3> for Isometry3<f64> {
+    fn from(se3: SE3) -> Self {
+        Isometry3::from_parts(Translation3::from(se3.translation), se3.rotation)
+    }
+}
+
+/// Similarity transform `x -> scale * rotation * x + translation`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Sim3 {
+    pub rotation: UnitQuaternion<f64>,
+    pub translation: Vector3<f64>,
+    pub scale: f64,
+}
+
+impl Default for Sim3 {
+    fn default() -> Self {
+        Self::identity()
+    }
+}
+
+impl Sim3 {
+    pub fn new(rotation: UnitQuaternion<f64>, translation: Vector3<f64>, scale: f64) -> Self {
+        Self {
+            rotation,
+            translation,
+            scale,
+        }
+    }
+
+    pub fn identity() -> Self {
+        Self::new(UnitQuaternion::identity(), Vector3::zeros(), 1.0)
+    }
+
+    pub fn from_se3(se3: &SE3, scale: f64) -> Self {
+        Self::new(se3.rotation, se3.translation, scale)
+    }
+
+    /// Rigid part, with the translation divided by the scale.
+    ///
+    /// For a world to camera similarity this is the camera pose in the
+    /// rescaled world, which is how corrected keyframe poses are read back.
+    pub fn to_se3(&self) -> SE3 {
+        SE3::new(self.rotation, self.translation / self.scale)
+    }
+
+    pub fn exp(tangent: &Vector7) -> Self {
+        let v = tangent.fixed_rows::<3>(0).into_owned();
+        let w = tangent.fixed_rows::<3>(3).into_owned();
+        let sigma = tangent[6];
+        Self::new(
+            UnitQuaternion::from_scaled_axis(w),
+            sim3_v(&w, sigma) * v,
+            sigma.exp(),
+        )
+    }
+
+    pub fn log(&self) -> Vector7 {
+        let w = self.rotation.scaled_axis();
+        let sigma = self.scale.ln();
+        let v = sim3_v(&w, sigma)
+            .try_inverse()
+            .unwrap_or_else(Matrix3::identity)
+            * self.translation;
+        let mut out = Vector7::zeros();
+        out.fixed_rows_mut::<3>(0).copy_from(&v);
+        out.fixed_rows_mut::<3>(3).copy_from(&w);
+        out[6] = sigma;
+        out
+    }
+
+    pub fn inverse(&self) -> Self {
+        let rotation = self.rotation.inverse();
+        let scale = 1.0 / self.scale;
+        Self::new(rotation, -(rotation * self.translation) * scale, scale)
+    }
+
+    pub fn transform_point(&self, point: &Vector3<f64>) -> Vector3<f64> {
+        self.scale * (self.rotation * point) + self.translation
+    }
+
+    /// Maps tangent vectors at the identity through conjugation,
+    /// `S * exp(x) * S^-1 == exp(adjoint() * x)`.
+    pub fn adjoint(&self) -> Matrix7 {
+        let r = self.rotation.to_rotation_matrix().into_inner();
+        let mut out = Matrix7::zeros();
+        out.fixed_view_mut::<3, 3>(0, 0)
+            .copy_from(&(self.scale * r));
+        out.fixed_view_mut::<3, 3>(0, 3)
+            .copy_from(&(hat(&self.translation) * r));
+        out.fixed_view_mut::<3, 1>(0, 6)
+            .copy_from(&-self.translation);
+        out.fixed_view_mut::<3, 3>(3, 3).copy_from(&r);
+        out[(6, 6)] = 1.0;
+        out
+    }
+}
+
+impl Mul for Sim3 {
+    type Output = Sim3;
+
+    fn mul(self, rhs: Sim3) -> Sim3 {
+        Sim3::new(
+            self.rotation * rhs.rotation,
+            self.scale * (self.rotation * rhs.translation) + self.translation,
+            self.scale * rhs.scale,
+        )
+    }
+}
+
+/// Matrix taking the translation tangent of Sim(3) to the translation of
+/// the group element, `t = V(w, sigma) * v`.
+fn sim3_v(w: &Vector3<f64>, sigma: f64) -> Matrix3<f64> {
+    let theta = w.norm();
+    let wx = hat(w);
+    let scale = sigma.exp();
+
+    let (a, b, c) = if sigma.abs() < EPSILON {
+        if theta < EPSILON {
+            (0.5, 1.0 / 6.0, 1.0)
+        } else {
+            let theta2 = theta * theta;
+            (
+                (1.0 - theta.cos()) / theta2,
+                (theta - theta.sin()) / (theta2 * theta),
+                1.0,
+            )
+        }
+    } else {
+        let c = (scale - 1.0) / sigma;
+        if theta < EPSILON {
+            let sigma2 = sigma * sigma;
+            (
+                ((sigma - 1.0) * scale + 1.0) / sigma2,
+                ((0.5 * sigma2 - sigma + 1.0) * scale - 1.0) / (sigma2 * sigma),
+                c,
+            )
+        } else {
+            let theta2 = theta * theta;
+            let sin = scale * theta.sin();
+            let cos = scale * theta.cos();
+            let denominator = theta2 + sigma * sigma;
+            (
+                (sin * sigma + (1.0 - cos) * theta) / (theta * denominator),
+                (c - ((cos - 1.0) * sigma + sin * theta) / denominator) / theta2,
+                c,
+            )
+        }
+    };
+
+    a * wx + b * wx * wx + c * Matrix3::identity()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use approx::assert_relative_eq;
+    use nalgebra::Matrix4;
+
+    /// Matrix exponential by Taylor series, as an independent reference.
+    fn expm(m: &Matrix4<f64>) -> Matrix4<f64> {
+        let mut out = Matrix4::identity();
+        let mut term = Matrix4::identity();
+        for k in 1..40 {
+            term = term * m / k as f64;
+            out += term;
+        }
+        out
+    }
+
+    fn sim3_matrix(s: &Sim3) -> Matrix4<f64> {
+        let mut m = Matrix4::identity();
+        m.fixed_view_mut::<3, 3>(0, 0)
+            .copy_from(&(s.scale * s.rotation.to_rotation_matrix().into_inner()));
+        m.fixed_view_mut::<3, 1>(0, 3).copy_from(&s.translation);
+        m
+    }
+
+    fn generator(x: &Vector7) -> Matrix4<f64> {
+        let v = x.fixed_rows::<3>(0).into_owned();
+        let w = x.fixed_rows::<3>(3).into_owned();
+        let mut m = Matrix4::zeros();
+        m.fixed_view_mut::<3, 3>(0, 0)
+            .copy_from(&(hat(&w) + x[6] * Matrix3::identity()));
+        m.fixed_view_mut::<3, 1>(0, 3).copy_from(&v);
+        m
+    }
+
+    fn tangents() -> Vec<Vector7> {
+        vec![
+            Vector7::from_column_slice(&[0.3, -0.2, 0.5, 0.4, 0.1, -0.7, 0.2]),
+            Vector7::from_column_slice(&[0.3, -0.2, 0.5, 0.4, 0.1, -0.7, 0.0]),
+            Vector7::from_column_slice(&[0.3, -0.2, 0.5, 0.0, 0.0, 0.0, -0.4]),
+            Vector7::from_column_slice(&[0.3, -0.2, 0.5, 0.0, 0.0, 0.0, 0.0]),
+        ]
+    }
+
+    #[test]
+    fn test_sim3_exp_matches_matrix_exponential() {
+        for x in tangents() {
+            let expected = expm(&generator(&x));
+            assert_relative_eq!(sim3_matrix(&Sim3::exp(&x)), expected, epsilon = 1e-9);
+            assert_relative_eq!(Sim3::exp(&x).log(), x, epsilon = 1e-9);
+        }
+    }
+
+    #[test]
+    fn test_sim3_group_operations() {
+        let [a, b, ..] = tangents()[..] else {
+            unreachable!()
+        };
+        let s = Sim3::exp(&a);
+        let t = Sim3::exp(&b);
+        let p = Vector3::new(1.0, -2.0, 0.5);
+
+        assert_relative_eq!(
+            (s * t).transform_point(&p),
+            s.transform_point(&t.transform_point(&p)),
+            epsilon = 1e-12
+        );
+        assert_relative_eq!((s * s.inverse()).log(), Vector7::zeros(), epsilon = 1e-12);
+
+        let conjugated = s * Sim3::exp(&b) * s.inverse();
+        assert_relative_eq!(conjugated.log(), s.adjoint() * b, epsilon = 1e-9);
+    }
+
+    #[test]
+    fn test_se3_round_trip_and_adjoint() {
+        let x = Vector6::from_column_slice(&[0.3, -0.2, 0.5, 0.4, 0.1, -0.7]);
+        let y = Vector6::from_column_slice(&[-0.1, 0.6, 0.2, -0.3, 0.2, 0.1]);
+        let t = SE3::exp(&x);
+        assert_relative_eq!(t.log(), x, epsilon = 1e-12);
+
+        let sim = Sim3::from_se3(&t, 1.0);
+        let tangent = Vector7::from_column_slice(&[x[0], x[1], x[2], x[3], x[4], x[5], 0.0]);
+        assert_relative_eq!(sim.log(), tangent, epsilon = 1e-9);
+
+        let conjugated = t * SE3::exp(&y) * t.inverse();
+        assert_relative_eq!(conjugated.log(), t.adjoint() * y, epsilon = 1e-9);
+    }
+}
@@ -0,0 +1,798 @@
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+
+use nalgebra::{Vector2, Vector3};
+use opencv::core::{Mat, Point2d, Point3d};
+use opencv::prelude::*;
+use r_slam_common::camera::Camera;
+
+use crate::OdometryError;
+use crate::grid::FeatureGrid;
+use crate::keyframe_database::KeyFrameDatabase;
+use crate::lie::Sim3;
+use crate::map::{KeyFrameId, Map, MapError, MapPointId};
+use crate::pose_graph::{PoseGraph, PoseGraphConfig};
+use crate::sim3_solver::{Sim3Correspondence, Sim3Solver, Sim3SolverConfig};
+use crate::utils;
+use crate::vocabulary;
+
+/// Covisible keyframes of a loop candidate whose points are projected into
+/// the current keyframe.
+const LOOP_GROUP_NEIGHBOURS: usize = 10;
+/// Candidate groups scoring below this fraction of the best group are dropped.
+const GROUP_SCORE_RATIO: f64 = 0.75;
+
+#[derive(Debug, Clone, Copy)]
+pub struct LoopClosingConfig {
+    /// Keyframes the map must hold, and keyframes inserted since the last
+    /// loop closure, before a loop is searched for.
+    pub min_keyframes_between: usize,
+    /// Consecutive keyframes that must detect the same place before a loop
+    /// is verified.
+    pub consistency: usize,
+    /// Levels above the vocabulary leaves indexed by keyframe feature vectors.
+    pub levels_up: usize,
+    pub max_descriptor_distance: u32,
+    /// Best match must be closer than `ratio` times the second best.
+    pub ratio: f32,
+    /// BoW matches with map points needed before a Sim(3) is estimated.
+    pub min_bow_matches: usize,
+    pub sim3: Sim3SolverConfig,
+    /// Matches, Sim(3) inliers plus those found by projection, needed to
+    /// accept a loop.
+    pub min_loop_matches: usize,
+    /// Radius around each projected loop point searched for keypoints, in pixels.
+    pub search_radius: f64,
+    /// Shared map points for a covisibility edge to join the essential graph.
+    pub min_covisibility_weight: usize,
+    pub pose_graph: PoseGraphConfig,
+}
+
+impl Default for LoopClosingConfig {
+    fn default() -> Self {
+        Self {
+            min_keyframes_between: 10,
+            consistency: 3,
+            levels_up: 4,
+            max_descriptor_distance: 50,
+            ratio: 0.75,
+            min_bow_matches: 20,
+            sim3: Sim3SolverConfig::default(),
+            min_loop_matches: 40,
+            search_radius: 10.0,
+            min_covisibility_weight: 100,
+            pose_graph: PoseGraphConfig::default(),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct LoopClosure {
+    pub keyframe: KeyFrameId,
+    /// Earlier keyframe of the same place.
+    pub loop_keyframe: KeyFrameId,
+    /// Map point matches supporting the loop.
+    pub matches: usize,
+    /// Maps the loop keyframe's camera coordinates into the current
+    /// keyframe's, including the accumulated scale drift.
+    pub sim3: Sim3,
+    /// Duplicated map points merged into their loop counterparts.
+    pub fused_points: usize,
+}
+
+/// A verified loop, ready to be corrected.
+struct LoopMatch {
+    loop_keyframe: KeyFrameId,
+    sim3: Sim3,
+    /// Corrected world to camera similarity of the current keyframe.
+    corrected: Sim3,
+    /// `(keypoint index in the current keyframe, loop map point)`.
+    matches: Vec<(usize, MapPointId)>,
+    /// Map points seen by the loop keyframe and its neighbours.
+    loop_points: Vec<MapPointId>,
+    loop_group: BTreeSet<KeyFrameId>,
+}
+
+/// Detects when the camera revisits a mapped place and removes the drift
+/// accumulated since.
+///
+/// Candidates come from the keyframe database and must be confirmed by
+/// several consecutive keyframes. The transform to an accepted candidate is a
+/// Sim(3), so scale drift is corrected too: it is propagated to the current
+/// keyframe and its neighbours, duplicated map points are fused, and the
+/// remaining error is spread over the whole trajectory with an essential
+/// graph optimization.
+pub struct LoopCloser {
+    camera: Camera,
+    config: LoopClosingConfig,
+    solver: Sim3Solver,
+    /// Keyframe groups seen by recent keyframes, with how many consecutive
+    /// keyframes saw them.
+    consistent_groups: Vec<(BTreeSet<KeyFrameId>, usize)>,
+    last_loop: Option<KeyFrameId>,
+}
+
+impl LoopCloser {
+    pub fn new(camera: Camera, config: LoopClosingConfig) -> Self {
+        Self {
+            solver: Sim3Solver::new(camera.clone(), config.sim3),
+            camera,
+            config,
+            consistent_groups: Vec::new(),
+            last_loop: None,
+        }
+    }
+
+    pub fn config(&self) -> &LoopClosingConfig {
+        &self.config
+    }
+
+    /// Forgets candidate groups, e.g. after the map was reset.
+    pub fn reset(&mut self) {
+        self.consistent_groups.clear();
+        self.last_loop = None;
+    }
+
+    /// Looks for a loop ending at `keyframe` and closes it. Call before
+    /// `keyframe` is added to `database`, so it cannot match itself.
+    pub fn process(
+        &mut self,
+        map: &mut Map,
+        database: &KeyFrameDatabase,
+        keyframe: KeyFrameId,
+    ) -> Result<Option<LoopClosure>, OdometryError> {
+        let recent_loop = self
+            .last_loop
+            .is_some_and(|last| keyframe < last + self.config.min_keyframes_between);
+        if recent_loop || map.num_keyframes() < self.config.min_keyframes_between {
+            return Ok(None);
+        }
+
+        for candidate in self.detect(map, database, keyframe)? {
+            let Some(found) = self.compute_sim3(map, keyframe, candidate)? else {
+                continue;
+            };
+            let closure = self.correct(map, keyframe, found)?;
+            self.consistent_groups.clear();
+            self.last_loop = Some(keyframe);
+            return Ok(Some(closure));
+        }
+        Ok(None)
+    }
+
+    /// Database candidates that were also detected, through their covisible
+    /// neighbours, by the previous `consistency` keyframes.
+    fn detect(
+        &mut self,
+        map: &Map,
+        database: &KeyFrameDatabase,
+        keyframe: KeyFrameId,
+    ) -> Result<Vec<KeyFrameId>, OdometryError> {
+        let current = map
+            .keyframe(keyframe)
+            .ok_or(MapError::UnknownKeyFrame(keyframe))?;
+        if current.bow.is_empty() {
+            return Ok(Vec::new());
+        }
+
+        // A revisited place must look at least as similar as the current
+        // neighbours do.
+        let neighbours: BTreeSet<KeyFrameId> = map
+            .covisible_keyframes(keyframe, 1)
+            .into_iter()
+            .map(|(id, _)| id)
+            .collect();
+        let min_score = neighbours
+            .iter()
+            .filter_map(|&id| map.keyframe(id))
+            .filter(|kf| !kf.bow.is_empty())
+            .map(|kf| current.bow.score(&kf.bow))
+            .reduce(f64::min)
+            .unwrap_or(0.0);
+
+        let mut exclude = neighbours;
+        exclude.insert(keyframe);
+        let candidates = database.query(&current.bow, min_score, &exclude);
+        if candidates.is_empty() {
+            self.consistent_groups.clear();
+            return Ok(Vec::new());
+        }
+
+        // Score each candidate together with its neighbours, so a place seen
+        // by several keyframes beats a single lucky match.
+        let scores: HashMap<KeyFrameId, f64> = candidates.iter().copied().collect();
+        let groups: Vec<(KeyFrameId, BTreeSet<KeyFrameId>, f64)> = candidates
+            .iter()
+            .map(|&(candidate, _)| {
+                let mut group: BTreeSet<KeyFrameId> = map
+                    .best_covisible_keyframes(candidate, LOOP_GROUP_NEIGHBOURS)
+                    .into_iter()
+                    .collect();
+                group.insert(candidate);
+                let total: f64 = group.iter().filter_map(|id| scores.get(id)).sum();
+                (candidate, group, total)
+            })
+            .collect();
+        let best_total = groups.iter().map(|g| g.2).fold(0.0, f64::max);
+
+        let mut consistent_groups = Vec::new();
+        let mut accepted = Vec::new();
+        for (candidate, group, total) in groups {
+            if total < GROUP_SCORE_RATIO * best_total {
+                continue;
+            }
+            let consistency = self
+                .consistent_groups
+                .iter()
+                .filter(|(previous, _)| !previous.is_disjoint(&group))
+                .map(|&(_, count)| count + 1)
+                .max()
+                .unwrap_or(1);
+            if consistency >= self.config.consistency {
+                accepted.push(candidate);
+            }
+            consistent_groups.push((group, consistency));
+        }
+        self.consistent_groups = consistent_groups;
+        Ok(accepted)
+    }
+
+    /// Estimates the similarity between `keyframe` and `candidate` from their
+    /// BoW matches and checks it against the points around `candidate`.
+    fn compute_sim3(
+        &self,
+        map: &Map,
+        keyframe: KeyFrameId,
+        candidate: KeyFrameId,
+    ) -> Result<Option<LoopMatch>, OdometryError> {
+        let current = map
+            .keyframe(keyframe)
+            .ok_or(MapError::UnknownKeyFrame(keyframe))?;
+        let other = map
+            .keyframe(candidate)
+            .ok_or(MapError::UnknownKeyFrame(candidate))?;
+
+        let bow_matches = vocabulary::search_by_bow(
+            &current.features,
+            &current.frame.descriptors,
+            &other.features,
+            &other.frame.descriptors,
+            |i, j| current.map_points[i].is_some() && other.map_points[j].is_some(),
+            self.config.max_descriptor_distance,
+            self.config.ratio,
+        )?;
+        if bow_matches.len() < self.config.min_bow_matches {
+            return Ok(None);
+        }
+
+        let current_pose = utils::pose_to_sim3(&current.pose)?;
+        let other_pose = utils::pose_to_sim3(&other.pose)?;
+        let current_pixels = utils::undistorted_keypoints(&self.camera, &current.frame.keypoints)?;
+        let other_pixels = utils::undistorted_keypoints(&self.camera, &other.frame.keypoints)?;
+        let mut correspondences = Vec::with_capacity(bow_matches.len());
+        let mut matched = Vec::with_capacity(bow_matches.len());
+        for &(i, j) in &bow_matches {
+            let (Some(p1), Some(p2)) = (current.map_points[i], other.map_points[j]) else {
+                continue;
+            };
+            let (Some(p1), Some(p2)) = (map.map_point(p1), map.map_point(p2)) else {
+                continue;
+            };
+            correspondences.push(Sim3Correspondence {
+                point1: current_pose.transform_point(&to_vector(&p1.position)),
+                point2: other_pose.transform_point(&to_vector(&p2.position)),
+                pixel1: Vector2::new(current_pixels[i].x, current_pixels[i].y),
+                pixel2: Vector2::new(other_pixels[j].x, other_pixels[j].y),
+            });
+            matched.push((i, p2.id));
+        }
+        let Some(estimate) = self.solver.solve(&correspondences) else {
+            return Ok(None);
+        };
+
+        let mut matches: Vec<(usize, MapPointId)> = matched
+            .into_iter()
+            .zip(&estimate.inliers)
+            .filter(|&(_, &inlier)| inlier)
+            .map(|(m, _)| m)
+            .collect();
+
+        // Look for more of the loop's points now that the current keyframe
+        // can be placed in the loop's frame.
+        let mut loop_group: BTreeSet<KeyFrameId> = map
+            .best_covisible_keyframes(candidate, LOOP_GROUP_NEIGHBOURS)
+            .into_iter()
+            .collect();
+        loop_group.insert(candidate);
+        let loop_points: Vec<MapPointId> = loop_group
+            .iter()
+            .filter_map(|&id| map.keyframe(id))
+            .flat_map(|kf| kf.observed_points())
+            .collect::<BTreeSet<_>>()
+            .into_iter()
+            .collect();
+
+        let corrected = estimate.sim3 * other_pose;
+        let taken: BTreeSet<usize> = matches.iter().map(|&(k, _)| k).collect();
+        let used: BTreeSet<MapPointId> = matches.iter().map(|&(_, p)| p).collect();
+        let candidates: Vec<MapPointId> = loop_points
+            .iter()
+            .copied()
+            .filter(|p| !used.contains(p))
+            .collect();
+        let grid = FeatureGrid::new(&current_pixels, self.config.search_radius);
+        for (point, keypoint) in self.search_by_projection(
+            map,
+            &current.frame.descriptors,
+            &grid,
+            &corrected,
+            &candidates,
+        )? {
+            if !taken.contains(&keypoint) {
+                matches.push((keypoint, point));
+            }
+        }
+
+        if matches.len() < self.config.min_loop_matches {
+            return Ok(None);
+        }
+        tracing::debug!(
+            keyframe,
+            candidate,
+            inliers = estimate.num_inliers,
+            matches = matches.len(),
+            "loop verified"
+        );
+        Ok(Some(LoopMatch {
+            loop_keyframe: candidate,
+            sim3: estimate.sim3,
+            corrected,
+            matches,
+            loop_points,
+            loop_group,
+        }))
+    }
+
+    /// Moves the current keyframe and its neighbours onto the loop, fuses
+    /// their duplicated points and optimizes the essential graph.
+    fn correct(
+        &self,
+        map: &mut Map,
+        keyframe: KeyFrameId,
+        found: LoopMatch,
+    ) -> Result<LoopClosure, OdometryError> {
+        let current_pose = map
+            .keyframe(keyframe)
+            .map(|kf| utils::pose_to_sim3(&kf.pose))
+            .ok_or(MapError::UnknownKeyFrame(keyframe))??;
+
+        // Every keyframe's pose before the correction, and the corrected poses
+        // of the current keyframe and its neighbours.
+        let mut uncorrected: BTreeMap<KeyFrameId, Sim3> = BTreeMap::new();
+        for kf in map.keyframes() {
+            uncorrected.insert(kf.id, utils::pose_to_sim3(&kf.pose)?);
+        }
+        let mut current_group: BTreeSet<KeyFrameId> = map
+            .covisible_keyframes(keyframe, 1)
+            .into_iter()
+            .map(|(id, _)| id)
+            .collect();
+        current_group.insert(keyframe);
+        let camera_to_world = current_pose.inverse();
+        let corrected: BTreeMap<KeyFrameId, Sim3> = current_group
+            .iter()
+            .map(|&id| {
+                let relative = uncorrected[&id] * camera_to_world;
+                (id, relative * found.corrected)
+            })
+            .collect();
+
+        // Points seen by the group move rigidly with the first group keyframe
+        // observing them.
+        let mut moved: BTreeMap<MapPointId, KeyFrameId> = BTreeMap::new();
+        for (&id, corrected_pose) in &corrected {
+            let correction = corrected_pose.inverse() * uncorrected[&id];
+            let points: Vec<MapPointId> = map
+                .keyframe(id)
+                .map(|kf| kf.observed_points().collect())
+                .unwrap_or_default();
+            for point in points {
+                if moved.insert(point, id).is_some() {
+                    continue;
+                }
+                if let Some(mp) = map.map_point_mut(point) {
+                    mp.position = to_point(&correction.transform_point(&to_vector(&mp.position)));
+                }
+            }
+            if let Some(kf) = map.keyframe_mut(id) {
+                kf.pose = utils::sim3_to_pose(corrected_pose)?;
+            }
+        }
+
+        // Merge the current keyframe's duplicates of the loop points, then
+        // those of its neighbours.
+        let loop_points: BTreeSet<MapPointId> = found.loop_points.iter().copied().collect();
+        let mut fused_points = 0;
+        for &(keypoint, point) in &found.matches {
+            fused_points += self.fuse(map, keyframe, keypoint, point, &loop_points)?;
+        }
+        for (&id, corrected_pose) in &corrected {
+            let Some(kf) = map.keyframe(id) else {
+                continue;
+            };
+            let pixels = utils::undistorted_keypoints(&self.camera, &kf.frame.keypoints)?;
+            let grid = FeatureGrid::new(&pixels, self.config.search_radius);
+            let descriptors = kf.frame.descriptors.clone();
+            let candidates: Vec<MapPointId> = found
+                .loop_points
+                .iter()
+                .copied()
+                .filter(|&p| {
+                    map.map_point(p)
+                        .is_some_and(|mp| !mp.observations.contains_key(&id))
+                })
+                .collect();
+            let projected =
+                self.search_by_projection(map, &descriptors, &grid, corrected_pose, &candidates)?;
+            for (point, keypoint) in projected {
+                fused_points += self.fuse(map, id, keypoint, point, &loop_points)?;
+            }
+        }
+        map.add_loop_edge(keyframe, found.loop_keyframe)?;
+
+        self.optimize_essential_graph(
+            map,
+            &uncorrected,
+            &corrected,
+            &moved,
+            &found.loop_group,
+            found.loop_keyframe,
+        )?;
+
+        Ok(LoopClosure {
+            keyframe,
+            loop_keyframe: found.loop_keyframe,
+            matches: found.matches.len(),
+            sim3: found.sim3,
+            fused_points,
+        })
+    }
+
+    /// Binds `point` to `keypoint` of `keyframe`, replacing whatever point the
+    /// keypoint observed. Returns 1 if a duplicate was merged.
+    fn fuse(
+        &self,
+        map: &mut Map,
+        keyframe: KeyFrameId,
+        keypoint: usize,
+        point: MapPointId,
+        loop_points: &BTreeSet<MapPointId>,
+    ) -> Result<usize, OdometryError> {
+        if map.map_point(point).is_none() {
+            return Ok(0);
+        }
+        let existing = map
+            .keyframe(keyframe)
+            .ok_or(MapError::UnknownKeyFrame(keyframe))?
+            .map_points
+            .get(keypoint)
+            .copied()
+            .flatten();
+        match existing {
+            Some(existing) if existing == point || loop_points.contains(&existing) => Ok(0),
+            Some(existing) => {
+                map.replace_map_point(existing, point)?;
+                Ok(1)
+            }
+            None => {
+                if map
+                    .map_point(point)
+                    .is_some_and(|mp| mp.observations.contains_key(&keyframe))
+                {
+                    return Ok(0);
+                }
+                map.add_observation(keyframe, point, keypoint)?;
+                map.update_descriptor(point)?;
+                map.update_viewing_direction(point)?;
+                Ok(0)
+            }
+        }
+    }
+
+    /// Spreads the loop correction over every keyframe and moves each map
+    /// point with the keyframe that already corrected it, or else its
+    /// reference keyframe.
+    ///
+    /// Keyframes are linked to the previous keyframe, to strongly covisible
+    /// keyframes and across earlier loops with their relative poses from
+    /// before the correction, and across the new loop with the corrected
+    /// ones. The loop keyframe is held fixed.
+    fn optimize_essential_graph(
+        &self,
+        map: &mut Map,
+        uncorrected: &BTreeMap<KeyFrameId, Sim3>,
+        corrected: &BTreeMap<KeyFrameId, Sim3>,
+        moved: &BTreeMap<MapPointId, KeyFrameId>,
+        loop_group: &BTreeSet<KeyFrameId>,
+        loop_keyframe: KeyFrameId,
+    ) -> Result<(), OdometryError> {
+        let initial: BTreeMap<KeyFrameId, Sim3> = uncorrected
+            .iter()
+            .map(|(&id, &pose)| (id, corrected.get(&id).copied().unwrap_or(pose)))
+            .collect();
+        let mut graph = PoseGraph::new();
+        for (&id, &pose) in &initial {
+            graph.add_vertex(id, pose, id == loop_keyframe);
+        }
+
+        let mut edges: BTreeSet<(KeyFrameId, KeyFrameId)> = BTreeSet::new();
+        let mut add_edge = |graph: &mut PoseGraph,
+                            from: KeyFrameId,
+                            to: KeyFrameId,
+                            poses: &BTreeMap<KeyFrameId, Sim3>| {
+            let key = (from.min(to), from.max(to));
+            if from == to || !edges.insert(key) {
+                return Ok(());
+            }
+            graph.add_edge(from, to, poses[&to] * poses[&from].inverse())
+        };
+
+        // The new loop: the current group against the loop group, as found.
+        for &i in corrected.keys() {
+            for &j in loop_group.iter().filter(|&j| !corrected.contains_key(j)) {
+                if initial.contains_key(&j) {
+                    add_edge(&mut graph, j, i, &initial)?;
+                }
+            }
+        }
+        let ids: Vec<KeyFrameId> = uncorrected.keys().copied().collect();
+        for pair in ids.windows(2) {
+            add_edge(&mut graph, pair[0], pair[1], uncorrected)?;
+        }
+        for &id in &ids {
+            let strong = map.covisible_keyframes(id, self.config.min_covisibility_weight);
+            for (other, _) in strong {
+                add_edge(&mut graph, id, other, uncorrected)?;
+            }
+            for other in map.loop_edges(id) {
+                add_edge(&mut graph, id, other, uncorrected)?;
+            }
+        }
+
+        let summary = graph.optimize(&self.config.pose_graph)?;
+        tracing::debug!(
+            iterations = summary.iterations,
+            initial_cost = summary.initial_cost,
+            final_cost = summary.final_cost,
+            "essential graph optimized"
+        );
+
+        let optimized: BTreeMap<KeyFrameId, Sim3> =
+            graph.vertices().map(|(id, v)| (id, v.pose)).collect();
+        let point_ids: Vec<MapPointId> = map.map_points().map(|mp| mp.id).collect();
+        for point in point_ids {
+            let Some(mp) = map.map_point(point) else {
+                continue;
+            };
+            let reference = if let Some(&id) = moved.get(&point) {
+                Some(id)
+            } else if initial.contains_key(&mp.reference_keyframe) {
+                Some(mp.reference_keyframe)
+            } else {
+                mp.observations.keys().next().copied()
+            };
+            let Some(reference) = reference.filter(|id| initial.contains_key(id)) else {
+                continue;
+            };
+            let correction = optimized[&reference].inverse() * initial[&reference];
+            let position = to_point(&correction.transform_point(&to_vector(&mp.position)));
+            if let Some(mp) = map.map_point_mut(point) {
+                mp.position = position;
+            }
+            map.update_viewing_direction(point)?;
+        }
+        for (id, pose) in &optimized {
+            if let Some(kf) = map.keyframe_mut(*id) {
+                kf.pose = utils::sim3_to_pose(pose)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Projects `points` with a world to camera similarity and matches each
+    /// against keypoints around its projection, keeping the nearest one only
+    /// when it is clearly nearer than the second. Returns `(point, keypoint)`
+    /// pairs with every keypoint used at most once.
+    fn search_by_projection(
+        &self,
+        map: &Map,
+        descriptors: &Mat,
+        grid: &FeatureGrid,
+        pose: &Sim3,
+        points: &[MapPointId],
+    ) -> Result<Vec<(MapPointId, usize)>, OdometryError> {
+        // keypoint index -> (point, distance)
+        let mut best_for_keypoint: HashMap<usize, (MapPointId, u32)> = HashMap::new();
+        for &point in points {
+            let Some(mp) = map.map_point(point) else {
+                continue;
+            };
+            let in_camera = pose.transform_point(&to_vector(&mp.position));
+            if in_camera.z <= 0.0 {
+                continue;
+            }
+            let projected = Point2d::new(
+                self.camera.fx * in_camera.x / in_camera.z + self.camera.cx,
+                self.camera.fy * in_camera.y / in_camera.z + self.camera.cy,
+            );
+
+            let descriptor = mp.descriptor.at_row::<u8>(0)?;
+            let mut best: Option<(usize, u32)> = None;
+            let mut second = u32::MAX;
+            for k in grid.within(projected, self.config.search_radius) {
+                let distance = utils::hamming(descriptor, descriptors.at_row::<u8>(k as i32)?);
+                match best {
+                    Some((_, d)) if distance >= d => second = second.min(distance),
+                    _ => {
+                        second = best.map_or(second, |(_, d)| d);
+                        best = Some((k, distance));
+                    }
+                }
+            }
+            let Some((k, distance)) = best else {
+                continue;
+            };
+            if distance > self.config.max_descriptor_distance
+                || distance as f32 >= self.config.ratio * second as f32
+            {
+                continue;
+            }
+            match best_for_keypoint.get(&k) {
+                Some(&(_, existing)) if existing <= distance => {}
+                _ => {
+                    best_for_keypoint.insert(k, (point, distance));
+                }
+            }
+        }
+
+        let mut matches: Vec<(MapPointId, usize)> = best_for_keypoint
+            .into_iter()
+            .map(|(k, (p, _))| (p, k))
+            .collect();
+        matches.sort_unstable();
+        Ok(matches)
+    }
+}
+
+fn to_vector(point: &Point3d) -> Vector3<f64> {
+    Vector3::new(point.x, point.y, point.z)
+}
+
+fn to_point(v: &Vector3<f64>) -> Point3d {
+    Point3d::new(v.x, v.y, v.z)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::{self, SceneMap};
+    use approx::assert_relative_eq;
+    use nalgebra::{Isometry3, UnitQuaternion};
+
+    /// Scene points around the revisited place, seen on both visits.
+    const PLACE: usize = 150;
+    /// Further points only the second visit sees.
+    const EXTRA: usize = 20;
+
+    /// Maps true world coordinates into the second visit's, which drifted in
+    /// scale, rotation and position.
+    fn drift() -> Sim3 {
+        Sim3::new(
+            UnitQuaternion::from_scaled_axis(Vector3::new(0.02, 0.1, -0.03)),
+            Vector3::new(0.2, -0.1, 0.15),
+            1.25,
+        )
+    }
+
+    fn first_visit(i: usize) -> Isometry3<f64> {
+        Isometry3::translation(-0.1 * i as f64, 0.0, 0.0)
+    }
+
+    fn second_visit(i: usize) -> Isometry3<f64> {
+        Isometry3::new(
+            Vector3::new(-0.05 - 0.1 * i as f64, 0.03, 0.0),
+            Vector3::new(0.0, 0.01, 0.0),
+        )
+    }
+
+    /// Scene points keyframe `i` of the second visit sees. They overlap less
+    /// with each other than with the first visit, which sees the whole place,
+    /// so the first visit outscores the current keyframe's neighbours.
+    fn second_visit_points(i: usize) -> Vec<usize> {
+        match i {
+            0 => (0..100).chain(PLACE..PLACE + EXTRA).collect(),
+            1 => (50..PLACE + EXTRA).collect(),
+            _ => (0..50).chain(100..PLACE).collect(),
+        }
+    }
+
+    #[test]
+    fn test_closes_loop_onto_first_visit() {
+        let mut scene = SceneMap::new(PLACE + EXTRA, 21);
+        let place: Vec<usize> = (0..PLACE).collect();
+        let mut first = vec![None; PLACE + EXTRA];
+        let mut loop_keyframes = Vec::new();
+        for i in 0..3 {
+            let keyframe =
+                scene.add_keyframe(&place, &first_visit(i), &Sim3::identity(), &mut first);
+            scene.index(keyframe);
+            loop_keyframes.push(keyframe);
+        }
+
+        let config = LoopClosingConfig {
+            min_keyframes_between: 4,
+            ..Default::default()
+        };
+        let mut closer = LoopCloser::new(test_util::camera(), config);
+        let mut second = vec![None; PLACE + EXTRA];
+        let mut current = Vec::new();
+        let mut closure = None;
+        for i in 0..config.consistency {
+            let keyframe = scene.add_keyframe(
+                &second_visit_points(i),
+                &second_visit(i),
+                &drift(),
+                &mut second,
+            );
+            current.push(keyframe);
+            closure = closer
+                .process(&mut scene.map, &scene.database, keyframe)
+                .unwrap();
+            // Only closed once enough consecutive keyframes saw the place.
+            assert_eq!(
+                closure.is_some(),
+                i + 1 == config.consistency,
+                "keyframe {i}"
+            );
+            scene.index(keyframe);
+        }
+        let closure = closure.unwrap();
+        assert_eq!(closure.keyframe, current[current.len() - 1]);
+        assert!(loop_keyframes.contains(&closure.loop_keyframe));
+        assert_relative_eq!(closure.sim3.scale, drift().scale, epsilon = 1e-4);
+
+        // The second visit's copies of the place are merged into the first
+        // visit's points, and its own points stay.
+        assert_eq!(closure.fused_points, PLACE);
+        assert_eq!(scene.map.num_map_points(), PLACE + EXTRA);
+        for (i, &keyframe) in current.iter().enumerate() {
+            let keyframe = scene.map.keyframe(keyframe).unwrap();
+            for (k, &point) in second_visit_points(i).iter().enumerate() {
+                let expected = if point < PLACE {
+                    first[point]
+                } else {
+                    second[point]
+                };
+                assert_eq!(
+                    keyframe.map_points[k], expected,
+                    "keyframe {i} point {point}"
+                );
+            }
+
+            let error =
+                utils::pose_to_isometry(&keyframe.pose).unwrap() * second_visit(i).inverse();
+            assert!(
+                error.translation.vector.norm() < 1e-3,
+                "keyframe {i}: {error}"
+            );
+            assert!(error.rotation.angle() < 1e-4, "keyframe {i}: {error}");
+        }
+        for point in PLACE..PLACE + EXTRA {
+            let position = scene
+                .map
+                .map_point(second[point].unwrap())
+                .unwrap()
+                .position;
+            assert!((to_vector(&position) - scene.points[point]).norm() < 1e-3);
+        }
+    }
+}
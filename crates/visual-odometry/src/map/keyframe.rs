@@ -0,0 +1,54 @@
+use opencv::core::{Mat, Point3d};
+
+use crate::frame::Frame;
+use crate::utils::{self, mul3v, scale3, transpose3};
+use crate::vocabulary::{BowVector, FeatureVector};
+
+use super::{KeyFrameId, MapPointId};
+
+#[derive(Debug, Clone)]
+pub struct KeyFrame {
+    pub id: KeyFrameId,
+    pub timestamp: f64,
+    /// Keypoints, descriptors and image the keyframe was created from.
+    pub frame: Frame,
+    /// World to camera transform (4x4, CV_64F).
+    pub pose: Mat,
+    /// Map point observed by each keypoint, index aligned with `frame.keypoints`.
+    pub map_points: Vec<Option<MapPointId>>,
+    /// Empty unless the tracker was given a vocabulary.
+    pub bow: BowVector,
+    /// Direct index of `frame.keypoints` into vocabulary nodes.
+    pub features: FeatureVector,
+}
+
+impl KeyFrame {
+    pub(crate) fn new(id: KeyFrameId, frame: Frame, timestamp: f64, pose: Mat) -> Self {
+        let map_points = vec![None; frame.keypoints.len()];
+        Self {
+            id,
+            timestamp,
+            frame,
+            pose,
+            map_points,
+            bow: BowVector::default(),
+            features: FeatureVector::new(),
+        }
+    }
+
+    /// Camera centre in world coordinates.
+    pub fn camera_center(&self) -> Result<Point3d, opencv::Error> {
+        let (rotation, translation) = utils::split_pose(&self.pose)?;
+        let c = scale3(&mul3v(&transpose3(&rotation), &translation), -1.0);
+        Ok(Point3d::new(c[0], c[1], c[2]))
+    }
+
+    /// Ids of the map points this keyframe observes.
+    pub fn observed_points(&self) -> impl Iterator<Item = MapPointId> + '_ {
+        self.map_points.iter().flatten().copied()
+    }
+
+    pub fn num_observations(&self) -> usize {
+        self.observed_points().count()
+    }
+}
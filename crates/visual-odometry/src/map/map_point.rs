@@ -0,0 +1,44 @@
+use std::collections::BTreeMap;
+
+use opencv::core::{Mat, Point3d};
+
+use super::KeyFrameId;
+use super::MapPointId;
+
+#[derive(Debug, Clone)]
+pub struct MapPoint {
+    pub id: MapPointId,
+    /// Position in world coordinates.
+    pub position: Point3d,
+    /// Single row descriptor, the observation with the smallest median
+    /// distance to all the others.
+    pub descriptor: Mat,
+    /// Mean unit vector from the observing cameras to the point.
+    pub viewing_direction: Point3d,
+    /// Keypoint index of this point in every keyframe that observes it.
+    pub observations: BTreeMap<KeyFrameId, usize>,
+    /// Keyframe the point was created from.
+    pub reference_keyframe: KeyFrameId,
+}
+
+impl MapPoint {
+    pub(crate) fn new(
+        id: MapPointId,
+        position: Point3d,
+        descriptor: Mat,
+        reference_keyframe: KeyFrameId,
+    ) -> Self {
+        Self {
+            id,
+            position,
+            descriptor,
+            viewing_direction: Point3d::new(0.0, 0.0, 0.0),
+            observations: BTreeMap::new(),
+            reference_keyframe,
+        }
+    }
+
+    pub fn num_observations(&self) -> usize {
+        self.observations.len()
+    }
+}
@@ -0,0 +1,612 @@
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::sync::{Arc, Mutex, MutexGuard};
+
+use opencv::core::{Mat, Point3d};
+use opencv::prelude::*;
+
+use crate::feature_extractor::DescriptorMetric;
+use crate::frame::Frame;
+use crate::pnp::LocalMapPoint;
+use crate::utils;
+
+mod keyframe;
+mod map_point;
+
+pub use keyframe::KeyFrame;
+pub use map_point::MapPoint;
+
+pub type KeyFrameId = usize;
+pub type MapPointId = usize;
+
+/// Map shared between the tracking and mapping stages.
+pub type SharedMap = Arc<Mutex<Map>>;
+
+/// Locks a shared map, turning a poisoned lock into an error.
+pub(crate) fn lock(map: &SharedMap) -> Result<MutexGuard<'_, Map>, MapError> {
+    map.lock().map_err(|_| MapError::Poisoned)
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum MapError {
+    #[error("Unknown keyframe {0}")]
+    UnknownKeyFrame(KeyFrameId),
+    #[error("Unknown map point {0}")]
+    UnknownMapPoint(MapPointId),
+    #[error("Keypoint {keypoint} out of range for keyframe {keyframe}")]
+    InvalidKeypoint {
+        keyframe: KeyFrameId,
+        keypoint: usize,
+    },
+    #[error("Map lock poisoned")]
+    Poisoned,
+    #[error(transparent)]
+    OpenCv(#[from] opencv::Error),
+}
+
+/// Keyframes, map points and the covisibility graph between keyframes.
+///
+/// Observations are kept consistent in both directions: a keyframe's
+/// `map_points` slot and the point's `observations` entry are always added and
+/// removed together, and every change updates the covisibility weights.
+#[derive(Debug, Default)]
+pub struct Map {
+    keyframes: BTreeMap<KeyFrameId, KeyFrame>,
+    map_points: BTreeMap<MapPointId, MapPoint>,
+    /// Number of map points each pair of keyframes both observe.
+    covisibility: HashMap<KeyFrameId, HashMap<KeyFrameId, usize>>,
+    /// Keyframe pairs joined by a loop closure, in both directions.
+    loop_edges: BTreeMap<KeyFrameId, BTreeSet<KeyFrameId>>,
+    next_keyframe_id: KeyFrameId,
+    next_map_point_id: MapPointId,
+    descriptor_metric: DescriptorMetric,
+}
+
+impl Map {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// A map whose keyframe descriptors are compared with `metric` rather
+    /// than Hamming distance.
+    pub fn with_descriptor_metric(metric: DescriptorMetric) -> Self {
+        Self {
+            descriptor_metric: metric,
+            ..Self::default()
+        }
+    }
+
+    pub fn descriptor_metric(&self) -> DescriptorMetric {
+        self.descriptor_metric
+    }
+
+    pub fn into_shared(self) -> SharedMap {
+        Arc::new(Mutex::new(self))
+    }
+
+    /// `pose` is a world to camera transform (4x4, CV_64F).
+    pub fn insert_keyframe(&mut self, frame: Frame, timestamp: f64, pose: Mat) -> KeyFrameId {
+        let id = self.next_keyframe_id;
+        self.next_keyframe_id += 1;
+        self.keyframes
+            .insert(id, KeyFrame::new(id, frame, timestamp, pose));
+        id
+    }
+
+    pub fn insert_map_point(
+        &mut self,
+        position: Point3d,
+        descriptor: Mat,
+        reference_keyframe: KeyFrameId,
+    ) -> Result<MapPointId, MapError> {
+        if !self.keyframes.contains_key(&reference_keyframe) {
+            return Err(MapError::UnknownKeyFrame(reference_keyframe));
+        }
+        let id = self.next_map_point_id;
+        self.next_map_point_id += 1;
+        self.map_points.insert(
+            id,
+            MapPoint::new(id, position, descriptor, reference_keyframe),
+        );
+        Ok(id)
+    }
+
+    /// Records that `keypoint` in `keyframe` observes `point`. A different
+    /// point already bound to that keypoint is unbound first.
+    pub fn add_observation(
+        &mut self,
+        keyframe: KeyFrameId,
+        point: MapPointId,
+        keypoint: usize,
+    ) -> Result<(), MapError> {
+        let kf = self
+            .keyframes
+            .get(&keyframe)
+            .ok_or(MapError::UnknownKeyFrame(keyframe))?;
+        if keypoint >= kf.map_points.len() {
+            return Err(MapError::InvalidKeypoint { keyframe, keypoint });
+        }
+        if !self.map_points.contains_key(&point) {
+            return Err(MapError::UnknownMapPoint(point));
+        }
+        if let Some(previous) = kf.map_points[keypoint].filter(|&p| p != point) {
+            self.remove_observation(keyframe, previous)?;
+        }
+
+        let observations = &self.map_points[&point].observations;
+        let existing = observations.get(&keyframe).copied();
+        let others: Vec<KeyFrameId> = observations.keys().copied().collect();
+        match existing {
+            // Already observed from this keyframe, only the keypoint moves.
+            Some(old) => {
+                if let Some(kf) = self.keyframes.get_mut(&keyframe) {
+                    kf.map_points[old] = None;
+                }
+            }
+            None => {
+                for other in others {
+                    *self
+                        .covisibility
+                        .entry(keyframe)
+                        .or_default()
+                        .entry(other)
+                        .or_default() += 1;
+                    *self
+                        .covisibility
+                        .entry(other)
+                        .or_default()
+                        .entry(keyframe)
+                        .or_default() += 1;
+                }
+            }
+        }
+
+        if let Some(mp) = self.map_points.get_mut(&point) {
+            mp.observations.insert(keyframe, keypoint);
+        }
+        if let Some(kf) = self.keyframes.get_mut(&keyframe) {
+            kf.map_points[keypoint] = Some(point);
+        }
+        Ok(())
+    }
+
+    /// Removes the observation of `point` from `keyframe`, if there is one.
+    pub fn remove_observation(
+        &mut self,
+        keyframe: KeyFrameId,
+        point: MapPointId,
+    ) -> Result<(), MapError> {
+        let mp = self
+            .map_points
+            .get_mut(&point)
+            .ok_or(MapError::UnknownMapPoint(point))?;
+        let Some(keypoint) = mp.observations.remove(&keyframe) else {
+            return Ok(());
+        };
+        let others: Vec<KeyFrameId> = mp.observations.keys().copied().collect();
+
+        if let Some(kf) = self.keyframes.get_mut(&keyframe) {
+            kf.map_points[keypoint] = None;
+        }
+        for other in others {
+            self.decrement_covisibility(keyframe, other);
+            self.decrement_covisibility(other, keyframe);
+        }
+        Ok(())
+    }
+
+    pub fn erase_map_point(&mut self, point: MapPointId) -> Option<MapPoint> {
+        let observers: Vec<KeyFrameId> = self
+            .map_points
+            .get(&point)?
+            .observations
+            .keys()
+            .copied()
+            .collect();
+        for keyframe in observers {
+            // Both ids are known to exist here.
+            let _ = self.remove_observation(keyframe, point);
+        }
+        self.map_points.remove(&point)
+    }
+
+    /// Removes a keyframe and all of its observations. Map points it created
+    /// stay in the map.
+    pub fn erase_keyframe(&mut self, keyframe: KeyFrameId) -> Option<KeyFrame> {
+        let observed: Vec<MapPointId> = self.keyframes.get(&keyframe)?.observed_points().collect();
+        for point in observed {
+            let _ = self.remove_observation(keyframe, point);
+        }
+        self.covisibility.remove(&keyframe);
+        for neighbours in self.covisibility.values_mut() {
+            neighbours.remove(&keyframe);
+        }
+        self.loop_edges.remove(&keyframe);
+        for partners in self.loop_edges.values_mut() {
+            partners.remove(&keyframe);
+        }
+        self.keyframes.remove(&keyframe)
+    }
+
+    pub fn clear(&mut self) {
+        self.keyframes.clear();
+        self.map_points.clear();
+        self.covisibility.clear();
+        self.loop_edges.clear();
+    }
+
+    pub fn keyframe(&self, id: KeyFrameId) -> Option<&KeyFrame> {
+        self.keyframes.get(&id)
+    }
+
+    pub fn keyframe_mut(&mut self, id: KeyFrameId) -> Option<&mut KeyFrame> {
+        self.keyframes.get_mut(&id)
+    }
+
+    pub fn map_point(&self, id: MapPointId) -> Option<&MapPoint> {
+        self.map_points.get(&id)
+    }
+
+    /// Observations must go through `add_observation` and
+    /// `remove_observation` to keep the covisibility graph in sync.
+    pub fn map_point_mut(&mut self, id: MapPointId) -> Option<&mut MapPoint> {
+        self.map_points.get_mut(&id)
+    }
+
+    pub fn keyframes(&self) -> impl Iterator<Item = &KeyFrame> {
+        self.keyframes.values()
+    }
+
+    pub fn map_points(&self) -> impl Iterator<Item = &MapPoint> {
+        self.map_points.values()
+    }
+
+    pub fn num_keyframes(&self) -> usize {
+        self.keyframes.len()
+    }
+
+    pub fn num_map_points(&self) -> usize {
+        self.map_points.len()
+    }
+
+    /// Number of map points both keyframes observe.
+    pub fn covisibility_weight(&self, a: KeyFrameId, b: KeyFrameId) -> usize {
+        self.covisibility
+            .get(&a)
+            .and_then(|neighbours| neighbours.get(&b))
+            .copied()
+            .unwrap_or(0)
+    }
+
+    /// Keyframes sharing at least `min_weight` map points with `keyframe`,
+    /// strongest first.
+    pub fn covisible_keyframes(
+        &self,
+        keyframe: KeyFrameId,
+        min_weight: usize,
+    ) -> Vec<(KeyFrameId, usize)> {
+        let mut neighbours: Vec<(KeyFrameId, usize)> = self
+            .covisibility
+            .get(&keyframe)
+            .into_iter()
+            .flatten()
+            .filter(|&(_, &weight)| weight >= min_weight)
+            .map(|(&id, &weight)| (id, weight))
+            .collect();
+        neighbours.sort_by(|a, b| b.1.cmp(&a.1).then(a.0.cmp(&b.0)));
+        neighbours
+    }
+
+    /// The `n` keyframes sharing the most map points with `keyframe`.
+    pub fn best_covisible_keyframes(&self, keyframe: KeyFrameId, n: usize) -> Vec<KeyFrameId> {
+        self.covisible_keyframes(keyframe, 1)
+            .into_iter()
+            .take(n)
+            .map(|(id, _)| id)
+            .collect()
+    }
+
+    /// Map points observed by `keyframe` and its `neighbours` best covisible
+    /// keyframes, ready for the PnP tracker.
+    pub fn local_map_points(&self, keyframe: KeyFrameId, neighbours: usize) -> Vec<LocalMapPoint> {
+        let mut keyframes = vec![keyframe];
+        keyframes.extend(self.best_covisible_keyframes(keyframe, neighbours));
+
+        let ids: BTreeSet<MapPointId> = keyframes
+            .iter()
+            .filter_map(|id| self.keyframes.get(id))
+            .flat_map(|kf| kf.observed_points())
+            .collect();
+        ids.into_iter()
+            .filter_map(|id| self.map_points.get(&id))
+            .map(|mp| LocalMapPoint {
+                id: mp.id,
+                position: mp.position,
+                descriptor: mp.descriptor.clone(),
+            })
+            .collect()
+    }
+
+    /// Moves every observation of `old` onto `new` and erases `old`, for
+    /// points found to be duplicates. Keyframes already observing `new` just
+    /// drop their observation of `old`.
+    pub fn replace_map_point(&mut self, old: MapPointId, new: MapPointId) -> Result<(), MapError> {
+        if old == new {
+            return Ok(());
+        }
+        let observations: Vec<(KeyFrameId, usize)> = self
+            .map_points
+            .get(&old)
+            .ok_or(MapError::UnknownMapPoint(old))?
+            .observations
+            .iter()
+            .map(|(&kf, &keypoint)| (kf, keypoint))
+            .collect();
+        let already_observing: BTreeSet<KeyFrameId> = self
+            .map_points
+            .get(&new)
+            .ok_or(MapError::UnknownMapPoint(new))?
+            .observations
+            .keys()
+            .copied()
+            .collect();
+
+        for (keyframe, keypoint) in observations {
+            if already_observing.contains(&keyframe) {
+                self.remove_observation(keyframe, old)?;
+            } else {
+                self.add_observation(keyframe, new, keypoint)?;
+            }
+        }
+        self.erase_map_point(old);
+        self.update_descriptor(new)?;
+        self.update_viewing_direction(new)
+    }
+
+    pub fn add_loop_edge(&mut self, a: KeyFrameId, b: KeyFrameId) -> Result<(), MapError> {
+        for id in [a, b] {
+            if !self.keyframes.contains_key(&id) {
+                return Err(MapError::UnknownKeyFrame(id));
+            }
+        }
+        self.loop_edges.entry(a).or_default().insert(b);
+        self.loop_edges.entry(b).or_default().insert(a);
+        Ok(())
+    }
+
+    /// Keyframes joined to `keyframe` by a loop closure.
+    pub fn loop_edges(&self, keyframe: KeyFrameId) -> impl Iterator<Item = KeyFrameId> + '_ {
+        self.loop_edges
+            .get(&keyframe)
+            .into_iter()
+            .flatten()
+            .copied()
+    }
+
+    /// Picks the observation descriptor with the smallest median distance to
+    /// all the other observations.
+    pub fn update_descriptor(&mut self, point: MapPointId) -> Result<(), MapError> {
+        let mp = self
+            .map_points
+            .get(&point)
+            .ok_or(MapError::UnknownMapPoint(point))?;
+
+        let mut descriptors = Vec::with_capacity(mp.observations.len());
+        for (keyframe, &keypoint) in &mp.observations {
+            if let Some(kf) = self.keyframes.get(keyframe) {
+                descriptors.push(kf.frame.descriptors.row(keypoint as i32)?.try_clone()?);
+            }
+        }
+        if descriptors.is_empty() {
+            return Ok(());
+        }
+
+        let mut best = (f32::MAX, 0);
+        for (i, a) in descriptors.iter().enumerate() {
+            let mut distances = Vec::with_capacity(descriptors.len());
+            for b in &descriptors {
+                distances.push(self.descriptor_metric.distance(a, 0, b, 0)?);
+            }
+            distances.sort_unstable_by(f32::total_cmp);
+            let median = distances[(distances.len() - 1) / 2];
+            if median < best.0 {
+                best = (median, i);
+            }
+        }
+
+        let descriptor = descriptors.swap_remove(best.1);
+        if let Some(mp) = self.map_points.get_mut(&point) {
+            mp.descriptor = descriptor;
+        }
+        Ok(())
+    }
+
+    /// Recomputes the mean viewing direction from the observing keyframes.
+    pub fn update_viewing_direction(&mut self, point: MapPointId) -> Result<(), MapError> {
+        let mp = self
+            .map_points
+            .get(&point)
+            .ok_or(MapError::UnknownMapPoint(point))?;
+
+        let mut sum = [0.0; 3];
+        for keyframe in mp.observations.keys() {
+            let Some(kf) = self.keyframes.get(keyframe) else {
+                continue;
+            };
+            let centre = kf.camera_center()?;
+            let ray = [
+                mp.position.x - centre.x,
+                mp.position.y - centre.y,
+                mp.position.z - centre.z,
+            ];
+            let norm = utils::norm3(&ray);
+            if norm > 0.0 {
+                sum = utils::add3(&sum, &utils::scale3(&ray, 1.0 / norm));
+            }
+        }
+
+        let norm = utils::norm3(&sum);
+        if let Some(mp) = self.map_points.get_mut(&point).filter(|_| norm > 0.0) {
+            let direction = utils::scale3(&sum, 1.0 / norm);
+            mp.viewing_direction = Point3d::new(direction[0], direction[1], direction[2]);
+        }
+        Ok(())
+    }
+
+    /// Erases map points seen by fewer than `min_observations` keyframes,
+    /// sparing points created from `created_before` or later so fresh points
+    /// get a chance to be observed again. Returns the number erased.
+    pub fn cull_map_points(
+        &mut self,
+        min_observations: usize,
+        created_before: KeyFrameId,
+    ) -> usize {
+        let doomed: Vec<MapPointId> = self
+            .map_points
+            .values()
+            .filter(|mp| {
+                mp.reference_keyframe < created_before && mp.num_observations() < min_observations
+            })
+            .map(|mp| mp.id)
+            .collect();
+        for &point in &doomed {
+            self.erase_map_point(point);
+        }
+        doomed.len()
+    }
+
+    fn decrement_covisibility(&mut self, from: KeyFrameId, to: KeyFrameId) {
+        if let Some(neighbours) = self.covisibility.get_mut(&from) {
+            if let Some(weight) = neighbours.get_mut(&to) {
+                *weight -= 1;
+                if *weight == 0 {
+                    neighbours.remove(&to);
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use opencv::core::{CV_8UC1, CV_64F, KeyPoint, Scalar, Vector};
+
+    fn make_frame(id: usize, n: i32) -> Frame {
+        let keypoints = Vector::<KeyPoint>::from_iter(
+            (0..n).map(|i| KeyPoint::new_coords_def(i as f32, i as f32, 31.0).unwrap()),
+        );
+        let descriptors =
+            Mat::new_rows_cols_with_default(n, 32, CV_8UC1, Scalar::all(0.0)).unwrap();
+        Frame::new(id, Mat::default(), keypoints, descriptors)
+    }
+
+    fn identity() -> Mat {
+        Mat::eye(4, 4, CV_64F).unwrap().to_mat().unwrap()
+    }
+
+    fn make_point(map: &mut Map, reference: KeyFrameId) -> MapPointId {
+        let descriptor = Mat::new_rows_cols_with_default(1, 32, CV_8UC1, Scalar::all(0.0)).unwrap();
+        map.insert_map_point(Point3d::new(0.0, 0.0, 1.0), descriptor, reference)
+            .unwrap()
+    }
+
+    #[test]
+    fn test_covisibility_tracks_shared_observations() {
+        let mut map = Map::new();
+        let kf0 = map.insert_keyframe(make_frame(0, 4), 0.0, identity());
+        let kf1 = map.insert_keyframe(make_frame(1, 4), 1.0, identity());
+        let kf2 = map.insert_keyframe(make_frame(2, 4), 2.0, identity());
+
+        for i in 0..3 {
+            let p = make_point(&mut map, kf0);
+            map.add_observation(kf0, p, i).unwrap();
+            map.add_observation(kf1, p, i).unwrap();
+            if i == 0 {
+                map.add_observation(kf2, p, 3).unwrap();
+            }
+        }
+
+        assert_eq!(map.covisibility_weight(kf0, kf1), 3);
+        assert_eq!(map.covisibility_weight(kf1, kf0), 3);
+        assert_eq!(map.covisibility_weight(kf0, kf2), 1);
+        assert_eq!(map.best_covisible_keyframes(kf0, 2), vec![kf1, kf2]);
+        assert_eq!(map.local_map_points(kf2, 0).len(), 1);
+        assert_eq!(map.local_map_points(kf2, 1).len(), 3);
+
+        // Re-adding the same observation must not double count.
+        map.add_observation(kf1, 0, 0).unwrap();
+        assert_eq!(map.covisibility_weight(kf0, kf1), 3);
+
+        map.remove_observation(kf1, 0).unwrap();
+        assert_eq!(map.covisibility_weight(kf0, kf1), 2);
+        assert_eq!(map.keyframe(kf1).unwrap().map_points[0], None);
+
+        map.erase_keyframe(kf2).unwrap();
+        assert_eq!(map.covisibility_weight(kf0, kf2), 0);
+        assert_eq!(map.map_point(0).unwrap().num_observations(), 1);
+    }
+
+    #[test]
+    fn test_rebinding_keypoint_unbinds_previous_point() {
+        let mut map = Map::new();
+        let kf0 = map.insert_keyframe(make_frame(0, 2), 0.0, identity());
+        let kf1 = map.insert_keyframe(make_frame(1, 2), 1.0, identity());
+        let a = make_point(&mut map, kf0);
+        let b = make_point(&mut map, kf0);
+
+        map.add_observation(kf0, a, 0).unwrap();
+        map.add_observation(kf1, a, 0).unwrap();
+        map.add_observation(kf1, b, 0).unwrap();
+
+        assert_eq!(map.keyframe(kf1).unwrap().map_points[0], Some(b));
+        assert!(!map.map_point(a).unwrap().observations.contains_key(&kf1));
+        assert_eq!(map.covisibility_weight(kf0, kf1), 0);
+        assert!(matches!(
+            map.add_observation(kf1, a, 5),
+            Err(MapError::InvalidKeypoint { .. })
+        ));
+    }
+
+    #[test]
+    fn test_cull_spares_recent_points() {
+        let mut map = Map::new();
+        let kf0 = map.insert_keyframe(make_frame(0, 2), 0.0, identity());
+        let kf1 = map.insert_keyframe(make_frame(1, 2), 1.0, identity());
+        let old = make_point(&mut map, kf0);
+        let fresh = make_point(&mut map, kf1);
+        map.add_observation(kf0, old, 0).unwrap();
+        map.add_observation(kf1, fresh, 1).unwrap();
+
+        assert_eq!(map.cull_map_points(2, kf1), 1);
+        assert!(map.map_point(old).is_none());
+        assert!(map.map_point(fresh).is_some());
+        assert_eq!(map.keyframe(kf0).unwrap().map_points[0], None);
+    }
+
+    #[test]
+    fn test_replace_map_point_merges_observations() {
+        let mut map = Map::new();
+        let kf0 = map.insert_keyframe(make_frame(0, 2), 0.0, identity());
+        let kf1 = map.insert_keyframe(make_frame(1, 2), 1.0, identity());
+        let kf2 = map.insert_keyframe(make_frame(2, 2), 2.0, identity());
+        let old = make_point(&mut map, kf0);
+        let new = make_point(&mut map, kf2);
+        map.add_observation(kf0, old, 0).unwrap();
+        map.add_observation(kf2, old, 1).unwrap();
+        map.add_observation(kf1, new, 1).unwrap();
+        map.add_observation(kf2, new, 0).unwrap();
+
+        map.replace_map_point(old, new).unwrap();
+        assert!(map.map_point(old).is_none());
+        assert_eq!(map.map_point(new).unwrap().num_observations(), 3);
+        assert_eq!(map.keyframe(kf0).unwrap().map_points[0], Some(new));
+        // kf2 already saw `new`, so its duplicate keypoint is just unbound.
+        assert_eq!(map.keyframe(kf2).unwrap().map_points[1], None);
+        assert_eq!(map.covisibility_weight(kf0, kf1), 1);
+
+        map.add_loop_edge(kf0, kf2).unwrap();
+        assert_eq!(map.loop_edges(kf2).collect::<Vec<_>>(), vec![kf0]);
+        map.erase_keyframe(kf2).unwrap();
+        assert_eq!(map.loop_edges(kf0).count(), 0);
+    }
+}
@@ -0,0 +1,129 @@
+use opencv::core::{CV_8U, CV_8UC1, Mat, Point2d, Rect, Scalar, Size};
+use opencv::imgproc::{FILLED, LINE_8, rectangle};
+use opencv::prelude::*;
+use r_slam_common::camera::Camera;
+
+use crate::OdometryError;
+use crate::pnp::LocalMapPoint;
+use crate::utils::{self, add3, mul3v};
+
+/// Regions of a frame features must not come from, typically people, cars
+/// and other objects that move independently of the camera.
+#[derive(Debug, Clone, Default)]
+pub enum FrameMask {
+    #[default]
+    None,
+    /// Single channel 8-bit image the size of the frame, zero where features
+    /// are excluded, as OpenCV detection masks and
+    /// `depth_estimate::yolo::exclusion_mask` are.
+    Image(Mat),
+    /// Excluded rectangles, in pixels. Parts outside the frame are ignored.
+    Boxes(Vec<Rect>),
+}
+
+impl FrameMask {
+    /// OpenCV detection mask for a frame of `size`: non-zero where features
+    /// may be detected. Empty when nothing is masked.
+    pub fn to_detection_mask(&self, size: Size) -> Result<Mat, OdometryError> {
+        match self {
+            FrameMask::None => Ok(Mat::default()),
+            FrameMask::Image(mask) => {
+                check_mask(mask, size)?;
+                Ok(mask.try_clone()?)
+            }
+            FrameMask::Boxes(boxes) => {
+                let mut mask = Mat::new_size_with_default(size, CV_8U, Scalar::all(255.0))?;
+                for &rect in boxes {
+                    rectangle(&mut mask, rect, Scalar::all(0.0), FILLED, LINE_8, 0)?;
+                }
+                Ok(mask)
+            }
+        }
+    }
+}
+
+fn check_mask(mask: &Mat, size: Size) -> Result<(), OdometryError> {
+    if mask.size()? != size || mask.typ() != CV_8UC1 {
+        return Err(OdometryError::InvalidMask(format!(
+            "expected a {}x{} CV_8UC1 mask, got {}x{} of type {}",
+            size.width,
+            size.height,
+            mask.cols(),
+            mask.rows(),
+            mask.typ()
+        )));
+    }
+    Ok(())
+}
+
+/// Whether `pt` falls on an excluded pixel of a detection mask. Nothing is
+/// masked by an empty mask or outside the image.
+pub(crate) fn is_masked(mask: &Mat, pt: Point2d) -> Result<bool, opencv::Error> {
+    if mask.empty() {
+        return Ok(false);
+    }
+    let (x, y) = (pt.x.floor() as i32, pt.y.floor() as i32);
+    if x < 0 || y < 0 || x >= mask.cols() || y >= mask.rows() {
+        return Ok(false);
+    }
+    Ok(*mask.at_2d::<u8>(y, x)? == 0)
+}
+
+/// Drops the map points that project into masked regions of a frame seen
+/// from `pose`, a world to camera transform (4x4, CV_64F). Whatever is
+/// behind a moving object is either occluded by it or, if the point was
+/// on the object, no longer where the map has it.
+pub(crate) fn unmasked_points(
+    camera: &Camera,
+    pose: &Mat,
+    points: &[LocalMapPoint],
+    mask: &Mat,
+) -> Result<Vec<LocalMapPoint>, OdometryError> {
+    if mask.empty() {
+        return Ok(points.to_vec());
+    }
+    let (rotation, translation) = utils::split_pose(pose)?;
+    let mut kept = Vec::with_capacity(points.len());
+    for point in points {
+        let position = [point.position.x, point.position.y, point.position.z];
+        let in_camera = add3(&mul3v(&rotation, &position), &translation);
+        if in_camera[2] > 0.0 {
+            let projected = Point2d::new(
+                camera.fx * in_camera[0] / in_camera[2] + camera.cx,
+                camera.fy * in_camera[1] / in_camera[2] + camera.cy,
+            );
+            if is_masked(mask, projected)? {
+                continue;
+            }
+        }
+        kept.push(point.clone());
+    }
+    Ok(kept)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_boxes_exclude_their_pixels() {
+        let mask = FrameMask::Boxes(vec![Rect::new(10, 20, 30, 40), Rect::new(90, 90, 50, 50)])
+            .to_detection_mask(Size::new(100, 100))
+            .unwrap();
+        assert!(is_masked(&mask, Point2d::new(10.0, 20.0)).unwrap());
+        assert!(is_masked(&mask, Point2d::new(39.5, 59.5)).unwrap());
+        assert!(!is_masked(&mask, Point2d::new(40.0, 20.0)).unwrap());
+        assert!(is_masked(&mask, Point2d::new(99.0, 99.0)).unwrap());
+        assert!(!is_masked(&mask, Point2d::new(120.0, 120.0)).unwrap());
+        assert!(!is_masked(&Mat::default(), Point2d::new(15.0, 25.0)).unwrap());
+    }
+
+    #[test]
+    fn test_mask_image_must_match_frame() {
+        let mask = Mat::new_size_with_default(Size::new(50, 50), CV_8U, Scalar::all(0.0)).unwrap();
+        assert!(matches!(
+            FrameMask::Image(mask).to_detection_mask(Size::new(100, 100)),
+            Err(OdometryError::InvalidMask(_))
+        ));
+    }
+}
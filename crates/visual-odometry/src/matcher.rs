@@ -0,0 +1,465 @@
+use std::collections::{HashMap, HashSet};
+
+use opencv::core::{DMatch, Point2d, Point2f, Ptr, Vector, no_array};
+use opencv::features2d::{BFMatcher, FlannBasedMatcher};
+use opencv::flann::{IndexParams, KDTreeIndexParams, LshIndexParams, SearchParams};
+use opencv::prelude::*;
+
+use crate::OdometryError;
+use crate::feature_extractor::DescriptorMetric;
+use crate::frame::Frame;
+use crate::grid::FeatureGrid;
+
+/// How `frame_match` pairs descriptors across the whole image.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum MatchStrategy {
+    /// Brute force two nearest neighbours with a ratio test.
+    #[default]
+    Knn,
+    /// Brute force, keeping pairs that are each other's nearest neighbour.
+    /// No ratio test.
+    CrossCheck,
+    /// Approximate two nearest neighbours with a ratio test, LSH for binary
+    /// descriptors and randomized kd-trees for float ones. Faster than brute
+    /// force once frames have several thousand features.
+    Flann,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct MatcherConfig {
+    pub strategy: MatchStrategy,
+    /// Best match must be closer than `ratio_threshold` times the second best.
+    pub ratio_threshold: f32,
+    /// In the units of the extractor's `DescriptorMetric`.
+    pub distance_threshold: f32,
+    /// Drop matches whose keypoint rotation disagrees with the dominant
+    /// rotation between the frames.
+    pub check_orientation: bool,
+    /// Bins of the rotation histogram used by `check_orientation`.
+    pub orientation_bins: usize,
+    /// Radius around each predicted location searched by guided matching, in
+    /// pixels.
+    pub search_radius: f64,
+    pub lsh_table_number: i32,
+    pub lsh_key_size: i32,
+    pub lsh_multi_probe_level: i32,
+}
+
+impl MatcherConfig {
+    /// Defaults with the distance threshold suited to `metric`.
+    pub fn for_metric(metric: DescriptorMetric) -> Self {
+        Self {
+            distance_threshold: metric.default_max_distance(),
+            ..Self::default()
+        }
+    }
+}
+
+impl Default for MatcherConfig {
+    /// Thresholds for ORB's Hamming distance.
+    fn default() -> Self {
+        Self {
+            strategy: MatchStrategy::Knn,
+            ratio_threshold: 0.7,
+            distance_threshold: DescriptorMetric::Hamming.default_max_distance(),
+            check_orientation: false,
+            orientation_bins: 30,
+            search_radius: 15.0,
+            lsh_table_number: 12,
+            lsh_key_size: 20,
+            lsh_multi_probe_level: 2,
+        }
+    }
+}
+
+/// Matches keypoints between two frames by descriptor.
+///
+/// Matches always have `query_idx` indexing the first frame and `train_idx`
+/// the second, with every keypoint of either frame used at most once.
+pub struct FeatureMatcher {
+    config: MatcherConfig,
+    metric: DescriptorMetric,
+}
+
+impl FeatureMatcher {
+    pub fn new(config: MatcherConfig, metric: DescriptorMetric) -> Self {
+        Self { config, metric }
+    }
+
+    pub fn config(&self) -> &MatcherConfig {
+        &self.config
+    }
+
+    pub fn metric(&self) -> DescriptorMetric {
+        self.metric
+    }
+
+    /// Matches over the whole image with the configured strategy.
+    pub fn match_frames(
+        &self,
+        frame1: &Frame,
+        frame2: &Frame,
+    ) -> Result<Vector<DMatch>, OdometryError> {
+        if frame1.descriptors.empty() || frame2.descriptors.empty() {
+            return Ok(Vector::new());
+        }
+
+        let mut matches: Vec<DMatch> = match self.config.strategy {
+            MatchStrategy::Knn => {
+                let matcher = BFMatcher::create(self.metric.norm_type(), false)?;
+                self.ratio_test(&matcher, frame1, frame2)?
+            }
+            MatchStrategy::Flann => {
+                let matcher = self.flann()?;
+                self.ratio_test(&matcher, frame1, frame2)?
+            }
+            MatchStrategy::CrossCheck => {
+                let matcher = BFMatcher::create(self.metric.norm_type(), true)?;
+                let mut matches = Vector::new();
+                matcher.train_match(
+                    &frame1.descriptors,
+                    &frame2.descriptors,
+                    &mut matches,
+                    &no_array(),
+                )?;
+                matches
+                    .into_iter()
+                    .filter(|m| m.distance < self.config.distance_threshold)
+                    .collect()
+            }
+        };
+        unique_train(&mut matches);
+        self.filter_orientation(frame1, frame2, matches)
+    }
+
+    /// Matches each keypoint of `frame1` only against keypoints of `frame2`
+    /// within `search_radius` of its predicted location, e.g. its map point
+    /// projected with a predicted pose. `predicted` has one entry per
+    /// `frame1` keypoint; those without a prediction are not matched.
+    pub fn match_guided(
+        &self,
+        frame1: &Frame,
+        frame2: &Frame,
+        predicted: &[Option<Point2f>],
+    ) -> Result<Vector<DMatch>, OdometryError> {
+        let positions: Vec<Point2d> = frame2
+            .keypoints
+            .iter()
+            .map(|k| Point2d::new(k.pt().x as f64, k.pt().y as f64))
+            .collect();
+        let grid = FeatureGrid::new(&positions, self.config.search_radius);
+
+        let mut matches = Vec::new();
+        for (i, prediction) in predicted.iter().enumerate() {
+            let Some(prediction) = prediction else {
+                continue;
+            };
+            let center = Point2d::new(prediction.x as f64, prediction.y as f64);
+            let mut best: Option<(usize, f32)> = None;
+            let mut second = f32::MAX;
+            for j in grid.within(center, self.config.search_radius) {
+                let distance = self.metric.distance(
+                    &frame1.descriptors,
+                    i as i32,
+                    &frame2.descriptors,
+                    j as i32,
+                )?;
+                match best {
+                    Some((_, d)) if distance >= d => second = second.min(distance),
+                    _ => {
+                        second = best.map_or(second, |(_, d)| d);
+                        best = Some((j, distance));
+                    }
+                }
+            }
+            let Some((j, distance)) = best else {
+                continue;
+            };
+            if distance < self.config.distance_threshold
+                && distance < self.config.ratio_threshold * second
+            {
+                matches.push(DMatch::new(i as i32, j as i32, distance)?);
+            }
+        }
+        unique_train(&mut matches);
+        self.filter_orientation(frame1, frame2, matches)
+    }
+
+    fn ratio_test(
+        &self,
+        matcher: &impl DescriptorMatcherTraitConst,
+        frame1: &Frame,
+        frame2: &Frame,
+    ) -> Result<Vec<DMatch>, OdometryError> {
+        let mut knn = Vector::<Vector<DMatch>>::new();
+        // The train variant matches against `frame2` without adding it to the
+        // matcher's own collection.
+        matcher.knn_train_match(
+            &frame1.descriptors,
+            &frame2.descriptors,
+            &mut knn,
+            2,
+            &no_array(),
+            false,
+        )?;
+
+        let mut matches = Vec::with_capacity(knn.len());
+        for candidates in knn.iter() {
+            if candidates.len() < 2 {
+                continue;
+            }
+            let nearest = candidates.get(0)?;
+            let second = candidates.get(1)?;
+            if nearest.distance < self.config.ratio_threshold * second.distance
+                && nearest.distance < self.config.distance_threshold
+            {
+                matches.push(nearest);
+            }
+        }
+        Ok(matches)
+    }
+
+    fn flann(&self) -> Result<FlannBasedMatcher, OdometryError> {
+        let index: Ptr<IndexParams> = match self.metric {
+            DescriptorMetric::Hamming | DescriptorMetric::Hamming2 => {
+                Ptr::new(LshIndexParams::new(
+                    self.config.lsh_table_number,
+                    self.config.lsh_key_size,
+                    self.config.lsh_multi_probe_level,
+                )?)
+                .into()
+            }
+            DescriptorMetric::L2 => Ptr::new(KDTreeIndexParams::new(4)?).into(),
+        };
+        let search = Ptr::new(SearchParams::new(32, 0.0, true, false)?);
+        Ok(FlannBasedMatcher::new(&index, &search)?)
+    }
+
+    fn filter_orientation(
+        &self,
+        frame1: &Frame,
+        frame2: &Frame,
+        matches: Vec<DMatch>,
+    ) -> Result<Vector<DMatch>, OdometryError> {
+        if !self.config.check_orientation {
+            return Ok(Vector::from_iter(matches));
+        }
+        let mut rotations = Vec::with_capacity(matches.len());
+        for m in &matches {
+            let angle1 = frame1.keypoints.get(m.query_idx as usize)?.angle();
+            let angle2 = frame2.keypoints.get(m.train_idx as usize)?.angle();
+            // Detectors without orientation report -1.
+            rotations.push((angle1 >= 0.0 && angle2 >= 0.0).then_some(angle1 - angle2));
+        }
+        let keep = consistent_rotations(&rotations, self.config.orientation_bins);
+        Ok(matches
+            .into_iter()
+            .zip(keep)
+            .filter_map(|(m, keep)| keep.then_some(m))
+            .collect())
+    }
+}
+
+/// Keeps the closest match for each train keypoint.
+fn unique_train(matches: &mut Vec<DMatch>) {
+    let mut best: HashMap<i32, f32> = HashMap::new();
+    for m in matches.iter() {
+        let entry = best.entry(m.train_idx).or_insert(f32::MAX);
+        *entry = entry.min(m.distance);
+    }
+    let mut taken = HashSet::new();
+    matches.retain(|m| best[&m.train_idx] == m.distance && taken.insert(m.train_idx));
+}
+
+/// ORB-SLAM's rotation consistency check: histograms the keypoint rotation
+/// of every match, in degrees, and keeps those in the three fullest bins,
+/// ignoring bins with less than a tenth of the fullest one. Matches without
+/// a rotation are kept.
+fn consistent_rotations(rotations: &[Option<f32>], bins: usize) -> Vec<bool> {
+    let bins = bins.max(1);
+    let bin_of = |rotation: f32| {
+        let bin = (rotation.rem_euclid(360.0) / 360.0 * bins as f32) as usize;
+        bin.min(bins - 1)
+    };
+
+    let mut counts = vec![0usize; bins];
+    for rotation in rotations.iter().flatten() {
+        counts[bin_of(*rotation)] += 1;
+    }
+    let mut order: Vec<usize> = (0..bins).collect();
+    order.sort_by(|&a, &b| counts[b].cmp(&counts[a]));
+    let max = counts[order[0]];
+    let kept: Vec<usize> = order
+        .into_iter()
+        .take(3)
+        .filter(|&b| counts[b] > 0 && counts[b] * 10 >= max)
+        .collect();
+
+    rotations
+        .iter()
+        .map(|rotation| rotation.is_none_or(|r| kept.contains(&bin_of(r))))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::utils::SplitMix64;
+    use opencv::core::{KeyPoint, Mat};
+
+    /// Frame with a keypoint at each of `points`, descriptor rows in order.
+    fn frame<T: opencv::core::DataType>(
+        points: &[(f32, f32)],
+        descriptors: &[impl AsRef<[T]>],
+    ) -> Frame {
+        let keypoints = Vector::from_iter(
+            points
+                .iter()
+                .map(|&(x, y)| KeyPoint::new_coords_def(x, y, 31.0).unwrap()),
+        );
+        Frame::new(
+            0,
+            Mat::default(),
+            keypoints,
+            Mat::from_slice_2d(descriptors).unwrap(),
+        )
+    }
+
+    /// `descriptor` with `bits` flipped.
+    fn flip(mut descriptor: [u8; 32], bits: std::ops::Range<usize>) -> [u8; 32] {
+        for bit in bits {
+            descriptor[bit / 8] ^= 1 << (bit % 8);
+        }
+        descriptor
+    }
+
+    fn pairs(matches: &Vector<DMatch>) -> Vec<(i32, i32)> {
+        let mut pairs: Vec<(i32, i32)> =
+            matches.iter().map(|m| (m.query_idx, m.train_idx)).collect();
+        pairs.sort();
+        pairs
+    }
+
+    #[test]
+    fn test_guided_matching_searches_around_prediction() {
+        let mut rng = SplitMix64(11);
+        let mut random = || [0u8; 32].map(|_| rng.next() as u8);
+        let (a, b, c, d) = (random(), random(), random(), random());
+
+        let frame1 = frame(&[(0.0, 0.0); 5], &[a, b, c, flip(c, 100..108), d]);
+        let frame2 = frame(
+            &[
+                (105.0, 100.0),
+                (140.0, 100.0),
+                (300.0, 305.0),
+                (305.0, 300.0),
+                (200.0, 202.0),
+                (210.0, 200.0),
+            ],
+            &[
+                flip(a, 0..5),
+                a,
+                flip(b, 0..10),
+                flip(b, 20..32),
+                flip(c, 0..4),
+                random(),
+            ],
+        );
+        let predicted = [
+            // The exact copy of `a` is 40 pixels away, outside the window.
+            Some(Point2f::new(100.0, 100.0)),
+            // 10 bits from the best candidate but 12 from the second.
+            Some(Point2f::new(300.0, 300.0)),
+            // Both want keypoint 4, the closer one keeps it.
+            Some(Point2f::new(200.0, 200.0)),
+            Some(Point2f::new(201.0, 200.0)),
+            None,
+        ];
+
+        let matcher = FeatureMatcher::new(MatcherConfig::default(), DescriptorMetric::Hamming);
+        let matches = matcher.match_guided(&frame1, &frame2, &predicted).unwrap();
+        assert_eq!(pairs(&matches), [(0, 0), (2, 4)]);
+        assert_eq!(matches.get(0).unwrap().distance, 5.0);
+    }
+
+    #[test]
+    fn test_strategies_match_the_same_pairs() {
+        let axis = |i: usize, length: f32| {
+            let mut v = [0.0f32; 8];
+            v[i] = length;
+            v
+        };
+        let plus = |mut v: [f32; 8], i: usize, length: f32| {
+            v[i] += length;
+            v
+        };
+        let frame1 = frame(
+            &[(0.0, 0.0); 5],
+            &[
+                axis(0, 10.0),
+                axis(1, 10.0),
+                axis(2, 10.0),
+                axis(3, 10.0),
+                plus(axis(3, 10.0), 6, 1.0),
+            ],
+        );
+        let frame2 = frame(
+            &[(0.0, 0.0); 6],
+            &[
+                plus(axis(1, 10.0), 7, 0.2),
+                plus(axis(0, 10.0), 7, 0.1),
+                // Two candidates for query 2, too close to tell apart.
+                plus(axis(2, 10.0), 5, 0.5),
+                plus(axis(2, 10.0), 6, 0.6),
+                // Nearest to both queries 3 and 4, but closer to 3.
+                axis(3, 10.0),
+                axis(4, 10.0),
+            ],
+        );
+
+        for (strategy, expected) in [
+            (MatchStrategy::Knn, vec![(0, 1), (1, 0), (3, 4)]),
+            (MatchStrategy::Flann, vec![(0, 1), (1, 0), (3, 4)]),
+            // No ratio test, but keypoint 4 of the second frame is nearer to
+            // query 3 than to query 4.
+            (
+                MatchStrategy::CrossCheck,
+                vec![(0, 1), (1, 0), (2, 2), (3, 4)],
+            ),
+        ] {
+            let config = MatcherConfig {
+                strategy,
+                ..MatcherConfig::for_metric(DescriptorMetric::L2)
+            };
+            let matcher = FeatureMatcher::new(config, DescriptorMetric::L2);
+            let matches = matcher.match_frames(&frame1, &frame2).unwrap();
+            assert_eq!(pairs(&matches), expected, "{strategy:?}");
+        }
+    }
+
+    #[test]
+    fn test_rotation_histogram_keeps_dominant_bins() {
+        let mut rotations: Vec<Option<f32>> = vec![Some(10.0); 18];
+        rotations.extend([Some(-350.0), Some(365.0)]);
+        rotations.extend([Some(100.0), Some(200.0), Some(300.0), Some(195.0)]);
+        rotations.push(None);
+
+        let keep = consistent_rotations(&rotations, 30);
+        // -350 and 365 both wrap into the dominant bin.
+        assert!(keep[..20].iter().all(|&k| k));
+        // Of the rest only the bin holding both 195 and 200 is large enough.
+        assert_eq!(keep[20..], [false, true, false, true, true]);
+    }
+
+    #[test]
+    fn test_unique_train_keeps_closest() {
+        let mut matches = vec![
+            DMatch::new(0, 5, 20.0).unwrap(),
+            DMatch::new(1, 5, 10.0).unwrap(),
+            DMatch::new(2, 6, 30.0).unwrap(),
+        ];
+        unique_train(&mut matches);
+        let pairs: Vec<(i32, i32)> = matches.iter().map(|m| (m.query_idx, m.train_idx)).collect();
+        assert_eq!(pairs, vec![(1, 5), (2, 6)]);
+    }
+}
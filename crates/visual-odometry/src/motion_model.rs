@@ -0,0 +1,181 @@
+use nalgebra::Isometry3;
+
+use crate::lie::{SE3, Vector6};
+
+#[derive(Debug, Clone, Copy)]
+pub struct MotionModelConfig {
+    /// Guided matching radius around map points projected with the predicted
+    /// pose, in pixels.
+    pub search_radius: f64,
+    /// Radius of the search retried from the last pose when matching with the
+    /// prediction fails, in pixels.
+    pub fallback_search_radius: f64,
+    /// Largest gap, in seconds, a velocity is measured or extrapolated over.
+    /// After a longer gap there is no prediction until the next frame.
+    pub max_time_gap: f64,
+}
+
+impl Default for MotionModelConfig {
+    fn default() -> Self {
+        Self {
+            search_radius: 7.5,
+            fallback_search_radius: 30.0,
+            max_time_gap: 1.0,
+        }
+    }
+}
+
+/// Predicts the next camera pose assuming the camera keeps the velocity
+/// measured between the last two tracked frames.
+///
+/// Velocities are twists per second, so frames arriving at an uneven rate
+/// are extrapolated by their actual time gap. When timestamps do not advance
+/// the last frame-to-frame motion is repeated instead.
+#[derive(Debug, Clone)]
+pub struct MotionModel {
+    config: MotionModelConfig,
+    /// World to camera transform of the last tracked frame, and its timestamp.
+    last: Option<(SE3, f64)>,
+    velocity: Option<Velocity>,
+}
+
+#[derive(Debug, Clone, Copy)]
+enum Velocity {
+    /// Left-multiplied twist, per second. Kept as a twist rather than a
+    /// transform so rates above pi rad/s do not wrap.
+    PerSecond(Vector6),
+    /// Motion between the last two frames, for timestamps that did not advance.
+    PerFrame(SE3),
+}
+
+impl MotionModel {
+    pub fn new(config: MotionModelConfig) -> Self {
+        Self {
+            config,
+            last: None,
+            velocity: None,
+        }
+    }
+
+    pub fn config(&self) -> &MotionModelConfig {
+        &self.config
+    }
+
+    /// Records the pose a frame was tracked at.
+    pub fn update(&mut self, pose: &Isometry3<f64>, timestamp: f64) {
+        let pose = SE3::from(*pose);
+        self.velocity = self.last.and_then(|(last, last_timestamp)| {
+            let motion = pose * last.inverse();
+            let dt = timestamp - last_timestamp;
+            if dt > self.config.max_time_gap {
+                None
+            } else if dt > 0.0 {
+                Some(Velocity::PerSecond(motion.log() / dt))
+            } else {
+                Some(Velocity::PerFrame(motion))
+            }
+        });
+        self.last = Some((pose, timestamp));
+    }
+
+    /// Pose expected at `timestamp`, or `None` until two frames in a row were
+    /// tracked.
+    pub fn predict(&self, timestamp: f64) -> Option<Isometry3<f64>> {
+        let (last, last_timestamp) = self.last?;
+        let motion = match self.velocity? {
+            Velocity::PerFrame(motion) => motion,
+            Velocity::PerSecond(twist) => {
+                let dt = timestamp - last_timestamp;
+                if dt <= 0.0 {
+                    SE3::identity()
+                } else {
+                    SE3::exp(&(twist * dt.min(self.config.max_time_gap)))
+                }
+            }
+        };
+        Some((motion * last).into())
+    }
+
+    /// Forgets the velocity, e.g. after tracking was lost.
+    pub fn reset(&mut self) {
+        self.last = None;
+        self.velocity = None;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use approx::assert_relative_eq;
+    use nalgebra::{Translation3, UnitQuaternion, Vector3};
+
+    fn pose_at(t: f64) -> Isometry3<f64> {
+        // Constant twist: 0.2 rad/s yaw and 1 m/s forward.
+        pose_with_twist(
+            &Vector6::from_column_slice(&[0.0, 0.0, 1.0, 0.0, 0.2, 0.0]),
+            t,
+        )
+    }
+
+    fn pose_with_twist(twist: &Vector6, t: f64) -> Isometry3<f64> {
+        let start = Isometry3::from_parts(
+            Translation3::new(0.5, 0.0, 0.0),
+            UnitQuaternion::from_scaled_axis(Vector3::new(0.0, 0.0, 0.1)),
+        );
+        (SE3::exp(&(twist * t)) * SE3::from(start)).into()
+    }
+
+    #[test]
+    fn test_prediction_uses_time_gap() {
+        let mut model = MotionModel::new(MotionModelConfig::default());
+        assert!(model.predict(0.0).is_none());
+        model.update(&pose_at(0.0), 0.0);
+        assert!(model.predict(0.1).is_none());
+        model.update(&pose_at(0.1), 0.1);
+
+        // A dropped frame doubles the gap, not the error.
+        let predicted = model.predict(0.3).unwrap();
+        let expected = pose_at(0.3);
+        assert_relative_eq!(
+            predicted.to_homogeneous(),
+            expected.to_homogeneous(),
+            epsilon = 1e-9
+        );
+    }
+
+    #[test]
+    fn test_fast_rotation_does_not_wrap() {
+        // 5 rad/s yaw, 0.5 rad per frame at 10 Hz: well past pi rad/s.
+        let twist = Vector6::from_column_slice(&[0.0, 0.0, 0.3, 0.0, 5.0, 0.0]);
+        let mut model = MotionModel::new(MotionModelConfig::default());
+        model.update(&pose_with_twist(&twist, 0.0), 0.0);
+        model.update(&pose_with_twist(&twist, 0.1), 0.1);
+
+        let predicted = model.predict(0.2).unwrap();
+        let expected = pose_with_twist(&twist, 0.2);
+        assert_relative_eq!(
+            predicted.to_homogeneous(),
+            expected.to_homogeneous(),
+            epsilon = 1e-9
+        );
+    }
+
+    #[test]
+    fn test_stale_velocity_is_dropped() {
+        let mut model = MotionModel::new(MotionModelConfig::default());
+        model.update(&pose_at(0.0), 0.0);
+        model.update(&pose_at(2.0), 2.0);
+        assert!(model.predict(2.1).is_none());
+
+        model.update(&pose_at(2.1), 2.1);
+        model.update(&pose_at(2.2), 2.1);
+        let repeated = model.predict(2.1).unwrap();
+        let step = SE3::from(pose_at(2.2)) * SE3::from(pose_at(2.1)).inverse();
+        let expected: Isometry3<f64> = (step * SE3::from(pose_at(2.2))).into();
+        assert_relative_eq!(
+            repeated.to_homogeneous(),
+            expected.to_homogeneous(),
+            epsilon = 1e-9
+        );
+    }
+}
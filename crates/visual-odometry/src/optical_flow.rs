@@ -0,0 +1,295 @@
+use opencv::core::{
+    CV_8U, DMatch, KeyPoint, Mat, Point, Point2d, Point2f, Scalar, Size, TermCriteria,
+    TermCriteria_Type, Vector,
+};
+use opencv::imgproc::{
+    COLOR_BGR2GRAY, FILLED, LINE_8, circle, cvt_color_def, good_features_to_track,
+};
+use opencv::prelude::*;
+use opencv::video::calc_optical_flow_pyr_lk;
+
+use crate::OdometryError;
+use crate::frame::Frame;
+use crate::masking;
+
+#[derive(Debug, Clone, Copy)]
+pub struct KltConfig {
+    /// Side of the square window matched at each pyramid level, in pixels.
+    pub window_size: i32,
+    /// Pyramid levels above the full resolution image.
+    pub max_level: i32,
+    pub max_iterations: i32,
+    pub epsilon: f64,
+    /// Largest distance between a corner and where tracking it back from
+    /// the next frame lands, in pixels.
+    pub max_forward_backward_error: f32,
+    /// Corners detected when replenishing bring the count up to this.
+    pub max_features: usize,
+    /// Replenish when fewer corners than this survive tracking.
+    pub min_features: usize,
+    /// Shi-Tomasi quality, relative to the strongest corner in the image.
+    pub quality_level: f64,
+    /// Smallest distance between new corners and any tracked one, in pixels.
+    pub min_distance: f64,
+}
+
+impl Default for KltConfig {
+    fn default() -> Self {
+        Self {
+            window_size: 21,
+            max_level: 3,
+            max_iterations: 30,
+            epsilon: 0.01,
+            max_forward_backward_error: 1.0,
+            max_features: 1000,
+            min_features: 300,
+            quality_level: 0.01,
+            min_distance: 10.0,
+        }
+    }
+}
+
+/// Keypoints of the next frame, and matches to the frame they were tracked
+/// from.
+#[derive(Debug, Clone)]
+pub struct FlowTracks {
+    /// Tracked corners first, in match order, then any replenished ones.
+    pub keypoints: Vector<KeyPoint>,
+    /// `query_idx` indexes the previous frame's keypoints and `train_idx`
+    /// indexes `keypoints`, as `VisualOdometry::frame_match` produces them.
+    pub matches: Vector<DMatch>,
+}
+
+/// Tracks corners between consecutive frames with pyramidal Lucas-Kanade
+/// optical flow instead of matching descriptors.
+///
+/// Every corner is tracked forward into the next frame and back again, and
+/// kept only if it returns close to where it started. When too few survive,
+/// new Shi-Tomasi corners are detected away from the tracked ones. At high
+/// frame rates this is both faster and denser than descriptor matching, but
+/// it only works between frames close enough for the flow to converge.
+pub struct KltTracker {
+    config: KltConfig,
+}
+
+impl KltTracker {
+    pub fn new(config: KltConfig) -> Self {
+        Self { config }
+    }
+
+    pub fn config(&self) -> &KltConfig {
+        &self.config
+    }
+
+    /// Corners to start tracking from in `image`. `mask` is an OpenCV
+    /// detection mask, empty when nothing is masked.
+    pub fn detect(&self, image: &Mat, mask: &Mat) -> Result<Vector<KeyPoint>, OdometryError> {
+        let gray = grayscale(image)?;
+        self.replenish(&gray, mask, &Vector::new())
+    }
+
+    /// Tracks `previous.keypoints` into `image`. Corners that land on zero
+    /// pixels of `mask` are dropped and none are detected there.
+    pub fn track(
+        &self,
+        previous: &Frame,
+        image: &Mat,
+        mask: &Mat,
+    ) -> Result<FlowTracks, OdometryError> {
+        let previous_gray = grayscale(&previous.image)?;
+        let gray = grayscale(image)?;
+
+        let points: Vector<Point2f> = previous.keypoints.iter().map(|k| k.pt()).collect();
+        let mut keypoints = Vector::<KeyPoint>::new();
+        let mut matches = Vector::<DMatch>::new();
+        if !points.is_empty() {
+            let (forward, forward_status) = self.flow(&previous_gray, &gray, &points, None)?;
+            let (backward, backward_status) =
+                self.flow(&gray, &previous_gray, &forward, Some(&points))?;
+
+            let consistent = forward_backward_consistent(
+                &points.to_vec(),
+                &forward.to_vec(),
+                &backward.to_vec(),
+                &forward_status.to_vec(),
+                &backward_status.to_vec(),
+                self.config.max_forward_backward_error,
+            );
+            let (width, height) = (gray.cols() as f32, gray.rows() as f32);
+            for (i, keep) in consistent.into_iter().enumerate() {
+                let pt = forward.get(i)?;
+                if !keep
+                    || pt.x < 0.0
+                    || pt.y < 0.0
+                    || pt.x >= width
+                    || pt.y >= height
+                    || masking::is_masked(mask, Point2d::new(pt.x as f64, pt.y as f64))?
+                {
+                    continue;
+                }
+                let mut keypoint = previous.keypoints.get(i)?;
+                keypoint.set_pt(pt);
+                let distance = (pt - points.get(i)?).norm() as f32;
+                matches.push(DMatch::new(i as i32, keypoints.len() as i32, distance)?);
+                keypoints.push(keypoint);
+            }
+        }
+
+        if keypoints.len() < self.config.min_features {
+            for keypoint in self.replenish(&gray, mask, &keypoints)? {
+                keypoints.push(keypoint);
+            }
+        }
+        Ok(FlowTracks { keypoints, matches })
+    }
+
+    /// Tracks `points` from `from` into `to`, starting from `initial` when
+    /// given. Returns the tracked points and their status.
+    fn flow(
+        &self,
+        from: &Mat,
+        to: &Mat,
+        points: &Vector<Point2f>,
+        initial: Option<&Vector<Point2f>>,
+    ) -> Result<(Vector<Point2f>, Vector<u8>), OdometryError> {
+        let mut tracked = initial.cloned().unwrap_or_default();
+        let mut status = Vector::<u8>::new();
+        let mut error = Vector::<f32>::new();
+        let flags = if initial.is_some() {
+            opencv::video::OPTFLOW_USE_INITIAL_FLOW
+        } else {
+            0
+        };
+        calc_optical_flow_pyr_lk(
+            from,
+            to,
+            points,
+            &mut tracked,
+            &mut status,
+            &mut error,
+            Size::new(self.config.window_size, self.config.window_size),
+            self.config.max_level,
+            TermCriteria::new(
+                TermCriteria_Type::COUNT as i32 + TermCriteria_Type::EPS as i32,
+                self.config.max_iterations,
+                self.config.epsilon,
+            )?,
+            flags,
+            1e-4,
+        )?;
+        Ok((tracked, status))
+    }
+
+    /// New corners outside `mask` and at least `min_distance` away from
+    /// `existing`, up to `max_features` in total.
+    fn replenish(
+        &self,
+        gray: &Mat,
+        mask: &Mat,
+        existing: &Vector<KeyPoint>,
+    ) -> Result<Vector<KeyPoint>, OdometryError> {
+        let mut found = Vector::<KeyPoint>::new();
+        let wanted = self.config.max_features.saturating_sub(existing.len());
+        if wanted == 0 {
+            return Ok(found);
+        }
+
+        let mut mask = if mask.empty() {
+            Mat::new_size_with_default(gray.size()?, CV_8U, Scalar::all(255.0))?
+        } else {
+            mask.try_clone()?
+        };
+        for keypoint in existing.iter() {
+            let pt = keypoint.pt();
+            circle(
+                &mut mask,
+                Point::new(pt.x.round() as i32, pt.y.round() as i32),
+                self.config.min_distance.round() as i32,
+                Scalar::all(0.0),
+                FILLED,
+                LINE_8,
+                0,
+            )?;
+        }
+
+        let mut corners = Vector::<Point2f>::new();
+        good_features_to_track(
+            gray,
+            &mut corners,
+            wanted as i32,
+            self.config.quality_level,
+            self.config.min_distance,
+            &mask,
+            3,
+            false,
+            0.04,
+        )?;
+        for corner in corners {
+            found.push(KeyPoint::new_point(
+                corner,
+                self.config.window_size as f32,
+                -1.0,
+                0.0,
+                0,
+                -1,
+            )?);
+        }
+        Ok(found)
+    }
+}
+
+fn grayscale(image: &Mat) -> Result<Mat, opencv::Error> {
+    if image.channels() == 1 {
+        return image.try_clone();
+    }
+    let mut gray = Mat::default();
+    cvt_color_def(image, &mut gray, COLOR_BGR2GRAY)?;
+    Ok(gray)
+}
+
+/// Whether each point was tracked both ways and came back within
+/// `max_error` pixels of where it started.
+fn forward_backward_consistent(
+    points: &[Point2f],
+    forward: &[Point2f],
+    backward: &[Point2f],
+    forward_status: &[u8],
+    backward_status: &[u8],
+    max_error: f32,
+) -> Vec<bool> {
+    (0..points.len())
+        .map(|i| {
+            forward_status[i] != 0
+                && backward_status[i] != 0
+                && forward[i].x.is_finite()
+                && forward[i].y.is_finite()
+                && (backward[i] - points[i]).norm() as f32 <= max_error
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_forward_backward_check() {
+        let points = [Point2f::new(10.0, 10.0); 4];
+        let forward = [Point2f::new(12.0, 10.0); 4];
+        let backward = [
+            Point2f::new(10.2, 10.1),
+            Point2f::new(13.0, 10.0),
+            Point2f::new(10.0, 10.0),
+            Point2f::new(10.0, 10.0),
+        ];
+        let consistent = forward_backward_consistent(
+            &points,
+            &forward,
+            &backward,
+            &[1, 1, 0, 1],
+            &[1, 1, 1, 0],
+            1.0,
+        );
+        assert_eq!(consistent, vec![true, false, false, false]);
+    }
+}
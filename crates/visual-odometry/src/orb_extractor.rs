@@ -0,0 +1,536 @@
+use std::cmp::Ordering;
+use std::collections::BinaryHeap;
+
+use opencv::core::{KeyPoint, Mat, Point2d, Point2f, Ptr, Rect, Size, Vector};
+use opencv::features2d::{ORB, fast};
+use opencv::imgproc::{COLOR_BGR2GRAY, INTER_LINEAR, INTER_NEAREST, cvt_color_def, resize};
+use opencv::prelude::*;
+
+use crate::feature_extractor::{DescriptorMetric, FeatureExtractor};
+use crate::masking;
+use crate::{ORBConfig, OdometryError};
+
+/// FAST needs this many pixels around a corner candidate.
+const FAST_RADIUS: i32 = 3;
+
+/// How `OrbExtractor` picks keypoints.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum KeypointDistribution {
+    /// `ORB::detect_and_compute`, keeping the strongest corners wherever they
+    /// are.
+    #[default]
+    Native,
+    /// Spread each pyramid level's budget evenly over grid cells.
+    Grid,
+    /// Split each pyramid level into a quadtree until there is a node per
+    /// keypoint, keeping the strongest corner of each node.
+    Quadtree,
+}
+
+/// ORB keypoints and descriptors, the default extractor.
+pub struct OrbExtractor {
+    orb: Ptr<ORB>,
+    config: ORBConfig,
+}
+
+impl OrbExtractor {
+    pub fn new(config: ORBConfig) -> Result<Self, OdometryError> {
+        let orb = ORB::create(
+            config.nfeatures,
+            config.scale_factor,
+            config.nlevels,
+            config.edge_threshold,
+            config.first_level,
+            config.wta_k,
+            config.score_type,
+            config.patch_size,
+            config.fast_threshold,
+        )?;
+        Ok(Self { orb, config })
+    }
+
+    pub fn config(&self) -> &ORBConfig {
+        &self.config
+    }
+}
+
+impl FeatureExtractor for OrbExtractor {
+    fn detect_and_compute(
+        &mut self,
+        image: &Mat,
+        mask: &Mat,
+    ) -> Result<(Vector<KeyPoint>, Mat), OdometryError> {
+        if self.config.distribution != KeypointDistribution::Native {
+            return Ok(detect_distributed(
+                &mut self.orb,
+                &self.config,
+                image,
+                mask,
+            )?);
+        }
+        let mut keypoints = Vector::<KeyPoint>::new();
+        let mut descriptors = Mat::default();
+        self.orb
+            .detect_and_compute(image, mask, &mut keypoints, &mut descriptors, false)?;
+        Ok((keypoints, descriptors))
+    }
+
+    fn metric(&self) -> DescriptorMetric {
+        // ORB compares pairs of bits per test above WTA_K 2.
+        if self.config.wta_k > 2 {
+            DescriptorMetric::Hamming2
+        } else {
+            DescriptorMetric::Hamming
+        }
+    }
+
+    fn descriptor_size(&self) -> Result<usize, OdometryError> {
+        Ok(self.orb.descriptor_size()? as usize)
+    }
+}
+
+/// A FAST corner in the coordinates of its pyramid level.
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct Corner {
+    x: f32,
+    y: f32,
+    response: f32,
+}
+
+/// Detects ORB keypoints spread evenly over every pyramid level, ORB-SLAM
+/// style, and computes their descriptors.
+///
+/// Each level is tiled into `config.cell_size` cells and FAST runs per cell,
+/// retrying with `config.min_fast_threshold` where `config.fast_threshold`
+/// finds nothing, so low-contrast regions still get corners. The level's
+/// share of `config.nfeatures` is then picked with `config.distribution`.
+/// `config.first_level` is ignored.
+fn detect_distributed(
+    orb: &mut Ptr<ORB>,
+    config: &ORBConfig,
+    image: &Mat,
+    mask: &Mat,
+) -> Result<(Vector<KeyPoint>, Mat), opencv::Error> {
+    let gray = if image.channels() > 1 {
+        let mut gray = Mat::default();
+        cvt_color_def(image, &mut gray, COLOR_BGR2GRAY)?;
+        gray
+    } else {
+        image.try_clone()?
+    };
+
+    let nlevels = config.nlevels.max(1) as usize;
+    let budgets = features_per_level(
+        config.nfeatures.max(0) as usize,
+        config.scale_factor as f64,
+        nlevels,
+    );
+    let half_patch = config.patch_size / 2;
+    let umax = circular_patch(half_patch);
+
+    let mut keypoints = Vector::<KeyPoint>::new();
+    for (level, &budget) in budgets.iter().enumerate() {
+        let scale = (config.scale_factor as f64).powi(level as i32);
+        let level_image = if level == 0 {
+            gray.try_clone()?
+        } else {
+            let size = Size::new(
+                (gray.cols() as f64 / scale).round() as i32,
+                (gray.rows() as f64 / scale).round() as i32,
+            );
+            let mut resized = Mat::default();
+            resize(&gray, &mut resized, size, 0.0, 0.0, INTER_LINEAR)?;
+            resized
+        };
+
+        let border = config.edge_threshold.max(half_patch + 1);
+        let (min_x, min_y) = (border - FAST_RADIUS, border - FAST_RADIUS);
+        let max_x = level_image.cols() - border + FAST_RADIUS;
+        let max_y = level_image.rows() - border + FAST_RADIUS;
+        if max_x - min_x <= 2 * FAST_RADIUS || max_y - min_y <= 2 * FAST_RADIUS {
+            break;
+        }
+
+        let level_mask = if level == 0 || mask.empty() {
+            mask.try_clone()?
+        } else {
+            let mut resized = Mat::default();
+            resize(
+                mask,
+                &mut resized,
+                level_image.size()?,
+                0.0,
+                0.0,
+                INTER_NEAREST,
+            )?;
+            resized
+        };
+        let cells = detect_cells(
+            config,
+            &level_image,
+            &level_mask,
+            (min_x, min_y),
+            (max_x, max_y),
+        )?;
+        let selected = match config.distribution {
+            KeypointDistribution::Grid => distribute_grid(cells, budget),
+            _ => distribute_quadtree(
+                cells.into_iter().flatten().collect(),
+                (min_x as f32, min_y as f32, max_x as f32, max_y as f32),
+                budget,
+            ),
+        };
+
+        let data = level_image.data_bytes()?;
+        let stride = level_image.cols() as usize;
+        for corner in selected {
+            let angle = intensity_centroid_angle(data, stride, &corner, &umax);
+            keypoints.push(KeyPoint::new_point(
+                Point2f::new(corner.x * scale as f32, corner.y * scale as f32),
+                config.patch_size as f32 * scale as f32,
+                angle,
+                corner.response,
+                level as i32,
+                -1,
+            )?);
+        }
+    }
+
+    // ORB picks the pyramid level from each keypoint's octave and keeps the
+    // angle it is given.
+    let mut descriptors = Mat::default();
+    orb.compute(&gray, &mut keypoints, &mut descriptors)?;
+    Ok((keypoints, descriptors))
+}
+
+/// FAST corners of each grid cell inside `[min, max)`, in level coordinates,
+/// leaving out those on zero pixels of `mask`.
+fn detect_cells(
+    config: &ORBConfig,
+    image: &Mat,
+    mask: &Mat,
+    (min_x, min_y): (i32, i32),
+    (max_x, max_y): (i32, i32),
+) -> Result<Vec<Vec<Corner>>, opencv::Error> {
+    let cell_size = config.cell_size.max(2 * FAST_RADIUS + 1);
+    let cols = ((max_x - min_x) / cell_size).max(1);
+    let rows = ((max_y - min_y) / cell_size).max(1);
+    let cell_w = (max_x - min_x + cols - 1) / cols;
+    let cell_h = (max_y - min_y + rows - 1) / rows;
+
+    let mut cells = Vec::with_capacity((cols * rows) as usize);
+    for r in 0..rows {
+        let y0 = min_y + r * cell_h;
+        if y0 >= max_y - 2 * FAST_RADIUS {
+            continue;
+        }
+        let y1 = (y0 + cell_h + 2 * FAST_RADIUS).min(max_y);
+        for c in 0..cols {
+            let x0 = min_x + c * cell_w;
+            if x0 >= max_x - 2 * FAST_RADIUS {
+                continue;
+            }
+            let x1 = (x0 + cell_w + 2 * FAST_RADIUS).min(max_x);
+            let cell = image.roi(Rect::new(x0, y0, x1 - x0, y1 - y0))?;
+
+            let mut corners = Vec::new();
+            for threshold in [config.fast_threshold, config.min_fast_threshold] {
+                let mut found = Vector::<KeyPoint>::new();
+                fast(&*cell, &mut found, threshold, true)?;
+                for k in found {
+                    let (x, y) = (k.pt().x + x0 as f32, k.pt().y + y0 as f32);
+                    if !masking::is_masked(mask, Point2d::new(x as f64, y as f64))? {
+                        corners.push(Corner {
+                            x,
+                            y,
+                            response: k.response(),
+                        });
+                    }
+                }
+                if !corners.is_empty() {
+                    break;
+                }
+            }
+            cells.push(corners);
+        }
+    }
+    Ok(cells)
+}
+
+/// Splits `nfeatures` over the pyramid in a geometric series of the scale
+/// factor, as ORB-SLAM does, with the rounding remainder going to the last
+/// level.
+fn features_per_level(nfeatures: usize, scale_factor: f64, nlevels: usize) -> Vec<usize> {
+    let inv = 1.0 / scale_factor;
+    let first = if (1.0 - inv.powi(nlevels as i32)).abs() < f64::EPSILON {
+        nfeatures as f64 / nlevels as f64
+    } else {
+        nfeatures as f64 * (1.0 - inv) / (1.0 - inv.powi(nlevels as i32))
+    };
+
+    let mut budgets = Vec::with_capacity(nlevels);
+    let mut assigned = 0;
+    for level in 0..nlevels.saturating_sub(1) {
+        let n = ((first * inv.powi(level as i32)).round() as usize).min(nfeatures - assigned);
+        budgets.push(n);
+        assigned += n;
+    }
+    budgets.push(nfeatures - assigned);
+    budgets
+}
+
+/// Keeps the strongest corners of every cell, an equal share each, then the
+/// strongest `n` overall.
+fn distribute_grid(cells: Vec<Vec<Corner>>, n: usize) -> Vec<Corner> {
+    let occupied = cells.iter().filter(|cell| !cell.is_empty()).count();
+    if occupied == 0 {
+        return Vec::new();
+    }
+    let per_cell = n.div_ceil(occupied);
+    let mut selected: Vec<Corner> = cells
+        .into_iter()
+        .flat_map(|mut cell| {
+            cell.sort_by(|a, b| b.response.total_cmp(&a.response));
+            cell.truncate(per_cell);
+            cell
+        })
+        .collect();
+    if selected.len() > n {
+        selected.sort_by(|a, b| b.response.total_cmp(&a.response));
+        selected.truncate(n);
+    }
+    selected
+}
+
+/// Quadtree node, ordered by how many corners it holds so the most crowded
+/// node is split first.
+struct Node {
+    bounds: (f32, f32, f32, f32),
+    corners: Vec<Corner>,
+}
+
+impl PartialEq for Node {
+    fn eq(&self, other: &Self) -> bool {
+        self.corners.len() == other.corners.len()
+    }
+}
+
+impl Eq for Node {}
+
+impl PartialOrd for Node {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for Node {
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.corners.len().cmp(&other.corners.len())
+    }
+}
+
+/// Splits `bounds` (min x, min y, max x, max y) into quadrants, most crowded
+/// first, until there are `n` nodes or no node can be split further, and
+/// keeps the strongest corner of each node, at most `n` overall.
+fn distribute_quadtree(
+    corners: Vec<Corner>,
+    bounds: (f32, f32, f32, f32),
+    n: usize,
+) -> Vec<Corner> {
+    if corners.is_empty() || n == 0 {
+        return Vec::new();
+    }
+    let mut nodes = BinaryHeap::new();
+    nodes.push(Node { bounds, corners });
+    // Nodes holding a single corner or less than a pixel across.
+    let mut leaves = Vec::new();
+    while nodes.len() + leaves.len() < n {
+        let Some(node) = nodes.pop() else {
+            break;
+        };
+        let (x0, y0, x1, y1) = node.bounds;
+        if node.corners.len() <= 1 || x1 - x0 < 1.0 || y1 - y0 < 1.0 {
+            leaves.push(node);
+            continue;
+        }
+
+        let (cx, cy) = ((x0 + x1) / 2.0, (y0 + y1) / 2.0);
+        let mut children = [
+            Node {
+                bounds: (x0, y0, cx, cy),
+                corners: Vec::new(),
+            },
+            Node {
+                bounds: (cx, y0, x1, cy),
+                corners: Vec::new(),
+            },
+            Node {
+                bounds: (x0, cy, cx, y1),
+                corners: Vec::new(),
+            },
+            Node {
+                bounds: (cx, cy, x1, y1),
+                corners: Vec::new(),
+            },
+        ];
+        for corner in node.corners {
+            let i = usize::from(corner.x >= cx) + 2 * usize::from(corner.y >= cy);
+            children[i].corners.push(corner);
+        }
+        nodes.extend(children.into_iter().filter(|c| !c.corners.is_empty()));
+    }
+
+    // The last split can leave up to three nodes too many.
+    let mut selected: Vec<Corner> = nodes
+        .into_iter()
+        .chain(leaves)
+        .filter_map(|node| {
+            node.corners
+                .into_iter()
+                .max_by(|a, b| a.response.total_cmp(&b.response))
+        })
+        .collect();
+    if selected.len() > n {
+        selected.sort_by(|a, b| b.response.total_cmp(&a.response));
+        selected.truncate(n);
+    }
+    selected
+}
+
+/// Half-widths of a circular patch, one per row offset from its centre.
+fn circular_patch(half_patch: i32) -> Vec<i32> {
+    let radius_sq = (half_patch * half_patch) as f64;
+    (0..=half_patch)
+        .map(|v| (radius_sq - (v * v) as f64).sqrt().round() as i32)
+        .collect()
+}
+
+/// Orientation of the intensity centroid of the circular patch around
+/// `corner`, in degrees. `data` is a continuous single channel image with
+/// `stride` pixels per row.
+fn intensity_centroid_angle(data: &[u8], stride: usize, corner: &Corner, umax: &[i32]) -> f32 {
+    let half_patch = umax.len() as i32 - 1;
+    let (cx, cy) = (corner.x.round() as i32, corner.y.round() as i32);
+    let (mut m01, mut m10) = (0i64, 0i64);
+    for v in -half_patch..=half_patch {
+        let width = umax[v.unsigned_abs() as usize];
+        let row = (cy + v) as usize * stride;
+        for u in -width..=width {
+            let value = data[row + (cx + u) as usize] as i64;
+            m10 += u as i64 * value;
+            m01 += v as i64 * value;
+        }
+    }
+    let angle = (m01 as f32).atan2(m10 as f32).to_degrees();
+    if angle < 0.0 { angle + 360.0 } else { angle }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn corner(x: f32, y: f32, response: f32) -> Corner {
+        Corner { x, y, response }
+    }
+
+    #[test]
+    fn test_level_budgets_sum_to_total() {
+        let budgets = features_per_level(1000, 1.2, 8);
+        assert_eq!(budgets.len(), 8);
+        assert_eq!(budgets.iter().sum::<usize>(), 1000);
+        assert!(budgets.windows(2).take(6).all(|w| w[0] >= w[1]));
+        assert_eq!(features_per_level(10, 1.0, 4).iter().sum::<usize>(), 10);
+    }
+
+    #[test]
+    fn test_quadtree_spreads_over_clusters() {
+        // A dense strong cluster in one corner and a few weak corners elsewhere.
+        let mut corners: Vec<Corner> = (0..50)
+            .map(|i| corner(5.0 + (i % 7) as f32, 5.0 + (i / 7) as f32, 100.0))
+            .collect();
+        corners.push(corner(90.0, 10.0, 1.0));
+        corners.push(corner(10.0, 90.0, 2.0));
+        corners.push(corner(90.0, 90.0, 3.0));
+
+        let selected = distribute_quadtree(corners.clone(), (0.0, 0.0, 100.0, 100.0), 4);
+        assert_eq!(selected.len(), 4);
+        for weak in &corners[50..] {
+            assert!(selected.contains(weak));
+        }
+
+        let grid = distribute_grid(vec![corners[..50].to_vec(), corners[50..].to_vec()], 4);
+        assert_eq!(grid.len(), 4);
+        assert_eq!(grid.iter().filter(|c| c.response == 100.0).count(), 2);
+    }
+
+    #[test]
+    fn test_quadtree_keeps_at_most_n_strongest() {
+        // One corner per quadrant of a 4x4 grid.
+        let grid: Vec<Corner> = (0..16)
+            .map(|i| {
+                corner(
+                    12.5 + 25.0 * (i % 4) as f32,
+                    12.5 + 25.0 * (i / 4) as f32,
+                    1.0,
+                )
+            })
+            .collect();
+        for n in 1..=16 {
+            let selected = distribute_quadtree(grid.clone(), (0.0, 0.0, 100.0, 100.0), n);
+            assert_eq!(selected.len(), n, "n = {n}");
+        }
+
+        // Splitting the crowded top left quadrant makes seven nodes out of
+        // four, two more than asked for, so its two weakest corners go.
+        let mut corners: Vec<Corner> = (0..4)
+            .map(|i| {
+                corner(
+                    12.5 + 25.0 * (i % 2) as f32,
+                    12.5 + 25.0 * (i / 2) as f32,
+                    i as f32,
+                )
+            })
+            .collect();
+        corners.extend(
+            [(75.0, 25.0, 10.0), (25.0, 75.0, 11.0), (75.0, 75.0, 12.0)]
+                .map(|(x, y, r)| corner(x, y, r)),
+        );
+        let selected = distribute_quadtree(corners, (0.0, 0.0, 100.0, 100.0), 5);
+        let mut responses: Vec<f32> = selected.iter().map(|c| c.response).collect();
+        responses.sort_by(f32::total_cmp);
+        assert_eq!(responses, [2.0, 3.0, 10.0, 11.0, 12.0]);
+    }
+
+    #[test]
+    fn test_quadtree_splits_past_unsplittable_nodes() {
+        // The most crowded node is six corners on one pixel, which cannot be
+        // split, while the four corners in the other quadrant still can be.
+        let mut corners: Vec<Corner> = (0..6).map(|i| corner(10.5, 10.5, i as f32)).collect();
+        corners.extend(
+            [(60.0, 60.0), (90.0, 60.0), (60.0, 90.0), (90.0, 90.0)]
+                .map(|(x, y)| corner(x, y, 10.0)),
+        );
+        let selected = distribute_quadtree(corners, (0.0, 0.0, 100.0, 100.0), 5);
+        assert_eq!(selected.len(), 5);
+        assert!(selected.contains(&corner(10.5, 10.5, 5.0)));
+    }
+
+    #[test]
+    fn test_angle_points_towards_brighter_side() {
+        let umax = circular_patch(3);
+        let stride = 9;
+        // Bright right half.
+        let data: Vec<u8> = (0..81)
+            .map(|i| if i % stride > 4 { 200 } else { 0 })
+            .collect();
+        let angle = intensity_centroid_angle(&data, stride, &corner(4.0, 4.0, 0.0), &umax);
+        assert!(!(1.0..=359.0).contains(&angle));
+
+        // Bright bottom half, y pointing down.
+        let data: Vec<u8> = (0..81)
+            .map(|i| if i / stride > 4 { 200 } else { 0 })
+            .collect();
+        let angle = intensity_centroid_angle(&data, stride, &corner(4.0, 4.0, 0.0), &umax);
+        assert!((angle - 90.0).abs() < 1.0);
+    }
+}
@@ -0,0 +1,421 @@
+use std::collections::HashMap;
+
+use opencv::calib3d::{SOLVEPNP_EPNP, solve_pnp_ransac, solve_pnp_refine_lm};
+use opencv::core::{Mat, Point2d, Point3d, TermCriteria, TermCriteria_Type, Vector};
+use opencv::prelude::*;
+use r_slam_common::camera::Camera;
+
+use crate::OdometryError;
+use crate::feature_extractor::DescriptorMetric;
+use crate::frame::Frame;
+use crate::grid::FeatureGrid;
+use crate::utils::{self, add3, mul3v};
+
+/// A map point as seen by the tracker.
+#[derive(Debug, Clone)]
+pub struct LocalMapPoint {
+    pub id: usize,
+    /// Position in world coordinates.
+    pub position: Point3d,
+    /// Single row descriptor used for guided matching.
+    pub descriptor: Mat,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct PnpConfig {
+    /// Radius around each projected map point searched for keypoints, in pixels.
+    pub search_radius: f64,
+    /// In the units of the frames' `DescriptorMetric`.
+    pub max_descriptor_distance: f32,
+    /// Best match must be closer than `ratio` times the second best.
+    pub ratio: f32,
+    /// Correspondences needed before RANSAC is attempted.
+    pub min_correspondences: usize,
+    pub ransac_iterations: i32,
+    /// RANSAC and final inlier threshold, in pixels.
+    pub reprojection_error: f32,
+    pub confidence: f64,
+    pub min_inliers: usize,
+}
+
+impl PnpConfig {
+    /// Defaults with the descriptor distance threshold suited to `metric`.
+    pub fn for_metric(metric: DescriptorMetric) -> Self {
+        Self {
+            max_descriptor_distance: metric.default_guided_max_distance(),
+            ..Self::default()
+        }
+    }
+}
+
+impl Default for PnpConfig {
+    /// Thresholds for ORB's Hamming distance.
+    fn default() -> Self {
+        Self {
+            search_radius: 15.0,
+            max_descriptor_distance: DescriptorMetric::Hamming.default_guided_max_distance(),
+            ratio: 0.9,
+            min_correspondences: 15,
+            ransac_iterations: 100,
+            reprojection_error: 4.0,
+            confidence: 0.99,
+            min_inliers: 15,
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct PnpResult {
+    /// World to camera transform (4x4, CV_64F).
+    pub pose: Mat,
+    /// `(map point id, keypoint index)` for every inlier.
+    pub matches: Vec<(usize, usize)>,
+    pub inliers: usize,
+}
+
+/// Localizes frames against 3D map points.
+///
+/// Map points are projected with a predicted pose, matched to nearby keypoints
+/// by descriptor, and the pose is solved with EPnP RANSAC followed by a
+/// Levenberg-Marquardt refinement over the inliers.
+pub struct PnpTracker {
+    camera: Camera,
+    config: PnpConfig,
+    metric: DescriptorMetric,
+}
+
+impl PnpTracker {
+    pub fn new(camera: Camera, config: PnpConfig) -> Self {
+        Self {
+            camera,
+            config,
+            metric: DescriptorMetric::Hamming,
+        }
+    }
+
+    /// Compares descriptors with `metric` instead of Hamming distance.
+    pub fn with_descriptor_metric(mut self, metric: DescriptorMetric) -> Self {
+        self.metric = metric;
+        self
+    }
+
+    /// `predicted_pose` is a world to camera transform (4x4, CV_64F), usually
+    /// the pose of the previous frame.
+    pub fn track(
+        &self,
+        frame: &Frame,
+        points: &[LocalMapPoint],
+        predicted_pose: &Mat,
+    ) -> Result<PnpResult, OdometryError> {
+        self.track_predicted(frame, points, predicted_pose, self.config.search_radius)
+    }
+
+    /// Like `track`, for a pose predicted well enough to search a window of
+    /// `search_radius` pixels and to start the optimization from. RANSAC only
+    /// runs if too few correspondences agree with the prediction.
+    pub fn track_predicted(
+        &self,
+        frame: &Frame,
+        points: &[LocalMapPoint],
+        predicted_pose: &Mat,
+        search_radius: f64,
+    ) -> Result<PnpResult, OdometryError> {
+        let keypoints = utils::undistorted_keypoints(&self.camera, &frame.keypoints)?;
+        let correspondences =
+            self.search_by_projection(frame, &keypoints, points, predicted_pose, search_radius)?;
+        self.solve(&keypoints, points, &correspondences, Some(predicted_pose))
+    }
+
+    /// Solves the pose from known `(point index, keypoint index)`
+    /// correspondences, when there is no pose to predict them from.
+    pub fn track_matches(
+        &self,
+        frame: &Frame,
+        points: &[LocalMapPoint],
+        correspondences: &[(usize, usize)],
+    ) -> Result<PnpResult, OdometryError> {
+        let keypoints = utils::undistorted_keypoints(&self.camera, &frame.keypoints)?;
+        self.solve(&keypoints, points, correspondences, None)
+    }
+
+    fn solve(
+        &self,
+        keypoints: &[Point2d],
+        points: &[LocalMapPoint],
+        correspondences: &[(usize, usize)],
+        seed: Option<&Mat>,
+    ) -> Result<PnpResult, OdometryError> {
+        if correspondences.len() < self.config.min_correspondences {
+            return Err(OdometryError::NotEnoughPoints);
+        }
+
+        let object =
+            Vector::<Point3d>::from_iter(correspondences.iter().map(|&(p, _)| points[p].position));
+        let image =
+            Vector::<Point2d>::from_iter(correspondences.iter().map(|&(_, k)| keypoints[k]));
+        // Keypoints are already undistorted.
+        let no_distortion = Mat::default();
+
+        let seeded = match seed {
+            Some(pose) => {
+                let inliers = self.inliers(keypoints, points, correspondences, pose)?;
+                (inliers.len() >= self.config.min_inliers).then_some((pose, inliers))
+            }
+            None => None,
+        };
+        let (mut rvec, mut tvec, inliers) = match seeded {
+            Some((pose, inliers)) => {
+                let (rvec, tvec) = utils::rvec_from_pose(pose)?;
+                (rvec, tvec, inliers)
+            }
+            None => {
+                let mut rvec = Mat::default();
+                let mut tvec = Mat::default();
+                let mut ransac_inliers = Vector::<i32>::new();
+                let found = solve_pnp_ransac(
+                    &object,
+                    &image,
+                    &self.camera.camera_matrix,
+                    &no_distortion,
+                    &mut rvec,
+                    &mut tvec,
+                    false,
+                    self.config.ransac_iterations,
+                    self.config.reprojection_error,
+                    self.config.confidence,
+                    &mut ransac_inliers,
+                    SOLVEPNP_EPNP,
+                )?;
+                if !found || ransac_inliers.len() < self.config.min_inliers {
+                    return Err(OdometryError::NotEnoughPoints);
+                }
+                let inliers = ransac_inliers.iter().map(|i| i as usize).collect();
+                (rvec, tvec, inliers)
+            }
+        };
+
+        // Motion-only refinement over the inliers.
+        let mut inlier_object = Vector::<Point3d>::with_capacity(inliers.len());
+        let mut inlier_image = Vector::<Point2d>::with_capacity(inliers.len());
+        for i in inliers {
+            inlier_object.push(object.get(i)?);
+            inlier_image.push(image.get(i)?);
+        }
+        solve_pnp_refine_lm(
+            &inlier_object,
+            &inlier_image,
+            &self.camera.camera_matrix,
+            &no_distortion,
+            &mut rvec,
+            &mut tvec,
+            TermCriteria::new(
+                TermCriteria_Type::COUNT as i32 + TermCriteria_Type::EPS as i32,
+                20,
+                f64::EPSILON,
+            )?,
+        )?;
+        let pose = utils::pose_from_rvec(&rvec, &tvec)?;
+
+        // Re-check every correspondence against the refined pose, so matches
+        // rejected under a worse hypothesis can come back.
+        let matches: Vec<(usize, usize)> = self
+            .inliers(keypoints, points, correspondences, &pose)?
+            .into_iter()
+            .map(|i| {
+                let (p, k) = correspondences[i];
+                (points[p].id, k)
+            })
+            .collect();
+        if matches.len() < self.config.min_inliers {
+            return Err(OdometryError::NotEnoughPoints);
+        }
+
+        Ok(PnpResult {
+            pose,
+            inliers: matches.len(),
+            matches,
+        })
+    }
+
+    /// Indices of the correspondences `pose` reprojects within the inlier
+    /// threshold.
+    fn inliers(
+        &self,
+        keypoints: &[Point2d],
+        points: &[LocalMapPoint],
+        correspondences: &[(usize, usize)],
+        pose: &Mat,
+    ) -> Result<Vec<usize>, OdometryError> {
+        let (rotation, translation) = utils::split_pose(pose)?;
+        let max_error_sq = (self.config.reprojection_error as f64).powi(2);
+        Ok(correspondences
+            .iter()
+            .enumerate()
+            .filter(|&(_, &(p, k))| {
+                let position = points[p].position;
+                let camera = add3(
+                    &mul3v(&rotation, &[position.x, position.y, position.z]),
+                    &translation,
+                );
+                camera[2] > 0.0
+                    && utils::reprojection_error_sq(&self.camera, &camera, &keypoints[k])
+                        <= max_error_sq
+            })
+            .map(|(i, _)| i)
+            .collect())
+    }
+
+    /// Projects map points with `pose` and matches each against keypoints in a
+    /// `search_radius` window around its projection. Returns `(point index, keypoint index)`
+    /// pairs with every keypoint used at most once.
+    fn search_by_projection(
+        &self,
+        frame: &Frame,
+        keypoints: &[Point2d],
+        points: &[LocalMapPoint],
+        pose: &Mat,
+        search_radius: f64,
+    ) -> Result<Vec<(usize, usize)>, OdometryError> {
+        let (rotation, translation) = utils::split_pose(pose)?;
+        let grid = FeatureGrid::new(keypoints, search_radius);
+        let camera = &self.camera;
+
+        // keypoint index -> (point index, distance)
+        let mut best_for_keypoint: HashMap<usize, (usize, f32)> = HashMap::new();
+        for (p, point) in points.iter().enumerate() {
+            let position = [point.position.x, point.position.y, point.position.z];
+            let in_camera = add3(&mul3v(&rotation, &position), &translation);
+            if in_camera[2] <= 0.0 {
+                continue;
+            }
+            let projected = Point2d::new(
+                camera.fx * in_camera[0] / in_camera[2] + camera.cx,
+                camera.fy * in_camera[1] / in_camera[2] + camera.cy,
+            );
+
+            let mut best: Option<(usize, f32)> = None;
+            let mut second = f32::MAX;
+            for k in grid.within(projected, search_radius) {
+                let distance =
+                    self.metric
+                        .distance(&point.descriptor, 0, &frame.descriptors, k as i32)?;
+                match best {
+                    Some((_, d)) if distance >= d => second = second.min(distance),
+                    _ => {
+                        second = best.map_or(second, |(_, d)| d);
+                        best = Some((k, distance));
+                    }
+                }
+            }
+
+            let Some((k, distance)) = best else {
+                continue;
+            };
+            if distance > self.config.max_descriptor_distance
+                || distance >= self.config.ratio * second
+            {
+                continue;
+            }
+            match best_for_keypoint.get(&k) {
+                Some(&(_, existing)) if existing <= distance => {}
+                _ => {
+                    best_for_keypoint.insert(k, (p, distance));
+                }
+            }
+        }
+
+        let mut correspondences: Vec<(usize, usize)> = best_for_keypoint
+            .into_iter()
+            .map(|(k, (p, _))| (p, k))
+            .collect();
+        correspondences.sort_unstable();
+        Ok(correspondences)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util;
+    use nalgebra::{Isometry3, Vector3};
+    use opencv::core::KeyPoint;
+
+    fn truth() -> Isometry3<f64> {
+        Isometry3::new(Vector3::new(0.2, -0.1, 0.5), Vector3::new(0.02, -0.1, 0.03))
+    }
+
+    /// Map points, each with its own random descriptor, and a frame seeing
+    /// them from `truth` with up to `noise` pixels of error. Every fifth
+    /// keypoint is moved far from its point.
+    fn scene(noise: f64) -> (Vec<LocalMapPoint>, Frame) {
+        let mut rng = utils::SplitMix64(11);
+        let pose = truth();
+        let positions = test_util::scene(100);
+        let descriptors = test_util::descriptors(positions.len(), 11);
+        let mut points = Vec::new();
+        let mut keypoints = Vector::<KeyPoint>::new();
+        for (i, position) in positions.iter().enumerate() {
+            let mut pixel = test_util::project(&pose.transform_point(&(*position).into()).coords);
+            pixel.x += noise * (2.0 * rng.unit() - 1.0);
+            pixel.y += noise * (2.0 * rng.unit() - 1.0);
+            if i % 5 == 0 {
+                pixel.x += 40.0 + 40.0 * rng.unit();
+                pixel.y -= 30.0 + 40.0 * rng.unit();
+            }
+            keypoints.push(KeyPoint::new_coords_def(pixel.x as f32, pixel.y as f32, 31.0).unwrap());
+            points.push(LocalMapPoint {
+                id: 1000 + i,
+                position: Point3d::new(position.x, position.y, position.z),
+                descriptor: descriptors.row(i as i32).unwrap().try_clone().unwrap(),
+            });
+        }
+        (
+            points,
+            Frame::new(0, Mat::default(), keypoints, descriptors),
+        )
+    }
+
+    fn assert_pose_near_truth(pose: &Mat) {
+        let error = utils::pose_to_isometry(pose).unwrap().inverse() * truth();
+        assert!(error.translation.vector.norm() < 2e-2, "{error:?}");
+        assert!(error.rotation.angle() < 2e-3, "{error:?}");
+    }
+
+    #[test]
+    fn test_recovers_pose_from_noisy_projections_with_outliers() {
+        let (points, frame) = scene(0.5);
+        let tracker = PnpTracker::new(test_util::camera(), PnpConfig::default());
+        let correspondences: Vec<(usize, usize)> = (0..points.len()).map(|i| (i, i)).collect();
+
+        let result = tracker
+            .track_matches(&frame, &points, &correspondences)
+            .unwrap();
+        assert_pose_near_truth(&result.pose);
+        // Every clean correspondence is kept and every moved one dropped.
+        assert_eq!(result.inliers, 80);
+        assert!(
+            result
+                .matches
+                .iter()
+                .all(|&(id, k)| id == 1000 + k && k % 5 != 0)
+        );
+    }
+
+    #[test]
+    fn test_tracks_from_predicted_pose() {
+        let (points, frame) = scene(0.5);
+        let tracker = PnpTracker::new(test_util::camera(), PnpConfig::default());
+        // A few pixels off, well within the search radius.
+        let predicted = Isometry3::new(Vector3::new(0.01, 0.0, 0.0), Vector3::zeros()) * truth();
+
+        let result = tracker
+            .track(
+                &frame,
+                &points,
+                &utils::isometry_to_pose(&predicted).unwrap(),
+            )
+            .unwrap();
+        assert_pose_near_truth(&result.pose);
+        assert_eq!(result.inliers, 80);
+    }
+}
@@ -0,0 +1,354 @@
+use std::collections::BTreeMap;
+
+use nalgebra::{DMatrix, DVector};
+
+use crate::lie::{Matrix7, Sim3, Vector7};
+
+/// Lower bound on the diagonal entries the damping is scaled by.
+const MIN_DIAGONAL: f64 = 1e-6;
+const MAX_LAMBDA: f64 = 1e12;
+
+#[derive(Debug, thiserror::Error)]
+pub enum PoseGraphError {
+    #[error("Unknown vertex {0}")]
+    UnknownVertex(usize),
+    #[error("Pose graph needs at least one fixed vertex")]
+    NoFixedVertex,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct PoseGraphConfig {
+    pub max_iterations: usize,
+    pub initial_lambda: f64,
+    /// Stop once an accepted step lowers the cost by less than this fraction.
+    pub min_relative_decrease: f64,
+}
+
+impl Default for PoseGraphConfig {
+    fn default() -> Self {
+        Self {
+            max_iterations: 20,
+            initial_lambda: 1e-6,
+            min_relative_decrease: 1e-8,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct Vertex {
+    /// World to camera similarity.
+    pub pose: Sim3,
+    pub fixed: bool,
+}
+
+/// Relative constraint between two vertices.
+#[derive(Debug, Clone, Copy)]
+pub struct Edge {
+    pub from: usize,
+    pub to: usize,
+    /// Expected `pose(to) * pose(from)^-1`, the transform from the `from`
+    /// camera to the `to` camera.
+    pub measurement: Sim3,
+    pub information: Matrix7,
+}
+
+#[derive(Debug, Clone, Copy, Default)]
+pub struct PoseGraphSummary {
+    /// Levenberg-Marquardt iterations run, including rejected steps.
+    pub iterations: usize,
+    pub initial_cost: f64,
+    pub final_cost: f64,
+}
+
+/// Keyframe poses tied together by relative Sim(3) constraints.
+///
+/// Vertices hold world to camera similarities so the optimizer can spread a
+/// scale correction along the graph, which is what closes a loop on a
+/// monocular trajectory whose scale drifted. The error of an edge is
+/// `log(measurement * pose(from) * pose(to)^-1)`, weighted by its information
+/// matrix, and Jacobians use the first-order adjoint approximation.
+#[derive(Debug, Clone, Default)]
+pub struct PoseGraph {
+    vertices: BTreeMap<usize, Vertex>,
+    edges: Vec<Edge>,
+}
+
+impl PoseGraph {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Adds a vertex, replacing any previous one with the same id.
+    pub fn add_vertex(&mut self, id: usize, pose: Sim3, fixed: bool) {
+        self.vertices.insert(id, Vertex { pose, fixed });
+    }
+
+    /// Adds a constraint with identity information.
+    pub fn add_edge(
+        &mut self,
+        from: usize,
+        to: usize,
+        measurement: Sim3,
+    ) -> Result<(), PoseGraphError> {
+        self.add_edge_with_information(from, to, measurement, Matrix7::identity())
+    }
+
+    pub fn add_edge_with_information(
+        &mut self,
+        from: usize,
+        to: usize,
+        measurement: Sim3,
+        information: Matrix7,
+    ) -> Result<(), PoseGraphError> {
+        for id in [from, to] {
+            if !self.vertices.contains_key(&id) {
+                return Err(PoseGraphError::UnknownVertex(id));
+            }
+        }
+        self.edges.push(Edge {
+            from,
+            to,
+            measurement,
+            information,
+        });
+        Ok(())
+    }
+
+    pub fn vertex(&self, id: usize) -> Option<&Vertex> {
+        self.vertices.get(&id)
+    }
+
+    pub fn vertices(&self) -> impl Iterator<Item = (usize, &Vertex)> {
+        self.vertices.iter().map(|(&id, v)| (id, v))
+    }
+
+    pub fn edges(&self) -> &[Edge] {
+        &self.edges
+    }
+
+    /// Weighted squared error of every edge.
+    pub fn cost(&self) -> f64 {
+        self.edges
+            .iter()
+            .map(|edge| {
+                let error = self.edge_error(edge, &self.vertices);
+                error.dot(&(edge.information * error))
+            })
+            .sum()
+    }
+
+    /// Refines the poses of all free vertices in place.
+    pub fn optimize(
+        &mut self,
+        config: &PoseGraphConfig,
+    ) -> Result<PoseGraphSummary, PoseGraphError> {
+        if !self.vertices.values().any(|v| v.fixed) {
+            return Err(PoseGraphError::NoFixedVertex);
+        }
+        let free: BTreeMap<usize, usize> = self
+            .vertices
+            .iter()
+            .filter(|(_, v)| !v.fixed)
+            .enumerate()
+            .map(|(i, (&id, _))| (id, i))
+            .collect();
+
+        let mut cost = self.cost();
+        let mut summary = PoseGraphSummary {
+            initial_cost: cost,
+            ..Default::default()
+        };
+        if free.is_empty() {
+            summary.final_cost = cost;
+            return Ok(summary);
+        }
+
+        let mut lambda = config.initial_lambda;
+        let (mut hessian, mut gradient) = self.linearize(&free);
+        while summary.iterations < config.max_iterations {
+            summary.iterations += 1;
+
+            let mut damped = hessian.clone();
+            for k in 0..damped.nrows() {
+                damped[(k, k)] += lambda * hessian[(k, k)].max(MIN_DIAGONAL);
+            }
+            let Some(cholesky) = damped.cholesky() else {
+                lambda *= 10.0;
+                if lambda > MAX_LAMBDA {
+                    break;
+                }
+                continue;
+            };
+            let step = cholesky.solve(&gradient);
+
+            let mut candidate = self.vertices.clone();
+            for (id, &i) in &free {
+                if let Some(vertex) = candidate.get_mut(id) {
+                    let delta: Vector7 = step.fixed_rows::<7>(7 * i).into_owned();
+                    vertex.pose = Sim3::exp(&delta) * vertex.pose;
+                }
+            }
+            let candidate_cost: f64 = self
+                .edges
+                .iter()
+                .map(|edge| {
+                    let error = self.edge_error(edge, &candidate);
+                    error.dot(&(edge.information * error))
+                })
+                .sum();
+
+            if candidate_cost < cost {
+                let decrease = (cost - candidate_cost) / cost.max(f64::EPSILON);
+                self.vertices = candidate;
+                cost = candidate_cost;
+                lambda = (lambda / 10.0).max(f64::EPSILON);
+                if decrease < config.min_relative_decrease {
+                    break;
+                }
+                (hessian, gradient) = self.linearize(&free);
+            } else {
+                lambda *= 10.0;
+                if lambda > MAX_LAMBDA {
+                    break;
+                }
+            }
+        }
+
+        summary.final_cost = cost;
+        Ok(summary)
+    }
+
+    fn edge_error(&self, edge: &Edge, vertices: &BTreeMap<usize, Vertex>) -> Vector7 {
+        let from = vertices[&edge.from].pose;
+        let to = vertices[&edge.to].pose;
+        (edge.measurement * from * to.inverse()).log()
+    }
+
+    /// Gauss-Newton system `H * delta = g` over the free vertices.
+    fn linearize(&self, free: &BTreeMap<usize, usize>) -> (DMatrix<f64>, DVector<f64>) {
+        let n = 7 * free.len();
+        let mut hessian = DMatrix::<f64>::zeros(n, n);
+        let mut gradient = DVector::<f64>::zeros(n);
+
+        for edge in &self.edges {
+            let from = self.vertices[&edge.from].pose;
+            let to = self.vertices[&edge.to].pose;
+            let relative = edge.measurement * from * to.inverse();
+            let error = relative.log();
+
+            // Left perturbations enter the error conjugated by whatever sits
+            // to their left in `measurement * from * to^-1`.
+            let blocks = [
+                (free.get(&edge.from), edge.measurement.adjoint()),
+                (free.get(&edge.to), -relative.adjoint()),
+            ];
+            for &(a, jacobian_a) in &blocks {
+                let Some(&a) = a else {
+                    continue;
+                };
+                let weighted = jacobian_a.transpose() * edge.information;
+                let mut rows = gradient.fixed_rows_mut::<7>(7 * a);
+                rows -= weighted * error;
+                for &(b, jacobian_b) in &blocks {
+                    let Some(&b) = b else {
+                        continue;
+                    };
+                    let mut block = hessian.fixed_view_mut::<7, 7>(7 * a, 7 * b);
+                    block += weighted * jacobian_b;
+                }
+            }
+        }
+        (hessian, gradient)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use nalgebra::{UnitQuaternion, Vector3};
+
+    /// Poses of a camera walking around a circle, looking outwards.
+    fn ring(n: usize) -> Vec<Sim3> {
+        (0..n)
+            .map(|i| {
+                let angle = i as f64 * std::f64::consts::TAU / n as f64;
+                let camera_to_world = Sim3::new(
+                    UnitQuaternion::from_scaled_axis(Vector3::new(0.0, angle, 0.0)),
+                    Vector3::new(3.0 * angle.cos(), 0.0, 3.0 * angle.sin()),
+                    1.0,
+                );
+                camera_to_world.inverse()
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_loop_edge_corrects_scale_drift() {
+        let truth = ring(12);
+        let last = truth.len() - 1;
+        let mut graph = PoseGraph::new();
+
+        // Odometry whose scale shrinks by 3% per step, integrated from vertex 0.
+        let drift = 0.97f64;
+        let mut drifted = vec![truth[0]];
+        for i in 1..truth.len() {
+            let relative = truth[i] * truth[i - 1].inverse();
+            let shrunk = Sim3::new(
+                relative.rotation,
+                relative.translation * drift.powi(i as i32),
+                1.0,
+            );
+            drifted.push(shrunk * drifted[i - 1]);
+        }
+        for (i, pose) in drifted.iter().enumerate() {
+            graph.add_vertex(i, *pose, i == 0);
+        }
+        for i in 1..truth.len() {
+            graph
+                .add_edge(i - 1, i, drifted[i] * drifted[i - 1].inverse())
+                .unwrap();
+        }
+
+        // The loop closure measures the first camera from the last one, in the
+        // last camera's drifted scale.
+        let scale = drift.powi(last as i32);
+        let relative = truth[last] * truth[0].inverse();
+        let measurement = Sim3::new(relative.rotation, relative.translation * scale, scale);
+        graph
+            .add_edge_with_information(0, last, measurement, Matrix7::identity() * 100.0)
+            .unwrap();
+
+        let summary = graph.optimize(&PoseGraphConfig::default()).unwrap();
+        assert!(summary.final_cost < 1e-3 * summary.initial_cost);
+
+        let centre = |s: &Sim3| s.inverse().translation;
+        let after = graph.vertex(last).unwrap().pose;
+        let error_before = (centre(&drifted[last]) - centre(&truth[last])).norm();
+        let error_after = (centre(&after) - centre(&truth[last])).norm();
+        assert!(error_after < 0.1 * error_before);
+        assert!((after.scale - scale).abs() < 0.05);
+    }
+
+    #[test]
+    fn test_consistent_graph_stays_put() {
+        let truth = ring(6);
+        let mut graph = PoseGraph::new();
+        for (i, pose) in truth.iter().enumerate() {
+            graph.add_vertex(i, *pose, i == 0);
+        }
+        for i in 0..truth.len() {
+            let j = (i + 1) % truth.len();
+            graph.add_edge(i, j, truth[j] * truth[i].inverse()).unwrap();
+        }
+
+        let summary = graph.optimize(&PoseGraphConfig::default()).unwrap();
+        assert!(summary.initial_cost < 1e-20);
+        for (i, vertex) in graph.vertices() {
+            assert!((vertex.pose.log() - truth[i].log()).norm() < 1e-9);
+        }
+        assert!(matches!(
+            graph.add_edge(0, 42, Sim3::identity()),
+            Err(PoseGraphError::UnknownVertex(42))
+        ));
+    }
+}
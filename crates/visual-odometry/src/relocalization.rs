@@ -0,0 +1,215 @@
+use std::collections::BTreeSet;
+
+use r_slam_common::camera::Camera;
+
+use crate::OdometryError;
+use crate::frame::Frame;
+use crate::keyframe_database::KeyFrameDatabase;
+use crate::map::{KeyFrameId, Map};
+use crate::pnp::{LocalMapPoint, PnpConfig, PnpResult, PnpTracker};
+use crate::vocabulary::{self, BowVector, FeatureVector};
+
+/// Covisible keyframes, besides the candidate, whose points refine the pose.
+const LOCAL_MAP_NEIGHBOURS: usize = 10;
+
+#[derive(Debug, Clone, Copy)]
+pub struct RelocalizationConfig {
+    /// Database candidates tried per frame, best scoring first.
+    pub max_candidates: usize,
+    pub max_descriptor_distance: u32,
+    /// Best match must be closer than `ratio` times the second best.
+    pub ratio: f32,
+    /// BoW matches with map points needed before PnP is attempted.
+    pub min_bow_matches: usize,
+    /// Used both for the pose from BoW matches and for its refinement against
+    /// the candidate's local map.
+    pub pnp: PnpConfig,
+    /// Inliers the refined pose needs before tracking resumes.
+    pub min_inliers: usize,
+}
+
+impl Default for RelocalizationConfig {
+    fn default() -> Self {
+        Self {
+            max_candidates: 5,
+            max_descriptor_distance: 50,
+            ratio: 0.75,
+            min_bow_matches: 15,
+            pnp: PnpConfig {
+                ransac_iterations: 300,
+                ..PnpConfig::default()
+            },
+            min_inliers: 30,
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Relocalization {
+    /// Keyframe the frame was recognized from.
+    pub keyframe: KeyFrameId,
+    pub tracked: PnpResult,
+}
+
+/// Recovers the pose of a frame with no usable prior, after tracking was lost.
+///
+/// Keyframes resembling the frame are looked up in the keyframe database,
+/// their map points are matched to the frame through shared vocabulary nodes,
+/// and the pose is solved with PnP RANSAC. A hypothesis is accepted once a
+/// guided search over the candidate's local map confirms it, so tracking
+/// resumes in the existing map's coordinate frame.
+pub struct Relocalizer {
+    config: RelocalizationConfig,
+    pnp: PnpTracker,
+}
+
+impl Relocalizer {
+    pub fn new(camera: Camera, config: RelocalizationConfig) -> Self {
+        Self {
+            pnp: PnpTracker::new(camera, config.pnp),
+            config,
+        }
+    }
+
+    pub fn config(&self) -> &RelocalizationConfig {
+        &self.config
+    }
+
+    /// `bow` and `features` must come from the same vocabulary, at the same
+    /// level, as the keyframes'.
+    pub fn relocalize(
+        &self,
+        map: &Map,
+        database: &KeyFrameDatabase,
+        frame: &Frame,
+        bow: &BowVector,
+        features: &FeatureVector,
+    ) -> Result<Option<Relocalization>, OdometryError> {
+        let candidates = database.query(bow, 0.0, &BTreeSet::new());
+        for (candidate, score) in candidates.into_iter().take(self.config.max_candidates) {
+            let Some(keyframe) = map.keyframe(candidate) else {
+                continue;
+            };
+            let matches = vocabulary::search_by_bow(
+                features,
+                &frame.descriptors,
+                &keyframe.features,
+                &keyframe.frame.descriptors,
+                |_, j| keyframe.map_points[j].is_some(),
+                self.config.max_descriptor_distance,
+                self.config.ratio,
+            )?;
+            if matches.len() < self.config.min_bow_matches {
+                continue;
+            }
+
+            let mut points = Vec::with_capacity(matches.len());
+            let mut correspondences = Vec::with_capacity(matches.len());
+            for (i, j) in matches {
+                let Some(point) = keyframe.map_points[j].and_then(|id| map.map_point(id)) else {
+                    continue;
+                };
+                correspondences.push((points.len(), i));
+                points.push(LocalMapPoint {
+                    id: point.id,
+                    position: point.position,
+                    descriptor: point.descriptor.clone(),
+                });
+            }
+            let initial = match self.pnp.track_matches(frame, &points, &correspondences) {
+                Ok(initial) => initial,
+                Err(OdometryError::NotEnoughPoints) => continue,
+                Err(e) => return Err(e),
+            };
+
+            // Guided search over the whole neighbourhood with the recovered
+            // pose, to confirm it and pick up the matches BoW missed.
+            let local_points = map.local_map_points(candidate, LOCAL_MAP_NEIGHBOURS);
+            let tracked = match self.pnp.track(frame, &local_points, &initial.pose) {
+                Ok(tracked) => tracked,
+                Err(OdometryError::NotEnoughPoints) => continue,
+                Err(e) => return Err(e),
+            };
+            if tracked.inliers < self.config.min_inliers {
+                continue;
+            }
+            tracing::debug!(
+                frame_id = frame.id,
+                candidate,
+                score,
+                inliers = tracked.inliers,
+                "relocalized"
+            );
+            return Ok(Some(Relocalization {
+                keyframe: candidate,
+                tracked,
+            }));
+        }
+        Ok(None)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::lie::Sim3;
+    use crate::loop_closing::LoopClosingConfig;
+    use crate::test_util::{self, SceneMap};
+    use crate::utils;
+    use nalgebra::{Isometry3, Vector3};
+
+    /// Four keyframes sliding along x, each seeing 80 points and sharing 40
+    /// with the next.
+    fn scene() -> (SceneMap, Vec<KeyFrameId>) {
+        let mut scene = SceneMap::new(200, 31);
+        let mut ids = vec![None; 200];
+        let keyframes = (0..4)
+            .map(|i| {
+                let seen: Vec<usize> = (40 * i..40 * i + 80).collect();
+                let pose = Isometry3::translation(-0.2 * i as f64, 0.0, 0.0);
+                let keyframe = scene.add_keyframe(&seen, &pose, &Sim3::identity(), &mut ids);
+                scene.index(keyframe);
+                keyframe
+            })
+            .collect();
+        (scene, keyframes)
+    }
+
+    fn relocalize(scene: &SceneMap, frame: &Frame) -> Option<Relocalization> {
+        let levels_up = LoopClosingConfig::default().levels_up;
+        let (bow, features) = scene.vocabulary.transform_frame(frame, levels_up).unwrap();
+        Relocalizer::new(test_util::camera(), RelocalizationConfig::default())
+            .relocalize(&scene.map, &scene.database, frame, &bow, &features)
+            .unwrap()
+    }
+
+    #[test]
+    fn test_recovers_pose_from_similar_keyframe() {
+        let (scene, keyframes) = scene();
+        // Near the third keyframe, seeing the same points.
+        let truth = Isometry3::new(Vector3::new(-0.45, 0.05, 0.1), Vector3::new(0.0, 0.03, 0.0));
+        let seen: Vec<usize> = (80..160).collect();
+        let frame = scene.frame(100, &seen, &truth);
+
+        let relocalization = relocalize(&scene, &frame).unwrap();
+        assert_eq!(relocalization.keyframe, keyframes[2]);
+        assert_eq!(relocalization.tracked.inliers, seen.len());
+        let error =
+            utils::pose_to_isometry(&relocalization.tracked.pose).unwrap() * truth.inverse();
+        assert!(error.translation.vector.norm() < 1e-3, "{error}");
+        assert!(error.rotation.angle() < 1e-4, "{error}");
+    }
+
+    #[test]
+    fn test_unrelated_frame_is_not_relocalized() {
+        let (scene, _) = scene();
+        let points = &scene.points[80..160];
+        let frame = test_util::frame(
+            100,
+            points,
+            &Isometry3::identity(),
+            &test_util::descriptors(points.len(), 99),
+        );
+        assert!(relocalize(&scene, &frame).is_none());
+    }
+}
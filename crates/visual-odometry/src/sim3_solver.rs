@@ -0,0 +1,259 @@
+use nalgebra::{Matrix3, Rotation3, UnitQuaternion, Vector2, Vector3};
+use r_slam_common::camera::Camera;
+
+use crate::lie::Sim3;
+use crate::utils::SplitMix64;
+
+#[derive(Debug, Clone, Copy)]
+pub struct Sim3SolverConfig {
+    pub ransac_iterations: usize,
+    pub min_inliers: usize,
+    /// Squared reprojection error, in pixels, an inlier may have in either
+    /// camera. The default is the 99% chi-square bound for two degrees of
+    /// freedom.
+    pub max_error_sq: f64,
+    /// Seed for hypothesis sampling, so results are repeatable.
+    pub seed: u64,
+}
+
+impl Default for Sim3SolverConfig {
+    fn default() -> Self {
+        Self {
+            ransac_iterations: 300,
+            min_inliers: 20,
+            max_error_sq: 9.21,
+            seed: 0x51_3d,
+        }
+    }
+}
+
+/// A 3D point seen from two cameras.
+#[derive(Debug, Clone, Copy)]
+pub struct Sim3Correspondence {
+    /// Position in the first camera's coordinates.
+    pub point1: Vector3<f64>,
+    /// Position in the second camera's coordinates.
+    pub point2: Vector3<f64>,
+    /// Undistorted pixel the point was observed at in the first camera.
+    pub pixel1: Vector2<f64>,
+    pub pixel2: Vector2<f64>,
+}
+
+#[derive(Debug, Clone)]
+pub struct Sim3Estimate {
+    /// Maps the second camera's coordinates into the first's.
+    pub sim3: Sim3,
+    /// One flag per correspondence.
+    pub inliers: Vec<bool>,
+    pub num_inliers: usize,
+}
+
+/// Estimates the similarity between two cameras from matched 3D points.
+///
+/// Each monocular map has its own drifting scale, so the transform between
+/// two keyframes that see the same place is a similarity, not a rigid motion.
+/// Hypotheses come from the closed-form Umeyama alignment of three sampled
+/// correspondences and are scored by reprojecting every point into both
+/// cameras; the best one is refitted on all of its inliers.
+pub struct Sim3Solver {
+    camera: Camera,
+    config: Sim3SolverConfig,
+}
+
+impl Sim3Solver {
+    pub fn new(camera: Camera, config: Sim3SolverConfig) -> Self {
+        Self { camera, config }
+    }
+
+    pub fn config(&self) -> &Sim3SolverConfig {
+        &self.config
+    }
+
+    /// Returns `None` unless some hypothesis reaches `min_inliers`.
+    pub fn solve(&self, correspondences: &[Sim3Correspondence]) -> Option<Sim3Estimate> {
+        if correspondences.len() < self.config.min_inliers.max(3) {
+            return None;
+        }
+
+        let mut rng = SplitMix64(self.config.seed);
+        let mut best: Option<Sim3Estimate> = None;
+        for _ in 0..self.config.ransac_iterations {
+            let sample = sample_three(&mut rng, correspondences.len());
+            let points2: Vec<_> = sample.iter().map(|&i| correspondences[i].point2).collect();
+            let points1: Vec<_> = sample.iter().map(|&i| correspondences[i].point1).collect();
+            let Some(sim3) = umeyama(&points2, &points1) else {
+                continue;
+            };
+            let estimate = self.score(sim3, correspondences);
+            if best
+                .as_ref()
+                .is_none_or(|b| estimate.num_inliers > b.num_inliers)
+            {
+                best = Some(estimate);
+            }
+        }
+
+        let best = best.filter(|b| b.num_inliers >= self.config.min_inliers)?;
+        let (points2, points1): (Vec<_>, Vec<_>) = correspondences
+            .iter()
+            .zip(&best.inliers)
+            .filter(|&(_, &inlier)| inlier)
+            .map(|(c, _)| (c.point2, c.point1))
+            .unzip();
+        let refined = umeyama(&points2, &points1)
+            .map(|sim3| self.score(sim3, correspondences))
+            .filter(|refined| refined.num_inliers >= best.num_inliers);
+        Some(refined.unwrap_or(best))
+    }
+
+    fn score(&self, sim3: Sim3, correspondences: &[Sim3Correspondence]) -> Sim3Estimate {
+        let inverse = sim3.inverse();
+        let inliers: Vec<bool> = correspondences
+            .iter()
+            .map(|c| {
+                let in1 = sim3.transform_point(&c.point2);
+                let in2 = inverse.transform_point(&c.point1);
+                self.error_sq(&in1, &c.pixel1) <= self.config.max_error_sq
+                    && self.error_sq(&in2, &c.pixel2) <= self.config.max_error_sq
+            })
+            .collect();
+        Sim3Estimate {
+            sim3,
+            num_inliers: inliers.iter().filter(|&&inlier| inlier).count(),
+            inliers,
+        }
+    }
+
+    fn error_sq(&self, point: &Vector3<f64>, pixel: &Vector2<f64>) -> f64 {
+        if point.z <= 0.0 {
+            return f64::INFINITY;
+        }
+        let u = self.camera.fx * point.x / point.z + self.camera.cx;
+        let v = self.camera.fy * point.y / point.z + self.camera.cy;
+        (u - pixel.x).powi(2) + (v - pixel.y).powi(2)
+    }
+}
+
+fn sample_three(rng: &mut SplitMix64, n: usize) -> [usize; 3] {
+    let a = rng.below(n);
+    let mut b = rng.below(n - 1);
+    if b >= a {
+        b += 1;
+    }
+    let (low, high) = (a.min(b), a.max(b));
+    let mut c = rng.below(n - 2);
+    if c >= low {
+        c += 1;
+    }
+    if c >= high {
+        c += 1;
+    }
+    [a, b, c]
+}
+
+/// Least-squares similarity taking `from` onto `to` (Umeyama, 1991).
+///
+/// Returns `None` for fewer than three points or when the points are
+/// degenerate, e.g. all coincident.
+pub fn umeyama(from: &[Vector3<f64>], to: &[Vector3<f64>]) -> Option<Sim3> {
+    let n = from.len();
+    if n < 3 || to.len() != n {
+        return None;
+    }
+    let mean_from = from.iter().sum::<Vector3<f64>>() / n as f64;
+    let mean_to = to.iter().sum::<Vector3<f64>>() / n as f64;
+
+    let mut covariance = Matrix3::zeros();
+    let mut variance = 0.0;
+    for (f, t) in from.iter().zip(to) {
+        let f = f - mean_from;
+        covariance += (t - mean_to) * f.transpose();
+        variance += f.norm_squared();
+    }
+    covariance /= n as f64;
+    variance /= n as f64;
+    if variance <= f64::EPSILON {
+        return None;
+    }
+
+    let svd = covariance.svd(true, true);
+    let (u, v_t) = (svd.u?, svd.v_t?);
+    // Flip the weakest axis when the best orthogonal fit is a reflection.
+    let mut signs = Vector3::new(1.0, 1.0, 1.0);
+    if u.determinant() * v_t.determinant() < 0.0 {
+        signs.z = -1.0;
+    }
+    let rotation = u * Matrix3::from_diagonal(&signs) * v_t;
+    let scale = svd.singular_values.dot(&signs) / variance;
+    if !scale.is_finite() || scale <= 0.0 {
+        return None;
+    }
+
+    let rotation =
+        UnitQuaternion::from_rotation_matrix(&Rotation3::from_matrix_unchecked(rotation));
+    let translation = mean_to - scale * (rotation * mean_from);
+    Some(Sim3::new(rotation, translation, scale))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::{self, project};
+    use approx::assert_relative_eq;
+
+    fn truth() -> Sim3 {
+        Sim3::new(
+            UnitQuaternion::from_scaled_axis(Vector3::new(0.05, -0.3, 0.02)),
+            Vector3::new(0.4, -0.1, 0.3),
+            1.7,
+        )
+    }
+
+    #[test]
+    fn test_umeyama_recovers_similarity() {
+        let s12 = truth();
+        let from: Vec<_> = (0..10)
+            .map(|i| {
+                let i = i as f64;
+                Vector3::new(i.sin(), (2.0 * i).cos(), 3.0 + 0.1 * i)
+            })
+            .collect();
+        let to: Vec<_> = from.iter().map(|p| s12.transform_point(p)).collect();
+
+        let estimate = umeyama(&from, &to).unwrap();
+        assert_relative_eq!(estimate.scale, s12.scale, epsilon = 1e-9);
+        assert_relative_eq!((estimate * s12.inverse()).log().norm(), 0.0, epsilon = 1e-9);
+        assert!(umeyama(&from[..2], &to[..2]).is_none());
+    }
+
+    #[test]
+    fn test_ransac_rejects_outliers() {
+        let s12 = truth();
+        let s21 = s12.inverse();
+        let mut correspondences: Vec<Sim3Correspondence> = test_util::scene(60)
+            .into_iter()
+            .map(|point2| {
+                let point1 = s12.transform_point(&point2);
+                Sim3Correspondence {
+                    point1,
+                    point2,
+                    pixel1: project(&point1),
+                    pixel2: project(&s21.transform_point(&point1)),
+                }
+            })
+            .collect();
+        // Every fourth match pairs unrelated points.
+        for i in (0..correspondences.len()).step_by(4) {
+            correspondences[i].point1 += Vector3::new(0.8, -0.5, 0.6);
+            correspondences[i].pixel1 = project(&correspondences[i].point1);
+        }
+
+        let solver = Sim3Solver::new(test_util::camera(), Sim3SolverConfig::default());
+        let estimate = solver.solve(&correspondences).unwrap();
+        assert_eq!(estimate.num_inliers, 45);
+        for (i, &inlier) in estimate.inliers.iter().enumerate() {
+            assert_eq!(inlier, i % 4 != 0);
+        }
+        assert_relative_eq!(estimate.sim3.scale, s12.scale, epsilon = 1e-6);
+    }
+}
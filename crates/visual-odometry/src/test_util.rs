@@ -0,0 +1,279 @@
+//! Fixtures shared by the unit tests: an undistorted pinhole camera,
+//! synthetic scenes seen from known poses and maps built from them.
+
+use nalgebra::{Isometry3, UnitQuaternion, Vector2, Vector3};
+use opencv::core::{CV_8UC1, DMatch, KeyPoint, Mat, Point3d, Scalar, Vector};
+use opencv::prelude::*;
+use r_slam_common::camera::Camera;
+
+use crate::OdometryError;
+use crate::feature_extractor::{DescriptorMetric, FeatureExtractor};
+use crate::frame::Frame;
+use crate::keyframe_database::KeyFrameDatabase;
+use crate::lie::Sim3;
+use crate::loop_closing::LoopClosingConfig;
+use crate::map::{KeyFrameId, Map, MapPointId};
+use crate::utils::{self, SplitMix64};
+use crate::vocabulary::{self, Descriptor, Vocabulary, VocabularyConfig};
+
+pub(crate) const WIDTH: i32 = 640;
+pub(crate) const HEIGHT: i32 = 480;
+
+/// 640x480 pinhole camera with a focal length of 500 pixels.
+pub(crate) fn camera() -> Camera {
+    let camera_matrix =
+        Mat::from_slice_2d(&[[500.0, 0.0, 320.0], [0.0, 500.0, 240.0], [0.0, 0.0, 1.0]]).unwrap();
+    Camera::new(camera_matrix, Vector::from_slice(&[0.0; 5]), WIDTH, HEIGHT).unwrap()
+}
+
+/// Pixel a point in `camera` coordinates projects to.
+pub(crate) fn project(point: &Vector3<f64>) -> Vector2<f64> {
+    Vector2::new(
+        500.0 * point.x / point.z + 320.0,
+        500.0 * point.y / point.z + 240.0,
+    )
+}
+
+/// `n` points spread over the view of a camera at the origin, 3 to 5 units
+/// in front of it.
+pub(crate) fn scene(n: usize) -> Vec<Vector3<f64>> {
+    (0..n)
+        .map(|i| {
+            let t = i as f64;
+            Vector3::new(
+                (t * 0.37).sin() * 1.5,
+                (t * 0.73).cos(),
+                4.0 + (t * 0.19).sin(),
+            )
+        })
+        .collect()
+}
+
+/// `n` random 32 byte descriptors, one per row. Rows are about 128 bits
+/// apart, and the same `seed` always gives the same rows.
+pub(crate) fn descriptors(n: usize, seed: u64) -> Mat {
+    let mut rng = SplitMix64(seed);
+    let mut descriptors =
+        Mat::new_rows_cols_with_default(n as i32, 32, CV_8UC1, Scalar::all(0.0)).unwrap();
+    for byte in descriptors.data_bytes_mut().unwrap() {
+        *byte = rng.next() as u8;
+    }
+    descriptors
+}
+
+/// Frame `id` seeing `points` from the world to camera `pose`: keypoint `i`
+/// is point `i` with descriptor row `i`. Points behind the camera or outside
+/// the image get a keypoint all the same.
+pub(crate) fn frame(
+    id: usize,
+    points: &[Vector3<f64>],
+    pose: &Isometry3<f64>,
+    descriptors: &Mat,
+) -> Frame {
+    let keypoints = Vector::from_iter(points.iter().map(|p| {
+        let pixel = project(&pose.transform_point(&(*p).into()).coords);
+        KeyPoint::new_coords_def(pixel.x as f32, pixel.y as f32, 31.0).unwrap()
+    }));
+    Frame::new(
+        id,
+        Mat::default(),
+        keypoints,
+        descriptors.try_clone().unwrap(),
+    )
+}
+
+/// Rows `indices` of `descriptors`, in that order.
+pub(crate) fn rows(descriptors: &Mat, indices: &[usize]) -> Mat {
+    let mut out = Mat::default();
+    if !indices.is_empty() {
+        let rows = Vector::<Mat>::from_iter(
+            indices
+                .iter()
+                .map(|&i| descriptors.row(i as i32).unwrap().try_clone().unwrap()),
+        );
+        opencv::core::vconcat(&rows, &mut out).unwrap();
+    }
+    out
+}
+
+/// Vocabulary trained with every row of `descriptors` as an image of its
+/// own, so rows rarely share a word and every word weighs the same.
+pub(crate) fn vocabulary(descriptors: &Mat) -> Vocabulary {
+    let images: Vec<Vec<Descriptor>> = vocabulary::descriptors_from_mat(descriptors)
+        .unwrap()
+        .into_iter()
+        .map(|descriptor| vec![descriptor])
+        .collect();
+    let config = VocabularyConfig {
+        branching: 8,
+        depth: 3,
+        ..Default::default()
+    };
+    Vocabulary::train(&images, &config).unwrap()
+}
+
+/// Map of a synthetic scene whose points have random descriptors, with a
+/// vocabulary trained on them and an empty keyframe database.
+pub(crate) struct SceneMap {
+    pub(crate) map: Map,
+    pub(crate) database: KeyFrameDatabase,
+    pub(crate) vocabulary: Vocabulary,
+    pub(crate) points: Vec<Vector3<f64>>,
+    pub(crate) descriptors: Mat,
+}
+
+impl SceneMap {
+    /// `scene(n)`, with descriptors drawn from `seed`.
+    pub(crate) fn new(n: usize, seed: u64) -> Self {
+        let descriptors = descriptors(n, seed);
+        Self {
+            map: Map::new(),
+            database: KeyFrameDatabase::new(),
+            vocabulary: vocabulary(&descriptors),
+            points: scene(n),
+            descriptors,
+        }
+    }
+
+    /// Frame `id` seeing the scene points `seen` from the world to camera
+    /// `pose`, keypoint `k` being point `seen[k]`.
+    pub(crate) fn frame(&self, id: usize, seen: &[usize], pose: &Isometry3<f64>) -> Frame {
+        let points: Vec<Vector3<f64>> = seen.iter().map(|&i| self.points[i]).collect();
+        frame(id, &points, pose, &rows(&self.descriptors, seen))
+    }
+
+    /// Adds a keyframe seeing the scene points `seen` from the world to
+    /// camera `pose`, with its BoW vectors but not yet in the database.
+    ///
+    /// The map holds the scene mapped through `drift`, as a reconstruction
+    /// that drifted would. Keypoint `k` observes `ids[seen[k]]`, created
+    /// there if still `None`, so keyframes sharing `ids` share points.
+    pub(crate) fn add_keyframe(
+        &mut self,
+        seen: &[usize],
+        pose: &Isometry3<f64>,
+        drift: &Sim3,
+        ids: &mut [Option<MapPointId>],
+    ) -> KeyFrameId {
+        let frame = self.frame(self.map.num_keyframes(), seen, pose);
+        // Camera coordinates grow with the drift's scale too, which leaves
+        // the stored pose rigid.
+        let scale = Sim3::new(UnitQuaternion::identity(), Vector3::zeros(), drift.scale);
+        let stored = scale * Sim3::from_se3(&(*pose).into(), 1.0) * drift.inverse();
+        let keyframe = self
+            .map
+            .insert_keyframe(frame, 0.0, utils::sim3_to_pose(&stored).unwrap());
+        for (k, &i) in seen.iter().enumerate() {
+            let point = match ids[i] {
+                Some(point) => point,
+                None => {
+                    let position = drift.transform_point(&self.points[i]);
+                    let descriptor = self.descriptors.row(i as i32).unwrap().try_clone().unwrap();
+                    let point = self
+                        .map
+                        .insert_map_point(
+                            Point3d::new(position.x, position.y, position.z),
+                            descriptor,
+                            keyframe,
+                        )
+                        .unwrap();
+                    ids[i] = Some(point);
+                    point
+                }
+            };
+            self.map.add_observation(keyframe, point, k).unwrap();
+        }
+
+        let levels_up = LoopClosingConfig::default().levels_up;
+        let kf = self.map.keyframe_mut(keyframe).unwrap();
+        (kf.bow, kf.features) = self
+            .vocabulary
+            .transform_frame(&kf.frame, levels_up)
+            .unwrap();
+        keyframe
+    }
+
+    /// Adds `keyframe` to the database.
+    pub(crate) fn index(&mut self, keyframe: KeyFrameId) {
+        let bow = self.map.keyframe(keyframe).unwrap().bow.clone();
+        self.database.add(keyframe, bow);
+    }
+}
+
+/// Matches pairing keypoint `i` of one frame with keypoint `i` of another.
+pub(crate) fn one_to_one(n: usize) -> Vector<DMatch> {
+    Vector::from_iter((0..n as i32).map(|i| DMatch::new(i, i, 0.0).unwrap()))
+}
+
+/// Extractor replaying synthetic views instead of detecting anything. An
+/// image from `view_image(i)` yields the keypoints and descriptors of view
+/// `i`, a black image yields none.
+pub(crate) struct ReplayExtractor {
+    views: Vec<(Vector<KeyPoint>, Mat)>,
+    descriptor_size: usize,
+}
+
+impl ReplayExtractor {
+    /// Views of `points`, with the matching rows of `descriptors`, from each
+    /// of `poses`. Only points in front of the camera and inside the image
+    /// are kept.
+    pub(crate) fn new(
+        points: &[Vector3<f64>],
+        descriptors: &Mat,
+        poses: &[Isometry3<f64>],
+    ) -> Self {
+        let views = poses
+            .iter()
+            .map(|pose| {
+                let mut keypoints = Vector::<KeyPoint>::new();
+                let mut visible = Vec::new();
+                for (i, point) in points.iter().enumerate() {
+                    let camera_point = pose.transform_point(&(*point).into()).coords;
+                    let pixel = project(&camera_point);
+                    let inside = (0.0..WIDTH as f64).contains(&pixel.x)
+                        && (0.0..HEIGHT as f64).contains(&pixel.y);
+                    if camera_point.z <= 0.0 || !inside {
+                        continue;
+                    }
+                    keypoints.push(
+                        KeyPoint::new_coords_def(pixel.x as f32, pixel.y as f32, 31.0).unwrap(),
+                    );
+                    visible.push(i);
+                }
+                (keypoints, rows(descriptors, &visible))
+            })
+            .collect();
+        Self {
+            views,
+            descriptor_size: descriptors.cols() as usize,
+        }
+    }
+}
+
+/// Image standing for view `index` of a `ReplayExtractor`, counting from 0.
+pub(crate) fn view_image(index: usize) -> Mat {
+    Mat::new_rows_cols_with_default(HEIGHT, WIDTH, CV_8UC1, Scalar::all(index as f64 + 1.0))
+        .unwrap()
+}
+
+impl FeatureExtractor for ReplayExtractor {
+    fn detect_and_compute(
+        &mut self,
+        image: &Mat,
+        _mask: &Mat,
+    ) -> Result<(Vector<KeyPoint>, Mat), OdometryError> {
+        let value = *image.at_2d::<u8>(0, 0)? as usize;
+        match value.checked_sub(1).and_then(|view| self.views.get(view)) {
+            Some((keypoints, descriptors)) => Ok((keypoints.clone(), descriptors.try_clone()?)),
+            None => Ok((Vector::new(), Mat::default())),
+        }
+    }
+
+    fn metric(&self) -> DescriptorMetric {
+        DescriptorMetric::Hamming
+    }
+
+    fn descriptor_size(&self) -> Result<usize, OdometryError> {
+        Ok(self.descriptor_size)
+    }
+}
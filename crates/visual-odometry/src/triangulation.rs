@@ -0,0 +1,271 @@
+use opencv::calib3d::triangulate_points;
+use opencv::core::{CV_32FC1, CV_64F, DMatch, Mat, Point2d, Point2f, Point3d, Scalar, Vector};
+use opencv::prelude::*;
+use r_slam_common::camera::Camera;
+
+use crate::OdometryError;
+use crate::frame::Frame;
+use crate::utils::{self, add3, dot3, mul3v, norm3, scale3, sub3, transpose3};
+
+#[derive(Debug, Clone, Copy)]
+pub struct TriangulationConfig {
+    /// Largest reprojection error accepted in either view, in pixels.
+    pub max_reprojection_error: f64,
+    /// Smallest angle between the two viewing rays, in degrees.
+    pub min_parallax_deg: f64,
+    /// Points must be further than this in front of both cameras.
+    pub min_depth: f64,
+    pub max_depth: Option<f64>,
+}
+
+impl Default for TriangulationConfig {
+    fn default() -> Self {
+        Self {
+            max_reprojection_error: 2.0,
+            min_parallax_deg: 1.0,
+            min_depth: 0.0,
+            max_depth: None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct TriangulatedPoint {
+    /// Index of the match this point came from.
+    pub match_index: usize,
+    /// Position in world coordinates.
+    pub position: Point3d,
+    /// Larger of the two reprojection errors, in pixels.
+    pub reprojection_error: f64,
+    pub parallax_deg: f64,
+    /// Keypoint location in the first frame's image.
+    pub pixel: Point2f,
+    /// Depth along the first camera's optical axis, in map units.
+    pub depth: f64,
+}
+
+/// Triangulates matched keypoints between two posed frames.
+///
+/// Poses are world to camera transforms (4x4, CV_64F). `query_idx` indexes
+/// `frame1` and `train_idx` indexes `frame2`, as produced by `frame_match`.
+/// Points behind either camera, with too little parallax or a large
+/// reprojection error are dropped.
+pub fn triangulate(
+    camera: &Camera,
+    frame1: &Frame,
+    pose1: &Mat,
+    frame2: &Frame,
+    pose2: &Mat,
+    matches: &Vector<DMatch>,
+    config: &TriangulationConfig,
+) -> Result<Vec<TriangulatedPoint>, OdometryError> {
+    if matches.is_empty() {
+        return Ok(Vec::new());
+    }
+
+    let (points1, points2) = utils::undistorted_matches(camera, frame1, frame2, matches)?;
+    let (rotation1, translation1) = utils::split_pose(pose1)?;
+    let (rotation2, translation2) = utils::split_pose(pose2)?;
+    let world = linear_triangulate(
+        &projection_matrix(camera, &rotation1, &translation1)?,
+        &projection_matrix(camera, &rotation2, &translation2)?,
+        &points1,
+        &points2,
+    )?;
+
+    let centre1 = scale3(&mul3v(&transpose3(&rotation1), &translation1), -1.0);
+    let centre2 = scale3(&mul3v(&transpose3(&rotation2), &translation2), -1.0);
+    let min_cos_parallax = config.min_parallax_deg.to_radians().cos();
+    let max_error_sq = config.max_reprojection_error * config.max_reprojection_error;
+
+    let mut out = Vec::new();
+    for (i, point) in world.iter().enumerate() {
+        if !point.iter().all(|v| v.is_finite()) {
+            continue;
+        }
+
+        let camera1 = add3(&mul3v(&rotation1, point), &translation1);
+        let camera2 = add3(&mul3v(&rotation2, point), &translation2);
+        let in_range = |z: f64| z > config.min_depth && config.max_depth.is_none_or(|max| z <= max);
+        if !in_range(camera1[2]) || !in_range(camera2[2]) {
+            continue;
+        }
+
+        let ray1 = sub3(point, &centre1);
+        let ray2 = sub3(point, &centre2);
+        let cos_parallax = dot3(&ray1, &ray2) / (norm3(&ray1) * norm3(&ray2));
+        if cos_parallax > min_cos_parallax {
+            continue;
+        }
+
+        let error1 = utils::reprojection_error_sq(camera, &camera1, &points1[i]);
+        let error2 = utils::reprojection_error_sq(camera, &camera2, &points2[i]);
+        if error1 > max_error_sq || error2 > max_error_sq {
+            continue;
+        }
+
+        let m = matches.get(i)?;
+        out.push(TriangulatedPoint {
+            match_index: i,
+            position: Point3d::new(point[0], point[1], point[2]),
+            reprojection_error: error1.max(error2).sqrt(),
+            parallax_deg: cos_parallax.clamp(-1.0, 1.0).acos().to_degrees(),
+            pixel: frame1.keypoints.get(m.query_idx as usize)?.pt(),
+            depth: camera1[2],
+        });
+    }
+
+    Ok(out)
+}
+
+/// Rasterizes triangulated points into a CV_32FC1 depth map of the first
+/// frame, zero where there is no point.
+///
+/// The layout matches the dense maps from `depth-estimate`, so the two can be
+/// compared pixel by pixel to recover a scale, or passed straight to
+/// `depth_map_to_point_cloud`.
+pub fn sparse_depth_map(
+    points: &[TriangulatedPoint],
+    rows: i32,
+    cols: i32,
+) -> Result<Mat, OdometryError> {
+    let mut depth = Mat::new_rows_cols_with_default(rows, cols, CV_32FC1, Scalar::all(0.0))?;
+    for point in points {
+        let u = point.pixel.x.round() as i32;
+        let v = point.pixel.y.round() as i32;
+        if u < 0 || v < 0 || u >= cols || v >= rows {
+            continue;
+        }
+        *depth.at_2d_mut::<f32>(v, u)? = point.depth as f32;
+    }
+    Ok(depth)
+}
+
+/// Builds `K [R | t]` as a 3x4 CV_64F matrix.
+pub(crate) fn projection_matrix(
+    camera: &Camera,
+    rotation: &[[f64; 3]; 3],
+    translation: &[f64; 3],
+) -> Result<Mat, OdometryError> {
+    let k = utils::mat3(&camera.camera_matrix)?;
+    let mut rt = [[0.0; 4]; 3];
+    for r in 0..3 {
+        rt[r][..3].copy_from_slice(&rotation[r]);
+        rt[r][3] = translation[r];
+    }
+    let mut p = [[0.0; 4]; 3];
+    for r in 0..3 {
+        for c in 0..4 {
+            p[r][c] = (0..3).map(|i| k[r][i] * rt[i][c]).sum();
+        }
+    }
+    Ok(Mat::from_slice_2d(&p)?)
+}
+
+/// Linear (DLT) triangulation of undistorted pixel correspondences. Points at
+/// infinity come back non-finite.
+pub(crate) fn linear_triangulate(
+    projection1: &Mat,
+    projection2: &Mat,
+    points1: &[Point2d],
+    points2: &[Point2d],
+) -> Result<Vec<[f64; 3]>, OdometryError> {
+    if points1.is_empty() {
+        return Ok(Vec::new());
+    }
+
+    let mut homogeneous = Mat::default();
+    triangulate_points(
+        projection1,
+        projection2,
+        &Vector::<Point2d>::from_iter(points1.iter().copied()),
+        &Vector::<Point2d>::from_iter(points2.iter().copied()),
+        &mut homogeneous,
+    )?;
+    let mut points4d = Mat::default();
+    homogeneous.convert_to(&mut points4d, CV_64F, 1.0, 0.0)?;
+
+    let mut out = Vec::with_capacity(points1.len());
+    for column in 0..points4d.cols() {
+        let w = *points4d.at_2d::<f64>(3, column)?;
+        out.push([
+            *points4d.at_2d::<f64>(0, column)? / w,
+            *points4d.at_2d::<f64>(1, column)? / w,
+            *points4d.at_2d::<f64>(2, column)? / w,
+        ]);
+    }
+    Ok(out)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util;
+    use nalgebra::{Isometry3, Vector3};
+
+    /// Triangulates the views of `points` from `pose1` and `pose2`, matched
+    /// one to one. Returns the first frame too.
+    fn triangulate_views(
+        points: &[Vector3<f64>],
+        pose1: &Isometry3<f64>,
+        pose2: &Isometry3<f64>,
+    ) -> (Frame, Vec<TriangulatedPoint>) {
+        let frame1 = test_util::frame(0, points, pose1, &Mat::default());
+        let frame2 = test_util::frame(1, points, pose2, &Mat::default());
+        let triangulated = triangulate(
+            &test_util::camera(),
+            &frame1,
+            &utils::isometry_to_pose(pose1).unwrap(),
+            &frame2,
+            &utils::isometry_to_pose(pose2).unwrap(),
+            &test_util::one_to_one(points.len()),
+            &TriangulationConfig::default(),
+        )
+        .unwrap();
+        (frame1, triangulated)
+    }
+
+    fn second_pose() -> Isometry3<f64> {
+        Isometry3::new(Vector3::new(-0.5, 0.05, 0.1), Vector3::new(0.0, 0.05, 0.0))
+    }
+
+    #[test]
+    fn test_recovers_projected_points() {
+        let points = test_util::scene(40);
+        let pose1 = Isometry3::translation(0.1, 0.0, 0.0);
+        let (frame1, triangulated) = triangulate_views(&points, &pose1, &second_pose());
+
+        assert_eq!(triangulated.len(), points.len());
+        for (i, point) in triangulated.iter().enumerate() {
+            assert_eq!(point.match_index, i);
+            let expected = points[i];
+            let position = Vector3::new(point.position.x, point.position.y, point.position.z);
+            assert!(
+                (position - expected).norm() < 1e-3,
+                "{position:?} != {expected:?}"
+            );
+            assert!((point.depth - expected.z).abs() < 1e-3);
+            assert!(point.reprojection_error < 0.01);
+            assert!(point.parallax_deg > 1.0);
+            assert_eq!(point.pixel, frame1.keypoints.get(i).unwrap().pt());
+        }
+    }
+
+    #[test]
+    fn test_rejects_points_behind_cameras() {
+        let mut points = test_util::scene(40);
+        // These project like points in front, mirrored through the centre,
+        // and triangulate to where they really are.
+        points.insert(3, Vector3::new(0.4, -0.2, -5.0));
+        points.insert(10, Vector3::new(-0.8, 0.3, -3.0));
+        let (_, triangulated) = triangulate_views(&points, &Isometry3::identity(), &second_pose());
+
+        assert_eq!(triangulated.len(), points.len() - 2);
+        assert!(triangulated.iter().all(|p| p.depth > 0.0));
+        assert!(
+            triangulated
+                .iter()
+                .all(|p| p.match_index != 3 && p.match_index != 10)
+        );
+    }
+}
@@ -0,0 +1,296 @@
+use nalgebra::{Isometry3, Matrix3, Rotation3, Translation3, UnitQuaternion};
+use opencv::calib3d::rodrigues;
+use opencv::core::{
+    CV_64F, DECOMP_LU, DMatch, KeyPoint, Mat, Point2d, Point2f, Rect, Vector, gemm, no_array,
+};
+use opencv::prelude::*;
+use r_slam_common::camera::Camera;
+
+use crate::frame::Frame;
+use crate::lie::Sim3;
+
+/// Builds a 4x4 CV_64F homogeneous transform from a 3x3 rotation and a 3x1 translation.
+pub(crate) fn to_homogeneous(rotation: &Mat, translation: &Mat) -> Result<Mat, opencv::Error> {
+    let mut transform = Mat::eye(4, 4, CV_64F)?.to_mat()?;
+    let mut r = Mat::default();
+    let mut t = Mat::default();
+    rotation.convert_to(&mut r, CV_64F, 1.0, 0.0)?;
+    translation.convert_to(&mut t, CV_64F, 1.0, 0.0)?;
+    r.copy_to(&mut Mat::roi_mut(&mut transform, Rect::new(0, 0, 3, 3))?)?;
+    t.copy_to(&mut Mat::roi_mut(&mut transform, Rect::new(3, 0, 1, 3))?)?;
+    Ok(transform)
+}
+
+/// Builds a 4x4 CV_64F transform from a Rodrigues rotation vector and a translation.
+pub(crate) fn pose_from_rvec(rvec: &Mat, tvec: &Mat) -> Result<Mat, opencv::Error> {
+    let mut rotation = Mat::default();
+    rodrigues(rvec, &mut rotation, &mut no_array())?;
+    to_homogeneous(&rotation, tvec)
+}
+
+/// Splits a 4x4 transform into a Rodrigues rotation vector and a translation,
+/// the inverse of `pose_from_rvec`.
+pub(crate) fn rvec_from_pose(pose: &Mat) -> Result<(Mat, Mat), opencv::Error> {
+    let rotation = pose.roi(Rect::new(0, 0, 3, 3))?.try_clone()?;
+    let translation = pose.roi(Rect::new(3, 0, 1, 3))?.try_clone()?;
+    let mut rvec = Mat::default();
+    rodrigues(&rotation, &mut rvec, &mut no_array())?;
+    Ok((rvec, translation))
+}
+
+/// Matrix product `a * b`.
+pub(crate) fn mat_mul(a: &Mat, b: &Mat) -> Result<Mat, opencv::Error> {
+    let mut out = Mat::default();
+    gemm(a, b, 1.0, &no_array(), 0.0, &mut out, 0)?;
+    Ok(out)
+}
+
+/// Inverse of a 4x4 homogeneous transform.
+pub(crate) fn invert_pose(pose: &Mat) -> Result<Mat, opencv::Error> {
+    pose.inv(DECOMP_LU)?.to_mat()
+}
+
+/// Reads a 3x3 CV_64F matrix into a row-major array.
+pub(crate) fn mat3(m: &Mat) -> Result<[[f64; 3]; 3], opencv::Error> {
+    let mut out = [[0.0; 3]; 3];
+    for (r, row) in out.iter_mut().enumerate() {
+        for (c, value) in row.iter_mut().enumerate() {
+            *value = *m.at_2d::<f64>(r as i32, c as i32)?;
+        }
+    }
+    Ok(out)
+}
+
+/// Reads a 3 element CV_64F vector (3x1 or 1x3).
+pub(crate) fn vec3(m: &Mat) -> Result<[f64; 3], opencv::Error> {
+    Ok([*m.at::<f64>(0)?, *m.at::<f64>(1)?, *m.at::<f64>(2)?])
+}
+
+/// Splits a 4x4 CV_64F transform into its rotation and translation.
+pub(crate) fn split_pose(pose: &Mat) -> Result<([[f64; 3]; 3], [f64; 3]), opencv::Error> {
+    let mut rotation = [[0.0; 3]; 3];
+    let mut translation = [0.0; 3];
+    for r in 0..3 {
+        for c in 0..3 {
+            rotation[r][c] = *pose.at_2d::<f64>(r as i32, c as i32)?;
+        }
+        translation[r] = *pose.at_2d::<f64>(r as i32, 3)?;
+    }
+    Ok((rotation, translation))
+}
+
+/// Converts a 4x4 CV_64F rigid transform to an isometry.
+pub(crate) fn pose_to_isometry(pose: &Mat) -> Result<Isometry3<f64>, opencv::Error> {
+    let (r, t) = split_pose(pose)?;
+    let rotation = Rotation3::from_matrix(&Matrix3::new(
+        r[0][0], r[0][1], r[0][2], r[1][0], r[1][1], r[1][2], r[2][0], r[2][1], r[2][2],
+    ));
+    Ok(Isometry3::from_parts(
+        Translation3::new(t[0], t[1], t[2]),
+        UnitQuaternion::from_rotation_matrix(&rotation),
+    ))
+}
+
+/// Converts an isometry to a 4x4 CV_64F transform.
+pub(crate) fn isometry_to_pose(isometry: &Isometry3<f64>) -> Result<Mat, opencv::Error> {
+    let m = isometry.to_homogeneous();
+    let mut rows = [[0.0; 4]; 4];
+    for (r, row) in rows.iter_mut().enumerate() {
+        for (c, value) in row.iter_mut().enumerate() {
+            *value = m[(r, c)];
+        }
+    }
+    Mat::from_slice_2d(&rows)
+}
+
+/// Converts a 4x4 CV_64F rigid transform to a unit-scale similarity.
+pub(crate) fn pose_to_sim3(pose: &Mat) -> Result<Sim3, opencv::Error> {
+    Ok(Sim3::from_se3(&pose_to_isometry(pose)?.into(), 1.0))
+}
+
+/// Converts a world to camera similarity back to a rigid 4x4 CV_64F pose, see
+/// `Sim3::to_se3`.
+pub(crate) fn sim3_to_pose(sim3: &Sim3) -> Result<Mat, opencv::Error> {
+    isometry_to_pose(&sim3.to_se3().into())
+}
+
+/// Squared pixel distance between the projection of a camera-frame point and
+/// an undistorted observation.
+pub(crate) fn reprojection_error_sq(camera: &Camera, point: &[f64; 3], observed: &Point2d) -> f64 {
+    let inv_z = 1.0 / point[2];
+    let u = camera.fx * point[0] * inv_z + camera.cx;
+    let v = camera.fy * point[1] * inv_z + camera.cy;
+    (u - observed.x).powi(2) + (v - observed.y).powi(2)
+}
+
+/// Builds a 3x1 CV_64F column vector.
+pub(crate) fn column3(v: [f64; 3]) -> Result<Mat, opencv::Error> {
+    Mat::from_slice_2d(&[[v[0]], [v[1]], [v[2]]])
+}
+
+/// Undistorts keypoint locations, keeping them in pixels.
+pub(crate) fn undistorted_keypoints(
+    camera: &Camera,
+    keypoints: &Vector<KeyPoint>,
+) -> Result<Vec<Point2d>, opencv::Error> {
+    if keypoints.is_empty() {
+        return Ok(Vec::new());
+    }
+    let points = Vector::<Point2f>::from_iter(keypoints.iter().map(|k| k.pt()));
+    Ok(to_pixels(camera, camera.undistort_points(&points)?))
+}
+
+/// Maps normalized image coordinates back to pixels.
+fn to_pixels(camera: &Camera, points: Vector<Point2f>) -> Vec<Point2d> {
+    points
+        .iter()
+        .map(|p| {
+            Point2d::new(
+                camera.fx * p.x as f64 + camera.cx,
+                camera.fy * p.y as f64 + camera.cy,
+            )
+        })
+        .collect()
+}
+
+/// Hamming distance between two binary descriptors.
+pub(crate) fn hamming(a: &[u8], b: &[u8]) -> u32 {
+    a.iter().zip(b).map(|(x, y)| (x ^ y).count_ones()).sum()
+}
+
+/// Number of differing bit pairs, the distance ORB uses for `wta_k` above 2.
+pub(crate) fn hamming2(a: &[u8], b: &[u8]) -> u32 {
+    a.iter()
+        .zip(b)
+        .map(|(x, y)| {
+            let diff = x ^ y;
+            ((diff | (diff >> 1)) & 0b0101_0101).count_ones()
+        })
+        .sum()
+}
+
+/// Euclidean distance between two float descriptors.
+pub(crate) fn l2(a: &[f32], b: &[f32]) -> f32 {
+    a.iter()
+        .zip(b)
+        .map(|(x, y)| (x - y) * (x - y))
+        .sum::<f32>()
+        .sqrt()
+}
+
+/// Looks up matched keypoints and returns them undistorted, in pixels.
+///
+/// `query_idx` indexes `frame1` and `train_idx` indexes `frame2`.
+pub(crate) fn undistorted_matches(
+    camera: &Camera,
+    frame1: &Frame,
+    frame2: &Frame,
+    matches: &Vector<DMatch>,
+) -> Result<(Vec<Point2d>, Vec<Point2d>), opencv::Error> {
+    let mut points1 = Vector::<Point2f>::with_capacity(matches.len());
+    let mut points2 = Vector::<Point2f>::with_capacity(matches.len());
+    for m in matches.iter() {
+        points1.push(frame1.keypoints.get(m.query_idx as usize)?.pt());
+        points2.push(frame2.keypoints.get(m.train_idx as usize)?.pt());
+    }
+
+    Ok((
+        to_pixels(camera, camera.undistort_points(&points1)?),
+        to_pixels(camera, camera.undistort_points(&points2)?),
+    ))
+}
+
+pub(crate) fn mul3(a: &[[f64; 3]; 3], b: &[[f64; 3]; 3]) -> [[f64; 3]; 3] {
+    let mut out = [[0.0; 3]; 3];
+    for r in 0..3 {
+        for c in 0..3 {
+            out[r][c] = (0..3).map(|i| a[r][i] * b[i][c]).sum();
+        }
+    }
+    out
+}
+
+pub(crate) fn mul3v(a: &[[f64; 3]; 3], v: &[f64; 3]) -> [f64; 3] {
+    [dot3(&a[0], v), dot3(&a[1], v), dot3(&a[2], v)]
+}
+
+pub(crate) fn transpose3(a: &[[f64; 3]; 3]) -> [[f64; 3]; 3] {
+    let mut out = [[0.0; 3]; 3];
+    for r in 0..3 {
+        for c in 0..3 {
+            out[r][c] = a[c][r];
+        }
+    }
+    out
+}
+
+pub(crate) fn invert3(a: &[[f64; 3]; 3]) -> Option<[[f64; 3]; 3]> {
+    let cofactor =
+        |r0: usize, r1: usize, c0: usize, c1: usize| a[r0][c0] * a[r1][c1] - a[r0][c1] * a[r1][c0];
+    let det = a[0][0] * cofactor(1, 2, 1, 2) - a[0][1] * cofactor(1, 2, 0, 2)
+        + a[0][2] * cofactor(1, 2, 0, 1);
+    if det.abs() < f64::EPSILON {
+        return None;
+    }
+    let inv_det = 1.0 / det;
+    Some([
+        [
+            cofactor(1, 2, 1, 2) * inv_det,
+            -cofactor(0, 2, 1, 2) * inv_det,
+            cofactor(0, 1, 1, 2) * inv_det,
+        ],
+        [
+            -cofactor(1, 2, 0, 2) * inv_det,
+            cofactor(0, 2, 0, 2) * inv_det,
+            -cofactor(0, 1, 0, 2) * inv_det,
+        ],
+        [
+            cofactor(1, 2, 0, 1) * inv_det,
+            -cofactor(0, 2, 0, 1) * inv_det,
+            cofactor(0, 1, 0, 1) * inv_det,
+        ],
+    ])
+}
+
+pub(crate) fn dot3(a: &[f64; 3], b: &[f64; 3]) -> f64 {
+    a[0] * b[0] + a[1] * b[1] + a[2] * b[2]
+}
+
+pub(crate) fn add3(a: &[f64; 3], b: &[f64; 3]) -> [f64; 3] {
+    [a[0] + b[0], a[1] + b[1], a[2] + b[2]]
+}
+
+pub(crate) fn sub3(a: &[f64; 3], b: &[f64; 3]) -> [f64; 3] {
+    [a[0] - b[0], a[1] - b[1], a[2] - b[2]]
+}
+
+pub(crate) fn scale3(a: &[f64; 3], s: f64) -> [f64; 3] {
+    [a[0] * s, a[1] * s, a[2] * s]
+}
+
+pub(crate) fn norm3(a: &[f64; 3]) -> f64 {
+    dot3(a, a).sqrt()
+}
+
+/// Small deterministic generator for seeding and RANSAC sampling, so results
+/// are repeatable without an extra dependency.
+pub(crate) struct SplitMix64(pub(crate) u64);
+
+impl SplitMix64 {
+    pub(crate) fn next(&mut self) -> u64 {
+        self.0 = self.0.wrapping_add(0x9e37_79b9_7f4a_7c15);
+        let mut z = self.0;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        z ^ (z >> 31)
+    }
+
+    /// Uniform in `[0, 1)`.
+    pub(crate) fn unit(&mut self) -> f64 {
+        (self.next() >> 11) as f64 / (1u64 << 53) as f64
+    }
+
+    pub(crate) fn below(&mut self, n: usize) -> usize {
+        (self.next() % n as u64) as usize
+    }
+}
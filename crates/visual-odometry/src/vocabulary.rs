@@ -0,0 +1,711 @@
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::io::{BufReader, BufWriter, Read, Write};
+use std::path::Path;
+
+use opencv::core::{CV_8U, Mat};
+use opencv::prelude::*;
+
+use crate::frame::Frame;
+use crate::utils::{self, SplitMix64};
+
+pub type WordId = u32;
+pub type NodeId = u32;
+
+/// Bytes in an ORB descriptor.
+pub const DESCRIPTOR_BYTES: usize = 32;
+pub type Descriptor = [u8; DESCRIPTOR_BYTES];
+
+const MAGIC: &[u8; 8] = b"RSLAMVOC";
+const VERSION: u32 = 1;
+const ROOT: NodeId = 0;
+/// Most nodes a vocabulary file may declare, well above the million words of
+/// the usual ORB vocabularies. Guards the allocation against corrupt headers.
+const MAX_NODES: usize = 1 << 24;
+
+#[derive(Debug, thiserror::Error)]
+pub enum VocabularyError {
+    #[error("IO error: {0}")]
+    Io(#[from] std::io::Error),
+    #[error("OpenCV error: {0}")]
+    OpenCv(#[from] opencv::Error),
+    #[error("Invalid vocabulary file: {0}")]
+    InvalidFormat(String),
+    #[error("Expected {DESCRIPTOR_BYTES} byte CV_8U descriptors, got {cols} columns of type {typ}")]
+    InvalidDescriptors { cols: i32, typ: i32 },
+    #[error("No descriptors to train on")]
+    Empty,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct VocabularyConfig {
+    /// Children per node.
+    pub branching: usize,
+    /// Levels below the root; words are the leaves.
+    pub depth: usize,
+    /// k-medians iterations per node.
+    pub max_iterations: usize,
+    /// Seed for the k-medians++ initialization, so training is repeatable.
+    pub seed: u64,
+}
+
+impl Default for VocabularyConfig {
+    fn default() -> Self {
+        Self {
+            branching: 10,
+            depth: 5,
+            max_iterations: 20,
+            seed: 0x5eed,
+        }
+    }
+}
+
+/// TF-IDF weighted word histogram of an image, L1 normalized.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct BowVector(BTreeMap<WordId, f64>);
+
+impl BowVector {
+    pub fn get(&self, word: WordId) -> Option<f64> {
+        self.0.get(&word).copied()
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (WordId, f64)> + '_ {
+        self.0.iter().map(|(&w, &v)| (w, v))
+    }
+
+    pub fn len(&self) -> usize {
+        self.0.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    /// L1 similarity in `[0, 1]`, 1 for identical vectors.
+    pub fn score(&self, other: &BowVector) -> f64 {
+        let mut a = self.0.iter().peekable();
+        let mut b = other.0.iter().peekable();
+        let mut score = 0.0;
+        while let (Some(&(wa, va)), Some(&(wb, vb))) = (a.peek(), b.peek()) {
+            match wa.cmp(wb) {
+                std::cmp::Ordering::Less => {
+                    a.next();
+                }
+                std::cmp::Ordering::Greater => {
+                    b.next();
+                }
+                std::cmp::Ordering::Equal => {
+                    score += va.abs() + vb.abs() - (va - vb).abs();
+                    a.next();
+                    b.next();
+                }
+            }
+        }
+        0.5 * score
+    }
+}
+
+/// Direct index: features of an image grouped by the vocabulary node they
+/// pass through at a fixed level, so matching can be restricted to features
+/// sharing a node.
+pub type FeatureVector = BTreeMap<NodeId, Vec<usize>>;
+
+#[derive(Debug, Clone, PartialEq)]
+struct Node {
+    parent: NodeId,
+    children: Vec<NodeId>,
+    descriptor: Descriptor,
+    /// IDF weight, for words.
+    weight: f64,
+    word: Option<WordId>,
+}
+
+/// Hierarchical vocabulary of binary words, trained with k-medians.
+///
+/// Descriptors are quantized by walking down the tree to the closest child by
+/// Hamming distance. Leaves are words and carry an inverse document frequency
+/// weight from the training images.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Vocabulary {
+    branching: usize,
+    depth: usize,
+    nodes: Vec<Node>,
+    /// Node of each word.
+    words: Vec<NodeId>,
+}
+
+impl Vocabulary {
+    /// Trains a vocabulary on the descriptors of a set of images, one entry
+    /// per image. The grouping only matters for the IDF weights.
+    pub fn train(
+        images: &[Vec<Descriptor>],
+        config: &VocabularyConfig,
+    ) -> Result<Self, VocabularyError> {
+        let descriptors: Vec<&Descriptor> = images.iter().flatten().collect();
+        if descriptors.is_empty() {
+            return Err(VocabularyError::Empty);
+        }
+
+        let mut vocabulary = Self {
+            branching: config.branching.max(2),
+            depth: config.depth.max(1),
+            nodes: vec![Node {
+                parent: ROOT,
+                children: Vec::new(),
+                descriptor: [0; DESCRIPTOR_BYTES],
+                weight: 0.0,
+                word: None,
+            }],
+            words: Vec::new(),
+        };
+        let mut rng = SplitMix64(config.seed);
+        let mut pending = vec![(ROOT, descriptors, 0)];
+        while let Some((parent, members, level)) = pending.pop() {
+            let clusters = k_medians(
+                &members,
+                vocabulary.branching,
+                config.max_iterations,
+                &mut rng,
+            );
+            for (centre, cluster) in clusters {
+                let id = vocabulary.nodes.len() as NodeId;
+                vocabulary.nodes.push(Node {
+                    parent,
+                    children: Vec::new(),
+                    descriptor: centre,
+                    weight: 0.0,
+                    word: None,
+                });
+                vocabulary.nodes[parent as usize].children.push(id);
+                if level + 1 < vocabulary.depth && cluster.len() > 1 {
+                    let members = cluster.iter().map(|&i| members[i]).collect();
+                    pending.push((id, members, level + 1));
+                }
+            }
+        }
+        vocabulary.assign_words();
+
+        // Inverse document frequency, counting each word once per image.
+        let mut images_with_word = vec![0usize; vocabulary.words.len()];
+        for image in images {
+            let mut seen: Vec<WordId> = image.iter().map(|d| vocabulary.lookup(d, 0).0).collect();
+            seen.sort_unstable();
+            seen.dedup();
+            for word in seen {
+                images_with_word[word as usize] += 1;
+            }
+        }
+        let num_images = images.len() as f64;
+        for (word, &count) in images_with_word.iter().enumerate() {
+            let node = vocabulary.words[word] as usize;
+            vocabulary.nodes[node].weight = if count > 0 {
+                (num_images / count as f64).ln()
+            } else {
+                0.0
+            };
+        }
+
+        Ok(vocabulary)
+    }
+
+    pub fn num_words(&self) -> usize {
+        self.words.len()
+    }
+
+    pub fn branching(&self) -> usize {
+        self.branching
+    }
+
+    pub fn depth(&self) -> usize {
+        self.depth
+    }
+
+    /// Quantizes descriptors into a BoW vector and a direct index over the
+    /// nodes `levels_up` levels above the words.
+    pub fn transform(
+        &self,
+        descriptors: &[Descriptor],
+        levels_up: usize,
+    ) -> (BowVector, FeatureVector) {
+        let mut bow = BTreeMap::new();
+        let mut features = FeatureVector::new();
+        for (i, descriptor) in descriptors.iter().enumerate() {
+            let (word, node) = self.lookup(descriptor, levels_up);
+            let weight = self.nodes[self.words[word as usize] as usize].weight;
+            if weight > 0.0 {
+                *bow.entry(word).or_insert(0.0) += weight;
+            }
+            features.entry(node).or_default().push(i);
+        }
+
+        let norm: f64 = bow.values().map(|v: &f64| v.abs()).sum();
+        if norm > 0.0 {
+            bow.values_mut().for_each(|v| *v /= norm);
+        }
+        (BowVector(bow), features)
+    }
+
+    /// `transform` over the descriptors of a frame.
+    pub fn transform_frame(
+        &self,
+        frame: &Frame,
+        levels_up: usize,
+    ) -> Result<(BowVector, FeatureVector), VocabularyError> {
+        Ok(self.transform(&descriptors_from_mat(&frame.descriptors)?, levels_up))
+    }
+
+    /// Word of `descriptor`, and the node it passes through `levels_up`
+    /// levels above the leaves.
+    pub fn lookup(&self, descriptor: &Descriptor, levels_up: usize) -> (WordId, NodeId) {
+        let target_level = self.depth.saturating_sub(levels_up);
+        let mut node = ROOT;
+        let mut level = 0;
+        let mut at_level = ROOT;
+        loop {
+            let current = &self.nodes[node as usize];
+            if let Some(word) = current.word {
+                // Shallow leaves count as their own ancestor at deeper levels.
+                if level < target_level {
+                    at_level = node;
+                }
+                return (word, at_level);
+            }
+            node = *current
+                .children
+                .iter()
+                .min_by_key(|&&child| {
+                    utils::hamming(descriptor, &self.nodes[child as usize].descriptor)
+                })
+                .unwrap_or(&node);
+            level += 1;
+            if level == target_level {
+                at_level = node;
+            }
+        }
+    }
+
+    pub fn save(&self, path: impl AsRef<Path>) -> Result<(), VocabularyError> {
+        let mut writer = BufWriter::new(File::create(path)?);
+        self.write_to(&mut writer)?;
+        writer.flush()?;
+        Ok(())
+    }
+
+    pub fn load(path: impl AsRef<Path>) -> Result<Self, VocabularyError> {
+        Self::read_from(&mut BufReader::new(File::open(path)?))
+    }
+
+    /// Writes the little-endian binary format: a header, then every node
+    /// after the root as parent, IDF weight, word flag and descriptor.
+    pub fn write_to(&self, writer: &mut impl Write) -> Result<(), VocabularyError> {
+        writer.write_all(MAGIC)?;
+        writer.write_all(&VERSION.to_le_bytes())?;
+        writer.write_all(&(self.branching as u32).to_le_bytes())?;
+        writer.write_all(&(self.depth as u32).to_le_bytes())?;
+        writer.write_all(&(self.nodes.len() as u32).to_le_bytes())?;
+        for node in &self.nodes[1..] {
+            writer.write_all(&node.parent.to_le_bytes())?;
+            writer.write_all(&node.weight.to_le_bytes())?;
+            writer.write_all(&[node.word.is_some() as u8])?;
+            writer.write_all(&node.descriptor)?;
+        }
+        Ok(())
+    }
+
+    pub fn read_from(reader: &mut impl Read) -> Result<Self, VocabularyError> {
+        let mut magic = [0u8; 8];
+        reader.read_exact(&mut magic)?;
+        if &magic != MAGIC {
+            return Err(VocabularyError::InvalidFormat("bad magic".to_string()));
+        }
+        let version = read_u32(reader)?;
+        if version != VERSION {
+            return Err(VocabularyError::InvalidFormat(format!(
+                "unsupported version {version}"
+            )));
+        }
+        let branching = read_u32(reader)? as usize;
+        let depth = read_u32(reader)? as usize;
+        let num_nodes = read_u32(reader)? as usize;
+        if num_nodes < 2 {
+            return Err(VocabularyError::InvalidFormat("no words".to_string()));
+        }
+        if num_nodes > tree_size(branching, depth, MAX_NODES) {
+            return Err(VocabularyError::InvalidFormat(format!(
+                "{num_nodes} nodes do not fit a tree of branching {branching} and depth {depth}"
+            )));
+        }
+
+        let mut vocabulary = Self {
+            branching,
+            depth,
+            nodes: Vec::with_capacity(num_nodes),
+            words: Vec::new(),
+        };
+        vocabulary.nodes.push(Node {
+            parent: ROOT,
+            children: Vec::new(),
+            descriptor: [0; DESCRIPTOR_BYTES],
+            weight: 0.0,
+            word: None,
+        });
+        let mut is_word = Vec::with_capacity(num_nodes);
+        is_word.push(false);
+        for id in 1..num_nodes {
+            let parent = read_u32(reader)?;
+            if parent as usize >= id {
+                return Err(VocabularyError::InvalidFormat(format!(
+                    "node {id} has parent {parent}"
+                )));
+            }
+            let mut weight = [0u8; 8];
+            reader.read_exact(&mut weight)?;
+            let mut flag = [0u8; 1];
+            reader.read_exact(&mut flag)?;
+            let mut descriptor = [0u8; DESCRIPTOR_BYTES];
+            reader.read_exact(&mut descriptor)?;
+
+            vocabulary.nodes.push(Node {
+                parent,
+                children: Vec::new(),
+                descriptor,
+                weight: f64::from_le_bytes(weight),
+                word: None,
+            });
+            vocabulary.nodes[parent as usize]
+                .children
+                .push(id as NodeId);
+            is_word.push(flag[0] != 0);
+        }
+
+        vocabulary.assign_words();
+        let leaves_match = vocabulary
+            .nodes
+            .iter()
+            .zip(&is_word)
+            .all(|(node, &flag)| node.word.is_some() == flag);
+        if !leaves_match {
+            return Err(VocabularyError::InvalidFormat(
+                "word flags do not match the tree".to_string(),
+            ));
+        }
+        Ok(vocabulary)
+    }
+
+    /// Numbers the leaves in node order.
+    fn assign_words(&mut self) {
+        self.words.clear();
+        for (id, node) in self.nodes.iter_mut().enumerate().skip(1) {
+            node.word = node.children.is_empty().then(|| {
+                self.words.push(id as NodeId);
+                (self.words.len() - 1) as WordId
+            });
+        }
+    }
+}
+
+/// Copies the rows of a CV_8U descriptor matrix with 32 columns.
+pub fn descriptors_from_mat(descriptors: &Mat) -> Result<Vec<Descriptor>, VocabularyError> {
+    if descriptors.empty() {
+        return Ok(Vec::new());
+    }
+    if descriptors.typ() != CV_8U || descriptors.cols() as usize != DESCRIPTOR_BYTES {
+        return Err(VocabularyError::InvalidDescriptors {
+            cols: descriptors.cols(),
+            typ: descriptors.typ(),
+        });
+    }
+    let mut out = Vec::with_capacity(descriptors.rows() as usize);
+    for row in 0..descriptors.rows() {
+        let mut descriptor = [0u8; DESCRIPTOR_BYTES];
+        descriptor.copy_from_slice(descriptors.at_row::<u8>(row)?);
+        out.push(descriptor);
+    }
+    Ok(out)
+}
+
+/// Matches two descriptor sets by comparing only descriptors that share a
+/// vocabulary node, as indexed by their feature vectors. Pairs for which
+/// `usable(index1, index2)` returns false are skipped. Returns `(index1, index2)` pairs that
+/// pass the distance and ratio tests, with every index used at most once.
+pub(crate) fn search_by_bow(
+    features1: &FeatureVector,
+    descriptors1: &Mat,
+    features2: &FeatureVector,
+    descriptors2: &Mat,
+    usable: impl Fn(usize, usize) -> bool,
+    max_distance: u32,
+    ratio: f32,
+) -> Result<Vec<(usize, usize)>, opencv::Error> {
+    // index2 -> (index1, distance)
+    let mut best_for: BTreeMap<usize, (usize, u32)> = BTreeMap::new();
+    for (node, indices1) in features1 {
+        let Some(indices2) = features2.get(node) else {
+            continue;
+        };
+        for &i in indices1 {
+            let descriptor = descriptors1.at_row::<u8>(i as i32)?;
+            let mut best: Option<(usize, u32)> = None;
+            let mut second = u32::MAX;
+            for &j in indices2.iter().filter(|&&j| usable(i, j)) {
+                let distance = utils::hamming(descriptor, descriptors2.at_row::<u8>(j as i32)?);
+                match best {
+                    Some((_, d)) if distance >= d => second = second.min(distance),
+                    _ => {
+                        second = best.map_or(second, |(_, d)| d);
+                        best = Some((j, distance));
+                    }
+                }
+            }
+
+            let Some((j, distance)) = best else {
+                continue;
+            };
+            if distance > max_distance || distance as f32 >= ratio * second as f32 {
+                continue;
+            }
+            match best_for.get(&j) {
+                Some(&(_, existing)) if existing <= distance => {}
+                _ => {
+                    best_for.insert(j, (i, distance));
+                }
+            }
+        }
+    }
+
+    let mut matches: Vec<(usize, usize)> = best_for.into_iter().map(|(j, (i, _))| (i, j)).collect();
+    matches.sort_unstable();
+    Ok(matches)
+}
+
+fn read_u32(reader: &mut impl Read) -> Result<u32, VocabularyError> {
+    let mut bytes = [0u8; 4];
+    reader.read_exact(&mut bytes)?;
+    Ok(u32::from_le_bytes(bytes))
+}
+
+/// Nodes in a full tree of `branching` children per node and `depth` levels
+/// below the root, counting no further than `limit`.
+fn tree_size(branching: usize, depth: usize, limit: usize) -> usize {
+    let mut level = 1usize;
+    let mut size = 1usize;
+    for _ in 0..depth {
+        level = level.saturating_mul(branching);
+        size = size.saturating_add(level);
+        if level == 0 || size >= limit {
+            break;
+        }
+    }
+    size.min(limit)
+}
+
+/// Clusters binary descriptors around `k` medians, seeded k-means++ style.
+/// Returns each non-empty cluster's centre and member indices.
+fn k_medians(
+    descriptors: &[&Descriptor],
+    k: usize,
+    max_iterations: usize,
+    rng: &mut SplitMix64,
+) -> Vec<(Descriptor, Vec<usize>)> {
+    if descriptors.len() <= k {
+        return descriptors
+            .iter()
+            .enumerate()
+            .map(|(i, &&d)| (d, vec![i]))
+            .collect();
+    }
+
+    let mut centres: Vec<Descriptor> = vec![*descriptors[rng.below(descriptors.len())]];
+    let mut nearest: Vec<f64> = descriptors
+        .iter()
+        .map(|d| utils::hamming(*d, &centres[0]) as f64)
+        .collect();
+    while centres.len() < k {
+        let total: f64 = nearest.iter().map(|d| d * d).sum();
+        if total == 0.0 {
+            break;
+        }
+        let mut target = rng.unit() * total;
+        let mut pick = descriptors.len() - 1;
+        for (i, d) in nearest.iter().enumerate() {
+            target -= d * d;
+            if target <= 0.0 {
+                pick = i;
+                break;
+            }
+        }
+        let centre = *descriptors[pick];
+        for (d, distance) in descriptors.iter().zip(nearest.iter_mut()) {
+            *distance = distance.min(utils::hamming(*d, &centre) as f64);
+        }
+        centres.push(centre);
+    }
+
+    let mut assignment = vec![usize::MAX; descriptors.len()];
+    for _ in 0..max_iterations.max(1) {
+        let mut changed = false;
+        for (d, slot) in descriptors.iter().zip(assignment.iter_mut()) {
+            let closest = (0..centres.len())
+                .min_by_key(|&c| utils::hamming(*d, &centres[c]))
+                .unwrap_or(0);
+            if *slot != closest {
+                *slot = closest;
+                changed = true;
+            }
+        }
+        if !changed {
+            break;
+        }
+
+        // Bitwise majority is the median under the Hamming distance.
+        let mut ones = vec![[0u32; DESCRIPTOR_BYTES * 8]; centres.len()];
+        let mut counts = vec![0u32; centres.len()];
+        for (d, &c) in descriptors.iter().zip(&assignment) {
+            counts[c] += 1;
+            for (bit, count) in ones[c].iter_mut().enumerate() {
+                *count += ((d[bit / 8] >> (bit % 8)) & 1) as u32;
+            }
+        }
+        for (c, centre) in centres.iter_mut().enumerate() {
+            if counts[c] == 0 {
+                continue;
+            }
+            *centre = [0; DESCRIPTOR_BYTES];
+            for (bit, &count) in ones[c].iter().enumerate() {
+                if 2 * count > counts[c] {
+                    centre[bit / 8] |= 1 << (bit % 8);
+                }
+            }
+        }
+    }
+
+    let mut clusters: Vec<(Descriptor, Vec<usize>)> =
+        centres.into_iter().map(|c| (c, Vec::new())).collect();
+    for (i, &c) in assignment.iter().enumerate() {
+        clusters[c].1.push(i);
+    }
+    clusters.retain(|(_, members)| !members.is_empty());
+    clusters
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeSet;
+
+    use super::*;
+
+    /// Images drawing descriptors from a few well separated prototypes, each
+    /// with a couple of bits flipped.
+    fn images() -> (Vec<Descriptor>, Vec<Vec<Descriptor>>) {
+        let mut rng = SplitMix64(7);
+        let prototypes: Vec<Descriptor> = (0..8)
+            .map(|_| {
+                let mut d = [0u8; DESCRIPTOR_BYTES];
+                d.iter_mut().for_each(|b| *b = rng.next() as u8);
+                d
+            })
+            .collect();
+        let images = (0..6)
+            .map(|image| {
+                (0..40)
+                    .map(|i| {
+                        // Consecutive images overlap in two of their three
+                        // prototypes.
+                        let p = (image + i % 3) % prototypes.len();
+                        let mut d = prototypes[p];
+                        let bit = rng.below(256);
+                        d[bit / 8] ^= 1 << (bit % 8);
+                        d
+                    })
+                    .collect()
+            })
+            .collect();
+        (prototypes, images)
+    }
+
+    fn config() -> VocabularyConfig {
+        VocabularyConfig {
+            branching: 4,
+            depth: 3,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_words_do_not_mix_prototypes() {
+        let (prototypes, images) = images();
+        let vocabulary = Vocabulary::train(&images, &config()).unwrap();
+
+        let mut prototype_of_word = BTreeMap::new();
+        for d in images.iter().flatten() {
+            let closest = (0..prototypes.len())
+                .min_by_key(|&p| utils::hamming(&prototypes[p], d))
+                .unwrap();
+            let word = vocabulary.lookup(d, 0).0;
+            assert_eq!(*prototype_of_word.entry(word).or_insert(closest), closest);
+        }
+        assert_eq!(
+            prototype_of_word.values().collect::<BTreeSet<_>>().len(),
+            prototypes.len()
+        );
+    }
+
+    #[test]
+    fn test_bow_vectors_score_similar_images_higher() {
+        let (_, images) = images();
+        let vocabulary = Vocabulary::train(&images, &config()).unwrap();
+        let (a, features) = vocabulary.transform(&images[0], 1);
+        let (b, _) = vocabulary.transform(&images[1], 1);
+        let (c, _) = vocabulary.transform(&images[3], 1);
+
+        assert!((a.iter().map(|(_, v)| v).sum::<f64>() - 1.0).abs() < 1e-12);
+        assert!((a.score(&a) - 1.0).abs() < 1e-12);
+        assert!(a.score(&b) > a.score(&c));
+        assert_eq!(
+            features.values().map(Vec::len).sum::<usize>(),
+            images[0].len()
+        );
+    }
+
+    #[test]
+    fn test_binary_round_trip() {
+        let (_, images) = images();
+        let vocabulary = Vocabulary::train(&images, &config()).unwrap();
+
+        let mut bytes = Vec::new();
+        vocabulary.write_to(&mut bytes).unwrap();
+        let loaded = Vocabulary::read_from(&mut bytes.as_slice()).unwrap();
+        assert_eq!(loaded, vocabulary);
+
+        bytes[0] = b'X';
+        assert!(matches!(
+            Vocabulary::read_from(&mut bytes.as_slice()),
+            Err(VocabularyError::InvalidFormat(_))
+        ));
+    }
+
+    #[test]
+    fn test_rejects_node_count_beyond_tree() {
+        let (_, images) = images();
+        let vocabulary = Vocabulary::train(&images, &config()).unwrap();
+        let mut bytes = Vec::new();
+        vocabulary.write_to(&mut bytes).unwrap();
+
+        // Magic, version, branching and depth come before the node count.
+        let offset = MAGIC.len() + 3 * 4;
+        for num_nodes in [
+            tree_size(config().branching, config().depth, MAX_NODES) + 1,
+            u32::MAX as usize,
+        ] {
+            let mut corrupt = bytes.clone();
+            corrupt[offset..offset + 4].copy_from_slice(&(num_nodes as u32).to_le_bytes());
+            assert!(
+                matches!(
+                    Vocabulary::read_from(&mut corrupt.as_slice()),
+                    Err(VocabularyError::InvalidFormat(_))
+                ),
+                "{num_nodes} nodes"
+            );
+        }
+    }
+}